@@ -0,0 +1,197 @@
+/*!
+
+  Whole-program benchmarking, for the `--bench` mode of the command line. `bench` evaluates a
+  program a number of times with `halt` bound to `Store::default_handler`, and reports the
+  wall-clock time the runs took and the number of continuations each run applied. The
+  applications are counted with `run_profiled` (see `Profile::applies`), so they do not depend on
+  the speed of the machine.
+
+  Every run of a program that does not depend on the clock applies the same number of
+  continuations to reach the same values. `BenchReport::consistent` records whether that held,
+  which guards the harness itself: a benchmark whose runs disagree is measuring something other
+  than the program.
+
+  `update_loop` and `nested_binds` build workloads for the two costs that dominate long runs:
+  writes to the store and bindings in the environment. Both are persistent, so a write copies a
+  path of the store rather than the whole store, and a binding shares the rest of its scope.
+  `BenchReport::cells_copied` measures the first independently of the machine.
+
+*/
+
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::ContinuationExpression,
+  cps::{
+    denotable_value::DenotableValueList,
+    store::Store
+  },
+  parser::parse,
+  primitive_op::PrimitiveOp,
+  profile::run_profiled,
+  value::Value
+};
+
+/// The number of cells of the store each run starts with.
+const STORE_SIZE: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+  pub iterations  : u32,
+  /// The wall-clock time of all runs together.
+  pub total       : Duration,
+  /// The number of continuations the first run applied. See `Profile::applies`.
+  pub applies     : u64,
+  /// The number of slots of the store the writes of the first run copied. See
+  /// `Profile::cells_copied`.
+  pub cells_copied: u64,
+  /// The final values of the first run.
+  pub values      : DenotableValueList,
+  /// Whether every run applied as many continuations to reach the same final values as the first.
+  pub consistent  : bool,
+}
+
+impl BenchReport {
+  /// The wall-clock time of one run, on average.
+  pub fn average(&self) -> Duration {
+    self.total / self.iterations.max(1)
+  }
+}
+
+/// Displays the report on one line of `key=value` pairs, for example
+///
+/// ```text
+/// iterations=10 total_ms=12.500 average_us=1250.000 applies=4003 cells_copied=0 consistent=true
+/// ```
+impl Display for BenchReport {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "iterations={} total_ms={:.3} average_us={:.3} applies={} cells_copied={} consistent={}",
+      self.iterations,
+      self.total.as_secs_f64() * 1e3,
+      self.average().as_secs_f64() * 1e6,
+      self.applies,
+      self.cells_copied,
+      self.consistent
+    )
+  }
+}
+
+/// Evaluates `expression` `iterations` times, which must be at least one, and reports the time
+/// and the applications the runs took.
+pub fn bench(expression: &ContinuationExpression, iterations: u32) -> BenchReport {
+  assert!(iterations > 0, "a benchmark needs at least one iteration");
+
+  let mut first: Option<(String, DenotableValueList, u64, u64)> = None;
+  let mut consistent = true;
+  let mut total      = Duration::ZERO;
+
+  for _ in 0..iterations {
+    let start = Instant::now();
+    let (values, profile) = run_profiled(
+      vec![Variable::new("halt")],
+      vec![Store::default_handler()],
+      expression.clone(),
+      STORE_SIZE
+    ).expect("one variable is bound to one value");
+    total += start.elapsed();
+
+    // Values are compared by their rendering, because `DValue` equality on strings and records
+    // is only conservative.
+    let rendered = format!("{:?}", values);
+    match &first {
+      None => first = Some((rendered, values, profile.applies, profile.cells_copied)),
+      Some((first_rendered, _, first_applies, _)) => {
+        consistent &= *first_rendered == rendered && *first_applies == profile.applies;
+      }
+    }
+  }
+
+  let (_, values, applies, cells_copied) = first.unwrap();
+  BenchReport { iterations, total, applies, cells_copied, values, consistent }
+}
+
+/// A program that makes an array of `cells` cells and writes each of them once, from the last to
+/// the first, then halts with the first. Each write produces a new store.
+pub fn update_loop(cells: usize) -> ContinuationExpression {
+  parse(&format!(
+    "(fix ((loop (a i)
+             (primop > (i 0) () (
+               (primop - (i 1) (j) ((primop update (a j j) () ((app loop a j)))))
+               (primop subscript (a 0) (x) ((app halt x)))))))
+       (primop mkarray ({} 1) (a) ((app loop a {}))))",
+    cells,
+    cells
+  )).expect("the update loop parses")
+}
+
+/// A program of `depth` nested scopes, each binding one more variable, `v1` to `v{depth}`, to the
+/// successor of the last, then halting with the last. It is built directly rather than parsed,
+/// because it nests deeper than the parser accepts.
+pub fn nested_binds(depth: usize) -> ContinuationExpression {
+  let variable = | i: usize | Variable::new(&format!("v{}", i));
+  let mut expression = ContinuationExpression::Apply {
+    function : Value::Variable(Variable::new("halt")),
+    arguments: vec![Value::Variable(variable(depth))]
+  };
+  for i in (0..depth).rev() {
+    let previous = if i == 0 { Value::Integer(0) } else { Value::Variable(variable(i)) };
+    expression = ContinuationExpression::PrimitiveOp {
+      operation  : PrimitiveOp::Add,
+      values     : vec![previous, Value::Integer(1)],
+      variables  : vec![variable(i + 1)],
+      expressions: vec![Box::new(expression)]
+    };
+  }
+  expression
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn repeated_runs_agree() {
+    let program = parse(
+      "(fix ((loop (i acc)
+               (primop + (acc i) (acc2) (
+                 (primop - (i 1) (j) (
+                   (primop > (j 0) () ((app loop j acc2) (app halt acc2)))))))))
+         (app loop 100 0))"
+    ).unwrap();
+
+    let report = bench(&program, 5);
+    assert!(report.consistent);
+    assert_eq!(format!("{:?}", report.values), "[5050]");
+    assert!(report.applies >= 100);
+    assert_eq!(bench(&program, 1).applies, report.applies);
+
+    let line = report.to_string();
+    assert!(line.starts_with("iterations=5 total_ms="));
+    assert!(line.ends_with(&format!(" applies={} cells_copied=0 consistent=true", report.applies)));
+  }
+
+  #[test]
+  fn a_write_copies_a_path_of_the_store_rather_than_the_store() {
+    let cells  = 4096;
+    let report = bench(&update_loop(cells), 2);
+    assert!(report.consistent);
+    assert_eq!(format!("{:?}", report.values), "[0]");
+    // Copying the whole store on every write would copy at least `cells` slots per write, where
+    // copying a path of the trie copies a few nodes.
+    assert!(report.cells_copied < 128 * cells as u64, "{}", report);
+  }
+
+  #[test]
+  fn a_thousand_nested_bindings_run() {
+    // Not deeper, because cloning an expression recurses on its depth.
+    let depth  = 1000;
+    let report = bench(&nested_binds(depth), 2);
+    assert!(report.consistent);
+    assert_eq!(format!("{:?}", report.values), format!("[{}]", depth));
+    assert!(report.applies >= depth as u64, "{}", report);
+  }
+}
@@ -0,0 +1,552 @@
+/*!
+
+  A flat instruction set for CPS programs and a virtual machine that executes it without walking
+  the expression tree.
+
+  `compile` lowers a `ContinuationExpression` to a `Program`, a list of instructions in which
+  every subexpression becomes a run of instructions starting at a `Label`. `Record`, `Select`,
+  `Offset`, and `Fix` bind their variables and fall through to the next instruction, where their
+  continuation is laid out. `Switch` and `PrimitiveOp` jump to one of the labels of their arms or
+  continuations, and `Apply` becomes a `Call`, which leaves the current run of instructions for the
+  entry of the called function. Variables are kept by name in an `Environment`, so the instructions
+  are three-address code over symbolic registers.
+
+  The VM runs in a loop rather than by recursion, so calls and continuations do not grow the Rust
+  stack. Primitive operations are delegated to `PrimitiveOp::evaluate`, which means they behave
+  exactly as they do for the tree-walking evaluator. To get control back from them, the VM hands
+  them continuations that merely record where to resume and then halt (see `Answer::halt`). The
+  functions a `Fix` defines are represented the same way, so they can be stored, passed to
+  primitive operations, and installed as exception handlers like any other `DValue::Function`.
+
+  Those functions and continuations only work while the VM that made them is running, since only
+  it can act on what they record. One that escapes the VM, for example as a final value, and is
+  applied after `VM::run` has returned raises `Undefined` rather than entering the program. The
+  tree-walking evaluator has no such limitation.
+
+*/
+
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::interpreter::{
+  FieldOffset,
+  Variable,
+  VariableList,
+  continuation_expression::ContinuationExpression,
+  cps::{
+    continuation::{Answer, Continuation, ContinuationList},
+    denotable_value::{DValue, DValueList, EMPTY, resolve_field},
+    store::{AccessPath, Store}
+  },
+  environment::Environment,
+  exception::Exception,
+  primitive_op::PrimitiveOp,
+  value::{Value, ValueList}
+};
+
+/// The index of an instruction in `Program::instructions`.
+pub type Label = usize;
+/// The index of a function in `Program::functions`.
+pub type FunctionId = usize;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Instr {
+  /// Binds `dest` to a new record of the given fields and falls through.
+  Record {
+    fields: Vec<(Value, Rc<AccessPath>)>,
+    dest  : Variable
+  },
+
+  /// Binds `dest` to the field `offset` of `record` and falls through.
+  Select {
+    offset: FieldOffset,
+    record: Value,
+    dest  : Variable
+  },
+
+  /// Binds `dest` to `record` offset by `offset` fields and falls through.
+  Offset {
+    offset: FieldOffset,
+    record: Value,
+    dest  : Variable
+  },
+
+  /// Binds the names of the mutually recursive `functions` to closures over the current
+  /// environment and falls through.
+  Closures {
+    functions: Vec<FunctionId>
+  },
+
+  /// Applies `function` to `arguments`. Control does not return.
+  Call {
+    function : Value,
+    arguments: ValueList
+  },
+
+  /// Jumps to the target selected by the integer `value`.
+  Switch {
+    value  : Value,
+    targets: Vec<Label>
+  },
+
+  /// Performs `operation` on `arguments`, binds `dests` to its results, and jumps to the target
+  /// of the continuation the operation selects.
+  Primitive {
+    operation: PrimitiveOp,
+    arguments: ValueList,
+    dests    : VariableList,
+    targets  : Vec<Label>
+  },
+}
+
+/// A function defined by a `Fix`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Function {
+  pub name             : Variable,
+  pub formal_parameters: VariableList,
+  pub entry            : Label,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Program {
+  pub instructions: Vec<Instr>,
+  pub functions   : Vec<Function>,
+}
+
+impl Program {
+  /// The label at which execution of the whole program starts.
+  pub const ENTRY: Label = 0;
+}
+
+/// Lowers `expression` to a `Program` whose execution from `Program::ENTRY` has the same meaning
+/// as the evaluation of `expression`.
+pub fn compile(expression: &ContinuationExpression) -> Program {
+  let mut program = Program::default();
+  program.compile_expression(expression);
+  program
+}
+
+impl Program {
+  /// Lays out the instructions of `expression` at the end of the program, returning the label of
+  /// the first one.
+  fn compile_expression(&mut self, expression: &ContinuationExpression) -> Label {
+    let label = self.instructions.len();
+
+    match expression {
+
+      ContinuationExpression::Record { values, variable, expression } => {
+        self.instructions.push(Instr::Record { fields: values.clone(), dest: variable.clone() });
+        self.compile_expression(expression);
+      }
+
+      ContinuationExpression::Select { location, value, variable, expression } => {
+        self.instructions.push(
+          Instr::Select { offset: *location, record: value.clone(), dest: variable.clone() }
+        );
+        self.compile_expression(expression);
+      }
+
+      ContinuationExpression::Offset { location, value, variable, expression } => {
+        self.instructions.push(
+          Instr::Offset { offset: *location, record: value.clone(), dest: variable.clone() }
+        );
+        self.compile_expression(expression);
+      }
+
+      ContinuationExpression::Apply { function, arguments } => {
+        self.instructions.push(
+          Instr::Call { function: function.clone(), arguments: arguments.clone() }
+        );
+      }
+
+      ContinuationExpression::Fix { function_defs, expression: continuation } => {
+        let first_id = self.functions.len();
+        for function_def in function_defs.iter() {
+          let (name, formal_parameters) = function_def.header();
+          self.functions.push(
+            Function {
+              name             : name.clone(),
+              formal_parameters: formal_parameters.clone(),
+              entry            : 0, // Patched below.
+            }
+          );
+        }
+        let functions: Vec<FunctionId> = (first_id..self.functions.len()).collect();
+
+        self.instructions.push(Instr::Closures { functions: functions.clone() });
+        self.compile_expression(continuation);
+
+        // The children of a `Fix` are the function bodies followed by the continuation.
+        for (id, body) in functions.into_iter().zip(expression.children()) {
+          self.functions[id].entry = self.compile_expression(body);
+        }
+      }
+
+      ContinuationExpression::Switch { value, arms } => {
+        self.instructions.push(Instr::Switch { value: value.clone(), targets: vec![] });
+        let arm_labels: Vec<Label> = arms.iter().map(|arm| self.compile_expression(arm)).collect();
+        if let Instr::Switch { targets, .. } = &mut self.instructions[label] {
+          *targets = arm_labels;
+        }
+      }
+
+      ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+        self.instructions.push(
+          Instr::Primitive {
+            operation: *operation,
+            arguments: values.clone(),
+            dests    : variables.clone(),
+            targets  : vec![]
+          }
+        );
+        let continuation_labels: Vec<Label> =
+            expressions.iter().map(|expression| self.compile_expression(expression)).collect();
+        if let Instr::Primitive { targets, .. } = &mut self.instructions[label] {
+          *targets = continuation_labels;
+        }
+      }
+
+    }
+
+    label
+  }
+}
+
+/// Where the VM continues after a function or a continuation it created is applied.
+enum Pending {
+  /// Enter the closure with the given arguments.
+  Enter {
+    closure  : Rc<Closure>,
+    arguments: DValueList,
+    store    : Store
+  },
+  /// Continue at `label` in `environment` with `variables` bound to `values`.
+  Resume {
+    label      : Label,
+    environment: Rc<Environment>,
+    variables  : Rc<VariableList>,
+    values     : DValueList,
+    store      : Store
+  },
+}
+
+type PendingCell = Rc<RefCell<Option<Pending>>>;
+
+/// A function of the program together with the environment of the `Fix` that defined it. The
+/// other functions of the same `Fix` are bound when the closure is entered.
+struct Closure {
+  group      : Rc<Vec<FunctionId>>,
+  function   : FunctionId,
+  environment: Environment,
+}
+
+/// Executes `Program`s.
+pub struct VM<'p> {
+  program: &'p Program,
+  pending: PendingCell,
+  /// Whether `run` has yet to return. The functions and continuations of the VM check it.
+  running: Rc<Cell<bool>>,
+}
+
+impl<'p> VM<'p> {
+  /**
+  Runs `program` from `Program::ENTRY` in `environment`, starting with `store`, and returns the
+  answer it ends with. The program ends when it applies a function that is not one of its own,
+  such as a continuation in `environment`, and that function's answer does not come back to the
+  program, or when an exception reaches a handler that does not return to the program, such as
+  `Store::default_handler`.
+
+  A function or continuation of the program that is applied after `run` returns raises
+  `Undefined`. See the module documentation.
+  */
+  pub fn run(program: &Program, environment: Environment, store: &Store) -> Answer {
+    let vm = VM { program, pending: Rc::new(RefCell::new(None)), running: Rc::new(Cell::new(true)) };
+    let answer = vm.execute(Program::ENTRY, environment, store.clone());
+    vm.running.set(false);
+    answer
+  }
+
+  fn execute(&self, mut label: Label, mut environment: Environment, mut store: Store) -> Answer {
+    loop {
+      let answer = match &self.program.instructions[label] {
+
+        Instr::Record { fields, dest } => {
+          let values: Result<DValueList, Exception> =
+              fields.iter()
+                    .map(|(value, access_path)| {
+                      environment.try_value_to_denotable_value(value)
+                                 .map(|d_value| resolve_field(d_value, access_path.clone()))
+                    })
+                    .collect();
+          match values {
+            Ok(values) => {
+              environment = environment.bind(dest.clone(), DValue::Record { values, idx: 0 });
+              label += 1;
+              continue;
+            }
+            Err(exception) => store.raise_exception(exception)
+          }
+        }
+
+        Instr::Select { offset, record, dest } => {
+          match environment.try_value_to_denotable_value(record) {
+            Ok(DValue::Record { values, idx }) => {
+              match idx.checked_add_signed(*offset).and_then(|field| values.get(field)) {
+                Some(field_value) => {
+                  environment = environment.bind(dest.clone(), field_value.clone());
+                  label += 1;
+                  continue;
+                }
+                None => store.raise_exception(Exception::IndexOutOfBounds)
+              }
+            }
+            Ok(_)          => store.raise_exception(Exception::InvalidAccess),
+            Err(exception) => store.raise_exception(exception)
+          }
+        }
+
+        Instr::Offset { offset, record, dest } => {
+          match environment.try_value_to_denotable_value(record) {
+            Ok(DValue::Record { values, idx }) => {
+              match idx.checked_add_signed(*offset).filter(|new_idx| *new_idx <= values.len()) {
+                Some(new_idx) => {
+                  environment = environment.bind(dest.clone(), DValue::Record { values, idx: new_idx });
+                  label += 1;
+                  continue;
+                }
+                None => store.raise_exception(Exception::IndexOutOfBounds)
+              }
+            }
+            Ok(_)          => store.raise_exception(Exception::InvalidAccess),
+            Err(exception) => store.raise_exception(exception)
+          }
+        }
+
+        Instr::Closures { functions } => {
+          let group = Rc::new(functions.clone());
+          environment = self.bind_group(&group, environment);
+          label += 1;
+          continue;
+        }
+
+        Instr::Call { function, arguments } => {
+          match (
+            environment.try_value_to_denotable_value(function),
+            environment.values_to_denotable_values(arguments)
+          ) {
+            (Ok(DValue::Function(function)), Ok(arguments)) => (function.f)(&arguments, &store),
+            (Ok(_), Ok(_))                                  => store.raise_exception(Exception::Undefined),
+            (Err(exception), _)
+            | (_, Err(exception))                           => store.raise_exception(exception)
+          }
+        }
+
+        Instr::Switch { value, targets } => {
+          match environment.try_value_to_denotable_value(value) {
+            Ok(DValue::Integer(i)) => {
+              match usize::try_from(i).ok().and_then(|i| targets.get(i)) {
+                Some(target) => {
+                  label = *target;
+                  continue;
+                }
+                None => store.raise_exception(Exception::Match)
+              }
+            }
+            Ok(_)          => store.raise_exception(Exception::Match),
+            Err(exception) => store.raise_exception(exception)
+          }
+        }
+
+        Instr::Primitive { operation, arguments, dests, targets } => {
+          match environment.values_to_denotable_values(arguments) {
+            Ok(arguments) => {
+              let shared_environment = Rc::new(environment.clone());
+              let dests = Rc::new(dests.clone());
+              let continuations: ContinuationList =
+                  targets.iter()
+                         .map(|target| self.resume_at(*target, shared_environment.clone(), dests.clone()))
+                         .collect();
+              // The answer of a primitive operation is applied to the store it must see.
+              operation.evaluate(arguments, continuations)
+            }
+            Err(exception) => store.raise_exception(exception)
+          }
+        }
+
+      };
+
+      // Drive the answer until it hands control back to the program or halts.
+      let mut answer = answer;
+      loop {
+        let pending = self.pending.borrow_mut().take();
+        match pending {
+
+          Some(Pending::Enter { closure, arguments, store: new_store }) => {
+            let function = &self.program.functions[closure.function];
+            if arguments.len() != function.formal_parameters.len() {
+              answer = new_store.raise_exception(Exception::Bind);
+              store  = new_store;
+              continue;
+            }
+            environment = self.bind_group(&closure.group, closure.environment.clone())
+                              .bindn(&function.formal_parameters, &arguments);
+            label = function.entry;
+            store = new_store;
+            break;
+          }
+
+          Some(Pending::Resume { label: target, environment: resumed, variables, values, store: new_store }) => {
+            environment = resumed.bindn(&variables, &values);
+            label = target;
+            store = new_store;
+            break;
+          }
+
+          None if answer.is_halted() => return answer,
+
+          None => answer = answer(&store)
+
+        }
+      }
+    }
+  }
+
+  /// Binds the names of the functions in `group` to closures over `environment`.
+  fn bind_group(&self, group: &Rc<Vec<FunctionId>>, environment: Environment) -> Environment {
+    let names: VariableList = group.iter()
+                                   .map(|id| self.program.functions[*id].name.clone())
+                                   .collect();
+    let closures: DValueList = group.iter()
+                                    .map(|id| {
+                                      self.closure(
+                                        Closure {
+                                          group      : group.clone(),
+                                          function   : *id,
+                                          environment: environment.clone()
+                                        }
+                                      )
+                                    })
+                                    .collect();
+    environment.bindn(&names, &closures)
+  }
+
+  /// A function value that, when applied, has the VM enter `closure`.
+  fn closure(&self, closure: Closure) -> DValue {
+    let pending = self.pending.clone();
+    let running = self.running.clone();
+    let closure = Rc::new(closure);
+    DValue::Function(
+      Continuation::from_fn(move | arguments, store | {
+        if !running.get() {
+          return store.raise_exception(Exception::Undefined);
+        }
+        *pending.borrow_mut() = Some(
+          Pending::Enter { closure: closure.clone(), arguments: arguments.clone(), store: store.clone() }
+        );
+        Answer::halt(EMPTY)
+      })
+    )
+  }
+
+  /// A continuation that, when applied, has the VM continue at `label` with `variables` bound to
+  /// its parameters.
+  fn resume_at(
+    &self,
+    label      : Label,
+    environment: Rc<Environment>,
+    variables  : Rc<VariableList>
+  ) -> Continuation
+  {
+    let pending = self.pending.clone();
+    let running = self.running.clone();
+    Continuation::from_fn(move | values, store | {
+      if !running.get() {
+        return store.raise_exception(Exception::Undefined);
+      }
+      *pending.borrow_mut() = Some(
+        Pending::Resume {
+          label,
+          environment: environment.clone(),
+          variables  : variables.clone(),
+          values     : values.clone(),
+          store      : store.clone()
+        }
+      );
+      Answer::halt(EMPTY)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{evaluate, parser::parse, reference::generate, run, run_with_fuel, tests::run_source};
+
+  /// The final values of `expression` run by the VM with `halt` bound to a continuation that
+  /// ends the program.
+  fn run_vm(expression: &ContinuationExpression) -> String {
+    let program     = compile(expression);
+    let store       = Store::new(16);
+    let environment = Environment::new().bind(Variable::new("halt"), Store::default_handler());
+    let answer      = VM::run(&program, environment, &store);
+    format!("{:?}", run_with_fuel(answer, store, 1_000_000).unwrap().parameters())
+  }
+
+  fn agree(source: &str) -> String {
+    let vm = run_vm(&parse(source).unwrap());
+    assert_eq!(vm, format!("{:?}", run_source(source)), "{}", source);
+    vm
+  }
+
+  #[test]
+  fn the_vm_agrees_with_the_evaluator() {
+    // A loop, mutual recursion, records with offsets, a switch, the store, and an exception.
+    assert_eq!(
+      agree("(fix ((loop (i acc) (primop > (i 0) () ((primop + (acc i) (a) ((primop - (i 1) (j) ((app loop j a))))) (app halt acc)))))
+               (app loop 100 0))"),
+      "[5050]"
+    );
+    assert_eq!(
+      agree("(fix ((even (n) (primop ieql (n 0) () ((app halt 1) (primop - (n 1) (m) ((app odd m))))))
+                  (odd (n) (primop ieql (n 0) () ((app halt 0) (primop - (n 1) (m) ((app even m)))))))
+               (app even 9))"),
+      "[0]"
+    );
+    assert_eq!(agree("(record (1 2 3) r (offset 2 r q (select -1 q x (select 0 q y (app halt x y)))))"), "[2, 3]");
+    assert_eq!(agree("(switch 2 (app halt 0) (app halt 1) (app halt 2))"), "[2]");
+    assert_eq!(
+      agree("(primop mkarray (2 0) (a) ((primop update (a 1 7) () ((primop subscript (a 1) (v) ((app halt v)))))))"),
+      "[7]"
+    );
+    assert_eq!(agree("(primop div (1 0) (x) ((app halt x)))"), format!("{:?}", vec![Exception::DivideByZero.as_record()]));
+  }
+
+  #[test]
+  fn the_vm_agrees_with_the_evaluator_on_generated_programs() {
+    for seed in 0..100 {
+      let program  = generate(seed, 6);
+      let answer   = evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], program.clone()).unwrap();
+      let expected = format!("{:?}", run(answer, 16));
+      assert_eq!(run_vm(&program), expected, "seed {}: {}", seed, program);
+    }
+  }
+  #[test]
+  fn a_function_that_escapes_the_vm_raises_undefined() {
+    let program     = compile(&parse("(fix ((f (x) (app halt x))) (app halt @f))").unwrap());
+    let store       = Store::new(16);
+    let environment = Environment::new().bind(Variable::new("halt"), Store::default_handler());
+    let answer      = VM::run(&program, environment, &store);
+    let values      = run_with_fuel(answer, store.clone(), 1000).unwrap().parameters().clone();
+
+    let escaped = match &values[..] {
+      [DValue::Function(function)] => function.clone(),
+      _                            => panic!("not a function: {:?}", values)
+    };
+    let answer = (escaped.f)(&vec![DValue::Integer(1)], &store);
+    assert_eq!(
+      format!("{:?}", run_with_fuel(answer, store, 1000).unwrap().parameters()),
+      format!("{:?}", vec![Exception::Undefined.as_record()])
+    );
+  }
+}
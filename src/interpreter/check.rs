@@ -0,0 +1,141 @@
+/*!
+
+  Static checks of a `ContinuationExpression` that find errors before it is evaluated.
+
+*/
+
+use std::collections::HashMap;
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::ContinuationExpression,
+  primitive_op::PrimitiveOp,
+  value::Value
+};
+
+/// Checks that every variable and label in `expression` is in scope, either bound by an enclosing
+/// binder according to the scope rules documented on each `ContinuationExpression` variant or
+/// among `top_level`. Returns the variables used out of scope, each once, in the order they are
+/// first used.
+pub fn check_scoping(expression: &ContinuationExpression, top_level: &[Variable]) -> Result<(), Vec<Variable>> {
+  let mut scope: HashMap<Variable, usize> = HashMap::new();
+  for variable in top_level {
+    *scope.entry(variable.clone()).or_insert(0) += 1;
+  }
+
+  let mut unbound = Vec::new();
+  check_scoping_in(expression, &mut scope, &mut unbound);
+
+  if unbound.is_empty() {
+    Ok(())
+  } else {
+    Err(unbound)
+  }
+}
+
+/// `scope` counts the binders of each variable that are in scope, so that leaving the scope of an
+/// inner binder does not unbind a variable that an outer binder binds too.
+fn check_scoping_in(
+  expression: &ContinuationExpression,
+  scope     : &mut HashMap<Variable, usize>,
+  unbound   : &mut Vec<Variable>
+)
+{
+  for value in expression.values() {
+    if let Value::Variable(v) | Value::Label(v) = value {
+      if !scope.contains_key(v) && !unbound.contains(v) {
+        unbound.push(v.clone());
+      }
+    }
+  }
+
+  for (child, binders) in expression.children().into_iter().zip(expression.child_scopes()) {
+    for binder in binders.iter() {
+      *scope.entry(binder.clone()).or_insert(0) += 1;
+    }
+    check_scoping_in(child, scope, unbound);
+    for binder in binders.iter() {
+      if let Some(count) = scope.get_mut(binder) {
+        *count -= 1;
+        if *count == 0 {
+          scope.remove(binder);
+        }
+      }
+    }
+  }
+}
+
+/// A `PrimitiveOp` node whose numbers of arguments and continuations do not match
+/// `PrimitiveOp::arity`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ArityError {
+  pub operation: PrimitiveOp,
+  /// The numbers of arguments and continuations the operation takes.
+  pub expected : (usize, usize),
+  /// The numbers of arguments and continuations the node gives it.
+  pub found    : (usize, usize)
+}
+
+/// Checks every `PrimitiveOp` node of `expression` against `PrimitiveOp::arity`. Returns the
+/// nodes that do not match in pre-order.
+pub fn check_primop_arity(expression: &ContinuationExpression) -> Result<(), Vec<ArityError>> {
+  let mut errors  = Vec::new();
+  let mut pending = vec![expression];
+  while let Some(expression) = pending.pop() {
+    if let ContinuationExpression::PrimitiveOp { operation, values, expressions, .. } = expression {
+      let found = (values.len(), expressions.len());
+      if found != operation.arity() {
+        errors.push(ArityError { operation: *operation, expected: operation.arity(), found });
+      }
+    }
+    // Reversed, so that the children are checked in order.
+    pending.extend(expression.children().into_iter().rev());
+  }
+
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(errors)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  fn check(source: &str, top_level: &[&str]) -> Result<(), Vec<String>> {
+    let top_level: Vec<Variable> = top_level.iter().map(|name| Variable::new(name)).collect();
+    check_scoping(&parse(source).unwrap(), &top_level)
+      .map_err(|unbound| unbound.iter().map(|v| v.name().to_string()).collect())
+  }
+
+  #[test]
+  fn a_well_scoped_program_passes() {
+    let source = "(fix ((f (x k) (primop + (x 1) (y) ((app k y)))) (g (k) (app f 1 k)))
+                    (record (1 2) r (select 0 r v (app @g halt))))";
+    assert_eq!(check(source, &["halt"]), Ok(()));
+  }
+
+  #[test]
+  fn a_variable_outside_the_scope_of_its_select_is_reported() {
+    // `v` is bound only in the continuation of the `select`, not in the other arm of the switch.
+    let source = "(switch i (select 0 r v (app halt v)) (app halt v w))";
+    assert_eq!(check(source, &["halt", "i", "r"]), Err(vec!["v".to_string(), "w".to_string()]));
+    // An unbound variable used twice is reported once.
+    assert_eq!(check("(app halt u u)", &["halt"]), Err(vec!["u".to_string()]));
+  }
+
+  #[test]
+  fn primitive_operations_are_checked_against_their_arity() {
+    let source = "(primop + (1) (x) ((primop < (x 1) () ((app halt 1) (app halt 0) (app halt 2)))))";
+    assert_eq!(
+      check_primop_arity(&parse(source).unwrap()),
+      Err(vec![
+        ArityError { operation: PrimitiveOp::Add, expected: (2, 1), found: (1, 1) },
+        ArityError { operation: PrimitiveOp::Less, expected: (2, 2), found: (2, 3) },
+      ])
+    );
+    assert_eq!(check_primop_arity(&parse("(primop + (1 2) (x) ((app halt x)))").unwrap()), Ok(()));
+  }
+}
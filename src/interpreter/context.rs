@@ -0,0 +1,355 @@
+/*!
+
+  An `EvalContext` holds the settings of the interpreter that are not part of the semantics of
+  \[Appel] but that an embedder may want to control, such as resource limits.
+
+  Evaluation never receives the context as an argument. Instead, the context is installed for the
+  current thread with `EvalContext::install`, and the interpreter consults it wherever it needs
+  to. A thread on which no context has been installed uses `EvalContext::default()`.
+
+*/
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use crate::interpreter::{
+  cps::{
+    continuation::Continuation,
+    observer::Observer
+  },
+  coverage::CoverageRecording,
+  primitive_op::PrimitiveOp,
+  tracer::Tracer
+};
+
+thread_local! {
+  static CURRENT_CONTEXT: RefCell<EvalContext> = RefCell::new(EvalContext::default());
+  /// Whether the current context counts primitive operations, that is, whether it tracks coverage
+  /// or is taking a profile. Kept apart from the context so that `record_primitive_op` costs one
+  /// read of a `Cell` when nothing is counted, rather than a borrow of the context.
+  pub(crate) static COUNTING_PRIMITIVE_OPS: Cell<bool> = const { Cell::new(false) };
+  /// Whether the current context is recording node coverage, kept apart from the context for the
+  /// same reason.
+  pub(crate) static RECORDING_COVERAGE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// How `ieql` and `ineq` compare records, strings, and arrays.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum EqualityMode {
+  /// The conservative equality of \[Appel] (see `DValue::eq`): records and strings that are
+  /// equal may or may not compare equal, as chosen by `arbitrarily`, and arrays are equal only if
+  /// they are the same array.
+  #[default]
+  Conservative,
+  /// Content equality (see `DValue::structurally_equal`). This is not Appel's semantics; it
+  /// exists so that golden tests comparing records get the same answer on every run.
+  StructuralDeep,
+  /// The conservative equality of \[Appel], but with each choice made by `arbitrarily` drawn
+  /// from the context's random number generator, so that a program can be tested against both
+  /// outcomes reproducibly. See `EvalContext::seed`.
+  Seeded,
+}
+
+/// Whether real arithmetic follows IEEE 754 or traps on results it cannot represent.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum FloatMode {
+  /// Real operations silently produce infinities and NaNs, as IEEE 754 prescribes.
+  #[default]
+  Ieee,
+  /// `fadd`, `fsub`, `fmul`, `fdiv`, and `fsqrt` raise `Exception::FloatOverflow` when they
+  /// produce an infinity from finite operands and `Exception::FloatInvalid` when they produce NaN
+  /// from non-NaN operands. Operands that are already infinite or NaN propagate as under `Ieee`.
+  Trapping,
+}
+
+#[derive(Clone, Default)]
+pub struct EvalContext {
+  /// The maximum number of cells the `Store` may grow to. Allocations that would exceed it raise
+  /// `Exception::OutOfMemory`. `None` means there is no limit but `store::MAX_STORE_CELLS`, which
+  /// also caps a larger limit.
+  pub max_store_size: Option<usize>,
+  /// The queue of suspended tasks of the cooperative scheduler, each ready to be resumed with an
+  /// empty parameter list. See `PrimitiveOp::Spawn` and `PrimitiveOp::Yield`.
+  pub(crate) tasks: VecDeque<Continuation>,
+  /// Whether to count how often each `PrimitiveOp` is evaluated. See `eval_coverage`.
+  pub track_coverage: bool,
+  pub(crate) coverage: HashMap<PrimitiveOp, u64>,
+  /// The counts of the `PrimitiveOp`s evaluated by the `profile::run_profiled` in progress.
+  pub(crate) profile: Option<HashMap<PrimitiveOp, u64>>,
+  /// The nodes evaluated by the `coverage::run_with_coverage` in progress.
+  pub(crate) node_coverage: Option<CoverageRecording>,
+  /// How `ieql` and `ineq` compare their operands.
+  pub equality_mode: EqualityMode,
+  /// The seed of the random number generator that makes every randomized decision of the
+  /// interpreter, such as the choices of `arbitrarily` under `EqualityMode::Seeded`. The same
+  /// seed yields the same decisions in the same order. A context that is not given a seed uses
+  /// the seed `0`, so evaluation is deterministic by default. Private, because the generator's
+  /// state must be reset along with it; see `with_seed`.
+  seed: u64,
+  pub(crate) random_state: u64,
+  /// How real arithmetic treats infinite and NaN results.
+  pub float_mode: FloatMode,
+  /// Notified of every allocation, update, and collection of store cells. See `Observer`.
+  pub observer: Option<Rc<dyn Observer>>,
+  /// Called on every step of `ContinuationExpression::evaluate`. See `Tracer`.
+  pub tracer: Option<Rc<RefCell<dyn Tracer>>>,
+}
+
+impl EvalContext {
+
+  pub fn new() -> Self {
+    EvalContext::default()
+  }
+
+  pub fn with_max_store_size(mut self, max_store_size: usize) -> Self {
+    self.max_store_size = Some(max_store_size);
+    self
+  }
+
+  pub fn with_coverage(mut self) -> Self {
+    self.track_coverage = true;
+    self
+  }
+
+  pub fn with_equality_mode(mut self, equality_mode: EqualityMode) -> Self {
+    self.equality_mode = equality_mode;
+    self
+  }
+
+  /// Seeds the random number generator, restarting its sequence of decisions.
+  pub fn with_seed(mut self, seed: u64) -> Self {
+    self.seed = seed;
+    self.random_state = seed;
+    self
+  }
+
+  /// The seed given to `with_seed`, or `0`.
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  pub fn with_float_mode(mut self, float_mode: FloatMode) -> Self {
+    self.float_mode = float_mode;
+    self
+  }
+
+  pub fn with_observer(mut self, observer: Rc<dyn Observer>) -> Self {
+    self.observer = Some(observer);
+    self
+  }
+
+  pub fn with_tracer(mut self, tracer: Rc<RefCell<dyn Tracer>>) -> Self {
+    self.tracer = Some(tracer);
+    self
+  }
+
+  /// Installs `self` as the context of the current thread, returning the context it replaces.
+  pub fn install(self) -> EvalContext {
+    COUNTING_PRIMITIVE_OPS.with(|counting| counting.set(self.counts_primitive_ops()));
+    RECORDING_COVERAGE.with(|recording| recording.set(self.node_coverage.is_some()));
+    CURRENT_CONTEXT.with(|current| current.replace(self))
+  }
+
+  fn counts_primitive_ops(&self) -> bool {
+    self.track_coverage || self.profile.is_some()
+  }
+
+  /// Calls `f` with the context of the current thread.
+  pub fn with_current<F, R>(f: F) -> R
+    where F: FnOnce(&EvalContext) -> R
+  {
+    CURRENT_CONTEXT.with(|current| f(&current.borrow()))
+  }
+
+  /// Calls `f` with mutable access to the context of the current thread. `f` must not evaluate
+  /// anything, as evaluation may itself consult the context.
+  pub(crate) fn with_current_mut<F, R>(f: F) -> R
+    where F: FnOnce(&mut EvalContext) -> R
+  {
+    CURRENT_CONTEXT.with(|current| f(&mut current.borrow_mut()))
+  }
+
+  /// Replaces the counts of the profile being taken, returning the previous ones. `None` stops
+  /// profiling.
+  pub(crate) fn replace_profile(profile: Option<HashMap<PrimitiveOp, u64>>) -> Option<HashMap<PrimitiveOp, u64>> {
+    EvalContext::with_current_mut(|context| {
+      let previous = std::mem::replace(&mut context.profile, profile);
+      COUNTING_PRIMITIVE_OPS.with(|counting| counting.set(context.counts_primitive_ops()));
+      previous
+    })
+  }
+
+  /// Replaces the node coverage being recorded, returning the previous recording. `None` stops
+  /// recording.
+  pub(crate) fn replace_node_coverage(recording: Option<CoverageRecording>) -> Option<CoverageRecording> {
+    EvalContext::with_current_mut(|context| {
+      RECORDING_COVERAGE.with(|flag| flag.set(recording.is_some()));
+      std::mem::replace(&mut context.node_coverage, recording)
+    })
+  }
+
+  /// Counts an evaluation of `operation` if coverage tracking is on or a profile is being taken.
+  pub(crate) fn record_primitive_op(operation: PrimitiveOp) {
+    if !COUNTING_PRIMITIVE_OPS.with(Cell::get) {
+      return;
+    }
+    EvalContext::with_current_mut(|context| {
+      if context.track_coverage {
+        *context.coverage.entry(operation).or_insert(0) += 1;
+      }
+      if let Some(profile) = &mut context.profile {
+        *profile.entry(operation).or_insert(0) += 1;
+      }
+    });
+  }
+
+  /// Adds a task to the back of the scheduler's queue.
+  pub(crate) fn enqueue_task(task: Continuation) {
+    EvalContext::with_current_mut(|context| context.tasks.push_back(task));
+  }
+
+  /// The next number drawn from the current context's random number generator (SplitMix64).
+  pub(crate) fn next_random() -> u64 {
+    EvalContext::with_current_mut(|context| {
+      context.random_state = context.random_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+      let mut z = context.random_state;
+      z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+      z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+      z ^ (z >> 31)
+    })
+  }
+
+  /// Removes the task at the front of the scheduler's queue.
+  pub(crate) fn dequeue_task() -> Option<Continuation> {
+    EvalContext::with_current_mut(|context| context.tasks.pop_front())
+  }
+
+}
+
+/// The number of times each `PrimitiveOp` has been evaluated on the current thread since coverage
+/// tracking was turned on with `EvalContext::with_coverage`. Operations that were never evaluated
+/// are absent. An operation that is defined in terms of another, like `!` in terms of
+/// `subscript`, counts for both.
+pub fn eval_coverage() -> HashMap<PrimitiveOp, u64> {
+  EvalContext::with_current(|context| context.coverage.clone())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{exception::Exception, tests::run_source};
+
+  #[test]
+  fn allocating_in_a_loop_stops_at_the_store_limit() {
+    let previous = EvalContext::new().with_max_store_size(64).install();
+    let values = run_source(
+      "(fix ((loop (n) (primop mkarray (8 0) (a) ((app loop a))))) (app loop 0))"
+    );
+    previous.install();
+    assert_eq!(format!("{:?}", values), format!("{:?}", vec![Exception::OutOfMemory.as_record()]));
+  }
+
+  #[test]
+  fn allocations_under_the_limit_succeed() {
+    let previous = EvalContext::new().with_max_store_size(64).install();
+    let values = run_source("(primop mkarray (8 0) (a) ((primop alength (a) (n) ((app halt n)))))");
+    previous.install();
+    assert_eq!(format!("{:?}", values), "[8]");
+  }
+
+  #[test]
+  fn coverage_counts_each_evaluated_operation() {
+    let previous = EvalContext::new().with_coverage().install();
+    let values = run_source(
+      "(fix ((loop (i a)
+               (primop subscript (a 1) (x) (
+                 (primop + (i x) (j) (
+                   (primop < (j 10) () ((app loop j a) (app halt j)))))))))
+         (primop mkarray (2 5) (a) ((app loop 0 a))))"
+    );
+    let coverage = eval_coverage();
+    previous.install();
+
+    assert_eq!(format!("{:?}", values), "[10]");
+    assert_eq!(coverage.get(&PrimitiveOp::Subscript), Some(&2));
+    assert_eq!(coverage.get(&PrimitiveOp::Add), Some(&2));
+    assert_eq!(coverage.get(&PrimitiveOp::Less), Some(&2));
+    assert_eq!(coverage.get(&PrimitiveOp::MakeArray), Some(&1));
+    assert_eq!(coverage.get(&PrimitiveOp::Multiply), None);
+  }
+
+  #[test]
+  fn primitive_operations_are_counted_only_while_coverage_is_tracked() {
+    let previous = EvalContext::new().install();
+    EvalContext::record_primitive_op(PrimitiveOp::Add);
+    assert!(eval_coverage().is_empty());
+
+    EvalContext::new().with_coverage().install();
+    EvalContext::record_primitive_op(PrimitiveOp::Add);
+    assert_eq!(eval_coverage().get(&PrimitiveOp::Add), Some(&1));
+
+    previous.install();
+    assert!(!COUNTING_PRIMITIVE_OPS.with(Cell::get));
+  }
+
+  #[test]
+  fn structural_equality_compares_distinct_records_by_content() {
+    let compare = | second: &str | {
+      let previous = EvalContext::new().with_equality_mode(EqualityMode::StructuralDeep).install();
+      let values = run_source(&format!(
+        "(record (1 \"two\") r (record ({}) s (primop ieql (r s) () ((app halt 1) (app halt 0)))))",
+        second
+      ));
+      previous.install();
+      format!("{:?}", values)
+    };
+    assert_eq!(compare("1 \"two\""), "[1]");
+    assert_eq!(compare("1 \"three\""), "[0]");
+    assert_eq!(compare("1"), "[0]");
+  }
+
+  #[test]
+  fn structural_equality_compares_arrays_by_their_cells() {
+    let previous = EvalContext::new().with_equality_mode(EqualityMode::StructuralDeep).install();
+    let values = run_source(
+      "(primop mkarray (2 7) (a) ((primop mkarray (2 7) (b) (
+         (primop ieql (a b) () ((app halt 1) (app halt 0)))))))"
+    );
+    previous.install();
+    assert_eq!(format!("{:?}", values), "[1]");
+  }
+
+  /// The choices of `arbitrarily` made by comparing the same two records `n` times under
+  /// `EqualityMode::Seeded`, with the given seed if there is one, as a string of `1`s and `0`s.
+  fn seeded_choices(seed: Option<u64>, n: usize) -> String {
+    let mut context = EvalContext::new().with_equality_mode(EqualityMode::Seeded);
+    if let Some(seed) = seed {
+      context = context.with_seed(seed);
+    }
+    let previous  = context.install();
+    let seed_seen = EvalContext::with_current(EvalContext::seed);
+    let choices: String = (0..n).map(|_| {
+      let values = run_source(
+        "(record (1) r (record (1) s (primop ieql (r s) () ((app halt 1) (app halt 0)))))"
+      );
+      format!("{:?}", values[0])
+    }).collect();
+    previous.install();
+    assert_eq!(seed_seen, seed.unwrap_or(0));
+    choices
+  }
+
+  #[test]
+  fn the_same_seed_makes_the_same_choices() {
+    let choices = seeded_choices(Some(7), 64);
+    assert_eq!(choices, seeded_choices(Some(7), 64));
+    assert_ne!(choices, seeded_choices(Some(8), 64));
+    assert!(choices.contains('0') && choices.contains('1'));
+  }
+
+  #[test]
+  fn an_unseeded_context_uses_seed_zero() {
+    assert_eq!(seeded_choices(None, 16), seeded_choices(Some(0), 16));
+  }
+}
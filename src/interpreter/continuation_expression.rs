@@ -2,11 +2,17 @@
 
 */
 
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
+use std::str::FromStr;
 
 use crate::{
   interpreter::{
-    Location,
+    FieldOffset,
     Variable,
     VariableList,
     cps::{
@@ -16,24 +22,113 @@ use crate::{
     },
     environment::Environment,
     exception::Exception,
+    gensym::next_fresh_variable,
+    parser::{parse, ParseError},
     primitive_op::PrimitiveOp,
     value::{Value, ValueList}
   }
 };
-use crate::interpreter::cps::continuation::ContinuationList;
+use crate::interpreter::cps::continuation::{ClosureInfo, ContinuationList};
 use crate::interpreter::environment::RcEnvironment;
+use crate::interpreter::coverage::{self, NodeId};
+use crate::interpreter::tracer::trace;
 
 
 pub type CExp = Box<ContinuationExpression>;
+/// Shared subexpressions are cheap to clone, which matters for the arms of a `Switch`: the
+/// continuation closures clone the whole `Switch` every time they run.
+pub type RcCExp = Rc<ContinuationExpression>;
 pub type FunctionDefinitionList = Vec<FunctionDefinition>;
 pub type RcFunctionDefinition = Rc<FunctionDefinition>;
 pub type RcFunctionDefinitionList = Rc<Vec<RcFunctionDefinition>>;
+type RcClosureInfoList = Rc<Vec<Rc<ClosureInfo>>>;
 
-#[derive(Clone, Eq, PartialEq)]
+/// The reason `ContinuationExpression::replace_at` could not follow a path.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum PathError {
+  /// The child index at `depth` in the path is not a child of the node reached so far, which has
+  /// `child_count` children.
+  NoSuchChild {
+    depth      : usize,
+    index      : usize,
+    child_count: usize
+  }
+}
+
+#[derive(Clone)]
 pub struct FunctionDefinition {
   name             : Variable,
   formal_parameters: VariableList,
-  body             : ContinuationExpression
+  body             : ContinuationExpression,
+  /// The digest of the definition, stored the first time it is computed and cleared when the
+  /// body is borrowed mutably. See `digest`.
+  digest           : OnceCell<u64>
+}
+
+/// Definitions are equal when their names, formal parameters, and bodies are, whether or not
+/// their digests have been computed.
+impl PartialEq for FunctionDefinition {
+  fn eq(&self, other: &Self) -> bool {
+    self.name == other.name
+        && self.formal_parameters == other.formal_parameters
+        && self.body == other.body
+  }
+}
+
+impl Eq for FunctionDefinition {}
+
+impl FunctionDefinition {
+  pub fn new(
+    name             : Variable,
+    formal_parameters: VariableList,
+    body             : ContinuationExpression
+  ) -> FunctionDefinition
+  {
+    FunctionDefinition { name, formal_parameters, body, digest: OnceCell::new() }
+  }
+
+  /// The name and the formal parameters of the function.
+  pub fn header(&self) -> (&Variable, &VariableList) {
+    (&self.name, &self.formal_parameters)
+  }
+
+  pub fn body(&self) -> &ContinuationExpression {
+    &self.body
+  }
+
+  pub(crate) fn body_mut(&mut self) -> &mut ContinuationExpression {
+    self.digest.take();
+    &mut self.body
+  }
+
+  /// A hash of the name, the formal parameters, and the body of the definition. It is computed
+  /// once and stored on the definition, so every `Fix` sharing the definition through its `Rc`
+  /// reuses it. Borrowing the body mutably with `body_mut` clears it.
+  pub fn digest(&self) -> u64 {
+    *self.digest.get_or_init(|| {
+      let mut hasher = DefaultHasher::new();
+      self.name.hash(&mut hasher);
+      self.formal_parameters.hash(&mut hasher);
+      self.body.hash(&mut hasher);
+      hasher.finish()
+    })
+  }
+
+  /// The variables and labels the body refers to that are not formal parameters. These are what a
+  /// closure for the function needs to capture.
+  pub(crate) fn free_names(&self) -> HashSet<Variable> {
+    self.free(true)
+  }
+
+  /// The free variables or, if `labels` is set, the free variables and labels of the body that
+  /// are not formal parameters. See `ContinuationExpression::free`.
+  fn free(&self, labels: bool) -> HashSet<Variable> {
+    let mut names = self.body.free(labels);
+    for formal_parameter in self.formal_parameters.iter() {
+      names.remove(formal_parameter);
+    }
+    names
+  }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -45,17 +140,19 @@ pub enum ContinuationExpression {
     expression: CExp
   },
 
-  /// In `Select(i,v,w,e)` the scope of `w` is just `e`.
+  /// In `Select(i,v,w,e)` the scope of `w` is just `e`. The field `i` is relative to where the
+  /// record `v` points and may be negative.
   Select {
-    location  : Location,
+    location  : FieldOffset,
     value     : Value,
     variable  : Variable,
     expression: CExp,
   },
 
-  /// In `Offset(i,v,w,e)` the scope of `w` is just `e`.
+  /// In `Offset(i,v,w,e)` the scope of `w` is just `e`. As with `Select`, `i` may be negative, so
+  /// that a pointer into the middle of a record can be moved back toward its start.
   Offset {
-    location  : Location,
+    location  : FieldOffset,
     value     : Value,
     variable  : Variable,
     expression: CExp,
@@ -87,6 +184,15 @@ pub enum ContinuationExpression {
 
   the scope of each `fi` includes all of the `Bj` and `E`; the scope
   of `vij` is just `Bi`.
+
+  Scopes nest as usual: a `Fix` inside the body `Bi` (or inside `E`) that defines a function
+  with the same name as an outer `fj` shadows `fj` in the inner bodies and the inner
+  continuation, and nowhere else. Each function closure ties its recursive knot with its own
+  `Fix`'s definition list, so calling the inner function always rebinds the inner names, while
+  code outside the inner `Fix`, which was evaluated in an environment that never saw the inner
+  binding, still resolves the name to the outer `fj`. A formal parameter `vij` with the same name
+  as a function of the group shadows that function within `Bi`, because the parameters are bound
+  after the functions are.
   */
   Fix {
     function_defs: RcFunctionDefinitionList,
@@ -96,7 +202,7 @@ pub enum ContinuationExpression {
   /// `Switch` does not bind variables and thus needs no scope rule.
   Switch {
     value: Value,
-    arms : Vec<CExp>
+    arms : Vec<RcCExp>
   },
 
   /// In the expression `PrimitiveOp(p, vl, [w], [e1, e2, ...])` the scope
@@ -110,19 +216,585 @@ pub enum ContinuationExpression {
 }
 
 impl ContinuationExpression {
+  /// The name of the expression's variant, for traces and error messages.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      ContinuationExpression::Record { .. }      => "Record",
+      ContinuationExpression::Select { .. }      => "Select",
+      ContinuationExpression::Offset { .. }      => "Offset",
+      ContinuationExpression::Apply { .. }       => "Apply",
+      ContinuationExpression::Fix { .. }         => "Fix",
+      ContinuationExpression::Switch { .. }      => "Switch",
+      ContinuationExpression::PrimitiveOp { .. } => "PrimitiveOp",
+    }
+  }
+
+  /// The immediate subexpressions of this node: the continuation of a `Record`, `Select`, or
+  /// `Offset`; the function bodies of a `Fix` followed by its continuation; the arms of a
+  /// `Switch`; and the continuations of a `PrimitiveOp`. `Apply` has no subexpressions.
+  pub fn children(&self) -> Vec<&ContinuationExpression> {
+    match self {
+
+      | ContinuationExpression::Record { expression, .. }
+      | ContinuationExpression::Select { expression, .. }
+      | ContinuationExpression::Offset { expression, .. } => vec![expression.as_ref()],
+
+      ContinuationExpression::Apply { .. } => vec![],
+
+      ContinuationExpression::Fix { function_defs, expression } => {
+        function_defs.iter()
+                     .map(|function_def| &function_def.body)
+                     .chain(std::iter::once(expression.as_ref()))
+                     .collect()
+      }
+
+      ContinuationExpression::Switch { arms, .. } => arms.iter().map(|arm| arm.as_ref()).collect(),
+
+      ContinuationExpression::PrimitiveOp { expressions, .. } => {
+        expressions.iter().map(|expression| expression.as_ref()).collect()
+      }
+
+    }
+  }
+
+  /// The variables each child is in the scope of that this node binds, in the order of
+  /// `children`: the variable of a `Record`, `Select`, or `Offset`; the function names together
+  /// with the formal parameters in each body of a `Fix` and the function names in its
+  /// continuation; nothing in the arms of a `Switch`; and the result variables in each
+  /// continuation of a `PrimitiveOp`.
+  pub fn child_scopes(&self) -> Vec<VariableList> {
+    match self {
+
+      | ContinuationExpression::Record { variable, .. }
+      | ContinuationExpression::Select { variable, .. }
+      | ContinuationExpression::Offset { variable, .. } => vec![vec![variable.clone()]],
+
+      ContinuationExpression::Apply { .. } => vec![],
+
+      ContinuationExpression::Fix { function_defs, .. } => {
+        let names: VariableList = function_defs.iter().map(|fd| fd.name.clone()).collect();
+        function_defs.iter()
+                     .map(|fd| names.iter().chain(fd.formal_parameters.iter()).cloned().collect())
+                     .chain(std::iter::once(names.clone()))
+                     .collect()
+      }
+
+      ContinuationExpression::Switch { arms, .. } => vec![vec![]; arms.len()],
+
+      ContinuationExpression::PrimitiveOp { variables, expressions, .. } => {
+        vec![variables.clone(); expressions.len()]
+      }
+
+    }
+  }
+
+  /// The values this node itself refers to, not including those of its children: the fields of
+  /// a `Record`, the record of a `Select` or `Offset`, the function and the arguments of an
+  /// `Apply`, the selector of a `Switch`, and the arguments of a `PrimitiveOp`.
+  pub fn values(&self) -> Vec<&Value> {
+    match self {
+
+      ContinuationExpression::Record { values, .. } => values.iter().map(|(value, _)| value).collect(),
+
+      | ContinuationExpression::Select { value, .. }
+      | ContinuationExpression::Offset { value, .. }
+      | ContinuationExpression::Switch { value, .. } => vec![value],
+
+      ContinuationExpression::Apply { function, arguments } => {
+        std::iter::once(function).chain(arguments.iter()).collect()
+      }
+
+      ContinuationExpression::Fix { .. } => vec![],
+
+      ContinuationExpression::PrimitiveOp { values, .. } => values.iter().collect(),
+
+    }
+  }
+
+  /// Mutable version of `children`. The function definitions of a `Fix` are shared behind `Rc`s,
+  /// so they are copied on write if they are shared with another expression.
+  pub fn children_mut(&mut self) -> Vec<&mut ContinuationExpression> {
+    match self {
+
+      | ContinuationExpression::Record { expression, .. }
+      | ContinuationExpression::Select { expression, .. }
+      | ContinuationExpression::Offset { expression, .. } => vec![expression.as_mut()],
+
+      ContinuationExpression::Apply { .. } => vec![],
+
+      ContinuationExpression::Fix { function_defs, expression } => {
+        Rc::make_mut(function_defs).iter_mut()
+                                   .map(|function_def| Rc::make_mut(function_def).body_mut())
+                                   .chain(std::iter::once(expression.as_mut()))
+                                   .collect()
+      }
+
+      ContinuationExpression::Switch { arms, .. } => {
+        arms.iter_mut().map(Rc::make_mut).collect()
+      }
+
+      ContinuationExpression::PrimitiveOp { expressions, .. } => {
+        expressions.iter_mut().map(|expression| expression.as_mut()).collect()
+      }
+
+    }
+  }
+
+  /// A copy of this expression made without recursion, so that cloning a term nested arbitrarily
+  /// deep cannot overflow the stack the way the derived `clone` can. The function definitions of
+  /// a `Fix` and the arms of a `Switch` are behind `Rc`s and are shared rather than copied, as
+  /// with `clone`; only the boxed continuations are copied, using an explicit work stack.
+  pub fn clone_iter(&self) -> ContinuationExpression {
+    enum Work<'a> {
+      /// Copy the boxed continuations of the node, then build the node.
+      Visit(&'a ContinuationExpression),
+      /// Build the node from the copies of its boxed continuations on top of `built`.
+      Build(&'a ContinuationExpression),
+    }
+
+    let mut work : Vec<Work> = vec![Work::Visit(self)];
+    let mut built: Vec<ContinuationExpression> = Vec::new();
+
+    while let Some(item) = work.pop() {
+      match item {
+
+        Work::Visit(node) => {
+          work.push(Work::Build(node));
+          // Pushed in order, so they are built last to first and popped off `built` first to last.
+          match node {
+            | ContinuationExpression::Record { expression, .. }
+            | ContinuationExpression::Select { expression, .. }
+            | ContinuationExpression::Offset { expression, .. }
+            | ContinuationExpression::Fix { expression, .. } => work.push(Work::Visit(expression)),
+
+            ContinuationExpression::PrimitiveOp { expressions, .. } => {
+              work.extend(expressions.iter().map(|expression| Work::Visit(expression.as_ref())));
+            }
+
+            | ContinuationExpression::Apply { .. }
+            | ContinuationExpression::Switch { .. } => {}
+          }
+        }
+
+        Work::Build(node) => {
+          let copy = match node {
+
+            ContinuationExpression::Record { values, variable, .. } => {
+              ContinuationExpression::Record {
+                values    : values.clone(),
+                variable  : variable.clone(),
+                expression: Box::new(built.pop().unwrap())
+              }
+            }
+
+            ContinuationExpression::Select { location, value, variable, .. } => {
+              ContinuationExpression::Select {
+                location  : *location,
+                value     : value.clone(),
+                variable  : variable.clone(),
+                expression: Box::new(built.pop().unwrap())
+              }
+            }
+
+            ContinuationExpression::Offset { location, value, variable, .. } => {
+              ContinuationExpression::Offset {
+                location  : *location,
+                value     : value.clone(),
+                variable  : variable.clone(),
+                expression: Box::new(built.pop().unwrap())
+              }
+            }
+
+            ContinuationExpression::Fix { function_defs, .. } => {
+              ContinuationExpression::Fix {
+                function_defs: function_defs.clone(),
+                expression   : Box::new(built.pop().unwrap())
+              }
+            }
+
+            ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+              ContinuationExpression::PrimitiveOp {
+                operation  : *operation,
+                values     : values.clone(),
+                variables  : variables.clone(),
+                expressions: expressions.iter().map(|_| Box::new(built.pop().unwrap())).collect()
+              }
+            }
+
+            | ContinuationExpression::Apply { .. }
+            | ContinuationExpression::Switch { .. } => node.clone(),
+
+          };
+          built.push(copy);
+        }
+
+      }
+    }
+
+    built.pop().unwrap()
+  }
+
+  /// A variable distinct from every other variable produced by `fresh_variable`, even across
+  /// threads. Fresh variables start with `gensym::FRESH_PREFIX`; to also rule out collisions with
+  /// the variables of a particular expression, use `Gensym::avoiding`.
+  pub fn fresh_variable(hint: &str) -> Variable {
+    next_fresh_variable(hint)
+  }
+
+  /// Every variable and label occurring anywhere in the expression, whether bound or free,
+  /// including binders and function names.
+  pub fn all_variables(&self) -> HashSet<Variable> {
+    fn value_names(value: &Value, names: &mut HashSet<Variable>) {
+      if let Value::Variable(v) | Value::Label(v) = value {
+        names.insert(v.clone());
+      }
+    }
+
+    let mut names = HashSet::new();
+    match self {
+
+      ContinuationExpression::Record { values, variable, .. } => {
+        names.insert(variable.clone());
+        for (value, _) in values.iter() {
+          value_names(value, &mut names);
+        }
+      }
+
+      | ContinuationExpression::Select { value, variable, .. }
+      | ContinuationExpression::Offset { value, variable, .. } => {
+        names.insert(variable.clone());
+        value_names(value, &mut names);
+      }
+
+      ContinuationExpression::Apply { function, arguments } => {
+        value_names(function, &mut names);
+        for argument in arguments.iter() {
+          value_names(argument, &mut names);
+        }
+      }
+
+      ContinuationExpression::Fix { function_defs, .. } => {
+        for function_def in function_defs.iter() {
+          names.insert(function_def.name.clone());
+          names.extend(function_def.formal_parameters.iter().cloned());
+        }
+      }
+
+      ContinuationExpression::Switch { value, .. } => value_names(value, &mut names),
+
+      ContinuationExpression::PrimitiveOp { values, variables, .. } => {
+        names.extend(variables.iter().cloned());
+        for value in values.iter() {
+          value_names(value, &mut names);
+        }
+      }
+
+    }
+
+    for child in self.children() {
+      names.extend(child.all_variables());
+    }
+    names
+  }
+
+  /// The variables and labels that occur in the expression outside the scope of a binder for
+  /// them, following the scope rules documented on each variant. Literals are not names.
+  pub(crate) fn free_names(&self) -> HashSet<Variable> {
+    self.free(true)
+  }
+
+  /// The variables that occur in the expression outside the scope of a binder for them, following
+  /// the scope rules documented on each variant. Unlike `free_names`, this omits labels, which
+  /// name functions rather than values, as well as literals.
+  pub fn free_variables(&self) -> HashSet<Variable> {
+    self.free(false)
+  }
+
+  /// The free variables and, if `labels` is set, the free labels of the expression.
+  fn free(&self, labels: bool) -> HashSet<Variable> {
+    let value_names = | value: &Value, names: &mut HashSet<Variable> | {
+      match value {
+        Value::Variable(v)        => { names.insert(v.clone()); }
+        Value::Label(v) if labels => { names.insert(v.clone()); }
+        _                         => {}
+      }
+    };
+
+    let mut names = HashSet::new();
+    match self {
+
+      ContinuationExpression::Record { values, variable, expression } => {
+        names = expression.free(labels);
+        names.remove(variable);
+        for (value, _) in values.iter() {
+          value_names(value, &mut names);
+        }
+      }
+
+      | ContinuationExpression::Select { value, variable, expression, .. }
+      | ContinuationExpression::Offset { value, variable, expression, .. } => {
+        names = expression.free(labels);
+        names.remove(variable);
+        value_names(value, &mut names);
+      }
+
+      ContinuationExpression::Apply { function, arguments } => {
+        value_names(function, &mut names);
+        for argument in arguments.iter() {
+          value_names(argument, &mut names);
+        }
+      }
+
+      ContinuationExpression::Fix { function_defs, expression } => {
+        names = expression.free(labels);
+        for function_def in function_defs.iter() {
+          names.extend(function_def.free(labels));
+        }
+        for function_def in function_defs.iter() {
+          names.remove(&function_def.name);
+        }
+      }
+
+      ContinuationExpression::Switch { value, arms } => {
+        for arm in arms.iter() {
+          names.extend(arm.free(labels));
+        }
+        value_names(value, &mut names);
+      }
+
+      ContinuationExpression::PrimitiveOp { values, variables, expressions, .. } => {
+        for expression in expressions.iter() {
+          names.extend(expression.free(labels));
+        }
+        for variable in variables.iter() {
+          names.remove(variable);
+        }
+        for value in values.iter() {
+          value_names(value, &mut names);
+        }
+      }
+
+    }
+    names
+  }
+
+  /// Replaces the subexpression at `path` with `replacement`. A path is a sequence of child
+  /// indices from the root, each indexing into `children` of the node reached so far. The empty
+  /// path denotes the root itself.
+  pub fn replace_at(
+    mut self,
+    path       : &[usize],
+    replacement: ContinuationExpression
+  ) -> Result<ContinuationExpression, PathError>
+  {
+    let mut node = &mut self;
+    for (depth, &index) in path.iter().enumerate() {
+      let mut children = node.children_mut();
+      let child_count  = children.len();
+      if index >= child_count {
+        return Err(PathError::NoSuchChild { depth, index, child_count });
+      }
+      node = children.swap_remove(index);
+    }
+    *node = replacement;
+    Ok(self)
+  }
+
+  /**
+  Canonicalizes the expression so that alpha-equivalent expressions compare equal: the
+  definitions of every `Fix` are sorted, and then every binder is renamed to `$0`, `$1`, ... in
+  pre-order. Free variables are left untouched.
+
+  Definitions are sorted by their structure with the names they bind erased (see
+  `definition_key`), never by their names, so reordering the definitions of a `Fix` and renaming
+  its functions both normalize away. Definitions whose erased structures are identical keep their
+  relative order.
+  */
+  pub fn normalize(self) -> ContinuationExpression {
+    let mut next_binder = 0;
+    self.normalize_with(&HashMap::new(), &mut next_binder)
+  }
+
+  fn normalize_with(
+    self,
+    renaming   : &HashMap<Variable, Variable>,
+    next_binder: &mut usize
+  ) -> ContinuationExpression
+  {
+    fn rename_value(value: Value, renaming: &HashMap<Variable, Variable>) -> Value {
+      match value {
+        Value::Variable(v) => Value::Variable(renaming.get(&v).cloned().unwrap_or(v)),
+        Value::Label(v)    => Value::Label(renaming.get(&v).cloned().unwrap_or(v)),
+        literal            => literal
+      }
+    }
+
+    fn bind_fresh(
+      variable   : Variable,
+      renaming   : &mut HashMap<Variable, Variable>,
+      next_binder: &mut usize
+    ) -> Variable
+    {
+      let fresh = Variable::new(&format!("${}", next_binder));
+      *next_binder += 1;
+      renaming.insert(variable, fresh.clone());
+      fresh
+    }
+
+    match self {
+
+      ContinuationExpression::Record { values, variable, expression } => {
+        let values = values.into_iter()
+                           .map(|(value, access_path)| (rename_value(value, renaming), access_path))
+                           .collect();
+        let mut inner = renaming.clone();
+        let variable = bind_fresh(variable, &mut inner, next_binder);
+        ContinuationExpression::Record {
+          values,
+          variable,
+          expression: Box::new(expression.normalize_with(&inner, next_binder))
+        }
+      }
+
+      ContinuationExpression::Select { location, value, variable, expression } => {
+        let value = rename_value(value, renaming);
+        let mut inner = renaming.clone();
+        let variable = bind_fresh(variable, &mut inner, next_binder);
+        ContinuationExpression::Select {
+          location,
+          value,
+          variable,
+          expression: Box::new(expression.normalize_with(&inner, next_binder))
+        }
+      }
+
+      ContinuationExpression::Offset { location, value, variable, expression } => {
+        let value = rename_value(value, renaming);
+        let mut inner = renaming.clone();
+        let variable = bind_fresh(variable, &mut inner, next_binder);
+        ContinuationExpression::Offset {
+          location,
+          value,
+          variable,
+          expression: Box::new(expression.normalize_with(&inner, next_binder))
+        }
+      }
+
+      ContinuationExpression::Apply { function, arguments } => {
+        ContinuationExpression::Apply {
+          function : rename_value(function, renaming),
+          arguments: arguments.into_iter().map(|value| rename_value(value, renaming)).collect()
+        }
+      }
+
+      ContinuationExpression::Fix { function_defs, expression } => {
+        let mut function_defs: Vec<RcFunctionDefinition> = function_defs.as_ref().clone();
+        let sibling_names: VariableList = function_defs.iter().map(|fd| fd.name.clone()).collect();
+        function_defs.sort_by_cached_key(
+          |function_def| ContinuationExpression::definition_key(function_def, &sibling_names, renaming)
+        );
+
+        // The function names scope over every body and the continuation.
+        let mut scope = renaming.clone();
+        let names: VariableList = function_defs.iter()
+                                               .map(|fd| bind_fresh(fd.name.clone(), &mut scope, next_binder))
+                                               .collect();
+
+        let function_defs = function_defs.into_iter().zip(names).map(
+          | (function_def, name) | {
+            let FunctionDefinition{ formal_parameters, body, .. } = Rc::unwrap_or_clone(function_def);
+            let mut inner = scope.clone();
+            let formal_parameters = formal_parameters.into_iter()
+                                                     .map(|v| bind_fresh(v, &mut inner, next_binder))
+                                                        .collect();
+            Rc::new(FunctionDefinition::new(name, formal_parameters, body.normalize_with(&inner, next_binder)))
+          }
+        ).collect();
+
+        ContinuationExpression::Fix {
+          function_defs: Rc::new(function_defs),
+          expression   : Box::new(expression.normalize_with(&scope, next_binder))
+        }
+      }
+
+      ContinuationExpression::Switch { value, arms } => {
+        ContinuationExpression::Switch {
+          value: rename_value(value, renaming),
+          arms : arms.into_iter()
+                     .map(|arm| Rc::new(Rc::unwrap_or_clone(arm).normalize_with(renaming, next_binder)))
+                     .collect()
+        }
+      }
+
+      ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+        let values = values.into_iter().map(|value| rename_value(value, renaming)).collect();
+        let mut inner = renaming.clone();
+        let variables = variables.into_iter()
+                                 .map(|v| bind_fresh(v, &mut inner, next_binder))
+                                 .collect();
+        ContinuationExpression::PrimitiveOp {
+          operation,
+          values,
+          variables,
+          expressions: expressions.into_iter()
+                                  .map(|e| Box::new(e.normalize_with(&inner, next_binder)))
+                                  .collect()
+        }
+      }
+
+    }
+  }
+
+  /// The key by which `normalize` sorts the definitions of a `Fix`: the number of formal
+  /// parameters and the normalized body, with every function of the `Fix` renamed to the same
+  /// placeholder and the parameters numbered from `$0`. Nothing in the key depends on the names
+  /// the `Fix` binds or on the position of the definition.
+  fn definition_key(
+    function_def : &FunctionDefinition,
+    sibling_names: &[Variable],
+    renaming     : &HashMap<Variable, Variable>
+  ) -> (usize, String)
+  {
+    let placeholder = Variable::new("$fix");
+    let mut inner   = renaming.clone();
+    for name in sibling_names {
+      inner.insert(name.clone(), placeholder.clone());
+    }
+    for (index, parameter) in function_def.formal_parameters.iter().enumerate() {
+      inner.insert(parameter.clone(), Variable::new(&format!("${}", index)));
+    }
+    let mut next_binder = function_def.formal_parameters.len();
+    let body = function_def.body.clone().normalize_with(&inner, &mut next_binder);
+    (function_def.formal_parameters.len(), body.to_string())
+  }
+
   pub fn evaluate(self, environment: Environment) -> Answer{
+    self.evaluate_node(environment, 0)
+  }
+
+  /// Evaluates the expression, whose id is `id` in the numbering of `coverage`. The ids of the
+  /// children, which are only known while coverage is being recorded, are passed down to them.
+  fn evaluate_node(self, environment: Environment, id: NodeId) -> Answer{
+    trace(&self, &environment);
+    coverage::record(id);
+    let child_ids = coverage::child_ids(&self, id);
+    let child_id  = | child: usize | child_ids.as_ref().map_or(0, |ids| ids[child]);
     match self {
 
       ContinuationExpression::Record { values, variable, expression } => {
 
         // Convert each `Value` to a `DValue`, and Resolve any access paths to the value it
         // ultimately points to.
-        let d_values: DValueList = values.iter().map(
+        let d_values: Result<DValueList, Exception> = values.iter().map(
             | (value, access_path) | {
-              let d_value = environment.value_to_denotable_value(value);
-              resolve_field(d_value, access_path.clone())
+              let d_value = environment.try_value_to_denotable_value(value)?;
+              Ok(resolve_field(d_value, access_path.clone()))
             }
           ).collect();
+        let d_values = match d_values {
+          Ok(d_values)   => d_values,
+          Err(exception) => return exception.as_answer()
+        };
 
         let record = DValue::Record {
             values: d_values,
@@ -131,7 +803,7 @@ impl ContinuationExpression {
 
         let new_environment = environment.bind(variable.clone(), record);
 
-        expression.evaluate(new_environment)
+        expression.evaluate_node(new_environment, child_id(0))
 
       }
 
@@ -141,9 +813,18 @@ impl ContinuationExpression {
         variable  : w_variable,
         expression: e_cexp
       } => {
-        if let DValue::Record {values, idx} = environment.value_to_denotable_value(&v_value) {
-          let new_environment = environment.bind(w_variable.clone(), values[i+idx].clone());
-          e_cexp.evaluate(new_environment)
+        let record = match environment.try_value_to_denotable_value(&v_value) {
+          Ok(record)     => record,
+          Err(exception) => return exception.as_answer()
+        };
+        if let DValue::Record {values, idx} = record {
+          match idx.checked_add_signed(i).and_then(|field| values.get(field)) {
+            Some(field_value) => {
+              let new_environment = environment.bind(w_variable.clone(), field_value.clone());
+              e_cexp.evaluate_node(new_environment, child_id(0))
+            }
+            None => Exception::IndexOutOfBounds.as_answer()
+          }
         } else {
           Exception::InvalidAccess.as_answer()
         }
@@ -155,10 +836,20 @@ impl ContinuationExpression {
         variable  : w_variable,
         expression: e_cexp
       } => {
-        if let DValue::Record {values, idx} = environment.value_to_denotable_value(&v_value) {
-          let bind_value = DValue::Record{values, idx:i+idx};
-          let new_environment = environment.bind(w_variable.clone(), bind_value);
-          e_cexp.evaluate(new_environment)
+        let record = match environment.try_value_to_denotable_value(&v_value) {
+          Ok(record)     => record,
+          Err(exception) => return exception.as_answer()
+        };
+        if let DValue::Record {values, idx} = record {
+          // The new pointer may point just past the last field, but not outside the record.
+          match idx.checked_add_signed(i).filter(|new_idx| *new_idx <= values.len()) {
+            Some(new_idx) => {
+              let bind_value = DValue::Record{values, idx: new_idx};
+              let new_environment = environment.bind(w_variable.clone(), bind_value);
+              e_cexp.evaluate_node(new_environment, child_id(0))
+            }
+            None => Exception::IndexOutOfBounds.as_answer()
+          }
         } else {
           Exception::InvalidAccess.as_answer()
         }
@@ -168,11 +859,15 @@ impl ContinuationExpression {
         function : f_value, // A label/variable bound to a function.
         arguments: l_values
       } => {
-        if let DValue::Function(denotable_function) = environment.value_to_denotable_value(&f_value) {
-          let parameters = l_values.iter()
-                                   .map(|x|environment.value_to_denotable_value(x))
-                                   .collect();
-          denotable_function(parameters) // : Answer
+        let function = match environment.try_value_to_denotable_value(&f_value) {
+          Ok(function)   => function,
+          Err(exception) => return exception.as_answer()
+        };
+        if let DValue::Function(denotable_function) = function {
+          match environment.values_to_denotable_values(&l_values) {
+            Ok(parameters) => denotable_function(parameters), // : Answer
+            Err(exception) => exception.as_answer()
+          }
         } else {
           Exception::Undefined.as_answer()
         }
@@ -204,30 +899,56 @@ impl ContinuationExpression {
         fn h(
              r1_environment: RcEnvironment,
              function_def  : RcFunctionDefinition,
-             fl_list       : RcFunctionDefinitionList
+             fl_list       : RcFunctionDefinitionList,
+             closure_info  : Rc<ClosureInfo>,
+             closure_infos : RcClosureInfoList,
+             body_id       : NodeId,
+             body_ids      : Rc<Vec<NodeId>>
         ) -> DValue
         {
           let continuation: Rc<RawContinuation> =
             Rc::new(move
               | actual_parameters, store | {
-                let bound_r1_environment = g(r1_environment.clone(), fl_list.clone());
+                if actual_parameters.len() != function_def.formal_parameters.len() {
+                  return store.raise_exception(Exception::Bind);
+                }
+                let bound_r1_environment =
+                    g(r1_environment.clone(), fl_list.clone(), closure_infos.clone(), body_ids.clone());
                 let new_environment =
                     bound_r1_environment.bindn(&function_def.formal_parameters, actual_parameters);
 
-                (function_def.body.clone().evaluate(new_environment))(store)
+                function_def.body.clone().evaluate_node(new_environment, body_id).suspended(store)
               }
             );
 
-          DValue::Function(DenotableFunction{f: continuation})
+          DValue::Function(DenotableFunction::closure(continuation, closure_info))
         }
         /// The function `g` takes an environment `r` as an argument and returns `r` augmented
         /// by binding all the function names (map #1 fl) to the function bodies (map (h r) fl).
         /// ~~This function captures `fl_list`.~~
-        fn g(r: RcEnvironment, fl_list: RcFunctionDefinitionList) -> Environment {
+        fn g(
+          r            : RcEnvironment,
+          fl_list      : RcFunctionDefinitionList,
+          closure_infos: RcClosureInfoList,
+          body_ids     : Rc<Vec<NodeId>>
+        ) -> Environment
+        {
           let function_names: VariableList = fl_list.iter().map(|fd | fd.name.clone() ).collect();
 
           let function_values = fl_list.iter()
-                                       .map(|fd| h(r.clone(), fd.clone(), fl_list.clone()))
+                                       .zip(closure_infos.iter())
+                                       .zip(body_ids.iter())
+                                       .map(|((fd, info), body_id)| {
+                                         h(
+                                           r.clone(),
+                                           fd.clone(),
+                                           fl_list.clone(),
+                                           info.clone(),
+                                           closure_infos.clone(),
+                                           *body_id,
+                                           body_ids.clone()
+                                         )
+                                       })
                                        .collect::<DValueList>();
           r.bindn(
             &function_names,
@@ -235,19 +956,74 @@ impl ContinuationExpression {
           )
         }
 
+        // The descriptions of the closures only depend on the definitions, so they are computed
+        // once here rather than every time `g` rebuilds the functions.
+        let function_names: VariableList = fl_list.iter().map(|fd | fd.name.clone() ).collect();
+        let closure_infos: RcClosureInfoList = Rc::new(
+          fl_list.iter().map(
+            | fd | {
+              let mut captured = fd.free_names();
+              for name in function_names.iter() {
+                captured.remove(name);
+              }
+              Rc::new(
+                ClosureInfo{
+                  name             : fd.name.clone(),
+                  formal_parameters: fd.formal_parameters.clone(),
+                  captured
+                }
+              )
+            }
+          ).collect()
+        );
+
+        // The closures capture only the part of the environment that the function bodies refer
+        // to, so that they don't keep every other binding alive. All functions of the `Fix` share
+        // one captured environment, because calling any one of them rebuilds the whole group from
+        // its own captured environment with `g`.
+        let captured_variables: HashSet<Variable> = fl_list.iter()
+                                                           .flat_map(|fd| fd.free_names())
+                                                           .collect();
+        let captured_environment = Rc::new(environment.restricted_to(&captured_variables));
 
+        // The children of a `Fix` are the function bodies followed by its continuation.
+        let body_ids: Rc<Vec<NodeId>> = Rc::new((0..fl_list.len()).map(child_id).collect());
 
-        e_cexp.evaluate(g(Rc::new(environment), fl_list))
+        let function_values = fl_list.iter()
+                                     .zip(closure_infos.iter())
+                                     .zip(body_ids.iter())
+                                     .map(|((fd, info), body_id)| {
+                                       h(
+                                         captured_environment.clone(),
+                                         fd.clone(),
+                                         fl_list.clone(),
+                                         info.clone(),
+                                         closure_infos.clone(),
+                                         *body_id,
+                                         body_ids.clone()
+                                       )
+                                     })
+                                     .collect::<DValueList>();
+
+        e_cexp.evaluate_node(environment.bindn(&function_names, &function_values), child_id(fl_list.len()))
       }
 
       ContinuationExpression::Switch {
         value,
-        arms: el_cexp_list
+        arms: mut el_cexp_list
       } => {
-        if let DValue::Integer(i) = environment.value_to_denotable_value(&value){
-          el_cexp_list[i as usize].clone().evaluate(environment)
-        } else {
-          Exception::IndexOutOfBounds.as_answer()
+        let selector = match environment.try_value_to_denotable_value(&value) {
+          Ok(selector)   => selector,
+          Err(exception) => return exception.as_answer()
+        };
+        let arm = match selector {
+          DValue::Integer(i) => usize::try_from(i).ok().filter(|i| *i < el_cexp_list.len()),
+          _                  => None
+        };
+        match arm {
+          // Only the selected arm is taken out of the list. It is copied only if it is shared.
+          Some(i) => Rc::unwrap_or_clone(el_cexp_list.swap_remove(i)).evaluate_node(environment, child_id(i)),
+          None    => Exception::Match.as_answer()
         }
       }
 
@@ -264,20 +1040,22 @@ impl ContinuationExpression {
         variables  : wl,
         expressions: el
       } => {
-        let d_values = vl.iter().map(|v| (&environment).value_to_denotable_value(v)).collect();
+        let d_values = match environment.values_to_denotable_values(&vl) {
+          Ok(d_values)   => d_values,
+          Err(exception) => return exception.as_answer()
+        };
         let mut continuations: ContinuationList = Vec::new();
         let rc_environment = Rc::new(environment);
         let rc_wl = Rc::new(wl);
-        for c in el.into_iter() {
+        for (k, c) in el.into_iter().enumerate() {
           // let ce: ContinuationExpression = *c;
           let environment = rc_environment.clone();
           let wl = rc_wl.clone();
+          let id = child_id(k);
           continuations.push(
-            Continuation {
-              f: Rc::new(move |parameters, store| {
-                c.clone().evaluate(environment.bindn(&wl, &parameters)).call_once((store,))
-              })
-            }
+            Continuation::from_fn(move |parameters, store| {
+              c.clone().evaluate_node(environment.bindn(&wl, parameters), id).suspended(store)
+            })
           )
         }
 
@@ -288,3 +1066,404 @@ impl ContinuationExpression {
   }
 }
 
+
+/**
+The hash is structural, so equal expressions hash equally however their subexpressions are
+shared. A function definition of a `Fix`, the unit a `Fix` shares behind an `Rc`, contributes its
+digest rather than its contents. The digest is stored on the definition the first time it is
+computed and reused by every later hash of any expression containing the same definition, so a
+definition shared many times, or hashed again and again, is walked only once. See
+`FunctionDefinition::digest`.
+*/
+impl Hash for ContinuationExpression {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    std::mem::discriminant(self).hash(state);
+
+    match self {
+
+      ContinuationExpression::Record { values, variable, expression } => {
+        values.hash(state);
+        variable.hash(state);
+        expression.hash(state);
+      }
+
+      | ContinuationExpression::Select { location, value, variable, expression }
+      | ContinuationExpression::Offset { location, value, variable, expression } => {
+        location.hash(state);
+        value.hash(state);
+        variable.hash(state);
+        expression.hash(state);
+      }
+
+      ContinuationExpression::Apply { function, arguments } => {
+        function.hash(state);
+        arguments.hash(state);
+      }
+
+      ContinuationExpression::Fix { function_defs, expression } => {
+        function_defs.len().hash(state);
+        for function_def in function_defs.iter() {
+          function_def.digest().hash(state);
+        }
+        expression.hash(state);
+      }
+
+      ContinuationExpression::Switch { value, arms } => {
+        value.hash(state);
+        arms.hash(state);
+      }
+
+      ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+        operation.hash(state);
+        values.hash(state);
+        variables.hash(state);
+        expressions.hash(state);
+      }
+
+    }
+  }
+}
+
+impl FromStr for ContinuationExpression {
+  type Err = ParseError;
+
+  /// Parses the S-expression syntax of `parser::parse`.
+  fn from_str(source: &str) -> Result<Self, Self::Err> {
+    parse(source)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{cps::store::Store, evaluate, run, tests::run_source};
+
+  fn expression(source: &str) -> ContinuationExpression {
+    parse(source).unwrap()
+  }
+
+  #[test]
+  fn children_of_each_variant() {
+    let cases = [
+      ("(record (1 2) r (app k r))", 1),
+      ("(select 0 r x (app k x))", 1),
+      ("(offset 1 r x (app k x))", 1),
+      ("(app k 1 2)", 0),
+      ("(fix ((f (x) (app x)) (g (y) (app y))) (app f g))", 3),
+      ("(switch i (app k 0) (app k 1) (app k 2) (app k 3))", 4),
+      ("(primop < (a b) () ((app k 1) (app k 0)))", 2),
+    ];
+    for (source, count) in cases.iter() {
+      let mut e = expression(source);
+      assert_eq!(e.children().len(), *count, "{}", source);
+      assert_eq!(e.children_mut().len(), *count, "{}", source);
+    }
+  }
+
+  #[test]
+  fn children_of_a_fix_are_the_bodies_then_the_continuation() {
+    let e = expression("(fix ((f (x) (app x 1)) (g (y) (app y 2))) (app f 3))");
+    let children: Vec<String> = e.children().iter().map(|child| child.to_string()).collect();
+    assert_eq!(children, ["APP(x, [1])", "APP(y, [2])", "APP(f, [3])"]);
+  }
+
+  #[test]
+  fn children_mut_edits_in_place() {
+    let mut e = expression("(switch i (app k 0) (app k 1))");
+    *e.children_mut()[1] = expression("(app k 9)");
+    assert_eq!(e, expression("(switch i (app k 0) (app k 9))"));
+  }
+
+  #[test]
+  fn switch_runs_the_selected_arm() {
+    let result = run_source("(switch 2 (app halt 10) (app halt 11) (app halt 12) (app halt 13))");
+    assert_eq!(format!("{:?}", result), "[12]");
+
+    let result = run_source("(switch 4 (app halt 10) (app halt 11))");
+    assert_eq!(format!("{:?}", result), format!("{:?}", vec![Exception::Match.as_record()]));
+  }
+
+  #[test]
+  fn cloning_a_switch_shares_its_arms() {
+    let e = expression("(switch i (app k 0) (app k 1))");
+    match (&e, &e.clone()) {
+      (ContinuationExpression::Switch { arms: a, .. }, ContinuationExpression::Switch { arms: b, .. }) => {
+        assert!(a.iter().zip(b.iter()).all(|(a, b)| Rc::ptr_eq(a, b)));
+      }
+      _ => unreachable!()
+    }
+  }
+
+  #[test]
+  fn switch_dispatch_in_a_loop() {
+    // Adds 1, 10, 100, or 1000 to the total depending on the low bits of the counter.
+    let result = run_source(
+      "(fix ((f (i total)
+               (primop ieql (i 0) () (
+                 (app halt total)
+                 (primop andb (i 3) (s) (
+                   (primop - (i 1) (j) (
+                     (switch s
+                       (primop + (total 1) (t) ((app f j t)))
+                       (primop + (total 10) (t) ((app f j t)))
+                       (primop + (total 100) (t) ((app f j t)))
+                       (primop + (total 1000) (t) ((app f j t))))))))))))
+         (app f 4000 0))"
+    );
+    assert_eq!(format!("{:?}", result), "[1111000]");
+  }
+
+  #[test]
+  fn normalize_identifies_alpha_equivalent_expressions() {
+    let a = expression("(record (1 2) r (primop + (r 1) (x) ((app k x))))");
+    let b = expression("(record (1 2) s (primop + (s 1) (y) ((app k y))))");
+    assert_eq!(a.normalize(), b.normalize());
+
+    // Free variables are not renamed.
+    let c = expression("(record (1 2) s (primop + (s 1) (y) ((app j y))))");
+    assert_ne!(expression("(record (1 2) r (primop + (r 1) (x) ((app k x))))").normalize(), c.normalize());
+  }
+
+  #[test]
+  fn normalize_ignores_definition_order() {
+    let a = expression("(fix ((f (x) (app x 1)) (g (y) (app y 2))) (app f g))");
+    let b = expression("(fix ((g (y) (app y 2)) (f (x) (app x 1))) (app f g))");
+    assert_eq!(a.normalize(), b.normalize());
+  }
+
+  #[test]
+  fn normalize_sorts_definitions_independently_of_their_names() {
+    // The same program with the names of its two functions swapped: sorting by name would pair
+    // `a` with a different body in each.
+    let a = expression("(fix ((a (x) (app x 1)) (b (y z) (app b y z))) (app a b))");
+    let b = expression("(fix ((b (x) (app x 1)) (a (y z) (app a y z))) (app b a))");
+    assert_eq!(a.normalize(), b.normalize());
+
+    let c = expression("(fix ((b (x) (app x 1)) (a (y z) (app a y z))) (app a b))");
+    assert_ne!(expression("(fix ((a (x) (app x 1)) (b (y z) (app b y z))) (app a b))").normalize(), c.normalize());
+  }
+
+  #[test]
+  fn replace_at_edits_a_nested_arm() {
+    let original = expression("(fix ((f (x) (switch x (app k 0) (app k 1)))) (app f 1))");
+    let edited   = original.clone().replace_at(&[0, 1], expression("(app k 9)")).unwrap();
+    assert_eq!(edited, expression("(fix ((f (x) (switch x (app k 0) (app k 9)))) (app f 1))"));
+    assert_eq!(original.replace_at(&[], expression("(app k 9)")).unwrap(), expression("(app k 9)"));
+  }
+
+  #[test]
+  fn replace_at_rejects_an_invalid_path() {
+    let original = expression("(fix ((f (x) (switch x (app k 0) (app k 1)))) (app f 1))");
+    assert_eq!(
+      original.replace_at(&[0, 5], expression("(app k 9)")),
+      Err(PathError::NoSuchChild { depth: 1, index: 5, child_count: 2 })
+    );
+  }
+
+  #[test]
+  fn a_closure_captures_only_the_free_variables_of_its_group() {
+    let names  = ["halt", "a", "unused"].iter().map(|name| Variable::new(name)).collect();
+    let values = vec![Store::default_handler(), DValue::Integer(40), DValue::Integer(0)];
+    let source = "(fix ((f (x) (primop + (x a) (y) ((app g y)))) (g (z) (app halt z))) (app halt f g))";
+    let result = run(evaluate(names, values, expression(source)).unwrap(), 16);
+
+    let captured = | value: &DValue | {
+      let mut names: Vec<&str> = value.closure_info().unwrap().captured.iter().map(|v| v.name()).collect();
+      names.sort_unstable();
+      names
+    };
+    assert_eq!(captured(&result[0]), vec!["a"]);
+    assert_eq!(captured(&result[1]), vec!["halt"]);
+  }
+
+  #[test]
+  fn a_trimmed_closure_still_sees_its_free_variables_and_siblings() {
+    let result = run_source(
+      "(primop + (40 0) (a) (
+         (fix ((f (x) (primop + (x a) (y) ((app g y)))) (g (z) (app halt z))) (app f 2))))"
+    );
+    assert_eq!(format!("{:?}", result), "[42]");
+  }
+
+  #[test]
+  fn an_inner_fix_shadows_an_outer_function_only_inside_itself() {
+    let result = run_source(
+      "(fix ((f (k) (app k 1))
+             (g (k) (fix ((f (k2) (app k2 2))) (app f k))))
+         (fix ((c1 (a) (fix ((c2 (b) (app halt a b))) (app f c2))))
+           (app g c1)))"
+    );
+    assert_eq!(format!("{:?}", result), "[2, 1]");
+  }
+
+  #[test]
+  fn a_nested_fix_refers_to_outer_and_sibling_functions() {
+    let result = run_source(
+      "(fix ((base (k) (app k 10))
+             (outer (k) (fix ((p (k2) (app base k2)) (q (k3) (app p k3))) (app q k))))
+         (app outer halt))"
+    );
+    assert_eq!(format!("{:?}", result), "[10]");
+
+    // The inner function calls back into the outer one recursively.
+    let result = run_source(
+      "(fix ((count (n k)
+               (switch n
+                 (app k 100)
+                 (fix ((step (m) (primop - (m 1) (m2) ((app count m2 k))))) (app step n)))))
+         (app count 1 halt))"
+    );
+    assert_eq!(format!("{:?}", result), "[100]");
+  }
+
+  #[test]
+  fn from_str_agrees_with_parse() {
+    let source = "(fix ((f (x k) (primop + (x 1) (y) ((app k y))))) (app f 1 halt))";
+    let parsed: ContinuationExpression = source.parse().unwrap();
+    assert_eq!(parsed, parse(source).unwrap());
+    assert!("(app f".parse::<ContinuationExpression>().is_err());
+  }
+
+  /// A chain of `depth` nested `Record`s around `(app k r)`, built without recursion.
+  fn deep_records(depth: usize) -> ContinuationExpression {
+    let mut term = expression("(app k r)");
+    for _ in 0..depth {
+      term = ContinuationExpression::Record {
+        values    : vec![(Value::Integer(1), Rc::new(AccessPath::Offset(0)))],
+        variable  : Variable::new("r"),
+        expression: Box::new(term)
+      };
+    }
+    term
+  }
+
+  /// The depth of a chain of `Record`s, consuming it one node at a time so that dropping it does
+  /// not recurse.
+  fn take_depth(mut term: ContinuationExpression) -> usize {
+    let mut depth = 0;
+    while let ContinuationExpression::Record { expression, .. } = term {
+      term = *expression;
+      depth += 1;
+    }
+    assert_eq!(term, expression("(app k r)"));
+    depth
+  }
+
+  #[test]
+  fn clone_iter_copies_a_moderate_term_exactly() {
+    let term = expression(
+      "(fix ((f (x) (switch x (app k 0) (app k 1)))) (record (1 2) r (primop + (1 2) (y) ((app f y)))))"
+    );
+    assert_eq!(term.clone_iter(), term);
+  }
+
+  #[test]
+  fn clone_iter_copies_a_very_deep_term() {
+    let term   = deep_records(100_000);
+    let copied = term.clone_iter();
+    assert_eq!(take_depth(copied), 100_000);
+    assert_eq!(take_depth(term), 100_000);
+  }
+
+  #[test]
+  fn select_reaches_back_from_an_offset_record() {
+    assert_eq!(
+      format!("{:?}", run_source("(record (10 20 30) r (offset 2 r q (select -2 q x (select -1 q y (app halt x y)))))")),
+      "[10, 20]"
+    );
+    assert_eq!(
+      format!("{:?}", run_source("(record (10 20 30) r (offset 1 r q (offset -1 q p (select 0 p x (app halt x)))))")),
+      "[10]"
+    );
+  }
+
+  #[test]
+  fn select_before_the_start_of_a_record_is_out_of_bounds() {
+    assert_eq!(
+      format!("{:?}", run_source("(record (10 20 30) r (offset 1 r q (select -2 q x (app halt x))))")),
+      format!("{:?}", vec![Exception::IndexOutOfBounds.as_record()])
+    );
+  }
+
+  fn hash_of(term: &ContinuationExpression) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    term.hash(&mut hasher);
+    hasher.finish()
+  }
+
+  #[test]
+  fn equal_terms_hash_equally() {
+    let source = "(fix ((f (x) (switch x (app k 0) (app k 1)))) (record (1 2) r (app f r)))";
+    let (a, b) = (expression(source), expression(source));
+    // Computing the digests of `a` first does not change its hash or its equality with `b`.
+    let first = hash_of(&a);
+    assert_eq!(a, b);
+    assert_eq!(hash_of(&a), first);
+    assert_eq!(hash_of(&b), first);
+    assert_ne!(hash_of(&expression("(fix ((f (x) (switch x (app k 0) (app k 2)))) (record (1 2) r (app f r)))")), first);
+  }
+
+  #[test]
+  fn a_shared_definition_is_hashed_once() {
+    // Each level defines the previous level's definition twice, through the same `Rc`, so the
+    // term has 2^60 paths to the innermost definition. It hashes quickly only if every
+    // definition's digest is computed once and reused.
+    let x = Variable::new("x");
+    let halt = expression("(app halt x)");
+    let mut definition = Rc::new(FunctionDefinition::new(Variable::new("f"), vec![x.clone()], halt.clone()));
+    for _ in 0..60 {
+      let body = ContinuationExpression::Fix {
+        function_defs: Rc::new(vec![definition.clone(), definition.clone()]),
+        expression   : Box::new(halt.clone())
+      };
+      definition = Rc::new(FunctionDefinition::new(Variable::new("f"), vec![x.clone()], body));
+    }
+    let term = ContinuationExpression::Fix { function_defs: Rc::new(vec![definition]), expression: Box::new(halt) };
+    assert_eq!(hash_of(&term), hash_of(&term.clone()));
+  }
+
+  #[test]
+  fn changing_a_body_changes_the_digest() {
+    let mut term = expression("(fix ((f (x) (app k 0))) (app f 1))");
+    let before   = hash_of(&term);
+    *term.children_mut()[0] = expression("(app k 1)");
+    assert_ne!(hash_of(&term), before);
+    assert_eq!(hash_of(&term), hash_of(&expression("(fix ((f (x) (app k 1))) (app f 1))")));
+  }
+
+  #[test]
+  fn an_unbound_variable_raises_undefined_wherever_it_is_used() {
+    let undefined = format!("{:?}", vec![Exception::Undefined.as_record()]);
+    for source in [
+      "(app halt y)",
+      "(app g 1)",
+      "(record (y) r (app halt r))",
+      "(select 0 y x (app halt x))",
+      "(switch y (app halt 0))",
+      "(primop + (y 1) (x) ((app halt x)))",
+    ].iter() {
+      assert_eq!(format!("{:?}", run_source(source)), undefined, "{}", source);
+    }
+  }
+
+  #[test]
+  fn free_variables_follow_the_scope_rules() {
+    let free = | source: &str | {
+      let mut names: Vec<String> = expression(source).free_variables()
+                                                     .iter()
+                                                     .map(|v| v.name().to_string())
+                                                     .collect();
+      names.sort();
+      names
+    };
+    // `w` is bound only in the continuation of the record, not in its fields.
+    assert_eq!(free("(record (a w) w (app k w b))"), vec!["a", "b", "k", "w"]);
+    // The functions of a `Fix` scope over every body and the continuation.
+    assert_eq!(free("(fix ((f (x) (app g x y)) (g (z) (app f z))) (app f c))"), vec!["c", "y"]);
+    // The results of an operation are bound in each of its continuations.
+    assert_eq!(free("(primop + (a 1) (s) ((app k s)))"), vec!["a", "k"]);
+    assert_eq!(free("(select 0 r v (offset 1 v u (switch u (app k v) (app k \"s\" 2.5))))"), vec!["k", "r"]);
+    // Labels and literals are not free variables.
+    assert_eq!(free("(app @f 1 2.0 \"three\")"), Vec::<String>::new());
+  }
+}
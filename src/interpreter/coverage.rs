@@ -0,0 +1,209 @@
+/*!
+
+  Node coverage: which subexpressions of a program were evaluated during a run. Every node of
+  the program gets the id of its position in a pre-order walk over `ContinuationExpression::children`,
+  the same numbering `listing` uses, and `run_with_coverage` returns the set of the ids of the
+  nodes that were evaluated. The arms of a `Switch` that were never selected, the continuations of
+  a `PrimitiveOp` that were never taken, and the bodies of functions that were never called are
+  left out.
+
+  `annotate` renders the program like its `Display` implementation, with every node that was not
+  evaluated marked by a leading `!`:
+
+  ```text
+  SWITCH(0, [
+    APP(halt, [1]),
+    !APP(halt, [2])])
+  ```
+
+  Before the run, `run_with_coverage` walks the program once to find the number of nodes under
+  each id, from which the ids of the children of a node are found in time proportional to their
+  number when the node is evaluated. Other evaluation pays for coverage with a read of a
+  thread-local flag per step.
+
+*/
+
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+use crate::interpreter::{
+  VariableList,
+  context::{EvalContext, RECORDING_COVERAGE},
+  continuation_expression::ContinuationExpression,
+  cps::{
+    denotable_value::DenotableValueList,
+    store::Store
+  },
+  evaluate,
+  exception::InternalException,
+  pretty::write_annotated
+};
+
+/// The position of a node in the pre-order walk of the program it belongs to. The root is `0`.
+pub type NodeId = usize;
+
+/// The ids of the nodes that were evaluated.
+pub type Coverage = HashSet<NodeId>;
+
+/// The coverage of the `run_with_coverage` in progress.
+#[derive(Clone, Default)]
+pub(crate) struct CoverageRecording {
+  covered: Coverage,
+  /// The number of nodes of the subexpression with each id, itself included.
+  sizes  : Vec<usize>
+}
+
+/// Like `evaluate` followed by `run`, but also returns the `Coverage` of the run.
+pub fn run_with_coverage(
+  variables : VariableList,
+  values    : DenotableValueList,
+  expression: ContinuationExpression,
+  store_size: usize
+) -> Result<(DenotableValueList, Coverage), InternalException>
+{
+  let mut sizes = Vec::new();
+  node_sizes(&expression, &mut sizes);
+  let previous = EvalContext::replace_node_coverage(Some(CoverageRecording { covered: Coverage::new(), sizes }));
+  let store    = Store::new(store_size);
+
+  let result = evaluate(variables, values, expression).map(|answer| {
+    let mut answer = answer;
+    while !answer.is_halted() {
+      answer = answer(&store);
+    }
+    answer.parameters().clone()
+  });
+
+  let coverage = EvalContext::replace_node_coverage(previous).unwrap_or_default().covered;
+  result.map(|parameters| (parameters, coverage))
+}
+
+/// Renders `expression` like its `Display` implementation, marking the nodes that are not in
+/// `coverage` with a leading `!`.
+pub fn annotate(expression: &ContinuationExpression, coverage: &Coverage) -> String {
+  Annotated { expression, coverage }.to_string()
+}
+
+struct Annotated<'a> {
+  expression: &'a ContinuationExpression,
+  coverage  : &'a Coverage
+}
+
+impl Display for Annotated<'_> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write_annotated(self.expression, Some(self.coverage), f)
+  }
+}
+
+/// Records that the node `id` is being evaluated, if coverage is being recorded.
+pub(crate) fn record(id: NodeId) {
+  if !RECORDING_COVERAGE.with(Cell::get) {
+    return;
+  }
+  EvalContext::with_current_mut(|context| {
+    if let Some(recording) = &mut context.node_coverage {
+      recording.covered.insert(id);
+    }
+  });
+}
+
+/// The ids of the children of `expression`, whose id is `id`, in the order of
+/// `ContinuationExpression::children`, if coverage is being recorded.
+pub(crate) fn child_ids(expression: &ContinuationExpression, id: NodeId) -> Option<Vec<NodeId>> {
+  if !RECORDING_COVERAGE.with(Cell::get) {
+    return None;
+  }
+
+  EvalContext::with_current(|context| {
+    let sizes = &context.node_coverage.as_ref()?.sizes;
+    let mut ids  = Vec::new();
+    let mut next = id + 1;
+    for _ in expression.children() {
+      ids.push(next);
+      next += sizes.get(next).copied().unwrap_or(1);
+    }
+    Some(ids)
+  })
+}
+
+/// Appends to `sizes` the number of nodes of each subexpression of `expression`, itself first, in
+/// pre-order, and returns the number of nodes of `expression`.
+fn node_sizes(expression: &ContinuationExpression, sizes: &mut Vec<usize>) -> usize {
+  let id = sizes.len();
+  sizes.push(1);
+  for child in expression.children() {
+    sizes[id] += node_sizes(child, sizes);
+  }
+  sizes[id]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{Variable, cps::store::Store, parser::parse};
+
+  fn coverage_of(source: &str) -> (String, Coverage) {
+    let expression = parse(source).unwrap();
+    let (values, coverage) = run_with_coverage(
+      vec![Variable::new("halt")],
+      vec![Store::default_handler()],
+      expression.clone(),
+      16
+    ).unwrap();
+    (format!("{:?}", values), coverage)
+  }
+
+  #[test]
+  fn a_switch_leaves_the_arms_it_did_not_select_uncovered() {
+    let source = "(switch 1 (app halt 1) (app halt 2) (app halt 3))";
+    let (values, coverage) = coverage_of(source);
+    assert_eq!(values, "[2]");
+    assert_eq!(coverage, [0, 2].iter().copied().collect());
+    assert_eq!(
+      annotate(&parse(source).unwrap(), &coverage),
+      "SWITCH(1, [\n  !APP(halt, [1]),\n  APP(halt, [2]),\n  !APP(halt, [3])])"
+    );
+  }
+
+  #[test]
+  fn uncalled_functions_and_untaken_branches_are_uncovered() {
+    // In pre-order: 0 the `fix`, 1 the body of `f`, 2 the body of `g`, 3 and 4 its branches,
+    // and 5 the `app g`.
+    let (values, coverage) = coverage_of(
+      "(fix ((f (x) (app halt x))
+             (g (x) (primop < (x 3) () ((app halt 0) (app halt 1)))))
+         (app g 5))"
+    );
+    assert_eq!(values, "[1]");
+    assert_eq!(coverage, [0, 2, 4, 5].iter().copied().collect());
+    // The coverage of the run is not left behind in the context.
+    assert!(EvalContext::with_current(|context| context.node_coverage.is_none()));
+  }
+
+  #[test]
+  fn the_ids_follow_the_pre_order_of_nested_subexpressions() {
+    // 0 the outer `switch`, 1 to 4 its first arm, 5 the `fix`, 6 the body of `f`, 7 the `app f`.
+    let (values, coverage) = coverage_of(
+      "(switch 1
+         (switch 0 (primop + (1 2) (x) ((app halt x))) (app halt 0))
+         (fix ((f (x) (app halt x))) (app f 9)))"
+    );
+    assert_eq!(values, "[9]");
+    assert_eq!(coverage, [0, 5, 6, 7].iter().copied().collect());
+
+    let mut sizes = Vec::new();
+    let expression = parse("(switch 1 (switch 0 (primop + (1 2) (x) ((app halt x))) (app halt 0)) (app halt 1))").unwrap();
+    node_sizes(&expression, &mut sizes);
+    assert_eq!(sizes, vec![6, 4, 2, 1, 1, 1]);
+  }
+
+  #[test]
+  fn nothing_is_recorded_outside_a_run() {
+    coverage_of("(app halt 0)");
+    assert!(!RECORDING_COVERAGE.with(Cell::get));
+    record(0);
+    assert!(EvalContext::with_current(|context| context.node_coverage.is_none()));
+    assert_eq!(child_ids(&parse("(switch 0 (app halt 0))").unwrap(), 0), None);
+  }
+}
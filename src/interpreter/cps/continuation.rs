@@ -12,12 +12,17 @@
 */
 
 
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::rc::Rc;
 use std::cmp::Eq;
+use std::fmt::{self, Debug, Formatter};
 
 
 use crate::{
   interpreter::{
+    Variable,
+    VariableList,
     cps::{
       denotable_value::DenotableValueList,
       store::Store
@@ -43,6 +48,58 @@ pub type ContinuationList = Vec<Continuation>;  // Defined below.
 #[derive(Clone)]
 pub struct Continuation{
   pub f: Rc<RawContinuation>, // (parameters, store) -> answer
+  /// Describes the function a `Fix` defined, if this continuation is one. See `ClosureInfo`.
+  pub(crate) info: Option<Rc<ClosureInfo>>,
+}
+
+/// What a debugger can know about a function defined by a `Fix`, whose closure is otherwise
+/// opaque.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ClosureInfo {
+  pub name             : Variable,
+  pub formal_parameters: VariableList,
+  /// The variables and labels the body refers to that are neither formal parameters nor names of
+  /// the functions of the same `Fix`, computed once when the closure is created. These are the
+  /// bindings the closure captures from its defining environment.
+  pub captured         : HashSet<Variable>,
+}
+
+impl Continuation {
+  /// Wraps a closure taking the parameters and the store.
+  pub fn from_fn<F>(f: F) -> Continuation
+    where F: Fn(&Parameters, &Store) -> Answer + 'static
+  {
+    Continuation{
+      f   : Rc::new(f),
+      info: None
+    }
+  }
+
+  /// A continuation for a function defined by a `Fix`, described by `info`.
+  pub fn closure(f: Rc<RawContinuation>, info: Rc<ClosureInfo>) -> Continuation {
+    Continuation{
+      f,
+      info: Some(info)
+    }
+  }
+
+  pub fn closure_info(&self) -> Option<&ClosureInfo> {
+    self.info.as_deref()
+  }
+
+  /// A terminal continuation, together with the cell in which it records the parameters it is
+  /// applied to. Applying it ends evaluation with a terminal `Answer` (see `Answer::halt`)
+  /// carrying the same parameters. The halt continuation ignores the store it is given, so the
+  /// final values are all it captures.
+  pub fn halt() -> (Continuation, Rc<RefCell<Option<Parameters>>>) {
+    let result: Rc<RefCell<Option<Parameters>>> = Rc::new(RefCell::new(None));
+    let captured = result.clone();
+    let halt = Continuation::from_fn(move | parameters, _store | {
+      *captured.borrow_mut() = Some(parameters.clone());
+      Answer::halt(parameters.clone())
+    });
+    (halt, result)
+  }
 }
 
 impl Eq for Continuation {}
@@ -100,6 +157,65 @@ pub struct Answer {
   pub(crate) parameters: Parameters
 }
 
+thread_local! {
+  /// The continuation of every terminal `Answer`. See `Answer::halt`.
+  static HALT: Rc<RawContinuation> = Rc::new(| parameters, _store | Answer::halt(parameters.clone()));
+}
+
+impl Answer {
+  /// A terminal answer carrying `parameters` as the final result of the program. Evaluating a
+  /// terminal answer with any store yields an equal terminal answer, so it is a fixed point of
+  /// evaluation.
+  pub fn halt(parameters: Parameters) -> Answer {
+    Answer{
+      f: HALT.with(Rc::clone),
+      parameters
+    }
+  }
+
+  /// Whether this answer was made by `Answer::halt`.
+  pub fn is_halted(&self) -> bool {
+    HALT.with(|halt| Rc::ptr_eq(halt, &self.f))
+  }
+
+  /**
+  Suspends the answer together with the store it is to be evaluated with: evaluating the
+  suspended answer with any store evaluates this answer with `store`. A terminal answer is
+  returned as it is.
+
+  A continuation that has produced the answer of the rest of the program returns it suspended
+  rather than evaluating it. The loop driving the program (see `interpreter::run`) then takes the
+  next step, so the Rust stack does not grow with every call the program makes, and a
+  tail-recursive loop runs in constant stack space as it does in \[Appel].
+  */
+  pub fn suspended(self, store: &Store) -> Answer {
+    if self.is_halted() {
+      return self;
+    }
+    let store      = store.clone();
+    let parameters = self.parameters.clone();
+    Answer{
+      f: Rc::new(move | _, _ | (self.f)(&self.parameters, &store)),
+      parameters
+    }
+  }
+
+  /// The parameters the answer's continuation will be applied to. For a terminal answer, this is
+  /// the result of the program.
+  pub fn parameters(&self) -> &Parameters {
+    &self.parameters
+  }
+}
+
+impl Debug for Answer {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Answer")
+     .field("halted", &self.is_halted())
+     .field("parameters", &self.parameters)
+     .finish()
+  }
+}
+
 // region impl Fn<Store> for Answer
 impl Fn<(&Store,)> for Answer {
   /// An `Answer` `c` is callable as `c(store)` and returns an `Answer`.
@@ -125,3 +241,29 @@ impl FnOnce<(&Store,)> for Answer {
 }
 
 // endregion
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{Variable, cps::denotable_value::DValue, evaluate, parser::parse, run};
+
+  #[test]
+  fn the_halt_continuation_captures_the_final_values() {
+    let (halt, result) = Continuation::halt();
+    assert!(result.borrow().is_none());
+
+    let expression = parse("(primop + (1 2) (x) ((app halt x 4)))").unwrap();
+    let answer     = evaluate(vec![Variable::new("halt")], vec![DValue::Function(halt)], expression).unwrap();
+    assert_eq!(format!("{:?}", run(answer, 4)), "[3, 4]");
+    assert_eq!(format!("{:?}", result.borrow()), "Some([3, 4])");
+  }
+
+  #[test]
+  fn a_terminal_answer_is_a_fixed_point() {
+    let answer = Answer::halt(vec![DValue::Integer(1)]);
+    assert!(answer.is_halted());
+    let again = answer(&Store::new(1));
+    assert!(again.is_halted());
+    assert_eq!(format!("{:?}", again.parameters()), "[1]");
+  }
+}
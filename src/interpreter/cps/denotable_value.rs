@@ -28,6 +28,9 @@
 */
 
 
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
 use std::ops::Range;
 use std::rc::Rc;
 
@@ -37,13 +40,14 @@ use crate::{
     Integer,
     Location,
     Real,
-    arbitrarily
+    arbitrarily,
+    value::Value
   }
 };
 
 use super::{
-  continuation::Continuation,
-  store::{AccessPath},
+  continuation::{ClosureInfo, Continuation},
+  store::{AccessPath, Store},
 };
 
 // use saucepan::{Span, Source, Sources, ByteIndex, ByteOffset};
@@ -102,12 +106,337 @@ pub enum DenotableValue {
   /// indices. (See `ByteArray`.)
   UnboxedArray(Range<Location>),
 
+  /// A `WeakRef` refers to the cells of an `Array` without keeping them alive: once
+  /// `Store::compact` finds the cells unreachable, the reference is cleared. See
+  /// `PrimitiveOp::MakeWeak` and `PrimitiveOp::DerefWeak`.
+  WeakRef(Range<Location>),
+
   /// A `DenotableFunction` is a type alias for `Continuation`.
   Function(DenotableFunction),
 
   Exception(Exception)
 }
 
+impl DenotableValue {
+  /// The name of the value's variant, for error messages.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      DValue::Record { .. }    => "Record",
+      DValue::Integer(_)       => "Integer",
+      DValue::Real(_)          => "Real",
+      DValue::String(_)        => "String",
+      DValue::ByteArray(_)     => "ByteArray",
+      DValue::Array(_)         => "Array",
+      DValue::UnboxedArray(_)  => "UnboxedArray",
+      DValue::WeakRef(_)       => "WeakRef",
+      DValue::Function(_)      => "Function",
+      DValue::Exception(_)     => "Exception",
+    }
+  }
+
+  /// The number of bytes the value itself occupies: the size of a `DValue` plus the bytes of a
+  /// `String`. The fields of a record and the cells of an array are not included. See
+  /// `deep_size`.
+  pub fn shallow_size(&self) -> usize {
+    let own_size = std::mem::size_of::<DValue>();
+    match self {
+      DValue::String(s) => own_size + s.len(),
+      _                 => own_size
+    }
+  }
+
+  /// The number of bytes occupied by the value graph rooted at this value: its shallow size plus,
+  /// recursively, the sizes of record fields and of the store cells that arrays point into. A
+  /// store cell reachable along several paths is counted once.
+  pub fn deep_size(&self, store: &Store) -> usize {
+    let mut visited: HashSet<Location> = HashSet::new();
+    self.deep_size_visiting(store, &mut visited)
+  }
+
+  fn deep_size_visiting(&self, store: &Store, visited: &mut HashSet<Location>) -> usize {
+    let cells_size = match self {
+
+      DValue::Record { values, .. } => {
+        values.iter()
+              .map(|value| value.deep_size_visiting(store, visited))
+              .sum()
+      }
+
+      DValue::Array(range) => {
+        let mut size = 0;
+        for location in range.clone() {
+          if !visited.insert(location) {
+            continue;
+          }
+          if let Ok(value) = store.fetch(location) {
+            size += value.deep_size_visiting(store, visited);
+          }
+        }
+        size
+      }
+
+      | DValue::UnboxedArray(range)
+      | DValue::ByteArray(range) => {
+        range.clone()
+             .filter(|location| visited.insert(*location))
+             .count() * std::mem::size_of::<Integer>()
+      }
+
+      _ => 0
+
+    };
+
+    self.shallow_size() + cells_size
+  }
+
+  /// Compares two values by content: records field by field (with the same offset), strings by
+  /// their characters, and arrays of the same kind by the contents of their store cells, again
+  /// recursively. Functions are never equal, and store cells that cannot be fetched compare
+  /// unequal. A pair of arrays met again while comparing them is assumed equal, so cyclic
+  /// structures compare without looping.
+  ///
+  /// This is _not_ the `eq` of \[Appel], which only promises a conservative answer for records
+  /// and strings. It is used by `ieql`/`ineq` under `EqualityMode::StructuralDeep` so that tests
+  /// get reproducible results.
+  pub fn structurally_equal(&self, other: &DValue, store: &Store) -> bool {
+    let mut visited: HashSet<(Location, Location)> = HashSet::new();
+    self.structurally_equal_visiting(other, store, &mut visited)
+  }
+
+  fn structurally_equal_visiting(
+    &self,
+    other  : &DValue,
+    store  : &Store,
+    visited: &mut HashSet<(Location, Location)>
+  ) -> bool
+  {
+    match (self, other) {
+
+      (
+        DValue::Record { values: values_lhs, idx: idx_lhs },
+        DValue::Record { values: values_rhs, idx: idx_rhs }
+      ) => {
+        idx_lhs == idx_rhs
+        && values_lhs.len() == values_rhs.len()
+        && values_lhs.iter()
+                     .zip(values_rhs.iter())
+                     .all(|(a, b)| a.structurally_equal_visiting(b, store, visited))
+      }
+
+      (DValue::String(a), DValue::String(b)) => a == b,
+
+      (DValue::Array(range_a), DValue::Array(range_b)) => {
+        if range_a.len() != range_b.len() {
+          return false;
+        }
+        for (a, b) in range_a.clone().zip(range_b.clone()) {
+          if !visited.insert((a, b)) {
+            continue;
+          }
+          let equal = match (store.fetch(a), store.fetch(b)) {
+            (Ok(x), Ok(y)) => x.structurally_equal_visiting(y, store, visited),
+            _              => false
+          };
+          if !equal {
+            return false;
+          }
+        }
+        true
+      }
+
+      (DValue::UnboxedArray(range_a), DValue::UnboxedArray(range_b))
+      | (DValue::ByteArray(range_a), DValue::ByteArray(range_b)) => {
+        range_a.len() == range_b.len()
+        && range_a.clone().zip(range_b.clone()).all(
+             |(a, b)| {
+               match (store.fetch_integer(a), store.fetch_integer(b)) {
+                 (Ok(x), Ok(y)) => x == y,
+                 _              => false
+               }
+             }
+           )
+      }
+
+      (DValue::Function(_), DValue::Function(_)) => false,
+
+      (DValue::Exception(a), DValue::Exception(b)) => a == b,
+
+      // Integers, reals, and weak references are immediate, so `eq` is already exact.
+      _ => self == other
+
+    }
+  }
+}
+
+/// Renders a `DValue` together with the contents of the store cells its arrays point into. See
+/// `DenotableValue::display_with_store`.
+pub struct StoreDisplay<'a> {
+  value: &'a DValue,
+  store: &'a Store
+}
+
+impl DenotableValue {
+  /// The field `i` of a record, counting from where the record value points. `None` if the value
+  /// is not a record or the record has no such field.
+  pub fn field(&self, i: usize) -> Option<&DValue> {
+    match self {
+      DValue::Record { values, idx } => idx.checked_add(i).and_then(|k| values.get(k)),
+      _                              => None
+    }
+  }
+
+  /// Describes the closure if the value is a function defined by a `Fix`: its name, its formal
+  /// parameters, and the variables it captures.
+  pub fn closure_info(&self) -> Option<ClosureInfo> {
+    match self {
+      DValue::Function(function) => function.closure_info().cloned(),
+      _                          => None
+    }
+  }
+
+  /// Displays the value with arrays resolved against `store`: records render as `{f0, f1, ...}`
+  /// (followed by `@idx` if the record is offset), boxed and unboxed arrays as `[e0, e1, ...]`,
+  /// byte arrays as `b"..."`, and scalars plainly. An array that contains itself renders as `...`
+  /// where it recurs.
+  pub fn display_with_store<'a>(&'a self, store: &'a Store) -> impl Display + 'a {
+    StoreDisplay { value: self, store }
+  }
+}
+
+impl<'a> StoreDisplay<'a> {
+  fn fmt_value(
+    &self,
+    value   : &DValue,
+    visiting: &mut Vec<Location>,
+    f       : &mut Formatter<'_>
+  ) -> fmt::Result
+  {
+    match value {
+
+      DValue::Record { values, idx } => {
+        write!(f, "{{")?;
+        for (i, field) in values.iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          self.fmt_value(field, visiting, f)?;
+        }
+        write!(f, "}}")?;
+        if *idx != 0 {
+          write!(f, "@{}", idx)?;
+        }
+        Ok(())
+      }
+
+      DValue::ByteArray(range) => {
+        let bytes: Vec<u8> = range.clone()
+                                  .map(|location| match self.store.fetch_integer(location) {
+                                    Ok(DValue::Integer(byte)) => byte as u8,
+                                    _                         => 0
+                                  })
+                                  .collect();
+        write!(f, "b\"{}\"", bytes.escape_ascii())
+      }
+
+      DValue::Array(range) => {
+        if visiting.contains(&range.start) {
+          return write!(f, "...");
+        }
+        visiting.push(range.start);
+        write!(f, "[")?;
+        for (i, location) in range.clone().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          match self.store.fetch(location) {
+            Ok(element) => self.fmt_value(element, visiting, f)?,
+            Err(_)      => write!(f, "<unallocated>")?
+          }
+        }
+        visiting.pop();
+        write!(f, "]")
+      }
+
+      DValue::UnboxedArray(range) => {
+        write!(f, "[")?;
+        for (i, location) in range.clone().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          match self.store.fetch_integer(location) {
+            Ok(DValue::Integer(n)) => write!(f, "{}", n)?,
+            _                      => write!(f, "<unallocated>")?
+          }
+        }
+        write!(f, "]")
+      }
+
+      // Values that do not live in the store render as they do without one.
+      value => write!(f, "{}", value),
+
+    }
+  }
+}
+
+impl<'a> Display for StoreDisplay<'a> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    self.fmt_value(self.value, &mut Vec::new(), f)
+  }
+}
+
+/// Displays the value without a store, so arrays render as the range of locations they occupy:
+/// `array[start..end]`, `uarray[start..end]`, `bytearray[start..end]`, or `weak[start..end]`.
+/// Records render as `{f0, f1, ...}`, followed by `@idx` if the record is offset, reals as float
+/// literals, strings quoted, functions as `<fn>`, and exceptions by name. See
+/// `display_with_store` to render the contents of arrays.
+impl Display for DenotableValue {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+
+      DValue::Record { values, idx } => {
+        write!(f, "{{")?;
+        for (i, field) in values.iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{}", field)?;
+        }
+        write!(f, "}}")?;
+        if *idx != 0 {
+          write!(f, "@{}", idx)?;
+        }
+        Ok(())
+      }
+
+      DValue::Integer(i) => write!(f, "{}", i),
+
+      DValue::Real(r) => write!(f, "{:?}", r.0),
+
+      DValue::String(s) => write!(f, "{:?}", s),
+
+      DValue::ByteArray(range) => write!(f, "bytearray[{}..{}]", range.start, range.end),
+
+      DValue::Array(range) => write!(f, "array[{}..{}]", range.start, range.end),
+
+      DValue::UnboxedArray(range) => write!(f, "uarray[{}..{}]", range.start, range.end),
+
+      DValue::WeakRef(range) => write!(f, "weak[{}..{}]", range.start, range.end),
+
+      DValue::Function(_) => write!(f, "<fn>"),
+
+      DValue::Exception(exception) => write!(f, "{}", exception),
+
+    }
+  }
+}
+
+/// The same as `Display`, so that the results of a run can be inspected with `{:?}`.
+impl fmt::Debug for DenotableValue {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    Display::fmt(self, f)
+  }
+}
+
 impl PartialEq for DValue {
   /// This definition of equality models the semantics of pointer equality. The
   /// instances of `arbitrarily` correspond to variant instances that may be
@@ -145,20 +474,23 @@ impl PartialEq for DValue {
         // \[Appel], real numbers are boxed.
         //
         // Also, all NaN values are equal to each other for `OrderedFloat`, so it is not a
-        // special case.
+        // special case. This keeps `ieql` on reals consistent with `feql`, which compares the
+        // same `OrderedFloat`s.
         // arbitrarily(a == b, false) // || (a.is_nan() && b.is_nan())
         a == b
       },
 
       (DValue::String(a), DValue::String(b)) => {
         // Strings and records are "pure values" and cannot be reliably compared by pointer.
-        // See the comment on `DValue::Record` equality.
-        arbitrarily(a.as_ptr() == b.as_ptr(), false)
+        // See the comment on `DValue::Record` equality. Strings with equal contents at different
+        // addresses may compare either way.
+        arbitrarily(a.as_ptr() == b.as_ptr(), a == b)
       },
 
       (DValue::ByteArray(range_a), DValue::ByteArray(range_b))
       | (DValue::Array(range_a), DValue::Array(range_b))
       | (DValue::UnboxedArray(range_a), DValue::UnboxedArray(range_b))
+      | (DValue::WeakRef(range_a), DValue::WeakRef(range_b))
       => {
         // Two `*Array`s of the same type are equal if and only if they represent the same range of
         // indices in the `Store`. Thus, two distinct `*Array`s might hold the same values and
@@ -205,6 +537,28 @@ impl From<String> for DValue {
   }
 }
 
+impl TryFrom<&Value> for DValue {
+  type Error = Exception;
+
+  /// The context-free part of `Environment::value_to_denotable_value`: literal integers, reals,
+  /// and strings convert directly. A `Value::Variable` or `Value::Label` can only be converted by
+  /// looking it up in an `Environment`, so it is `Exception::Undefined` here.
+  fn try_from(value: &Value) -> Result<Self, Self::Error> {
+    match value {
+
+      Value::Integer(i) => Ok(DValue::Integer(*i)),
+
+      Value::Real(r) => Ok(DValue::Real(*r)),
+
+      Value::String(s) => Ok(DValue::String(s.clone())),
+
+      | Value::Variable(_)
+      | Value::Label(_) => Err(Exception::Undefined),
+
+    }
+  }
+}
+
 /// Accesses the value of the field pointed to by an `AccessPath`. This is function `F` in [Appel].
 // Todo: Do we return a value or a reference to a value?
 pub fn resolve_field(value: DValue, access_path: Rc<AccessPath>) -> DValue {
@@ -215,8 +569,12 @@ pub fn resolve_field(value: DValue, access_path: Rc<AccessPath>) -> DValue {
     (DValue::Record {values, idx}, AccessPath::Offset(j))
       => DValue::Record {values, idx: idx + j},
 
-    (DValue::Record {values, idx}, AccessPath::Select {offset, access_path})
-      => resolve_field(values[idx + offset].clone(), access_path.clone()),
+    (record @ DValue::Record { .. }, AccessPath::Select {offset, access_path}) => {
+      match record.field(*offset) {
+        Some(value) => resolve_field(value.clone(), access_path.clone()),
+        None        => DValue::Exception(Exception::InvalidAccess)
+      }
+    }
 
     (_, _) => {
       DValue::Exception(Exception::InvalidAccess)
@@ -226,3 +584,92 @@ pub fn resolve_field(value: DValue, access_path: Rc<AccessPath>) -> DValue {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::Variable;
+
+  #[test]
+  fn literal_values_convert_without_an_environment() {
+    assert!(matches!(DValue::try_from(&Value::Integer(3)), Ok(DValue::Integer(3))));
+    assert!(matches!(DValue::try_from(&Value::Real(Real::from(2.5))), Ok(DValue::Real(r)) if r == Real::from(2.5)));
+    match DValue::try_from(&Value::String("abc".to_string())) {
+      Ok(DValue::String(s)) => assert_eq!(s.as_str(), "abc"),
+      other                 => panic!("expected a string, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn variables_and_labels_need_an_environment() {
+    assert_eq!(DValue::try_from(&Value::Variable(Variable::new("x"))).err(), Some(Exception::Undefined));
+    assert_eq!(DValue::try_from(&Value::Label(Variable::new("f"))).err(), Some(Exception::Undefined));
+  }
+
+  #[test]
+  fn deep_size_counts_the_cells_of_a_contained_array_once() {
+    let (store, range) = Store::new(4).allocate(3).unwrap();
+    let store = store.fill(range.clone(), &DValue::Integer(1)).unwrap();
+    let array = DValue::Array(range);
+    let record = DValue::Record { values: vec![array.clone(), array, DValue::Integer(2)], idx: 0 };
+
+    let cell = std::mem::size_of::<DValue>();
+    // The record, its three fields, and the three cells, each counted once.
+    assert_eq!(record.deep_size(&store), cell + 3 * cell + 3 * cell);
+    assert_eq!(record.shallow_size(), cell);
+  }
+
+  #[test]
+  fn display_with_store_resolves_a_contained_array() {
+    let (store, range) = Store::new(4).allocate(2).unwrap();
+    let store = store.update(range.start, DValue::Integer(1)).unwrap()
+                     .update(range.start + 1, DValue::String("two".to_string())).unwrap();
+    let record = DValue::Record { values: vec![DValue::Array(range), DValue::Real(Real::from(2.5))], idx: 0 };
+
+    assert_eq!(record.display_with_store(&store).to_string(), r#"{[1, "two"], 2.5}"#);
+  }
+
+  #[test]
+  fn closure_info_reports_the_captured_variables() {
+    let result = crate::interpreter::tests::run_source(
+      "(primop + (1 2) (x) ((primop + (x 3) (y) (
+         (fix ((f (k) (primop + (x y) (z) ((app k z))))) (app halt f))))))"
+    );
+    let info = result[0].closure_info().unwrap();
+    assert_eq!(info.name.name(), "f");
+    assert_eq!(info.formal_parameters, vec![Variable::new("k")]);
+    let mut captured: Vec<&str> = info.captured.iter().map(|v| v.name()).collect();
+    captured.sort_unstable();
+    assert_eq!(captured, vec!["x", "y"]);
+
+    assert!(DValue::Integer(1).closure_info().is_none());
+    assert!(Store::default_handler().closure_info().is_none());
+  }
+
+  #[test]
+  fn field_is_relative_to_the_offset_of_a_record() {
+    let record = DValue::Record { values: vec![DValue::Integer(10), DValue::Integer(20), DValue::Integer(30)], idx: 1 };
+    assert!(matches!(record.field(0), Some(DValue::Integer(20))));
+    assert!(matches!(record.field(1), Some(DValue::Integer(30))));
+    assert!(record.field(2).is_none());
+    assert!(record.field(usize::MAX).is_none());
+    assert!(DValue::Integer(1).field(0).is_none());
+  }
+
+  #[test]
+  fn display_renders_each_variant() {
+    let record = DValue::Record {
+      values: vec![DValue::Integer(-3), DValue::Real(1.0.into()), DValue::String("a\"b".into())],
+      idx   : 0
+    };
+    assert_eq!(record.to_string(), r#"{-3, 1.0, "a\"b"}"#);
+    assert_eq!(DValue::Record { values: vec![DValue::Integer(1), DValue::Integer(2)], idx: 1 }.to_string(), "{1, 2}@1");
+    assert_eq!(DValue::Array(2..5).to_string(), "array[2..5]");
+    assert_eq!(DValue::UnboxedArray(2..5).to_string(), "uarray[2..5]");
+    assert_eq!(DValue::ByteArray(0..1).to_string(), "bytearray[0..1]");
+    assert_eq!(DValue::WeakRef(3..4).to_string(), "weak[3..4]");
+    assert_eq!(Store::default_handler().to_string(), "<fn>");
+    assert_eq!(DValue::Exception(Exception::Overflow).to_string(), "Overflow");
+    assert_eq!(format!("{:?}", vec![DValue::Real(2.5.into())]), "[2.5]");
+  }
+}
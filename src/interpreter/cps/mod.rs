@@ -1,6 +1,7 @@
 /*!
 
-
+  The denotational machinery of [Appel, Chapter 3]: denotable values, the store, and
+  continuations.
 
  */
 
@@ -10,4 +11,6 @@
 
 pub mod denotable_value;
 pub mod store;
+pub mod observer;
+pub mod persistent_vector;
 pub mod continuation;
@@ -0,0 +1,84 @@
+/*!
+
+  An `Observer` is notified of every change the interpreter makes to the `Store`: allocation,
+  update, and collection of cells. This lets a tool such as a heap visualizer follow the evolution
+  of the store as it happens instead of comparing snapshots.
+
+  An observer is installed with `EvalContext::with_observer`. Without one, the notifications cost
+  a check of the context and nothing more.
+
+*/
+
+use std::ops::Range;
+
+use crate::interpreter::{
+  Location,
+  context::EvalContext,
+  cps::denotable_value::DValue
+};
+
+/// Every method has an empty default implementation, so an observer only implements the events
+/// it cares about. Observers receive `&self`; one that records events needs interior mutability.
+pub trait Observer {
+  /// The cells in `range` have been allocated.
+  fn on_allocate(&self, _range: &Range<Location>) {}
+
+  /// The cell at `location` has changed from `old` to `new`. Cells written with `update_integer`
+  /// report their integers as `DValue::Integer`s.
+  fn on_update(&self, _location: Location, _old: &DValue, _new: &DValue) {}
+
+  /// The cells in `range` have been found unreachable and cleared by `Store::compact`.
+  fn on_free(&self, _range: &Range<Location>) {}
+}
+
+/// Calls `f` with the observer of the current context, if there is one. The observer is called
+/// outside of the borrow of the context, so it may itself consult the context.
+pub(crate) fn notify<F>(f: F)
+  where F: FnOnce(&dyn Observer)
+{
+  if let Some(observer) = EvalContext::with_current(|context| context.observer.clone()) {
+    f(observer.as_ref());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::rc::Rc;
+
+  use super::*;
+  use crate::interpreter::tests::run_source;
+
+  #[derive(Default)]
+  struct Recorder {
+    events: RefCell<Vec<String>>
+  }
+
+  impl Observer for Recorder {
+    fn on_allocate(&self, range: &Range<Location>) {
+      self.events.borrow_mut().push(format!("allocate {:?}", range));
+    }
+
+    fn on_update(&self, location: Location, old: &DValue, new: &DValue) {
+      self.events.borrow_mut().push(format!("update {} {:?} -> {:?}", location, old, new));
+    }
+  }
+
+  #[test]
+  fn a_recording_observer_sees_allocation_then_updates() {
+    let recorder = Rc::new(Recorder::default());
+    let previous = EvalContext::new().with_observer(recorder.clone()).install();
+    let values   = run_source(
+      "(primop mkarray (2 7) (a) ((primop update (a 1 8) () ((primop update (a 0 9) () ((app halt 1)))))))"
+    );
+    previous.install();
+
+    assert_eq!(format!("{:?}", values), "[1]");
+    // The array occupies cells 1 and 2, after the cell of the store's exception handler, and is
+    // filled with its initial value before the program's updates.
+    assert_eq!(
+      *recorder.events.borrow(),
+      vec!["allocate 1..3", "update 1 0 -> 7", "update 2 0 -> 7", "update 2 7 -> 8", "update 1 7 -> 9"]
+    );
+  }
+}
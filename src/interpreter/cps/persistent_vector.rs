@@ -0,0 +1,230 @@
+/*!
+
+  A persistent vector: cloning is O(1), and the clones share their structure until one of them
+  is written. The elements are kept in the leaves of a trie with `WIDTH` children per node, and a
+  write copies only the nodes on the path from the root to the element written, which are
+  O(log n) nodes of at most `WIDTH` slots each, rather than the whole vector.
+
+  This is what lets every `Store` threaded through a continuation be its own snapshot without
+  each `update` copying the entire store.
+
+*/
+
+use std::ops::Index;
+use std::rc::Rc;
+
+/// The number of bits of an index consumed by each level of the trie.
+const BITS : u32   = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK : usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum Node<T> {
+  Branch(Vec<Rc<Node<T>>>),
+  Leaf(Vec<T>)
+}
+
+impl<T> Node<T> {
+  /// The number of children or elements the node holds, which is what copying it costs.
+  fn slots(&self) -> usize {
+    match self {
+      Node::Branch(children) => children.len(),
+      Node::Leaf(values)     => values.len()
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct PersistentVector<T> {
+  root : Rc<Node<T>>,
+  /// The number of index bits below the root. Zero when the root is a leaf.
+  shift: u32,
+  len  : usize
+}
+
+impl<T: Clone> PersistentVector<T> {
+  pub fn new() -> Self {
+    PersistentVector {
+      root : Rc::new(Node::Leaf(Vec::new())),
+      shift: 0,
+      len  : 0
+    }
+  }
+
+  /// A vector of `len` copies of `value`, like `vec![value; len]`.
+  pub fn from_elem(value: T, len: usize) -> Self {
+    let mut vector = PersistentVector::new();
+    vector.extend_to(len, value);
+    vector
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn get(&self, idx: usize) -> Option<&T> {
+    if idx >= self.len {
+      return None;
+    }
+    let mut node  = self.root.as_ref();
+    let mut shift = self.shift;
+    loop {
+      match node {
+        Node::Branch(children) => {
+          node   = &children[(idx >> shift) & MASK];
+          shift -= BITS;
+        }
+        Node::Leaf(values) => return Some(&values[idx & MASK])
+      }
+    }
+  }
+
+  /// Writes `value` at `idx`, copying the nodes on the path to `idx` that are shared with other
+  /// vectors. Returns the number of slots copied, which is zero if the path was not shared.
+  ///
+  /// Panics if `idx` is out of bounds.
+  pub fn set(&mut self, idx: usize, value: T) -> usize {
+    assert!(idx < self.len, "index out of bounds: the len is {} but the index is {}", self.len, idx);
+    let mut copied = 0;
+    let mut node   = &mut self.root;
+    let mut shift  = self.shift;
+    loop {
+      if Rc::strong_count(node) > 1 {
+        copied += node.slots();
+      }
+      match Rc::make_mut(node) {
+        Node::Branch(children) => {
+          node   = &mut children[(idx >> shift) & MASK];
+          shift -= BITS;
+        }
+        Node::Leaf(values) => {
+          values[idx & MASK] = value;
+          return copied;
+        }
+      }
+    }
+  }
+
+  /// Appends `value`, adding a level to the trie when it is full.
+  pub fn push(&mut self, value: T) {
+    if self.len == WIDTH << self.shift {
+      let old_root = self.root.clone();
+      self.root    = Rc::new(Node::Branch(vec![old_root]));
+      self.shift  += BITS;
+    }
+
+    let idx       = self.len;
+    let mut node  = &mut self.root;
+    let mut shift = self.shift;
+    loop {
+      match Rc::make_mut(node) {
+        Node::Branch(children) => {
+          let child = (idx >> shift) & MASK;
+          if child == children.len() {
+            let new_node = if shift == BITS {
+              Node::Leaf(Vec::with_capacity(WIDTH))
+            } else {
+              Node::Branch(Vec::with_capacity(WIDTH))
+            };
+            children.push(Rc::new(new_node));
+          }
+          node   = &mut children[child];
+          shift -= BITS;
+        }
+        Node::Leaf(values) => {
+          values.push(value);
+          break;
+        }
+      }
+    }
+    self.len += 1;
+  }
+
+  /// Grows the vector to `len` elements by appending copies of `value`. A vector that is already
+  /// at least `len` long is left as it is.
+  pub fn extend_to(&mut self, len: usize, value: T) {
+    while self.len < len {
+      self.push(value.clone());
+    }
+  }
+}
+
+impl<T: Clone> Default for PersistentVector<T> {
+  fn default() -> Self {
+    PersistentVector::new()
+  }
+}
+
+impl<T: Clone> Index<usize> for PersistentVector<T> {
+  type Output = T;
+
+  fn index(&self, idx: usize) -> &T {
+    match self.get(idx) {
+      Some(value) => value,
+      None        => panic!("index out of bounds: the len is {} but the index is {}", self.len, idx)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn elements_read_back_across_every_level_of_the_trie() {
+    // Past 32^3 elements, so the trie has four levels.
+    let len = WIDTH * WIDTH * WIDTH + 7;
+    let mut vector = PersistentVector::new();
+    for i in 0..len {
+      vector.push(i);
+    }
+    assert_eq!(vector.len(), len);
+    assert!((0..len).all(|i| vector[i] == i));
+    assert_eq!(vector.get(len), None);
+    assert_eq!(PersistentVector::from_elem('a', 3).get(2), Some(&'a'));
+  }
+
+  #[test]
+  fn a_write_leaves_clones_untouched_and_copies_only_its_path() {
+    let original = PersistentVector::from_elem(0, WIDTH * WIDTH * 2);
+    let mut copy = original.clone();
+
+    // The root and one node per level below it: three nodes of at most `WIDTH` slots.
+    let copied = copy.set(1000, 1);
+    assert!(copied > 0 && copied <= 3 * WIDTH, "copied {}", copied);
+    // The path is no longer shared, so writing it again copies nothing.
+    assert_eq!(copy.set(1001, 2), 0);
+
+    assert_eq!((copy[1000], copy[1001]), (1, 2));
+    assert_eq!((original[1000], original[1001]), (0, 0));
+    assert_eq!(copy.len(), original.len());
+  }
+
+  #[test]
+  fn a_tight_update_loop_keeping_every_version_copies_a_path_per_write() {
+    let len = 10_000;
+    let mut vector   = PersistentVector::from_elem(0, len);
+    let mut versions = Vec::new();
+    let mut copied   = 0;
+    for i in 0..len {
+      versions.push(vector.clone());
+      copied += vector.set(i, i + 1);
+    }
+
+    // A full copy per write would copy `len * len` slots.
+    assert!(copied <= len * 3 * WIDTH, "copied {}", copied);
+    for (n, version) in versions.iter().enumerate().step_by(997) {
+      assert!((0..len).all(|i| version[i] == if i < n { i + 1 } else { 0 }), "version {}", n);
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "index out of bounds")]
+  fn writing_past_the_end_panics() {
+    PersistentVector::from_elem(0, 3).set(3, 1);
+  }
+}
@@ -4,78 +4,431 @@
 
 */
 
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::ops::Range;
 use std::rc::Rc;
 
-use crate::interpreter::{Integer, IntegerList, Location};
+use crate::interpreter::{Integer, Location};
+use crate::interpreter::context::EvalContext;
 use crate::interpreter::exception::Exception;
 
 use super::{
-  continuation::Answer,
+  continuation::{Answer, Continuation},
+  observer::notify,
+  persistent_vector::PersistentVector,
   denotable_value::{
     DenotableValueList,
-    DValue
+    DValue,
+    ZERO
   },
 };
 
+/// Identifies a region of the `Store`. See `Region`.
+pub type RegionId = usize;
+
+/// The region every `Store` starts with. Allocation in the default region bumps
+/// `Store::next_unused_address` and is bounded only by the size of the store.
+pub const DEFAULT_REGION: RegionId = 0;
+
+/// The number of cells no `Store` grows past, whatever `EvalContext::max_store_size` says, so that
+/// a program that allocates an absurd number of cells raises `OutOfMemory` rather than exhausting
+/// the memory of the process.
+pub const MAX_STORE_CELLS: usize = 1 << 24;
+
+/// A region is a window of the store's address space with its own allocation pointer. Regions are
+/// carved out of the default region when they are created, so the windows of distinct regions
+/// never overlap, and an array allocated in one region can never alias an array in another.
+/// Because all regions share one address space, `fetch`/`update` route a `Location` to its region
+/// implicitly; `Store::region_of` recovers the region explicitly.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Region {
+  pub(crate) addresses          : Range<Location>,
+  pub(crate) next_unused_address: Location,
+}
+
 /// From [Appel, p.25]:
 ///   The store (whose type is `(loc*(loc->dvalue)*(loc->int)))` has three components: the next unused location, a mapping
 ///   from locations to denotable values, and a mapping from locations to integers.
 ///
 /// Instead of using a global variable for the current exception handler as in \[Appel], we keep it
 /// with the `Store`.
+///
+/// The two mappings are `PersistentVector`s, so cloning a `Store` is cheap and the many stores
+/// threaded through continuations share the cells none of them has written.
 #[derive(Clone)]
 pub struct Store{
   pub(crate) next_unused_address: Location,
   pub(crate) exception_handler: Location,
-  values: DenotableValueList, // "mapping" from locations to denotable values
-  integer_values: IntegerList,
+  values: PersistentVector<DValue>, // "mapping" from locations to denotable values
+  integer_values: PersistentVector<Integer>,
+  /// The regions other than `DEFAULT_REGION`. The region with id `n` is `regions[n - 1]`.
+  regions: Vec<Region>,
+  /// Locations whose contents were found unreachable by `compact`. Weak references into these
+  /// locations are cleared.
+  collected: HashSet<Location>,
+  /// Shared by every `Store` derived from the same original store.
+  clone_stats: Rc<Cell<CloneStats>>,
+}
+
+/// Counts the copying done by the writes of `Store::update`, `Store::update_integer`,
+/// `Store::fill`, and `Store::fill_integer`. A write copies only the nodes of the backing
+/// `PersistentVector` that it shares with other stores, not the whole store.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct CloneStats {
+  /// The number of stores produced by writes.
+  pub clones: u64,
+  /// The total number of slots of shared nodes copied, counting both the denotable and the
+  /// integer mappings. See `PersistentVector::set`.
+  pub cells_copied: u64,
 }
 
 impl Store{
+  /**
+  Creates a store with room for `size` cells whose exception handler is `Store::default_handler`,
+  so an uncaught exception ends the program with the exception as its result.
+
+  The address layout of a new store is:
+
+  | Location                   | Contents                                  |
+  |:---------------------------|:------------------------------------------|
+  | `0`                        | the exception handler                     |
+  | `1..next_unused_address`   | cells allocated by the program            |
+  | `next_unused_address..`    | unallocated cells                         |
+
+  `next_unused_address` starts at `1`. Allocation, whether by `allocate` or by `makeref`, takes
+  cells from `next_unused_address` upward, and the backing vectors grow past `size` as needed, so
+  `size` is only the initial capacity.
+  */
+  pub fn new(size: usize) -> Store {
+    Store::new_with_handler(size, Store::default_handler())
+  }
+
+  /// Creates a store with room for `size` cells whose exception handler is `handler`. The handler
+  /// occupies the first cell, which is reserved for it, so the first location available
+  /// to the program is `1`. See `Store::default_handler` for a handler that ends evaluation.
+  pub fn new_with_handler(size: usize, handler: DValue) -> Store {
+    let size = size.max(1);
+    let mut values = PersistentVector::from_elem(ZERO, size);
+    values.set(0, handler);
+
+    Store{
+      next_unused_address: 1,
+      exception_handler  : 0,
+      values,
+      integer_values     : PersistentVector::from_elem(0, size),
+      regions            : Vec::new(),
+      collected          : HashSet::new(),
+      clone_stats        : Rc::new(Cell::new(CloneStats::default())),
+    }
+  }
+
+  /// An exception handler that terminates the program: it produces the terminal `Answer` (see
+  /// `Answer::halt`) carrying the exception it receives, in its record encoding.
+  pub fn default_handler() -> DValue {
+    DValue::Function(Continuation::from_fn(| parameters, _store | Answer::halt(parameters.clone())))
+  }
+
+  /// A copy of the store whose exception handler is `handler`, for an evaluation that must leave
+  /// no trace on this store. Installing the handler is not a write: the observer is not notified,
+  /// and the copy counts its writes in `CloneStats` of its own.
+  pub(crate) fn detached_with_handler(&self, handler: DValue) -> Store {
+    let mut store = self.clone();
+    store.clone_stats = Rc::new(Cell::new(CloneStats::default()));
+    store.values.set(store.exception_handler, handler);
+    store
+  }
+
   // TODO: Should `fetch` return a clone of the `DValue`?
   /// Returns a reference to the (non `Integer`) `DValue` at `Location idx` in the `Store`. For `Integer`s, use
-  /// `fetch_integer`.
-  pub fn fetch(&self, idx: Location) -> &DValue {
-    // TODO: Determine if bounds checking is necessary here. I suspect that index out of bounds is a bug in the
-    //       interpreter, not in the user program.
-    self.values.get(idx).unwrap()
+  /// `fetch_integer`. Reading a location that has not been allocated is an `IndexOutOfBounds` exception, even if the
+  /// backing vector happens to have a cell there.
+  pub fn fetch(&self, idx: Location) -> Result<&DValue, Exception> {
+    if !self.is_initialized(idx) {
+      return Err(Exception::IndexOutOfBounds);
+    }
+    self.values.get(idx).ok_or(Exception::IndexOutOfBounds)
   }
 
   // TODO: Should this return a `&DValue`?
-  pub fn fetch_integer(&self, idx: Location) -> DValue {
-    let n = *self.integer_values.get(idx).unwrap();
-    DValue::Integer(n)
+  pub fn fetch_integer(&self, idx: Location) -> Result<DValue, Exception> {
+    if !self.is_initialized(idx) {
+      return Err(Exception::IndexOutOfBounds);
+    }
+    let n = *self.integer_values.get(idx).ok_or(Exception::IndexOutOfBounds)?;
+    Ok(DValue::Integer(n))
+  }
+
+  /// The range of locations that have been allocated in the default region. Locations of other regions lie within this
+  /// range and are initialized up to the region's own allocation pointer.
+  pub fn initialized_range(&self) -> Range<Location> {
+    0..self.next_unused_address
+  }
+
+  /// Whether `Location idx` has been allocated, taking into account the allocation pointer of its region.
+  fn is_initialized(&self, idx: Location) -> bool {
+    match self.region_of(idx) {
+      DEFAULT_REGION => self.initialized_range().contains(&idx),
+      region         => idx < self.regions[region - 1].next_unused_address
+    }
   }
 
   /// Produces a new `Store` which is identical to the current store except that the value at `Location idx` has value
-  /// `value`.
-  pub fn update(&self, idx: Location, value: DValue) -> Store {
+  /// `value`. This is `upd` of \[Appel]: it always writes the mapping to denotable values, even when `value` is an
+  /// `Integer`, because the cells of a (boxed) `Array` are read with `fetch`. The integer mapping is only written by
+  /// `update_integer`.
+  ///
+  /// Writes are bounded exactly as reads are: writing a location that has not been allocated is an
+  /// `IndexOutOfBounds` exception. Writing an allocated location past the end of the backing
+  /// vectors grows them, filling the new cells with zeros. See `writable_cell`.
+  pub fn update(&self, idx: Location, value: DValue) -> Result<Store, Exception> {
     let mut updated_store: Store = self.clone();
+    updated_store.writable_cell(idx)?;
+    notify(|observer| observer.on_update(idx, &updated_store.values[idx], &value));
+    let copied = updated_store.values.set(idx, value);
+    self.record_write(copied);
+    Ok(updated_store)
+  }
+
+  /// Produces a new `Store` which is identical to the current store except that the integer at `Location idx` has value
+  /// `value`. If the integer is wrapped in a `DValue` you may use `update`. Bounded as `update` is.
+  pub fn update_integer(&self, idx: Location, value: Integer) -> Result<Store, Exception> {
+    let mut updated_store: Store = self.clone();
+    updated_store.writable_cell(idx)?;
+    notify(|observer| {
+      observer.on_update(
+        idx,
+        &DValue::Integer(updated_store.integer_values[idx]),
+        &DValue::Integer(value)
+      )
+    });
+    let copied = updated_store.integer_values.set(idx, value);
+    self.record_write(copied);
+    Ok(updated_store)
+  }
+
+  /// Produces a new `Store` in which every location of `range` has value `value`. This is a
+  /// sequence of `update`s, but produces only one store, so each shared node is copied once.
+  pub fn fill(&self, range: Range<Location>, value: &DValue) -> Result<Store, Exception> {
+    let mut updated_store: Store = self.clone();
+    let mut copied = 0;
+    for idx in range {
+      updated_store.writable_cell(idx)?;
+      notify(|observer| observer.on_update(idx, &updated_store.values[idx], value));
+      copied += updated_store.values.set(idx, value.clone());
+    }
+    self.record_write(copied);
+    Ok(updated_store)
+  }
+
+  /// Produces a new `Store` in which every location of `range` has integer `value`. This is a
+  /// sequence of `update_integer`s, but produces only one store. See `fill`.
+  pub fn fill_integer(&self, range: Range<Location>, value: Integer) -> Result<Store, Exception> {
+    let mut updated_store: Store = self.clone();
+    let mut copied = 0;
+    for idx in range {
+      updated_store.writable_cell(idx)?;
+      notify(|observer| {
+        observer.on_update(
+          idx,
+          &DValue::Integer(updated_store.integer_values[idx]),
+          &DValue::Integer(value)
+        )
+      });
+      copied += updated_store.integer_values.set(idx, value);
+    }
+    self.record_write(copied);
+    Ok(updated_store)
+  }
+
+  /// Checks that `idx` may be written, which is the case exactly when it may be read (see
+  /// `is_initialized`), and grows the backing vectors so that `idx` is a cell of both. Writing a
+  /// location that has not been allocated is `IndexOutOfBounds`.
+  fn writable_cell(&mut self, idx: Location) -> Result<(), Exception> {
+    if !self.is_initialized(idx) {
+      return Err(Exception::IndexOutOfBounds);
+    }
+    if idx >= self.values.len() || idx >= self.integer_values.len() {
+      self.grow_to(idx + 1);
+    }
+    Ok(())
+  }
+
+  /// The copying done by the writes to this store and to every store derived from the same
+  /// original store.
+  pub fn clone_stats(&self) -> CloneStats {
+    self.clone_stats.get()
+  }
+
+  /// Records a write that produced a new store by copying `copied` slots in the `CloneStats`.
+  fn record_write(&self, copied: usize) {
+    let mut stats = self.clone_stats.get();
+    stats.clones       += 1;
+    stats.cells_copied += copied as u64;
+    self.clone_stats.set(stats);
+  }
+
+  /// Produces a new `Store` with a fresh region of `size` cells reserved from the default region,
+  /// together with the id of the new region. Raises `OutOfMemory` if the reservation would exceed
+  /// `EvalContext::max_store_size` or `MAX_STORE_CELLS`.
+  pub fn add_region(&self, size: usize) -> Result<(Store, RegionId), Exception> {
+    let start = self.next_unused_address;
+    Store::check_store_size(start.checked_add(size).ok_or(Exception::OutOfMemory)?)?;
+    let mut new_store: Store = self.clone();
+
+    new_store.next_unused_address = start + size;
+    new_store.grow_to(start + size);
+    new_store.regions.push(
+      Region{
+        addresses          : start..start + size,
+        next_unused_address: start
+      }
+    );
+
+    let region = new_store.regions.len();
+    Ok((new_store, region))
+  }
+
+  /// Allocates `n` consecutive cells in `region`, producing the new `Store` and the range of
+  /// allocated locations. Raises `OutOfMemory` if `region` has fewer than `n` unused cells left or
+  /// if the allocation would exceed `EvalContext::max_store_size` or `MAX_STORE_CELLS`, and
+  /// `InvalidAccess` if `region` does not exist. The size is checked before the store grows.
+  pub fn allocate_in(&self, region: RegionId, n: usize) -> Result<(Store, Range<Location>), Exception> {
+    let mut new_store: Store = self.clone();
+
+    let start = if region == DEFAULT_REGION {
+      let start = new_store.next_unused_address;
+      Store::check_store_size(start.checked_add(n).ok_or(Exception::OutOfMemory)?)?;
+      new_store.next_unused_address = start + n;
+      start
+    } else {
+      let window = new_store.regions.get_mut(region - 1).ok_or(Exception::InvalidAccess)?;
+      let start  = window.next_unused_address;
+      if n > window.addresses.end - start {
+        return Err(Exception::OutOfMemory);
+      }
+      window.next_unused_address = start + n;
+      start
+    };
+
+    new_store.grow_to(start + n);
+    notify(|observer| observer.on_allocate(&(start..start + n)));
+    Ok((new_store, start..start + n))
+  }
 
-    // Accommodate the integer GC optimization.
-    if let DValue::Integer(i) = value {
-      updated_store.integer_values[idx] = i;
+  /// Allocates `n` consecutive cells in the default region. See `allocate_in`.
+  pub fn allocate(&self, n: usize) -> Result<(Store, Range<Location>), Exception> {
+    self.allocate_in(DEFAULT_REGION, n)
+  }
+
+  /// Raises `OutOfMemory` if a store of `size` cells would exceed `EvalContext::max_store_size`
+  /// or `MAX_STORE_CELLS`.
+  pub(crate) fn check_store_size(size: usize) -> Result<(), Exception> {
+    let max_store_size = EvalContext::with_current(|context| context.max_store_size);
+    if size > max_store_size.map_or(MAX_STORE_CELLS, |max| max.min(MAX_STORE_CELLS)) {
+      Err(Exception::OutOfMemory)
     } else {
-      updated_store.values[idx] = value;
+      Ok(())
     }
+  }
 
-    return updated_store;
+  /// The region the `Location idx` belongs to.
+  pub fn region_of(&self, idx: Location) -> RegionId {
+    self.regions
+        .iter()
+        .position(|region| region.addresses.contains(&idx))
+        .map_or(DEFAULT_REGION, |position| position + 1)
   }
 
-  /// Produces a new `Store` which is identical to the current store except that the integer at `Location idx` has value
-  /// `value`. If the integer is wrapped in a `DValue` you may use `update`.
-  pub fn update_integer(&self, idx: Location, value: Integer) -> Store {
-    let mut updated_store: Store = self.clone();
-    updated_store.integer_values[idx] = value;
-    return updated_store;
+  /// Ensures the backing vectors can hold `size` cells.
+  fn grow_to(&mut self, size: usize) {
+    self.values.extend_to(size, ZERO);
+    self.integer_values.extend_to(size, 0);
+  }
+
+  /**
+  Produces a new `Store` in which every allocated cell that is not reachable from `roots` is
+  cleared (reset to zero) and marked as collected. A cell is reachable if it belongs to an array
+  that is reachable, where records are traced through their fields and boxed array cells through
+  their contents. The exception handler is always a root. Weak references do not make their
+  targets reachable.
+
+  The closures inside `DValue::Function`s are opaque, so the values they capture cannot be
+  traced. Any value that is only reachable through a closure must be passed in `roots`.
+
+  Cells are never moved, because arrays outside the store refer to them by location.
+  */
+  pub fn compact(&self, roots: &[DValue]) -> Store {
+    let mut reachable: HashSet<Location> = HashSet::new();
+    let mut pending  : DenotableValueList = roots.to_vec();
+
+    reachable.insert(self.exception_handler);
+    if let Some(handler) = self.values.get(self.exception_handler) {
+      pending.push(handler.clone());
+    }
+
+    while let Some(value) = pending.pop() {
+      match value {
+
+        DValue::Record { values, .. } => pending.extend(values),
+
+        DValue::Array(range) => {
+          for location in range {
+            // The visited set also keeps cyclic structures from being traced forever.
+            if reachable.insert(location) {
+              if let Some(contents) = self.values.get(location) {
+                pending.push(contents.clone());
+              }
+            }
+          }
+        }
+
+        | DValue::UnboxedArray(range)
+        | DValue::ByteArray(range) => reachable.extend(range),
+
+        _ => { /* Not a pointer into the store. */ }
+
+      }
+    }
+
+    let mut new_store: Store = self.clone();
+    // Runs of newly freed cells, reported to the observer.
+    let mut freed: Vec<Range<Location>> = Vec::new();
+    for location in self.initialized_range() {
+      if !reachable.contains(&location) && self.is_initialized(location) {
+        new_store.values.set(location, ZERO);
+        new_store.integer_values.set(location, 0);
+        if new_store.collected.insert(location) {
+          match freed.last_mut() {
+            Some(run) if run.end == location => run.end += 1,
+            _                                => freed.push(location..location + 1)
+          }
+        }
+      }
+    }
+    for range in freed.iter() {
+      notify(|observer| observer.on_free(range));
+    }
+    new_store
+  }
+
+  /// Whether any of the cells in `range` has been collected by `compact`.
+  pub fn is_collected(&self, range: &Range<Location>) -> bool {
+    range.clone().any(|location| self.collected.contains(&location))
   }
 
-  /// Uses the `Store`'s exception handler to handle the given exception.
+  /// Uses the `Store`'s exception handler to handle the given exception. The handler receives the
+  /// exception in its record encoding. See `Exception::as_record`.
   pub fn raise_exception(&self, exception: Exception) -> Answer{
     eprintln!("Exception raised: {:?}", &exception);
+    self.raise(exception.as_record())
+  }
 
+  /// Applies the `Store`'s exception handler to `exception`, which may be any value. This is how
+  /// both `raise_exception` and `PrimitiveOp::Raise` invoke the handler.
+  pub fn raise(&self, exception: DValue) -> Answer{
     if let DValue::Function(continuation) = &self.values[self.exception_handler] {
-      (continuation.f).call((&vec![exception.into()], self))
+      (continuation.f).call((&vec![exception], self))
     } else {
       // Todo: Handle exceptions raised by the interpreter rather than the program being
       //       interpreted.
@@ -92,3 +445,154 @@ pub enum AccessPath{
   Offset(Location),
   Select { offset: Location, access_path: Rc<AccessPath> }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn regions_do_not_alias() {
+    let store = Store::new(4);
+    let (store, a) = store.add_region(4).unwrap();
+    let (store, b) = store.add_region(4).unwrap();
+
+    let (store, in_a) = store.allocate_in(a, 2).unwrap();
+    let (store, in_b) = store.allocate_in(b, 2).unwrap();
+    assert!(in_a.end <= in_b.start);
+    assert_eq!(store.region_of(in_a.start), a);
+    assert_eq!(store.region_of(in_b.start), b);
+
+    let store = store.update(in_a.start, DValue::Integer(1)).unwrap();
+    let store = store.update(in_b.start, DValue::Integer(2)).unwrap();
+    assert_eq!(format!("{:?}", store.fetch(in_a.start)), "Ok(1)");
+    assert_eq!(format!("{:?}", store.fetch(in_b.start)), "Ok(2)");
+  }
+
+  #[test]
+  fn a_full_region_is_out_of_memory() {
+    let (store, region) = Store::new(4).add_region(2).unwrap();
+    let (store, _) = store.allocate_in(region, 2).unwrap();
+
+    assert_eq!(store.allocate_in(region, 1).err(), Some(Exception::OutOfMemory));
+    assert_eq!(store.allocate_in(region + 1, 1).err(), Some(Exception::InvalidAccess));
+    // The default region is unaffected.
+    assert!(store.allocate(8).is_ok());
+  }
+
+  #[test]
+  fn reads_past_the_allocated_range_fail() {
+    let store = Store::new(8);
+    assert_eq!(store.initialized_range(), 0..1);
+    // Cell 3 is within the capacity of the backing vectors but has not been allocated.
+    assert_eq!(store.fetch(3).err(), Some(Exception::IndexOutOfBounds));
+    assert_eq!(store.fetch_integer(3).err(), Some(Exception::IndexOutOfBounds));
+
+    let (store, range) = store.allocate(3).unwrap();
+    assert_eq!(range, 1..4);
+    assert_eq!(store.initialized_range(), 0..4);
+    assert!(store.fetch(3).is_ok());
+    assert!(store.fetch_integer(3).is_ok());
+    assert_eq!(store.fetch(4).err(), Some(Exception::IndexOutOfBounds));
+  }
+
+  #[test]
+  fn a_weak_reference_clears_when_its_target_is_collected() {
+    let (store, range) = Store::new(4).allocate(2).unwrap();
+    let store = store.fill(range.clone(), &DValue::Integer(7)).unwrap();
+    let weak  = DValue::WeakRef(range.clone());
+
+    let kept = store.compact(&[weak.clone(), DValue::Array(range.clone())]);
+    assert!(!kept.is_collected(&range));
+    assert!(matches!(kept.fetch(range.start), Ok(DValue::Integer(7))));
+
+    let cleared = store.compact(&[weak]);
+    assert!(cleared.is_collected(&range));
+  }
+
+  #[test]
+  fn clone_stats_count_each_write_once() {
+    let (mut store, range) = Store::new(4).allocate(256).unwrap();
+    let before = store.clone_stats();
+    let mut versions = Vec::new();
+    for (n, location) in range.clone().enumerate().take(100) {
+      versions.push(store.clone());
+      store = store.update(location, DValue::Integer(n as Integer)).unwrap();
+    }
+
+    let stats = store.clone_stats();
+    assert_eq!(stats.clones - before.clones, 100);
+    // Each write copies only a path of the persistent vector, far fewer than the 256 cells of
+    // the store a full clone would copy.
+    assert!((stats.cells_copied - before.cells_copied) < 100 * 256 / 4);
+    assert!(versions.iter().all(|version| version.clone_stats() == stats));
+  }
+
+  #[test]
+  fn compaction_terminates_on_a_cycle() {
+    let (store, range) = Store::new(4).allocate(2).unwrap();
+    let array = DValue::Array(range.clone());
+    let store = store.update(range.start, array.clone()).unwrap().update(range.start + 1, array.clone()).unwrap();
+
+    let compacted = store.compact(&[array]);
+    assert!(!compacted.is_collected(&range));
+  }
+
+  #[test]
+  fn the_default_handler_ends_evaluation_with_the_exception() {
+    let store  = Store::new(4);
+    let answer = store.raise_exception(Exception::DivideByZero);
+    assert!(answer.is_halted());
+    assert_eq!(format!("{:?}", answer.parameters()), format!("{:?}", vec![Exception::DivideByZero.as_record()]));
+  }
+
+  #[test]
+  fn a_store_applies_the_handler_it_was_created_with() {
+    let handler = DValue::Function(Continuation::from_fn(| _, _ | Answer::halt(vec![DValue::Integer(99)])));
+    let store   = Store::new_with_handler(4, handler);
+    assert_eq!(format!("{:?}", store.raise_exception(Exception::Overflow).parameters()), "[99]");
+    // The handler occupies the reserved first cell.
+    assert_eq!(store.allocate(1).unwrap().1.start, 1);
+  }
+
+  #[test]
+  fn writes_are_bounded_as_reads_are() {
+    // The backing vectors have two cells, so the allocated cells 1..4 lie partly past their end.
+    let (store, range) = Store::new(2).allocate(3).unwrap();
+    let store = store.update(3, DValue::Integer(5)).unwrap();
+    assert_eq!(format!("{:?}", store.fetch(3)), "Ok(5)");
+    let store = store.update_integer(2, 6).unwrap();
+    assert_eq!(format!("{:?}", store.fetch_integer(2)), "Ok(6)");
+
+    // `next_unused_address` has not been allocated, so neither reads nor writes reach it.
+    assert_eq!(store.next_unused_address, range.end);
+    assert_eq!(store.fetch(range.end).err(), Some(Exception::IndexOutOfBounds));
+    assert_eq!(store.update(range.end, DValue::Integer(1)).err(), Some(Exception::IndexOutOfBounds));
+    assert_eq!(store.update_integer(1000, 1).err(), Some(Exception::IndexOutOfBounds));
+    assert_eq!(store.fill(3..6, &DValue::Integer(1)).err(), Some(Exception::IndexOutOfBounds));
+  }
+
+  #[test]
+  fn a_new_store_reserves_the_first_cell_for_the_handler() {
+    let store = Store::new(0);
+    assert_eq!(store.exception_handler, 0);
+    assert_eq!(store.initialized_range(), 0..1);
+    assert!(matches!(store.fetch(0), Ok(DValue::Function(_))));
+
+    // Allocation starts after the handler and grows the store past its initial size.
+    let (store, range) = store.allocate(3).unwrap();
+    assert_eq!(range, 1..4);
+    let store = store.fill(range, &DValue::Integer(2)).unwrap();
+    assert_eq!(format!("{:?}", store.fetch(3)), "Ok(2)");
+  }
+
+  #[test]
+  fn an_allocation_past_the_cell_limit_is_out_of_memory_before_the_store_grows() {
+    let store = Store::new(4);
+    assert_eq!(store.allocate(MAX_STORE_CELLS).err(), Some(Exception::OutOfMemory));
+    assert_eq!(store.allocate(usize::MAX).err(), Some(Exception::OutOfMemory));
+    assert_eq!(store.add_region(usize::MAX).err(), Some(Exception::OutOfMemory));
+    let (store, region) = store.add_region(8).unwrap();
+    assert_eq!(store.allocate_in(region, usize::MAX).err(), Some(Exception::OutOfMemory));
+    assert!(store.allocate(1000).is_ok());
+  }
+}
@@ -0,0 +1,202 @@
+/*!
+
+  Support for evaluating small expressions while a program is stopped, as for the "watch" or
+  "evaluate expression" feature of a debugger.
+
+  A watch expression is a fragment of CPS code evaluated against the environment and store of the
+  stopped program. To be sure that it terminates, it may not define functions with `Fix`, the
+  only function it may apply is the continuation `halt`, which ends the evaluation with the values
+  it is applied to, and it may not use the operations that apply functions or resume other tasks,
+  `mkcyclic`, `spawn`, and `yield`, nor install a function of the program as the handler that
+  `raise` applies with `sethdlr`:
+
+  ```text
+  PrimitiveOp(+, [x, 3], [w], [Apply(halt, [w])])
+  ```
+
+*/
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Variable,
+  context::EvalContext,
+  continuation_expression::ContinuationExpression,
+  cps::{
+    continuation::{Answer, Continuation},
+    denotable_value::{DValue, DValueList},
+    store::Store
+  },
+  environment::Environment,
+  exception::Exception,
+  primitive_op::PrimitiveOp,
+  run_with_fuel,
+  value::Value
+};
+
+/// The name of the continuation a watch expression applies to its result.
+pub const HALT: &str = "halt";
+
+/// The number of steps of the trampoline a watch expression may take. The constructs a watch
+/// expression may use already ensure that it terminates; the budget bounds it regardless.
+pub const WATCH_FUEL: u64 = 100_000;
+
+/**
+Evaluates the watch expression `expression` in `environment` and `store`, returning the values
+`halt` is applied to. An exception raised by the expression is returned as the error instead of
+being passed to the program's exception handler. Neither `environment` nor `store` is changed,
+and the evaluation is invisible to the program's `Observer` and to the `CloneStats` of `store`.
+
+Raises `Undefined` without evaluating anything if `expression` contains a `Fix`, applies a
+function other than `halt`, or uses `mkcyclic`, `spawn`, `yield`, or `sethdlr`, as such an
+expression might not terminate, and raises `Undefined` if the evaluation takes more than
+`WATCH_FUEL` steps.
+*/
+pub fn eval_expr_in_context(
+  expression : &ContinuationExpression,
+  environment: &Environment,
+  store      : &Store
+) -> Result<DValueList, Exception>
+{
+  if might_not_terminate(expression) {
+    return Err(Exception::Undefined);
+  }
+
+  let (halt, _) = Continuation::halt();
+  let halt = DValue::Function(halt);
+
+  // The handler records the exception and ends the evaluation.
+  let raised: Rc<RefCell<Option<Exception>>> = Rc::new(RefCell::new(None));
+  let handler = {
+    let raised = raised.clone();
+    DValue::Function(
+      Continuation::from_fn(move | parameters, _ | {
+        let exception = match parameters.first().and_then(|record| record.field(0)) {
+          Some(DValue::Integer(tag)) => Exception::from_tag(*tag).unwrap_or(Exception::Undefined),
+          _                          => Exception::Undefined
+        };
+        *raised.borrow_mut() = Some(exception);
+        Answer::halt(parameters.clone())
+      })
+    )
+  };
+  let watch_store = store.detached_with_handler(handler);
+
+  let observer = EvalContext::with_current_mut(|context| context.observer.take());
+  let answer   = expression.clone().evaluate(environment.bind(Variable::new(HALT), halt));
+  let result   = run_with_fuel(answer, watch_store, WATCH_FUEL);
+  EvalContext::with_current_mut(|context| context.observer = observer);
+
+  let exception = raised.borrow_mut().take();
+  match (exception, result) {
+    (Some(exception), _) => Err(exception),
+    (None, Ok(answer))   => Ok(answer.parameters().clone()),
+    (None, Err(_))       => Err(Exception::Undefined)
+  }
+}
+
+/// Whether `expression` contains a construct that might keep a watch expression from
+/// terminating.
+fn might_not_terminate(expression: &ContinuationExpression) -> bool {
+  let mut pending = vec![expression];
+
+  while let Some(expression) = pending.pop() {
+    match expression {
+
+      ContinuationExpression::Apply { function: Value::Variable(f), .. }
+      | ContinuationExpression::Apply { function: Value::Label(f), .. }
+      if f.name() == HALT => {}
+
+      // Functions, applications of anything but `halt`, and the operations that apply functions,
+      // resume tasks, or install the handler that `raise` applies.
+      | ContinuationExpression::Fix { .. }
+      | ContinuationExpression::Apply { .. }
+      | ContinuationExpression::PrimitiveOp { operation: PrimitiveOp::MakeCyclic, .. }
+      | ContinuationExpression::PrimitiveOp { operation: PrimitiveOp::Spawn, .. }
+      | ContinuationExpression::PrimitiveOp { operation: PrimitiveOp::Yield, .. }
+      | ContinuationExpression::PrimitiveOp { operation: PrimitiveOp::SetHandler, .. } => return true,
+
+      _ => pending.extend(expression.children())
+
+    }
+  }
+
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+  use std::ops::Range;
+
+  use super::*;
+  use crate::interpreter::{Location, cps::observer::Observer, parser::parse};
+
+  fn watch(source: &str, environment: &Environment, store: &Store) -> Result<DValueList, Exception> {
+    eval_expr_in_context(&parse(source).unwrap(), environment, store)
+  }
+
+  #[test]
+  fn a_watch_expression_sees_the_environment() {
+    let environment = Environment::new().bind(Variable::new("x"), DValue::Integer(7));
+    let store       = Store::new(4);
+    assert_eq!(format!("{:?}", watch("(primop + (x 3) (w) ((app halt w)))", &environment, &store)), "Ok([10])");
+    assert_eq!(watch("(primop div (x 0) (w) ((app halt w)))", &environment, &store).err(), Some(Exception::DivideByZero));
+  }
+
+  #[test]
+  fn constructs_that_might_not_terminate_are_rejected() {
+    let environment = Environment::new().bind(Variable::new("f"), Store::default_handler());
+    let store       = Store::new(4);
+    for source in [
+      "(fix ((g (y) (app halt y))) (app g 1))",
+      "(app f 1)",
+      "(primop mkcyclic (1 f) (a) ((app halt a)))",
+      "(primop spawn (f) () ((app halt 1)))",
+      "(primop yield () () ((app halt 1)))",
+      "(primop sethdlr (f) () ((primop raise (1) () ())))",
+    ] {
+      assert_eq!(watch(source, &environment, &store).err(), Some(Exception::Undefined), "{}", source);
+    }
+  }
+
+  #[derive(Default)]
+  struct Counter {
+    events: Cell<usize>
+  }
+
+  impl Observer for Counter {
+    fn on_allocate(&self, _range: &Range<Location>) {
+      self.events.set(self.events.get() + 1);
+    }
+
+    fn on_update(&self, _location: Location, _old: &DValue, _new: &DValue) {
+      self.events.set(self.events.get() + 1);
+    }
+  }
+
+  #[test]
+  fn a_watch_leaves_no_trace_on_the_program() {
+    let (store, range) = Store::new(4).allocate(2).unwrap();
+    let store          = store.fill(range.clone(), &DValue::Integer(1)).unwrap();
+    let environment    = Environment::new().bind(Variable::new("a"), DValue::Array(range.clone()));
+    let stats          = store.clone_stats();
+
+    let counter  = Rc::new(Counter::default());
+    let previous = EvalContext::new().with_observer(counter.clone()).install();
+    let result   = watch(
+      "(primop update (a 0 9) () ((primop subscript (a 0) (v) ((primop mkarray (1 0) (b) ((app halt v)))))))",
+      &environment,
+      &store
+    );
+    let observer_kept = EvalContext::with_current(|context| context.observer.is_some());
+    previous.install();
+
+    assert_eq!(format!("{:?}", result), "Ok([9])");
+    assert_eq!(counter.events.get(), 0);
+    assert!(observer_kept);
+    assert_eq!(store.clone_stats(), stats);
+    assert_eq!(format!("{:?}", store.fetch(range.start)), "Ok(1)");
+  }
+}
@@ -0,0 +1,195 @@
+/*!
+
+  Exports a `ContinuationExpression` as a Graphviz digraph, for example to render with
+  `dot -Tsvg`. Each subexpression is a node labeled with its kind and the variables it binds, and
+  solid edges run from each node to its children in order. A dashed edge, labeled with the
+  variable, runs from the node binding a variable to each node in its scope that uses it.
+
+  ```text
+  digraph cps {
+    n0 [label="fix f"];
+    n1 [label="primop + y"];
+    ...
+    n0 -> n1;
+    n0 -> n3 [style=dashed, label="f"];
+  }
+  ```
+
+  Variables that are not bound within the expression, such as the top-level continuation, get no
+  dashed edges.
+
+*/
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::ContinuationExpression,
+  value::Value
+};
+
+/// Renders `expression` as a Graphviz digraph.
+pub fn to_dot(expression: &ContinuationExpression) -> String {
+  let mut graph = Graph::default();
+  graph.add(expression);
+
+  let mut text = String::from("digraph cps {\n");
+  for (id, label) in graph.nodes.iter().enumerate() {
+    writeln!(text, "  n{} [label=\"{}\"];", id, escape(label)).unwrap();
+  }
+  for (from, to) in graph.edges {
+    writeln!(text, "  n{} -> n{};", from, to).unwrap();
+  }
+  for (from, to, variable) in graph.scope_edges {
+    writeln!(text, "  n{} -> n{} [style=dashed, label=\"{}\"];", from, to, escape(variable.name())).unwrap();
+  }
+  text.push_str("}\n");
+  text
+}
+
+#[derive(Default)]
+struct Graph {
+  /// The label of each node, indexed by node id in pre-order.
+  nodes      : Vec<String>,
+  /// The edges from each node to its children.
+  edges      : Vec<(usize, usize)>,
+  /// The edges from the node binding a variable to the nodes using it.
+  scope_edges: Vec<(usize, usize, Variable)>,
+  /// For each variable in scope, the ids of the nodes binding it, innermost last.
+  binders    : HashMap<Variable, Vec<usize>>
+}
+
+impl Graph {
+  /// Adds the subtree rooted at `expression`, whose root gets the next id.
+  fn add(&mut self, expression: &ContinuationExpression) {
+    let id = self.nodes.len();
+    self.nodes.push(label(expression));
+
+    for value in expression.values() {
+      if let Value::Variable(v) | Value::Label(v) = value {
+        if let Some(&binder) = self.binders.get(v).and_then(|ids| ids.last()) {
+          self.scope_edges.push((binder, id, v.clone()));
+        }
+      }
+    }
+
+    for (child, scope) in expression.children().into_iter().zip(expression.child_scopes()) {
+      for variable in scope.iter() {
+        self.binders.entry(variable.clone()).or_default().push(id);
+      }
+      self.edges.push((id, self.nodes.len()));
+      self.add(child);
+      for variable in scope.iter() {
+        if let Some(ids) = self.binders.get_mut(variable) {
+          ids.pop();
+          if ids.is_empty() {
+            self.binders.remove(variable);
+          }
+        }
+      }
+    }
+  }
+}
+
+/// The kind of `expression` followed by the variables it binds, or, for an `Apply` and a
+/// `Switch`, which bind nothing, by the value they act on.
+fn label(expression: &ContinuationExpression) -> String {
+  match expression {
+    ContinuationExpression::Record { variable, .. }      => format!("record {}", variable.name()),
+    ContinuationExpression::Select { variable, .. }      => format!("select {}", variable.name()),
+    ContinuationExpression::Offset { variable, .. }      => format!("offset {}", variable.name()),
+    ContinuationExpression::Apply { function, .. }       => format!("app {}", function),
+    ContinuationExpression::Switch { value, .. }         => format!("switch {}", value),
+
+    ContinuationExpression::Fix { function_defs, .. } => {
+      let names: Vec<&str> = function_defs.iter().map(|function_def| function_def.header().0.name()).collect();
+      format!("fix {}", names.join(", "))
+    }
+
+    ContinuationExpression::PrimitiveOp { operation, variables, .. } => {
+      let mut label = format!("primop {}", operation);
+      for variable in variables {
+        write!(label, " {}", variable.name()).unwrap();
+      }
+      label
+    }
+  }
+}
+
+/// Escapes `text` for use inside a double-quoted DOT string.
+fn escape(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '"'  => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      c    => escaped.push(c)
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  /// A dashed scope edge: binder, user, variable.
+  type ScopeEdge = (usize, usize, String);
+
+  /// The node ids, the solid edges, and the dashed edges with their labels of a digraph written by
+  /// `to_dot`, checking that every line between the braces is a node or an edge statement.
+  fn read_dot(dot: &str) -> (Vec<usize>, Vec<(usize, usize)>, Vec<ScopeEdge>) {
+    let mut lines = dot.lines();
+    assert_eq!(lines.next(), Some("digraph cps {"));
+    assert_eq!(dot.lines().last(), Some("}"));
+
+    let id = | node: &str | node.trim().strip_prefix('n').unwrap().parse::<usize>().unwrap();
+    let (mut nodes, mut edges, mut scope_edges) = (Vec::new(), Vec::new(), Vec::new());
+    for line in lines.take_while(|line| *line != "}") {
+      let statement = line.strip_prefix("  ").unwrap().strip_suffix(';').unwrap();
+      if let Some((from, rest)) = statement.split_once(" -> ") {
+        match rest.split_once(" [style=dashed, label=\"") {
+          Some((to, label)) => scope_edges.push((id(from), id(to), label.strip_suffix("\"]").unwrap().to_string())),
+          None              => edges.push((id(from), id(rest)))
+        }
+      } else {
+        let (node, label) = statement.split_once(" [label=\"").unwrap();
+        assert!(label.ends_with("\"]"), "{}", line);
+        nodes.push(id(node));
+      }
+    }
+    (nodes, edges, scope_edges)
+  }
+
+  #[test]
+  fn the_graph_has_a_node_per_subexpression_and_an_edge_per_child() {
+    let expression = parse(
+      "(fix ((f (x k) (primop + (x 1) (y) ((app k y))))) (switch i (app f 41 halt) (app halt \"a \\\"b\\\"\")))"
+    ).unwrap();
+    let dot = to_dot(&expression);
+    let (nodes, edges, scope_edges) = read_dot(&dot);
+
+    assert_eq!(nodes, (0..6).collect::<Vec<_>>());
+    // A tree: every node but the root has exactly one parent.
+    assert_eq!(edges, vec![(0, 1), (1, 2), (0, 3), (3, 4), (3, 5)]);
+    assert_eq!(
+      scope_edges,
+      vec![
+        (0, 1, "x".to_string()),
+        (0, 2, "k".to_string()),
+        (1, 2, "y".to_string()),
+        (0, 4, "f".to_string()),
+      ]
+    );
+    assert!(dot.contains(r#"  n5 [label="app halt"];"#));
+    assert!(dot.contains(r#"  n1 [label="primop + y"];"#));
+  }
+
+  #[test]
+  fn labels_are_escaped() {
+    assert_eq!(escape("say \"hi\"\\\n"), "say \\\"hi\\\"\\\\\\n");
+  }
+}
@@ -0,0 +1,469 @@
+/*!
+
+  Encodes programs and the values they compute as `Json`, and decodes them back, so that they can
+  be saved and loaded with `json::parse` and the `Display` of `Json`. Decoding what `encode`
+  produced gives back an equal program.
+
+  Variables are their names, primitive operations are their tokens (see `PrimitiveOp::token`),
+  and integers are numbers. Reals are numbers too, except that NaN and the infinities, which JSON
+  cannot represent, are the strings `"NaN"`, `"inf"`, and `"-inf"`. A `Value` is an object with
+  one member naming its kind, as `{"variable": "x"}`, `{"label": "f"}`, `{"integer": 1}`,
+  `{"real": 2.0}`, or `{"string": "a"}`, and an `AccessPath` is `{"offset": i}` or
+  `{"select": i, "path": p}`. A `ContinuationExpression` is an object whose `kind` is the name of
+  its variant (see `ContinuationExpression::kind`) and whose other members are its fields:
+
+  ```text
+  {"kind": "Fix",
+   "functions": [{"name": "f", "parameters": ["x", "k"], "body": {"kind": "Apply", ...}}],
+   "expression": {"kind": "Apply", "function": {"label": "f"}, "arguments": [...]}}
+  ```
+
+  The values a program computes are encoded much as by the `server`: records as `{"record":
+  [fields...]}` (with `"offset"` if the record is offset), arrays as `{"array": kind, "start": s,
+  "end": e}`, and exceptions as `{"exception": tag}` with the tag of `Exception::tag`. A function
+  cannot be encoded, because it closes over an environment, so `encode_value` rejects
+  `DValue::Function` with an `EncodingError`.
+
+  Decoding recurses on the nesting of the program, like `json::parse`, which already rejects input
+  nested more than `json::MAX_DEPTH` deep. A program saved from a deeper expression does not load.
+
+*/
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::{ContinuationExpression, FunctionDefinition},
+  cps::{denotable_value::DValue, store::AccessPath},
+  exception::Exception,
+  json::Json,
+  primitive_op::PrimitiveOp,
+  value::Value
+};
+
+/// Why a `Json` could not be decoded, or a value could not be encoded.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EncodingError {
+  pub message: String
+}
+
+impl Display for EncodingError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+fn error<T>(message: String) -> Result<T, EncodingError> {
+  Err(EncodingError { message })
+}
+
+/// The encoding of `expression` described in the module documentation.
+pub fn encode(expression: &ContinuationExpression) -> Json {
+  let expressions = | expressions: &mut dyn Iterator<Item = &ContinuationExpression> | {
+    Json::Array(expressions.map(encode).collect())
+  };
+  let mut members = vec![("kind", Json::String(expression.kind().to_string()))];
+  match expression {
+
+    ContinuationExpression::Record { values, variable, expression } => {
+      let fields = values.iter().map(|(value, path)| {
+        Json::object(vec![("value", encode_operand(value)), ("path", encode_access_path(path))])
+      });
+      members.push(("values", Json::Array(fields.collect())));
+      members.push(("variable", encode_variable(variable)));
+      members.push(("expression", encode(expression)));
+    }
+
+    | ContinuationExpression::Select { location, value, variable, expression }
+    | ContinuationExpression::Offset { location, value, variable, expression } => {
+      members.push(("location", Json::Integer(*location as i64)));
+      members.push(("value", encode_operand(value)));
+      members.push(("variable", encode_variable(variable)));
+      members.push(("expression", encode(expression)));
+    }
+
+    ContinuationExpression::Apply { function, arguments } => {
+      members.push(("function", encode_operand(function)));
+      members.push(("arguments", Json::Array(arguments.iter().map(encode_operand).collect())));
+    }
+
+    ContinuationExpression::Fix { function_defs, expression } => {
+      let functions = function_defs.iter().map(|function_def| {
+        let (name, formal_parameters) = function_def.header();
+        Json::object(vec![
+          ("name", encode_variable(name)),
+          ("parameters", encode_variables(formal_parameters)),
+          ("body", encode(function_def.body()))
+        ])
+      });
+      members.push(("functions", Json::Array(functions.collect())));
+      members.push(("expression", encode(expression)));
+    }
+
+    ContinuationExpression::Switch { value, arms } => {
+      members.push(("value", encode_operand(value)));
+      members.push(("arms", expressions(&mut arms.iter().map(|arm| arm.as_ref()))));
+    }
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, expressions: arms } => {
+      members.push(("operation", Json::String(operation.token().to_string())));
+      members.push(("values", Json::Array(values.iter().map(encode_operand).collect())));
+      members.push(("variables", encode_variables(variables)));
+      members.push(("expressions", expressions(&mut arms.iter().map(|arm| arm.as_ref()))));
+    }
+
+  }
+  Json::object(members)
+}
+
+/// Reads back an expression encoded by `encode`.
+pub fn decode(json: &Json) -> Result<ContinuationExpression, EncodingError> {
+  let kind = json.get("kind").and_then(Json::as_str).unwrap_or("");
+  let expression = || decode(member(json, "expression")?).map(Box::new);
+  let expression = match kind {
+
+    "Record" => {
+      let field = | field: &Json | {
+        let path = decode_access_path(member(field, "path")?)?;
+        Ok((decode_operand(member(field, "value")?)?, Rc::new(path)))
+      };
+      ContinuationExpression::Record {
+        values    : elements(json, "values", field)?,
+        variable  : decode_variable(member(json, "variable")?)?,
+        expression: expression()?
+      }
+    }
+
+    "Select" => ContinuationExpression::Select {
+      location  : integer(member(json, "location")?)?,
+      value     : decode_operand(member(json, "value")?)?,
+      variable  : decode_variable(member(json, "variable")?)?,
+      expression: expression()?
+    },
+
+    "Offset" => ContinuationExpression::Offset {
+      location  : integer(member(json, "location")?)?,
+      value     : decode_operand(member(json, "value")?)?,
+      variable  : decode_variable(member(json, "variable")?)?,
+      expression: expression()?
+    },
+
+    "Apply" => ContinuationExpression::Apply {
+      function : decode_operand(member(json, "function")?)?,
+      arguments: elements(json, "arguments", decode_operand)?
+    },
+
+    "Fix" => {
+      let function_def = | function_def: &Json | {
+        Ok(Rc::new(FunctionDefinition::new(
+          decode_variable(member(function_def, "name")?)?,
+          decode_variables(member(function_def, "parameters")?)?,
+          decode(member(function_def, "body")?)?
+        )))
+      };
+      ContinuationExpression::Fix {
+        function_defs: Rc::new(elements(json, "functions", function_def)?),
+        expression   : expression()?
+      }
+    }
+
+    "Switch" => ContinuationExpression::Switch {
+      value: decode_operand(member(json, "value")?)?,
+      arms : elements(json, "arms", |arm| decode(arm).map(Rc::new))?
+    },
+
+    "PrimitiveOp" => {
+      let token = string(member(json, "operation")?)?;
+      ContinuationExpression::PrimitiveOp {
+        operation  : PrimitiveOp::from_token(token).map_or_else(
+          || error(format!("`{}` is not a primitive operation", token)),
+          Ok
+        )?,
+        values     : elements(json, "values", decode_operand)?,
+        variables  : decode_variables(member(json, "variables")?)?,
+        expressions: elements(json, "expressions", |arm| decode(arm).map(Box::new))?
+      }
+    }
+
+    _ => return error(format!("{} is not an encoded expression", json))
+
+  };
+  Ok(expression)
+}
+
+/// The encoding of a value the program computes, described in the module documentation. A
+/// function is rejected, since its environment cannot be encoded.
+#[allow(clippy::unnecessary_cast)] // `Integer` is `i32` under the `int32` feature.
+pub fn encode_value(value: &DValue) -> Result<Json, EncodingError> {
+  let encoded = match value {
+
+    DValue::Integer(i) => Json::Integer(*i as i64),
+
+    DValue::Real(x) => encode_real(x.0),
+
+    DValue::String(s) => Json::String(s.clone()),
+
+    DValue::Record { values, idx } => {
+      let fields = values.iter().map(encode_value).collect::<Result<_, _>>()?;
+      let mut members = vec![("record", Json::Array(fields))];
+      if *idx != 0 {
+        members.push(("offset", Json::Integer(*idx as i64)));
+      }
+      Json::object(members)
+    }
+
+    | DValue::Array(range)
+    | DValue::UnboxedArray(range)
+    | DValue::ByteArray(range)
+    | DValue::WeakRef(range) => Json::object(vec![
+      ("array", Json::String(value.kind().to_string())),
+      ("start", Json::Integer(range.start as i64)),
+      ("end", Json::Integer(range.end as i64))
+    ]),
+
+    DValue::Function(_) => return error("a function cannot be encoded".to_string()),
+
+    DValue::Exception(exception) => {
+      Json::object(vec![("exception", Json::Integer(exception.tag() as i64))])
+    }
+
+  };
+  Ok(encoded)
+}
+
+/// Reads back a value encoded by `encode_value`.
+pub fn decode_value(json: &Json) -> Result<DValue, EncodingError> {
+  let value = match json {
+
+    Json::Integer(_) => DValue::Integer(integer(json)?),
+
+    Json::Real(_) => DValue::Real(decode_real(json)?.into()),
+
+    Json::String(s) => DValue::String(s.clone()),
+
+    Json::Object(_) if json.get("record").is_some() => DValue::Record {
+      values: elements(json, "record", decode_value)?,
+      idx   : json.get("offset").map_or(Ok(0), integer)?
+    },
+
+    Json::Object(_) if json.get("array").is_some() => {
+      let range = integer(member(json, "start")?)?..integer(member(json, "end")?)?;
+      match string(member(json, "array")?)? {
+        "Array"        => DValue::Array(range),
+        "UnboxedArray" => DValue::UnboxedArray(range),
+        "ByteArray"    => DValue::ByteArray(range),
+        "WeakRef"      => DValue::WeakRef(range),
+        kind           => return error(format!("`{}` is not a kind of array", kind))
+      }
+    }
+
+    Json::Object(_) if json.get("exception").is_some() => {
+      let tag = integer(member(json, "exception")?)?;
+      DValue::Exception(Exception::from_tag(tag).map_or_else(
+        || error(format!("{} is not the tag of an exception", tag)),
+        Ok
+      )?)
+    }
+
+    _ => return error(format!("{} is not an encoded value", json))
+
+  };
+  Ok(value)
+}
+
+#[allow(clippy::unnecessary_cast)] // `Integer` is `i32` under the `int32` feature.
+fn encode_operand(value: &Value) -> Json {
+  let (kind, encoded) = match value {
+    Value::Variable(v) => ("variable", encode_variable(v)),
+    Value::Label(v)    => ("label", encode_variable(v)),
+    Value::Integer(i)  => ("integer", Json::Integer(*i as i64)),
+    Value::Real(r)     => ("real", encode_real(r.0)),
+    Value::String(s)   => ("string", Json::String(s.clone())),
+  };
+  Json::object(vec![(kind, encoded)])
+}
+
+fn decode_operand(json: &Json) -> Result<Value, EncodingError> {
+  match json {
+    Json::Object(members) if members.len() == 1 => {
+      let (kind, encoded) = &members[0];
+      match kind.as_str() {
+        "variable" => Ok(Value::Variable(decode_variable(encoded)?)),
+        "label"    => Ok(Value::Label(decode_variable(encoded)?)),
+        "integer"  => Ok(Value::Integer(integer(encoded)?)),
+        "real"     => Ok(Value::Real(decode_real(encoded)?.into())),
+        "string"   => Ok(Value::String(string(encoded)?.to_string())),
+        _          => error(format!("{} is not an encoded operand", json))
+      }
+    }
+    _ => error(format!("{} is not an encoded operand", json))
+  }
+}
+
+fn encode_access_path(path: &AccessPath) -> Json {
+  match path {
+    AccessPath::Offset(offset) => Json::object(vec![("offset", Json::Integer(*offset as i64))]),
+    AccessPath::Select { offset, access_path } => Json::object(vec![
+      ("select", Json::Integer(*offset as i64)),
+      ("path", encode_access_path(access_path))
+    ])
+  }
+}
+
+fn decode_access_path(json: &Json) -> Result<AccessPath, EncodingError> {
+  match (json.get("offset"), json.get("select")) {
+    (Some(offset), None) => Ok(AccessPath::Offset(integer(offset)?)),
+    (None, Some(offset)) => Ok(AccessPath::Select {
+      offset     : integer(offset)?,
+      access_path: Rc::new(decode_access_path(member(json, "path")?)?)
+    }),
+    _ => error(format!("{} is not an encoded access path", json))
+  }
+}
+
+fn encode_variable(variable: &Variable) -> Json {
+  Json::String(variable.name().to_string())
+}
+
+fn encode_variables(variables: &[Variable]) -> Json {
+  Json::Array(variables.iter().map(encode_variable).collect())
+}
+
+fn decode_variable(json: &Json) -> Result<Variable, EncodingError> {
+  string(json).map(Variable::new)
+}
+
+fn decode_variables(json: &Json) -> Result<Vec<Variable>, EncodingError> {
+  array(json)?.iter().map(decode_variable).collect()
+}
+
+/// A finite real as a number, in the shortest decimal form of the single precision real rather
+/// than its exact double, and any other real as a string `f32::from_str` reads.
+fn encode_real(x: f32) -> Json {
+  if x.is_finite() {
+    Json::Real(x.to_string().parse().unwrap_or(f64::NAN))
+  } else {
+    Json::String(x.to_string())
+  }
+}
+
+fn decode_real(json: &Json) -> Result<f32, EncodingError> {
+  match json {
+    Json::Real(x)    => Ok(*x as f32),
+    Json::Integer(i) => Ok(*i as f32),
+    Json::String(s)  => s.parse().map_or_else(|_| error(format!("`{}` is not a real", s)), Ok),
+    _                => error(format!("{} is not a real", json))
+  }
+}
+
+fn member<'a>(json: &'a Json, key: &str) -> Result<&'a Json, EncodingError> {
+  json.get(key).map_or_else(|| error(format!("{} has no member `{}`", json, key)), Ok)
+}
+
+/// Decodes each element of the array that is the member `key` of `json`.
+fn elements<T>(
+  json  : &Json,
+  key   : &str,
+  decode: impl Fn(&Json) -> Result<T, EncodingError>
+) -> Result<Vec<T>, EncodingError>
+{
+  array(member(json, key)?)?.iter().map(decode).collect()
+}
+
+fn array(json: &Json) -> Result<&Vec<Json>, EncodingError> {
+  json.as_array().map_or_else(|| error(format!("{} is not an array", json)), Ok)
+}
+
+fn string(json: &Json) -> Result<&str, EncodingError> {
+  json.as_str().map_or_else(|| error(format!("{} is not a string", json)), Ok)
+}
+
+/// An integer of whatever type is wanted, if `json` is one that fits in it.
+fn integer<T: TryFrom<i64>>(json: &Json) -> Result<T, EncodingError> {
+  json.as_i64()
+      .and_then(|i| i.try_into().ok())
+      .map_or_else(|| error(format!("{} is not an integer in range", json)), Ok)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{json, parser::parse, tests::run_source};
+
+  /// Encodes `expression`, prints the encoding, parses it, and decodes the result.
+  fn round_trip(expression: &ContinuationExpression) -> ContinuationExpression {
+    decode(&json::parse(&encode(expression).to_string()).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn a_program_round_trips_through_json() {
+    let source = "
+      (fix ((f (x k) (primop + (x 1) (y) ((app k y))))
+            (g () (app @f 41 halt)))
+        (record (1 (r 1) (r (sel 0 (sel 2 1))) \"a \\\"quoted\\\"\" 2.5 -0.0 1e-10 @f) w
+          (select -1 w v (offset 2 w u (switch v
+            (app halt u)
+            (primop < (u 3) () ((app g) (app halt -7)))
+            (primop mkarray (2 0) (a) ((primop update (a 0 1) () ((app halt a))))))))))";
+    let expression = parse(source).unwrap();
+    assert_eq!(round_trip(&expression), expression);
+  }
+
+  #[test]
+  fn a_reloaded_program_computes_the_same_values() {
+    let source = "(fix ((f (x k) (primop * (x 2) (y) ((app k y))))) (app @f 21 halt))";
+    let reloaded = crate::interpreter::parser::printer::print(&round_trip(&parse(source).unwrap()));
+    assert_eq!(format!("{:?}", run_source(&reloaded)), format!("{:?}", run_source(source)));
+  }
+
+  #[test]
+  fn reals_json_cannot_represent_are_strings() {
+    for x in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 0.1, -2.0].iter() {
+      let value = Value::Real((*x).into());
+      let reloaded = decode_operand(&json::parse(&encode_operand(&value).to_string()).unwrap());
+      assert_eq!(reloaded, Ok(value));
+    }
+  }
+
+  #[test]
+  fn values_round_trip_through_json() {
+    let value = DValue::Record {
+      values: vec![
+        DValue::Integer(-3),
+        DValue::Real(1.5.into()),
+        DValue::String("s".to_string()),
+        DValue::Array(2..5),
+        DValue::Exception(Exception::Arity),
+        DValue::Record { values: vec![DValue::Integer(0)], idx: 0 }
+      ],
+      idx   : 1
+    };
+    let reloaded = decode_value(&json::parse(&encode_value(&value).unwrap().to_string()).unwrap());
+    assert_eq!(format!("{:?}", reloaded.unwrap()), format!("{:?}", value));
+  }
+
+  #[test]
+  fn a_function_value_is_rejected() {
+    let values = run_source("(fix ((f (k) (app k 1))) (app halt @f))");
+    assert_eq!(
+      encode_value(&values[0]).unwrap_err().message,
+      "a function cannot be encoded"
+    );
+  }
+
+  #[test]
+  fn malformed_encodings_are_errors() {
+    let decoded = | text: &str | decode(&json::parse(text).unwrap()).unwrap_err().message;
+    assert_eq!(decoded(r#"{"kind": "Loop"}"#), r#"{"kind":"Loop"} is not an encoded expression"#);
+    assert_eq!(
+      decoded(r#"{"kind": "Apply", "function": {"label": "f"}}"#),
+      r#"{"kind":"Apply","function":{"label":"f"}} has no member `arguments`"#
+    );
+    assert_eq!(
+      decoded(r#"{"kind": "PrimitiveOp", "operation": "frob", "values": [], "variables": []}"#),
+      "`frob` is not a primitive operation"
+    );
+  }
+}
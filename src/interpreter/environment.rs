@@ -1,11 +1,14 @@
 /*!
 
-An `Environment` stores the bindings of variables to values.
+An `Environment` stores the bindings of variables to values. The bindings are a
+`PersistentMap`, so binding a variable produces a new environment in O(log n) that shares the
+rest of its bindings with the old one.
 
 */
 
-use std::collections::HashMap;
-use std::ops::{Deref, Index};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ops::Index;
 use std::rc::Rc;
 
 use crate::interpreter::cps::denotable_value::{DValue, DValueList};
@@ -13,6 +16,8 @@ use crate::interpreter::cps::denotable_value::{DValue, DValueList};
 use crate::interpreter::{
   Variable,
   VariableList,
+  exception::Exception,
+  persistent_map::PersistentMap,
   value::Value,
 };
 
@@ -21,7 +26,7 @@ pub type RcEnvironment = Rc<Environment>;
 
 #[derive(Clone)]
 pub struct Environment {
-  pub(crate) bindings: Rc<Bindings>
+  pub(crate) bindings: PersistentMap<Variable, DValue>
 }
 
 
@@ -29,13 +34,13 @@ impl Environment {
 
   pub fn new() -> Self {
     Environment{
-      bindings: Rc::new(HashMap::new())
+      bindings: PersistentMap::new()
     }
   }
 
   pub fn with_bindings(bindings: Bindings) -> Environment {
     Environment{
-      bindings: Rc::new(bindings)
+      bindings: bindings.into_iter().collect()
     }
   }
 
@@ -48,56 +53,77 @@ impl Environment {
       }
     }
 
-    // The following will always clone, because `self` will always have a strong reference to
-    // `self.bindings`. Since there is no way to know if self will still be needed afterwards, we
-    // cannot steal the bindings vector from this environment.
-    let mut new_environment: Environment = self.deep_copy();
-    unsafe {
-      Rc::get_mut_unchecked(&mut new_environment.bindings).insert(variable, value);
-    }
+    let mut new_environment: Environment = self.clone();
+    new_environment.bindings.insert(variable, value);
     new_environment
   }
 
   /// Creates a copy of the environment in which the given list of variables and values are bound.
   /// Unlike bind, does not optimize the case that the variables are already bound.
   pub fn bindn(&self, variables: &VariableList, values: &DValueList) -> Environment {
-    let mut new_environment = self.deep_copy();
+    let mut new_environment = self.clone();
     new_environment.extend(variables.iter().cloned().zip(values.iter().cloned()));
     new_environment
   }
 
-  pub fn deep_copy(&self) -> Environment {
-    let new_bindings: Bindings = self.bindings.deref().clone();
-    Environment{ bindings: Rc::new(new_bindings) }
-  }
-
   // Creates an environment in which `variable` is free.
   pub fn unbind(&self, variable: &Variable) -> Environment{
     // Avoid making a new environment if `variable` is already unbound.
-    if !self.bindings.contains_key(&variable){
+    if !self.bindings.contains_key(variable){
       return self.clone();
     }
 
-    let mut new_environment = self.deep_copy();
-    unsafe {
-      Rc::get_mut_unchecked(&mut new_environment.bindings).remove(&variable);
+    let mut new_environment = self.clone();
+    new_environment.bindings.remove(variable);
+    new_environment
+  }
+
+  /// Creates an environment in which all of the given variables are free. Variables that are not
+  /// bound in `self` are ignored, and if none of them are bound, the bindings are shared with
+  /// `self`.
+  pub fn without(&self, variables: &HashSet<Variable>) -> Environment {
+    let mut new_environment = self.clone();
+    for variable in variables {
+      new_environment.bindings.remove(variable);
     }
     new_environment
   }
 
+  /// Creates an environment containing only the bindings of the given variables. Variables that
+  /// are not bound in `self` are ignored.
+  pub fn restricted_to(&self, variables: &HashSet<Variable>) -> Environment {
+    let bindings = variables.iter()
+                            .filter_map(|v| self.get(v).map(|value| (v.clone(), value.clone())))
+                            .collect();
+    Environment{ bindings }
+  }
+
+  /// The number of variables bound in the environment.
+  pub fn len(&self) -> usize {
+    self.bindings.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bindings.is_empty()
+  }
+
   pub fn get(&self, variable: &Variable) -> Option<&DValue> {
     self.bindings.get(variable)
   }
 
   /// This method is trivial for number variants and strings. `Value::Variable`s and
   /// `Value::Label`s must be looked up in the environment. This is function `V` in [Appel].
+  ///
+  /// An unbound `Value::Variable` or `Value::Label` yields `DValue::Exception(Undefined)`. Use
+  /// `try_value_to_denotable_value` to handle unbound variables separately from values.
   pub fn value_to_denotable_value(&self, value: &Value) -> DValue {
     match value{
 
       | Value::Variable(v)
       | Value::Label(v) => {
-        // Todo: What if `v` is not bound?
-        self[v].clone()
+        self.get(v)
+            .cloned()
+            .unwrap_or_else(|| Exception::Undefined.as_denotable_value())
       },
 
       Value::Integer(i) => DValue::Integer(*i),
@@ -109,11 +135,33 @@ impl Environment {
     }
   }
 
+  /// Like `value_to_denotable_value`, but an unbound `Value::Variable` or `Value::Label` yields
+  /// `Exception::Undefined` instead of panicking.
+  pub fn try_value_to_denotable_value(&self, value: &Value) -> Result<DValue, Exception> {
+    match value {
+
+      | Value::Variable(v)
+      | Value::Label(v) => {
+        self.get(v).cloned().ok_or(Exception::Undefined)
+      },
+
+      _ => DValue::try_from(value)
+
+    }
+  }
+
+  /// Converts a whole `ValueList` to a `DValueList`, short-circuiting on the first `Value` that
+  /// cannot be converted and propagating its exception.
+  pub fn values_to_denotable_values(&self, values: &[Value]) -> Result<DValueList, Exception> {
+    values.iter()
+          .map(|value| self.try_value_to_denotable_value(value))
+          .collect()
+  }
+
   pub fn extend<T>(&mut self, iterator: T)
     where T: IntoIterator<Item = (Variable, DValue)>
   {
-    let new_bindings = Rc::make_mut(&mut self.bindings);
-    new_bindings.extend(iterator);
+    self.bindings.extend(iterator);
   }
 
 }
@@ -122,7 +170,96 @@ impl Index<&Variable> for Environment {
   type Output = DValue;
 
   fn index(&self, index: &Variable) -> &Self::Output {
-    &self.bindings[index]
+    match self.bindings.get(index) {
+      Some(value) => value,
+      None        => panic!("variable {} is unbound", index.name())
+    }
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::Integer;
+
+  #[test]
+  fn values_to_denotable_values_converts_literals_and_bound_variables() {
+    let environment = Environment::new().bind(Variable::new("x"), DValue::Integer(7));
+    let values = vec![
+      Value::Integer(1),
+      Value::Variable(Variable::new("x")),
+      Value::String("s".to_string())
+    ];
+
+    let converted = environment.values_to_denotable_values(&values).unwrap();
+    assert_eq!(format!("{:?}", converted), r#"[1, 7, "s"]"#);
+  }
+
+  #[test]
+  fn values_to_denotable_values_stops_at_an_unbound_variable() {
+    let environment = Environment::new().bind(Variable::new("x"), DValue::Integer(7));
+    let values = vec![
+      Value::Variable(Variable::new("x")),
+      Value::Variable(Variable::new("unbound")),
+      Value::Integer(3)
+    ];
+
+    assert_eq!(environment.values_to_denotable_values(&values).err(), Some(Exception::Undefined));
+  }
+
+  #[test]
+  fn restricted_to_keeps_only_the_named_bound_variables() {
+    let environment = Environment::new().bind(Variable::new("a"), DValue::Integer(1))
+                                        .bind(Variable::new("b"), DValue::Integer(2))
+                                        .bind(Variable::new("c"), DValue::Integer(3));
+    let names: HashSet<Variable> = [Variable::new("a"), Variable::new("c"), Variable::new("unbound")]
+                                     .iter().cloned().collect();
+
+    let restricted = environment.restricted_to(&names);
+    assert_eq!(restricted.len(), 2);
+    assert!(matches!(restricted.get(&Variable::new("a")), Some(DValue::Integer(1))));
+    assert!(restricted.get(&Variable::new("b")).is_none());
+    assert!(matches!(restricted.get(&Variable::new("c")), Some(DValue::Integer(3))));
+  }
+
+  #[test]
+  fn without_removes_a_set_of_variables() {
+    let environment = Environment::new().bind(Variable::new("a"), DValue::Integer(1))
+                                        .bind(Variable::new("b"), DValue::Integer(2))
+                                        .bind(Variable::new("c"), DValue::Integer(3));
+    let removed: HashSet<Variable> = [Variable::new("a"), Variable::new("b"), Variable::new("absent")]
+                                       .iter().cloned().collect();
+
+    let remaining = environment.without(&removed);
+    assert_eq!(remaining.len(), 1);
+    assert!(matches!(remaining.get(&Variable::new("c")), Some(DValue::Integer(3))));
+    assert!(remaining.get(&Variable::new("a")).is_none());
+    // The original is unchanged.
+    assert_eq!(environment.len(), 3);
+  }
+
+  #[test]
+  fn binding_across_thousands_of_nested_scopes_leaves_each_scope_as_it_was() {
+    let depth = 5000;
+    let mut scopes = vec![Environment::new()];
+    for i in 0..depth {
+      // Each scope binds a fresh variable and shadows `x`.
+      let scope = scopes[i].bind(Variable::new(&format!("v{}", i)), DValue::Integer(i as Integer))
+                           .bind(Variable::new("x"), DValue::Integer(i as Integer));
+      scopes.push(scope);
+    }
+
+    let innermost = &scopes[depth];
+    assert_eq!(innermost.len(), depth + 1);
+    assert!(matches!(innermost.get(&Variable::new("x")), Some(&DValue::Integer(x)) if x == (depth - 1) as Integer));
+    for (i, scope) in scopes.iter().enumerate().step_by(499) {
+      assert_eq!(scope.len(), if i == 0 { 0 } else { i + 1 });
+      assert!(scope.get(&Variable::new(&format!("v{}", i))).is_none());
+      if i > 0 {
+        assert!(matches!(scope.get(&Variable::new("x")), Some(&DValue::Integer(x)) if x == (i - 1) as Integer));
+        assert!(matches!(scope.get(&Variable::new("v0")), Some(&DValue::Integer(0))));
+      }
+    }
+  }
+}
@@ -10,6 +10,7 @@ use strum::Display;
 
 use crate::{
   interpreter::{
+    Integer,
     cps::{
       continuation::Answer,
       denotable_value::{DenotableValue, DValue}
@@ -22,17 +23,120 @@ pub enum Exception {
   Overflow,
   DivideByZero,
   InvalidAccess,    // Attempt to access a field of a non-`Record`
-  // Bind,
-  // Match,
   Undefined,
   IndexOutOfBounds, // Called `Nth` in [Appel], an invalid subscript.
+  TypeMismatch,     // An operand of the wrong kind, e.g. a non-integer where an integer is required.
+  OutOfMemory,      // An allocation would grow the store past its limit. See `Store::check_store_size`.
+  FloatInvalid,     // A real operation produced NaN from non-NaN operands under `FloatMode::Trapping`.
+  FloatOverflow,    // A real operation produced an infinity from finite operands under `FloatMode::Trapping`.
+  DomainError,      // An argument outside the domain of a real function, e.g. the square root of a negative real.
+  Bind,             // A function applied to a different number of arguments than it has formal parameters.
+  Match,            // A `Switch` on a value that selects none of its arms.
+  Arity,            // A primitive operation given a different number of arguments or continuations than it takes.
 }
 
 impl Exception {
+  /// Appel's name for an invalid subscript. It is the same exception as `IndexOutOfBounds`.
+  pub const NTH: Exception = Exception::IndexOutOfBounds;
+
+  /// The tag of an exception raised by the program itself with `PrimitiveOp::Raise`. See
+  /// `user_record`.
+  pub const USER_TAG: Integer = 13;
+
   pub fn as_denotable_value(self) -> DenotableValue {
     DValue::Exception(self)
   }
 
+  /// The integer tag identifying the exception in its record encoding (see `as_record`). The tags
+  /// are dense so that a handler can dispatch on them directly with a `Switch`:
+  ///
+  /// | Tag | Exception          |
+  /// |:----|:-------------------|
+  /// | 0   | `Overflow`         |
+  /// | 1   | `DivideByZero`     |
+  /// | 2   | `InvalidAccess`    |
+  /// | 3   | `Undefined`        |
+  /// | 4   | `IndexOutOfBounds` |
+  /// | 5   | `TypeMismatch`     |
+  /// | 6   | `OutOfMemory`      |
+  /// | 7   | `FloatInvalid`     |
+  /// | 8   | `FloatOverflow`    |
+  /// | 9   | `DomainError`      |
+  /// | 10  | `Bind`             |
+  /// | 11  | `Match`            |
+  /// | 12  | `Arity`            |
+  /// | 13  | user exceptions    |
+  pub fn tag(self) -> Integer {
+    match self {
+      Exception::Overflow         => 0,
+      Exception::DivideByZero     => 1,
+      Exception::InvalidAccess    => 2,
+      Exception::Undefined        => 3,
+      Exception::IndexOutOfBounds => 4,
+      Exception::TypeMismatch     => 5,
+      Exception::OutOfMemory      => 6,
+      Exception::FloatInvalid     => 7,
+      Exception::FloatOverflow    => 8,
+      Exception::DomainError      => 9,
+      Exception::Bind             => 10,
+      Exception::Match            => 11,
+      Exception::Arity            => 12,
+    }
+  }
+
+  /// The exception with the given tag, if there is one. This is the inverse of `tag`.
+  pub fn from_tag(tag: Integer) -> Option<Exception> {
+    match tag {
+      0  => Some(Exception::Overflow),
+      1  => Some(Exception::DivideByZero),
+      2  => Some(Exception::InvalidAccess),
+      3  => Some(Exception::Undefined),
+      4  => Some(Exception::IndexOutOfBounds),
+      5  => Some(Exception::TypeMismatch),
+      6  => Some(Exception::OutOfMemory),
+      7  => Some(Exception::FloatInvalid),
+      8  => Some(Exception::FloatOverflow),
+      9  => Some(Exception::DomainError),
+      10 => Some(Exception::Bind),
+      11 => Some(Exception::Match),
+      12 => Some(Exception::Arity),
+      _  => None
+    }
+  }
+
+  /// The canonical encoding of an exception as a value a CPS program can inspect. This is what
+  /// the exception handler receives:
+  ///
+  /// ```text
+  /// Record { values: [Integer(tag), String(name)], idx: 0 }
+  /// ```
+  ///
+  /// The tag can be read with `PrimitiveOp::ExceptionTag` (or `Select(0, ...)`), and the name
+  /// is the variant's name as rendered by `Display`.
+  pub fn as_record(self) -> DValue {
+    DValue::Record {
+      values: vec![DValue::Integer(self.tag()), DValue::String(self.to_string())],
+      idx   : 0
+    }
+  }
+
+  /// The record encoding of an exception raised by the program with `PrimitiveOp::Raise`. It is
+  /// encoded like the interpreter's own exceptions, but with `USER_TAG` as its tag and the raised
+  /// value in place of the name:
+  ///
+  /// ```text
+  /// Record { values: [Integer(USER_TAG), payload], idx: 0 }
+  /// ```
+  ///
+  /// A handler can thus dispatch on `exntag` and read the payload with `getpayload` (or
+  /// `Select(1, ...)`).
+  pub fn user_record(payload: DValue) -> DValue {
+    DValue::Record {
+      values: vec![DValue::Integer(Exception::USER_TAG), payload],
+      idx   : 0
+    }
+  }
+
   pub fn as_answer(&self) -> Answer {
     Answer{
       f: Rc::new(
@@ -53,7 +157,9 @@ impl Exception {
 #[derive(Copy, Clone, Display, Debug, Hash)]
 pub enum InternalException {
   Undefined,
-  WrongNumberOfParameters
+  WrongNumberOfParameters,
+  TypeMismatch,
+  IndexOutOfBounds
 }
 
 pub fn raise_exception(exception: InternalException) {
@@ -63,3 +169,63 @@ pub fn raise_exception(exception: InternalException) {
 pub fn raise_exception_msg(exception: InternalException, msg: &str) {
   eprint!("Internal exception raised:: {}: {}", exception, msg);
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::tests::run_source;
+
+  #[test]
+  fn tags_are_dense_and_invert() {
+    for tag in 0..Exception::USER_TAG {
+      assert_eq!(Exception::from_tag(tag).map(Exception::tag), Some(tag));
+    }
+    assert_eq!(Exception::from_tag(Exception::USER_TAG), None);
+  }
+
+  #[test]
+  fn a_handler_dispatches_on_the_tag_of_a_caught_exception() {
+    // Arm 1 of the handler's switch is `DivideByZero`.
+    let result = run_source(
+      "(fix ((h (e) (primop exntag (e) (t) ((switch t (app halt 100) (app halt 101) (app halt 102))))))
+         (primop sethdlr (h) () ((primop div (1 0) (x) ((app halt x))))))"
+    );
+    assert_eq!(format!("{:?}", result), "[101]");
+  }
+
+  #[test]
+  fn an_uncaught_exception_ends_the_program_with_its_record() {
+    let result = run_source("(primop div (1 0) (x) ((app halt x)))");
+    assert_eq!(format!("{:?}", result), r#"[{1, "DivideByZero"}]"#);
+  }
+
+  #[test]
+  fn bind_match_and_nth_are_raised_in_their_situations() {
+    let raised = | source: &str | format!("{:?}", run_source(source));
+    let record = | exception: Exception | format!("{:?}", vec![exception.as_record()]);
+
+    // A function applied to too few or too many arguments.
+    assert_eq!(raised("(fix ((f (x k) (app k x))) (app f 1))"), record(Exception::Bind));
+    assert_eq!(raised("(fix ((f (x k) (app k x))) (app f 1 halt 2))"), record(Exception::Bind));
+    // A switch on an index that selects none of its arms.
+    assert_eq!(raised("(switch 2 (app halt 0) (app halt 1))"), record(Exception::Match));
+    // A select past the end of a record.
+    assert_eq!(raised("(record (1 2) r (select 2 r x (app halt x)))"), record(Exception::NTH));
+
+    assert_eq!(Exception::NTH, Exception::IndexOutOfBounds);
+    assert_eq!(Exception::Bind.to_string(), "Bind");
+    assert_eq!(Exception::Match.to_string(), "Match");
+  }
+
+  #[test]
+  fn a_raised_value_reaches_the_handler_as_the_payload_of_a_user_exception() {
+    let result = run_source(
+      "(fix ((h (e) (primop exntag (e) (t) ((select 1 e p (app halt t p))))))
+         (primop sethdlr (h) () ((primop raise (42) () ()))))"
+    );
+    assert_eq!(format!("{:?}", result), format!("[{}, 42]", Exception::USER_TAG));
+
+    let uncaught = run_source("(record (1 2) r (primop raise (r) () ()))");
+    assert_eq!(format!("{:?}", uncaught), format!("[{{{}, {{1, 2}}}}]", Exception::USER_TAG));
+  }
+}
@@ -0,0 +1,111 @@
+/*!
+
+  Generation of fresh variables for transformation passes (alpha-renaming, inlining, ...).
+
+  A fresh variable has the form `<prefix><hint>#<n>`. The `#` keeps the hint and the counter apart,
+  so that, say, the hint `x1` with counter `1` and the hint `x` with counter `11` differ. A
+  `Gensym` never produces the same name twice, and a `Gensym` made with `Gensym::avoiding` uses a
+  prefix that no variable of the given expression starts with, so its names cannot collide with
+  any binder or free variable already in the program.
+
+  `ContinuationExpression::fresh_variable` draws from a single process-wide counter and is
+  therefore safe to use from any thread.
+
+*/
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::ContinuationExpression
+};
+
+/// The prefix of fresh variables. It is not a character that occurs in ordinary identifiers.
+pub const FRESH_PREFIX: &str = "%";
+
+static NEXT_FRESH: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct Gensym {
+  prefix : String,
+  counter: usize
+}
+
+impl Gensym {
+
+  pub fn new() -> Self {
+    Gensym::with_prefix(FRESH_PREFIX)
+  }
+
+  pub fn with_prefix(prefix: &str) -> Self {
+    Gensym {
+      prefix : prefix.to_string(),
+      counter: 0
+    }
+  }
+
+  /// A generator whose variables cannot collide with any variable occurring in `expression`.
+  pub fn avoiding(expression: &ContinuationExpression) -> Self {
+    Gensym::with_prefix(&unused_prefix(expression))
+  }
+
+  /// Produces a variable different from every variable this generator has produced before. The
+  /// `hint` is only used to make the name readable.
+  pub fn fresh(&mut self, hint: &str) -> Variable {
+    let variable = Variable::new(&format!("{}{}#{}", self.prefix, hint, self.counter));
+    self.counter += 1;
+    variable
+  }
+
+}
+
+impl Default for Gensym {
+  fn default() -> Self {
+    Gensym::new()
+  }
+}
+
+/// A prefix that no variable in `expression` starts with: `FRESH_PREFIX` repeated as often as
+/// necessary.
+pub fn unused_prefix(expression: &ContinuationExpression) -> String {
+  let variables  = expression.all_variables();
+  let mut prefix = FRESH_PREFIX.to_string();
+  while variables.iter().any(|variable| variable.name().starts_with(&prefix)) {
+    prefix.push_str(FRESH_PREFIX);
+  }
+  prefix
+}
+
+/// Produces a variable that is distinct from every other variable produced by this function in
+/// this process. Safe to call from any thread.
+pub(crate) fn next_fresh_variable(hint: &str) -> Variable {
+  let n = NEXT_FRESH.fetch_add(1, Ordering::Relaxed);
+  Variable::new(&format!("{}{}#{}", FRESH_PREFIX, hint, n))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  #[test]
+  fn fresh_variables_are_unique_and_avoid_the_program() {
+    let program = parse("(fix ((% (x) (app x %%a))) (record (1) %x1 (app % %x1)))").unwrap();
+    let existing = program.all_variables();
+
+    let mut gensym = Gensym::avoiding(&program);
+    let hints = ["x", "x1", "", "k"];
+    let fresh: HashSet<Variable> = (0..1000).map(|n| gensym.fresh(hints[n % hints.len()])).collect();
+
+    assert_eq!(fresh.len(), 1000);
+    assert!(fresh.iter().all(|variable| !existing.contains(variable)));
+  }
+
+  #[test]
+  fn process_wide_fresh_variables_are_unique() {
+    let fresh: HashSet<Variable> = (0..1000).map(|_| next_fresh_variable("t")).collect();
+    assert_eq!(fresh.len(), 1000);
+  }
+}
@@ -0,0 +1,76 @@
+/*!
+
+  A process-wide string interner for the names of `Variable`s. Interning a name gives it a
+  `Symbol`, a small integer that is the same for every occurrence of the name, so variables
+  are compared, hashed, and cloned as integers and only resolved back to text for display.
+
+  Interned names live for the rest of the process. Like `gensym::next_fresh_variable`, the
+  interner is shared by all threads, so a `Variable` means the same name in any of them.
+
+*/
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/// The id of an interned name.
+pub type Symbol = u32;
+
+#[derive(Default)]
+struct Interner {
+  symbols: HashMap<&'static str, Symbol>,
+  /// The name of each symbol, indexed by symbol.
+  names  : Vec<&'static str>
+}
+
+static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+
+fn with_interner<T>(f: impl FnOnce(&mut Interner) -> T) -> T {
+  let interner = INTERNER.get_or_init(Default::default);
+  // The interner is never left inconsistent, so a panic in another thread is no reason to fail.
+  let mut interner = interner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+  f(&mut interner)
+}
+
+/// The symbol of `name`, interning it if it is new.
+pub fn intern(name: &str) -> Symbol {
+  with_interner(|interner| {
+    if let Some(&symbol) = interner.symbols.get(name) {
+      return symbol;
+    }
+    let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+    let symbol = interner.names.len() as Symbol;
+    interner.names.push(name);
+    interner.symbols.insert(name, symbol);
+    symbol
+  })
+}
+
+/// The name `symbol` was interned from.
+pub fn resolve(symbol: Symbol) -> &'static str {
+  with_interner(|interner| interner.names[symbol as usize])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn a_name_is_interned_once_and_resolves_back() {
+    let symbol = intern("interner_test_name");
+    assert_eq!(intern("interner_test_name"), symbol);
+    assert_ne!(intern("interner_test_other"), symbol);
+    assert_eq!(resolve(symbol), "interner_test_name");
+    assert_eq!(resolve(intern("")), "");
+  }
+
+  #[test]
+  fn every_thread_shares_the_symbols() {
+    let symbol  = intern("interner_test_shared");
+    let symbols: Vec<Symbol> = (0..4).map(|_| std::thread::spawn(|| intern("interner_test_shared")))
+                                     .collect::<Vec<_>>()
+                                     .into_iter()
+                                     .map(|thread| thread.join().unwrap())
+                                     .collect();
+    assert!(symbols.iter().all(|&s| s == symbol));
+  }
+}
@@ -0,0 +1,378 @@
+/*!
+
+  A minimal JSON value with a parser and a printer, enough for the line-delimited protocol of the
+  `server` without a dependency.
+
+  Numbers without a fraction or an exponent are kept as integers, so integers round-trip exactly.
+  The members of an object keep their order. A `Json` prints on one line, as the line-delimited
+  protocol needs; NaN and the infinities, which JSON cannot represent, print as `null`.
+
+  The parser recurses on the nesting of arrays and objects, so it rejects values nested more than
+  `MAX_DEPTH` deep rather than overflow the stack.
+
+*/
+
+use std::fmt::{self, Display, Formatter, Write};
+
+/// The deepest that arrays and objects may nest in a value `parse` accepts.
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Integer(i64),
+  Real(f64),
+  String(String),
+  Array(Vec<Json>),
+  /// The members of an object, in order.
+  Object(Vec<(String, Json)>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct JsonError {
+  /// The byte offset in the source at which the error was detected.
+  pub offset : usize,
+  pub message: String
+}
+
+impl Display for JsonError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "at byte {}: {}", self.offset, self.message)
+  }
+}
+
+impl Json {
+  /// The value of the member `key` if this is an object that has one.
+  pub fn get(&self, key: &str) -> Option<&Json> {
+    match self {
+      Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+      _                     => None
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::String(s) => Some(s),
+      _               => None
+    }
+  }
+
+  pub fn as_i64(&self) -> Option<i64> {
+    match self {
+      Json::Integer(i) => Some(*i),
+      _                => None
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&Vec<Json>> {
+    match self {
+      Json::Array(elements) => Some(elements),
+      _                     => None
+    }
+  }
+
+  /// An object with the given members, for building responses.
+  pub fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+  }
+}
+
+impl Display for Json {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+
+      Json::Null       => write!(f, "null"),
+      Json::Bool(b)    => write!(f, "{}", b),
+      Json::Integer(i) => write!(f, "{}", i),
+      // `Debug` keeps a fraction on integral reals, so they read back as reals.
+      Json::Real(x) if x.is_finite() => write!(f, "{:?}", x),
+      Json::Real(_)    => write!(f, "null"),
+      Json::String(s)  => write_string(f, s),
+
+      Json::Array(elements) => {
+        f.write_char('[')?;
+        for (i, element) in elements.iter().enumerate() {
+          if i > 0 {
+            f.write_char(',')?;
+          }
+          write!(f, "{}", element)?;
+        }
+        f.write_char(']')
+      }
+
+      Json::Object(members) => {
+        f.write_char('{')?;
+        for (i, (key, value)) in members.iter().enumerate() {
+          if i > 0 {
+            f.write_char(',')?;
+          }
+          write_string(f, key)?;
+          write!(f, ":{}", value)?;
+        }
+        f.write_char('}')
+      }
+
+    }
+  }
+}
+
+fn write_string(f: &mut Formatter<'_>, s: &str) -> fmt::Result {
+  f.write_char('"')?;
+  for c in s.chars() {
+    match c {
+      '"'  => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+      c    => f.write_char(c)?
+    }
+  }
+  f.write_char('"')
+}
+
+/// Parses `source`, which must contain exactly one JSON value, possibly surrounded by whitespace.
+pub fn parse(source: &str) -> Result<Json, JsonError> {
+  let mut parser = Parser { source, bytes: source.as_bytes(), next: 0, depth: 0 };
+  let value = parser.value()?;
+  parser.skip_whitespace();
+  if parser.next < parser.bytes.len() {
+    return Err(parser.error("unexpected text after the value"));
+  }
+  Ok(value)
+}
+
+struct Parser<'a> {
+  source: &'a str,
+  bytes : &'a [u8],
+  next  : usize,
+  /// The number of arrays and objects being read.
+  depth : usize
+}
+
+impl<'a> Parser<'a> {
+  fn error(&self, message: &str) -> JsonError {
+    JsonError { offset: self.next, message: message.to_string() }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.next < self.bytes.len() && matches!(self.bytes[self.next], b' ' | b'\t' | b'\n' | b'\r') {
+      self.next += 1;
+    }
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.bytes.get(self.next).copied()
+  }
+
+  fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+    self.skip_whitespace();
+    if self.peek() == Some(byte) {
+      self.next += 1;
+      Ok(())
+    } else {
+      Err(self.error(&format!("expected `{}`", byte as char)))
+    }
+  }
+
+  fn value(&mut self) -> Result<Json, JsonError> {
+    self.skip_whitespace();
+    match self.peek() {
+      None        => Err(self.error("unexpected end of input")),
+      Some(b'{') | Some(b'[') if self.depth == MAX_DEPTH => {
+        Err(self.error(&format!("arrays and objects nested more than {} deep", MAX_DEPTH)))
+      }
+      Some(b'{')  => self.nested(Parser::object),
+      Some(b'[')  => self.nested(Parser::array),
+      Some(b'"')  => self.string().map(Json::String),
+      Some(b't')  => self.keyword("true", Json::Bool(true)),
+      Some(b'f')  => self.keyword("false", Json::Bool(false)),
+      Some(b'n')  => self.keyword("null", Json::Null),
+      Some(b'-') | Some(b'0'..=b'9') => self.number(),
+      Some(_)     => Err(self.error("unexpected character"))
+    }
+  }
+
+  /// Reads an array or an object with `read`, one level deeper.
+  fn nested(&mut self, read: fn(&mut Self) -> Result<Json, JsonError>) -> Result<Json, JsonError> {
+    self.depth += 1;
+    let value = read(self);
+    self.depth -= 1;
+    value
+  }
+
+  fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, JsonError> {
+    if self.source[self.next..].starts_with(keyword) {
+      self.next += keyword.len();
+      Ok(value)
+    } else {
+      Err(self.error("unexpected character"))
+    }
+  }
+
+  fn object(&mut self) -> Result<Json, JsonError> {
+    self.next += 1;
+    let mut members = Vec::new();
+    self.skip_whitespace();
+    if self.peek() == Some(b'}') {
+      self.next += 1;
+      return Ok(Json::Object(members));
+    }
+    loop {
+      self.skip_whitespace();
+      if self.peek() != Some(b'"') {
+        return Err(self.error("expected a member name"));
+      }
+      let key = self.string()?;
+      self.expect(b':')?;
+      let value = self.value()?;
+      members.push((key, value));
+      self.skip_whitespace();
+      match self.peek() {
+        Some(b',') => self.next += 1,
+        Some(b'}') => {
+          self.next += 1;
+          return Ok(Json::Object(members));
+        }
+        _ => return Err(self.error("expected `,` or `}`"))
+      }
+    }
+  }
+
+  fn array(&mut self) -> Result<Json, JsonError> {
+    self.next += 1;
+    let mut elements = Vec::new();
+    self.skip_whitespace();
+    if self.peek() == Some(b']') {
+      self.next += 1;
+      return Ok(Json::Array(elements));
+    }
+    loop {
+      elements.push(self.value()?);
+      self.skip_whitespace();
+      match self.peek() {
+        Some(b',') => self.next += 1,
+        Some(b']') => {
+          self.next += 1;
+          return Ok(Json::Array(elements));
+        }
+        _ => return Err(self.error("expected `,` or `]`"))
+      }
+    }
+  }
+
+  fn string(&mut self) -> Result<String, JsonError> {
+    self.next += 1;
+    let mut text = String::new();
+    loop {
+      let c = match self.source[self.next..].chars().next() {
+        Some(c) => c,
+        None    => return Err(self.error("unterminated string"))
+      };
+      self.next += c.len_utf8();
+      match c {
+        '"'  => return Ok(text),
+        '\\' => {
+          let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+          self.next += 1;
+          match escape {
+            b'"'  => text.push('"'),
+            b'\\' => text.push('\\'),
+            b'/'  => text.push('/'),
+            b'b'  => text.push('\u{8}'),
+            b'f'  => text.push('\u{c}'),
+            b'n'  => text.push('\n'),
+            b'r'  => text.push('\r'),
+            b't'  => text.push('\t'),
+            b'u'  => text.push(self.unicode_escape()?),
+            _     => return Err(self.error("unknown escape"))
+          }
+        }
+        c if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+        c    => text.push(c)
+      }
+    }
+  }
+
+  /// The character of a `\u` escape whose `u` has been read, combining a surrogate pair.
+  fn unicode_escape(&mut self) -> Result<char, JsonError> {
+    let high = self.hex4()?;
+    let code = if (0xD800..0xDC00).contains(&high) {
+      if !self.source[self.next..].starts_with("\\u") {
+        return Err(self.error("unpaired surrogate"));
+      }
+      self.next += 2;
+      let low = self.hex4()?;
+      if !(0xDC00..0xE000).contains(&low) {
+        return Err(self.error("unpaired surrogate"));
+      }
+      0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+    } else {
+      high
+    };
+    char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+  }
+
+  fn hex4(&mut self) -> Result<u32, JsonError> {
+    let digits = self.source.get(self.next..self.next + 4).ok_or_else(|| self.error("short unicode escape"))?;
+    let code = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid unicode escape"))?;
+    self.next += 4;
+    Ok(code)
+  }
+
+  fn number(&mut self) -> Result<Json, JsonError> {
+    let start = self.next;
+    let mut integral = true;
+    while let Some(byte) = self.peek() {
+      match byte {
+        b'0'..=b'9' | b'-' | b'+' => {}
+        b'.' | b'e' | b'E'        => integral = false,
+        _                         => break
+      }
+      self.next += 1;
+    }
+    let text = &self.source[start..self.next];
+    if integral {
+      if let Ok(i) = text.parse::<i64>() {
+        return Ok(Json::Integer(i));
+      }
+    }
+    text.parse::<f64>()
+        .map(Json::Real)
+        .map_err(|_| JsonError { offset: start, message: format!("invalid number `{}`", text) })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn values_round_trip_through_text() {
+    let text = r#"{"id":1,"list":[true,false,null,-2.5,3.0],"name":"a \"quoted\"\nline é"}"#;
+    let value = parse(text).unwrap();
+    assert_eq!(value.get("id"), Some(&Json::Integer(1)));
+    assert_eq!(value.get("list").unwrap().as_array().unwrap()[4], Json::Real(3.0));
+    assert_eq!(value.to_string(), text);
+    assert_eq!(parse(&value.to_string()).unwrap(), value);
+  }
+
+  #[test]
+  fn escapes_and_errors() {
+    assert_eq!(parse(r#" "\u00e9\ud83d\ude00\/" "#).unwrap(), Json::String("é😀/".to_string()));
+    assert_eq!(parse("[1, 2").unwrap_err().message, "expected `,` or `]`");
+    assert_eq!(parse("{} x").unwrap_err().offset, 3);
+    assert!(parse(r#""\ud83d""#).is_err());
+  }
+
+  #[test]
+  fn values_nested_too_deeply_are_an_error() {
+    let nested = | depth: usize | format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    assert!(parse(&nested(MAX_DEPTH)).is_ok());
+    assert_eq!(parse(&nested(MAX_DEPTH + 1)).unwrap_err().offset, MAX_DEPTH);
+    let error = parse(&"{\"a\":".repeat(100_000)).unwrap_err();
+    assert_eq!(error.message, format!("arrays and objects nested more than {} deep", MAX_DEPTH));
+  }
+}
@@ -0,0 +1,121 @@
+/*!
+
+  A flat, numbered listing of a CPS program, in the manner of a bytecode disassembly. Each node
+  gets a sequential id in a pre-order walk and is printed on its own line as
+
+  ```text
+  id: kind operands -> [child ids]
+  ```
+
+  A listing is easier to diff than a nested pretty-print and can be cross-referenced with trace
+  output by id.
+
+*/
+
+use std::fmt::Write;
+
+use crate::interpreter::{
+  continuation_expression::ContinuationExpression,
+  value::Value
+};
+
+/// Produces the numbered listing of `expression`, one line per node.
+pub fn listing(expression: &ContinuationExpression) -> String {
+  let mut lines: Vec<String> = Vec::new();
+  list_node(expression, &mut lines);
+
+  let mut text = String::new();
+  for line in lines {
+    text.push_str(&line);
+    text.push('\n');
+  }
+  text
+}
+
+/// Appends the lines of the subtree rooted at `expression` and returns the id of its root.
+fn list_node(expression: &ContinuationExpression, lines: &mut Vec<String>) -> usize {
+  let id = lines.len();
+  lines.push(String::new()); // Filled in once the ids of the children are known.
+
+  let child_ids: Vec<String> = expression.children()
+                                         .into_iter()
+                                         .map(|child| list_node(child, lines).to_string())
+                                         .collect();
+
+  let mut line = format!("{}: ", id);
+  match expression {
+
+    ContinuationExpression::Record { values, variable, .. } => {
+      let fields: Vec<String> = values.iter()
+                                      .map(|(value, access_path)| format!("{} {:?}", value, access_path))
+                                      .collect();
+      write!(line, "record [{}] {}", fields.join(", "), variable.name()).unwrap();
+    }
+
+    ContinuationExpression::Select { location, value, variable, .. } => {
+      write!(line, "select {} {} {}", location, value, variable.name()).unwrap();
+    }
+
+    ContinuationExpression::Offset { location, value, variable, .. } => {
+      write!(line, "offset {} {} {}", location, value, variable.name()).unwrap();
+    }
+
+    ContinuationExpression::Apply { function, arguments } => {
+      write!(line, "app {} [{}]", function, operands(arguments)).unwrap();
+    }
+
+    ContinuationExpression::Fix { function_defs, .. } => {
+      let headers: Vec<String> = function_defs.iter()
+                                              .map(|function_def| {
+                                                let (name, formal_parameters) = function_def.header();
+                                                let formal_parameters: Vec<&str> =
+                                                    formal_parameters.iter().map(|v| v.name()).collect();
+                                                format!("{}({})", name.name(), formal_parameters.join(", "))
+                                              })
+                                              .collect();
+      write!(line, "fix [{}]", headers.join(", ")).unwrap();
+    }
+
+    ContinuationExpression::Switch { value, .. } => {
+      write!(line, "switch {}", value).unwrap();
+    }
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, .. } => {
+      let variables: Vec<&str> = variables.iter().map(|v| v.name()).collect();
+      write!(line, "primop {} [{}] [{}]", operation, operands(values), variables.join(", ")).unwrap();
+    }
+
+  }
+  write!(line, " -> [{}]", child_ids.join(", ")).unwrap();
+
+  lines[id] = line;
+  id
+}
+
+pub(crate) fn operands(values: &[Value]) -> String {
+  values.iter().map(Value::to_string).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  #[test]
+  fn listing_numbers_every_node() {
+    let expression = parse(
+      "(fix ((f (x k) (primop + (x 1) (y) ((app k y))))) (switch 0 (app f 1 halt) (app halt 0)))"
+    ).unwrap();
+    let text = listing(&expression);
+    assert_eq!(text.lines().count(), 6);
+    assert_eq!(
+      text,
+      "0: fix [f(x, k)] -> [1, 3]\n\
+       1: primop + [x, 1] [y] -> [2]\n\
+       2: app k [y] -> []\n\
+       3: switch 0 -> [4, 5]\n\
+       4: app f [1, halt] -> []\n\
+       5: app halt [0] -> []\n"
+    );
+  }
+}
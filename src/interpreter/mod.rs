@@ -14,64 +14,135 @@
 
 */
 
+pub mod bench;
+pub mod bytecode;
+pub mod check;
+pub mod context;
+pub mod coverage;
+pub mod debugger;
+pub mod dot;
+pub mod encoding;
 pub mod exception;
+pub mod gensym;
+pub mod interner;
+pub mod json;
+pub mod listing;
+pub mod module;
+pub mod optimize;
+pub mod parser;
+pub mod persistent_map;
+pub mod pretty;
 pub mod primitive_op;
+pub mod profile;
+#[cfg(test)]
+mod reference;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod substitution;
+pub mod tracer;
 pub mod value;
+pub mod visitor;
 pub mod environment;
 pub mod continuation_expression;
 pub mod cps;
 
 use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
 
 
 use ordered_float::OrderedFloat;
 
 use crate::{
   interpreter::{
+    context::{EqualityMode, EvalContext},
     continuation_expression::ContinuationExpression,
     environment::Environment,
-    exception::{InternalException, raise_exception},
+    exception::InternalException,
+    interner::Symbol,
     cps::{
       continuation::Answer,
-      denotable_value::DenotableValueList
+      denotable_value::DenotableValueList,
+      store::Store
     }
   }
 };
 
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+/// A variable is an interned name, so comparing, hashing, and cloning it are operations on its
+/// `Symbol`. See `interner`.
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Variable{
-  name: String
-  // TODO: If `name` is the only field, define `Variable(String)` instead.
-  // TODO: Use interned strings.
+  symbol: Symbol
+}
+
+impl Variable {
+  pub fn new(name: &str) -> Variable {
+    Variable{ symbol: interner::intern(name) }
+  }
+
+  pub fn name(&self) -> &'static str {
+    interner::resolve(self.symbol)
+  }
+
+  /// The id of the variable's name, shared by every `Variable` with the same name.
+  pub fn symbol(&self) -> Symbol {
+    self.symbol
+  }
+}
+
+impl Display for Variable {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+impl Debug for Variable {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Variable").field("name", &self.name()).finish()
+  }
 }
 
 pub type VariableList = Vec<Variable>;
 
+/// The integers of the interpreted program. Arithmetic overflow is detected relative to the width
+/// of this type, which is 64 bits unless the `int32` feature selects 32 bits.
+#[cfg(not(feature = "int32"))]
 pub type Integer     = i64;
+#[cfg(feature = "int32")]
+pub type Integer     = i32;
 pub type IntegerList = Vec<Integer>;
 pub type Real        = OrderedFloat<f32>;
 pub type Location    = usize;
+/// The position of a field relative to where a record value points. Record values may point into
+/// the middle of a record, so a field offset may be negative.
+pub type FieldOffset = isize;
 
-/// "arbitrarily" selects one of the two provided alternatives.
-// Todo: It's not clear what this function should do.
-pub fn arbitrarily(a: bool, _b: bool) -> bool {
-  a
+/// "arbitrarily" selects one of the two provided alternatives. It models the nondeterminism of
+/// \[Appel]'s conservative equality, under which records and strings with equal contents "might
+/// return true or false." It selects `a` unless the current `EvalContext` uses
+/// `EqualityMode::Seeded`, in which case the choice is drawn from the context's seeded random
+/// number generator, so a test can drive both outcomes reproducibly with `EvalContext::with_seed`.
+pub fn arbitrarily(a: bool, b: bool) -> bool {
+  if EvalContext::with_current(|context| context.equality_mode) == EqualityMode::Seeded {
+    if EvalContext::next_random() & 1 == 0 { a } else { b }
+  } else {
+    a
+  }
 }
 
 
 /// The entry point of the interpreter, `evaluate` takes a `VariableList`, a
 /// `ContinuationExpression`, and a list of values to be bound to the corresponding variables in
 /// the`VariableList`, and returns the denotation of the expression in the resulting environment.
+/// Returns `WrongNumberOfParameters` if there are not as many values as variables.
 pub fn evaluate(
   mut variables: VariableList,
   mut values   : DenotableValueList,
   expression   : ContinuationExpression,
-) -> Answer
+) -> Result<Answer, InternalException>
 {
   if variables.len() != values.len() {
-    raise_exception(InternalException::WrongNumberOfParameters);
-    panic!();
+    return Err(InternalException::WrongNumberOfParameters);
   }
 
   let bindings = variables.drain(..)
@@ -79,5 +150,171 @@ pub fn evaluate(
                           .collect::<HashMap<_, _>>();
   let environment = Environment::with_bindings(bindings);
 
-  expression.evaluate(environment)
+  Ok(expression.evaluate(environment))
+}
+
+/// Drives `answer` to completion against a fresh `Store` of `store_size` cells (see `Store::new`)
+/// and returns the final values of the program: the parameters of the terminal answer it ends
+/// with (see `Answer::halt`). A program ends by applying a continuation that halts, such as the
+/// result continuation a caller binds for it or the store's default exception handler, in which
+/// case the final value is the record encoding of the uncaught exception.
+///
+/// This loop is the trampoline of the interpreter: continuations return the answers of the calls
+/// they make suspended (see `Answer::suspended`), and each iteration takes one of those steps, so
+/// the depth of the Rust stack does not depend on how many calls the program makes.
+pub fn run(answer: Answer, store_size: usize) -> DenotableValueList {
+  let store = Store::new(store_size);
+  let mut answer = answer;
+  while !answer.is_halted() {
+    answer = answer(&store);
+  }
+  answer.parameters().clone()
+}
+
+/// The program given to `run_with_fuel` did not halt within its budget of steps.
+#[derive(Debug)]
+pub struct FuelExhausted {
+  /// The next step of the program. Driving it further, for example with another call of
+  /// `run_with_fuel`, resumes the program where it stopped.
+  pub answer: Answer
+}
+
+/// Like `run`, but takes at most `fuel` steps of the trampoline, each of which applies one
+/// continuation, against `store`. Returns the terminal answer if the program halts within the
+/// budget and `FuelExhausted` with the answer it stopped at otherwise, so that a program that
+/// does not terminate can be evaluated safely.
+pub fn run_with_fuel(answer: Answer, store: Store, fuel: u64) -> Result<Answer, FuelExhausted> {
+  let mut answer = answer;
+  for _ in 0..fuel {
+    if answer.is_halted() {
+      return Ok(answer);
+    }
+    answer = answer(&store);
+  }
+
+  if answer.is_halted() {
+    Ok(answer)
+  } else {
+    Err(FuelExhausted { answer })
+  }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::interpreter::cps::denotable_value::DValue;
+
+  /// Parses `source` and runs it to completion with `halt` bound to a continuation that ends the
+  /// program, returning its final values. An uncaught exception ends the program too, with the
+  /// exception's record encoding as the final value.
+  pub(crate) fn run_source(source: &str) -> DenotableValueList {
+    let expression = parser::parse(source).unwrap();
+    let answer = evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], expression).unwrap();
+    run(answer, 16)
+  }
+
+  #[test]
+  fn run_drives_an_answer_to_its_final_values() {
+    let expression = parser::parse("(primop mkarray (1 x) (a) ((primop subscript (a 0) (y) ((primop + (y 1) (z) ((app halt z)))))))").unwrap();
+    let answer = evaluate(
+      vec![Variable::new("halt"), Variable::new("x")],
+      vec![Store::default_handler(), DValue::Integer(41)],
+      expression
+    ).unwrap();
+    assert_eq!(format!("{:?}", run(answer, 1)), "[42]");
+    assert_eq!(format!("{:?}", run(Answer::halt(vec![DValue::Integer(7)]), 1)), "[7]");
+  }
+
+  #[test]
+  fn arbitrarily_chooses_the_first_alternative_unless_seeded() {
+    assert!((0..16).all(|_| arbitrarily(true, false)));
+
+    let choices = | seed: u64 | {
+      let previous = EvalContext::new().with_equality_mode(EqualityMode::Seeded).with_seed(seed).install();
+      let choices: Vec<bool> = (0..32).map(|_| arbitrarily(true, false)).collect();
+      // Unequal values are never equal, whichever alternative is chosen.
+      let unequal = (0..32).all(|_| !arbitrarily(false, false));
+      previous.install();
+      assert!(unequal);
+      choices
+    };
+    assert!(choices(3).contains(&true) && choices(3).contains(&false));
+    assert_eq!(choices(3), choices(3));
+
+    // `DValue::eq` consults it for strings with equal contents.
+    let previous = EvalContext::new().with_equality_mode(EqualityMode::Seeded).with_seed(3).install();
+    let equal: Vec<bool> = (0..32).map(|_| DValue::String("s".into()) == DValue::String("s".into())).collect();
+    previous.install();
+    assert!(equal.contains(&true) && equal.contains(&false));
+  }
+
+  #[test]
+  fn evaluate_reports_a_parameter_count_mismatch() {
+    let expression = parser::parse("(app halt 1)").unwrap();
+    let result = evaluate(vec![Variable::new("halt"), Variable::new("x")], vec![Store::default_handler()], expression.clone());
+    assert!(matches!(result, Err(InternalException::WrongNumberOfParameters)));
+
+    let answer = evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], expression).unwrap();
+    assert_eq!(format!("{:?}", run(answer, 1)), "[1]");
+  }
+
+  #[test]
+  fn variables_with_the_same_name_are_equal_and_share_a_symbol() {
+    let (x, also_x, y) = (Variable::new("x"), Variable::new("x"), Variable::new("y"));
+    assert_eq!(x, also_x);
+    assert_eq!(x.symbol(), also_x.symbol());
+    assert_ne!(x, y);
+    assert_ne!(x.symbol(), y.symbol());
+    assert_eq!((x.name(), x.to_string()), ("x", "x".to_string()));
+
+    let mut set = std::collections::HashSet::new();
+    set.insert(x);
+    assert!(set.contains(&also_x));
+  }
+
+  #[test]
+  fn a_million_tail_calls_run_in_constant_stack() {
+    // Counts up to a million through a tail call of `loop` and a call of the continuation `k`
+    // per iteration, on the default test thread stack.
+    let answer = run_source(
+      "(fix ((loop (i k) (primop ieql (i 1000000) () ((app k i) (primop + (i 1) (j) ((app loop j k)))))))
+         (app loop 0 halt))"
+    );
+    assert!(matches!(answer.as_slice(), [DValue::Integer(1000000)]), "{:?}", answer);
+  }
+
+  fn evaluate_source(source: &str) -> Answer {
+    evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], parser::parse(source).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn an_infinite_loop_exhausts_its_fuel() {
+    let answer = evaluate_source("(fix ((loop () (app loop))) (app loop))");
+    let exhausted = run_with_fuel(answer, Store::new(16), 1000).unwrap_err();
+    assert!(!exhausted.answer.is_halted());
+    // The loop goes on where it stopped.
+    assert!(run_with_fuel(exhausted.answer, Store::new(16), 1000).is_err());
+  }
+
+  #[test]
+  fn an_exhausted_program_resumes_where_it_stopped() {
+    let store  = Store::new(16);
+    let answer = evaluate_source(
+      "(fix ((loop (i) (primop ieql (i 2000) () ((app halt i) (primop + (i 1) (j) ((app loop j)))))))
+         (app loop 0))"
+    );
+    let mut exhausted = run_with_fuel(answer, store.clone(), 1000).unwrap_err();
+    let mut resumptions = 0;
+    let halted = loop {
+      match run_with_fuel(exhausted.answer, store.clone(), 1000) {
+        Ok(halted)  => break halted,
+        Err(partly) => exhausted = partly
+      }
+      resumptions += 1;
+    };
+    assert!(resumptions > 0);
+    assert!(matches!(halted.parameters().as_slice(), [DValue::Integer(2000)]));
+    assert!(run_with_fuel(evaluate_source("(app halt 1)"), store, 1000).is_ok());
+  }
 }
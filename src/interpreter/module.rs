@@ -0,0 +1,154 @@
+/*!
+
+  A `Module` is a whole program: a list of top-level functions followed by a main expression.
+
+  The top-level functions are mutually recursive, and each of them, as well as the main
+  expression, may refer to any of them. `evaluate_module` gives a module the meaning of a single
+  `Fix` that defines all of the top-level functions and has the main expression as its
+  continuation.
+
+*/
+
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Variable,
+  VariableList,
+  evaluate,
+  continuation_expression::{ContinuationExpression, FunctionDefinition},
+  cps::{
+    continuation::Answer,
+    denotable_value::DenotableValueList,
+    store::Store
+  },
+  exception::InternalException,
+  value::Value
+};
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Module {
+  /// The top-level functions, all in scope in each other's bodies and in `main`.
+  pub functions : Vec<FunctionDefinition>,
+  /// The variables `main` expects to be bound to the arguments of `evaluate_module`. They are
+  /// bound around the `Fix` of `into_expression`, so the top-level functions see them too, as
+  /// `evaluate_entry` relies on to bind `halt`.
+  pub parameters: VariableList,
+  pub main      : ContinuationExpression
+}
+
+impl Module {
+  pub fn new(
+    functions : Vec<FunctionDefinition>,
+    parameters: VariableList,
+    main      : ContinuationExpression
+  ) -> Module
+  {
+    Module { functions, parameters, main }
+  }
+
+  /// The module as a single expression: one `Fix` that defines all the top-level functions and
+  /// continues with `main`.
+  pub fn into_expression(self) -> ContinuationExpression {
+    ContinuationExpression::Fix {
+      function_defs: Rc::new(self.functions.into_iter().map(Rc::new).collect()),
+      expression   : Box::new(self.main)
+    }
+  }
+
+  /// The top-level function named `name`, if there is one.
+  pub fn function(&self, name: &str) -> Option<&FunctionDefinition> {
+    self.functions.iter().find(|function| function.header().0.name() == name)
+  }
+}
+
+/// Evaluates `module` with its parameters bound to `arguments`. See `evaluate`.
+pub fn evaluate_module(
+  module   : Module,
+  arguments: DenotableValueList
+) -> Result<Answer, InternalException>
+{
+  let parameters = module.parameters.clone();
+  evaluate(parameters, arguments, module.into_expression())
+}
+
+/// Evaluates the application of the top-level function `entry_name` of `module` to `arguments`,
+/// with all top-level functions in scope and `halt` bound to `Store::default_handler`, so that a
+/// function can end the program by applying `halt` to its results. The module's `main` is not
+/// evaluated, and its parameters are not bound. Returns `InternalException::Undefined` if the
+/// module has no function named `entry_name`.
+pub fn evaluate_entry(
+  module    : Module,
+  entry_name: &str,
+  arguments : DenotableValueList
+) -> Result<Answer, InternalException>
+{
+  if module.function(entry_name).is_none() {
+    return Err(InternalException::Undefined);
+  }
+
+  let argument_variables: VariableList = arguments.iter()
+                                                  .map(|_| ContinuationExpression::fresh_variable("arg"))
+                                                  .collect();
+  let main = ContinuationExpression::Apply {
+    function : Value::Label(Variable::new(entry_name)),
+    arguments: argument_variables.iter().cloned().map(Value::Variable).collect()
+  };
+  let mut parameters = vec![Variable::new("halt")];
+  parameters.extend(argument_variables);
+  let mut values = vec![Store::default_handler()];
+  values.extend(arguments);
+
+  evaluate_module(Module { main, parameters, ..module }, values)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{cps::denotable_value::DValue, parser::parse_module, run};
+
+  /// `even` and `odd` call each other; `main` asks whether 7 is odd.
+  const PARITY: &str =
+    "(module ((even (n k) (primop ieql (n 0) () ((app k 1) (primop - (n 1) (m) ((app odd m k))))))
+              (odd (n k) (primop ieql (n 0) () ((app k 0) (primop - (n 1) (m) ((app even m k)))))))
+             (halt)
+             (app odd 7 halt))";
+
+  #[test]
+  fn top_level_functions_call_each_other_from_main() {
+    let module = parse_module(PARITY).unwrap();
+    assert_eq!(module.functions.len(), 2);
+    assert!(module.function("even").is_some());
+
+    let answer = evaluate_module(module, vec![Store::default_handler()]).unwrap();
+    assert_eq!(format!("{:?}", run(answer, 16)), "[1]");
+  }
+
+  #[test]
+  fn a_module_needs_the_module_form() {
+    assert!(parse_module("(app halt 1)").is_err());
+    assert!(parse_module("(module () (halt))").is_err());
+  }
+
+  #[test]
+  fn entry_points_of_one_module_run_with_halt_bound() {
+    let source = "(module ((double (x) (primop + (x x) (y) ((app halt y))))
+                           (quadruple (x) (fix ((k (y) (app double y))) (primop + (x x) (y) ((app k y))))))
+                          ()
+                          (app halt 0))";
+    let module = parse_module(source).unwrap();
+
+    let double = evaluate_entry(module.clone(), "double", vec![DValue::Integer(5)]).unwrap();
+    assert_eq!(format!("{:?}", run(double, 16)), "[10]");
+    let quadruple = evaluate_entry(module.clone(), "quadruple", vec![DValue::Integer(5)]).unwrap();
+    assert_eq!(format!("{:?}", run(quadruple, 16)), "[20]");
+
+    assert!(matches!(evaluate_entry(module, "triple", vec![DValue::Integer(5)]), Err(InternalException::Undefined)));
+  }
+
+  #[test]
+  fn top_level_functions_see_the_parameters() {
+    let module = parse_module("(module ((get (k) (app k n))) (n halt) (app get halt))").unwrap();
+    let answer = evaluate_module(module, vec![DValue::Integer(5), Store::default_handler()]).unwrap();
+    assert_eq!(format!("{:?}", run(answer, 16)), "[5]");
+  }
+}
@@ -0,0 +1,223 @@
+/*!
+
+  Beta-contraction of functions that are called exactly once. A function defined in a `Fix` whose
+  name occurs exactly once in the scope of the `Fix`, as the function of an `Apply`, is inlined:
+  the `Apply` is replaced by the body of the function with the formal parameters substituted by
+  the arguments, and the definition is dropped.
+
+  ```text
+  FIX([(f, [x], B)], ... APP(f, [a]) ...)  ⟶  ... B[a/x] ...
+  ```
+
+  A function is not inlined into its own body, nor when the call is applied to the wrong number
+  of arguments (which raises `Bind`), nor when a binder between the `Fix` and the call would
+  capture a variable the body refers to.
+
+*/
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::{ContinuationExpression, FunctionDefinition, RcFunctionDefinition},
+  optimize::map_children,
+  substitution::Substitution,
+  value::Value
+};
+
+/// Inlines every function that is called exactly once, innermost `Fix` first.
+pub fn beta_contract(expression: ContinuationExpression) -> ContinuationExpression {
+  match map_children(expression, beta_contract) {
+
+    ContinuationExpression::Fix { function_defs, expression } => {
+      contract_fix(Rc::unwrap_or_clone(function_defs), *expression)
+    }
+
+    expression => expression
+
+  }
+}
+
+/// Inlines the functions of `Fix(function_defs, continuation)` that are called once until none
+/// remain, dropping the `Fix` if all of its functions are inlined. Inlining one function can
+/// leave another with a single call, so the search starts over after each inlining.
+fn contract_fix(
+  mut function_defs: Vec<RcFunctionDefinition>,
+  mut continuation : ContinuationExpression
+) -> ContinuationExpression
+{
+  let mut i = 0;
+  while i < function_defs.len() {
+    if is_called_once(&function_defs, &continuation, i) && inline(&mut function_defs, &mut continuation, i) {
+      function_defs.remove(i);
+      i = 0;
+    } else {
+      i += 1;
+    }
+  }
+
+  if function_defs.is_empty() {
+    continuation
+  } else {
+    ContinuationExpression::Fix {
+      function_defs: Rc::new(function_defs),
+      expression   : Box::new(continuation)
+    }
+  }
+}
+
+/// Whether function `i` occurs exactly once in the scope of the `Fix`, outside its own body, as
+/// the function of an `Apply`.
+fn is_called_once(function_defs: &[RcFunctionDefinition], continuation: &ContinuationExpression, i: usize) -> bool {
+  let name = function_defs[i].header().0;
+  let mut uses = count_uses(continuation, name);
+  for (j, function_def) in function_defs.iter().enumerate() {
+    // A formal parameter of the same name shadows the function in the body.
+    if function_def.header().1.contains(name) {
+      continue;
+    }
+    let body_uses = count_uses(function_def.body(), name);
+    if j == i && body_uses.calls + body_uses.others > 0 {
+      // The function is recursive.
+      return false;
+    }
+    uses.calls  += body_uses.calls;
+    uses.others += body_uses.others;
+  }
+  uses.calls == 1 && uses.others == 0
+}
+
+/// Replaces the call of function `i` with its body, returning whether it could.
+fn inline(
+  function_defs: &mut [RcFunctionDefinition],
+  continuation : &mut ContinuationExpression,
+  i            : usize
+) -> bool
+{
+  let function_def = function_defs[i].clone();
+  let name         = function_def.header().0;
+  let free         = function_def.free_names();
+
+  for (j, body) in function_defs.iter_mut().enumerate() {
+    let formal_parameters = body.header().1;
+    if j == i || formal_parameters.contains(name) {
+      continue;
+    }
+    let blocked = formal_parameters.iter().any(|v| free.contains(v));
+    if inline_call(Rc::make_mut(body).body_mut(), name, &function_def, &free, blocked) {
+      return true;
+    }
+  }
+  inline_call(continuation, name, &function_def, &free, false)
+}
+
+/// The free occurrences of a name: as the function of an `Apply`, and anywhere else.
+#[derive(Default)]
+struct Uses {
+  calls : usize,
+  others: usize
+}
+
+fn count_uses(expression: &ContinuationExpression, name: &Variable) -> Uses {
+  let mut uses = Uses::default();
+  for (position, value) in expression.values().into_iter().enumerate() {
+    if let Value::Variable(v) | Value::Label(v) = value {
+      if v == name {
+        if position == 0 && matches!(expression, ContinuationExpression::Apply { .. }) {
+          uses.calls += 1;
+        } else {
+          uses.others += 1;
+        }
+      }
+    }
+  }
+  for (child, scope) in expression.children().into_iter().zip(expression.child_scopes()) {
+    if !scope.contains(name) {
+      let child_uses = count_uses(child, name);
+      uses.calls  += child_uses.calls;
+      uses.others += child_uses.others;
+    }
+  }
+  uses
+}
+
+/// Replaces the free call of `name` in `expression` by the body of `function_def`, whose free
+/// names are `free`, returning whether it did. `blocked` is set once a binder on the way to the
+/// call would capture one of `free`, in which case the call is left alone.
+fn inline_call(
+  expression  : &mut ContinuationExpression,
+  name        : &Variable,
+  function_def: &FunctionDefinition,
+  free        : &HashSet<Variable>,
+  blocked     : bool
+) -> bool
+{
+  if let ContinuationExpression::Apply { function: Value::Variable(f) | Value::Label(f), arguments } = expression {
+    let formal_parameters = function_def.header().1;
+    if f != name || blocked || arguments.len() != formal_parameters.len() {
+      return false;
+    }
+    let substitution: Substitution = formal_parameters.iter()
+                                                      .cloned()
+                                                      .zip(arguments.iter().cloned())
+                                                      .collect();
+    *expression = function_def.body().substitute(&substitution);
+    return true;
+  }
+
+  let scopes = expression.child_scopes();
+  for (child, scope) in expression.children_mut().into_iter().zip(scopes) {
+    if scope.contains(name) {
+      continue;
+    }
+    let blocked = blocked || scope.iter().any(|binder| free.contains(binder));
+    if inline_call(child, name, function_def, free, blocked) {
+      return true;
+    }
+  }
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  fn contracted(source: &str) -> ContinuationExpression {
+    beta_contract(parse(source).unwrap())
+  }
+
+  #[test]
+  fn a_function_called_once_is_inlined() {
+    assert_eq!(
+      contracted("(fix ((f (x k) (primop + (x 1) (y) ((app k y))))) (app f 41 halt))"),
+      parse("(primop + (41 1) (y) ((app halt y)))").unwrap()
+    );
+  }
+
+  #[test]
+  fn a_function_called_twice_is_kept() {
+    let source = "(fix ((f (x k) (app k x))) (switch i (app f 1 halt) (app f 2 halt)))";
+    assert_eq!(contracted(source), parse(source).unwrap());
+  }
+
+  #[test]
+  fn a_recursive_function_is_not_inlined_into_itself() {
+    let source = "(fix ((f (x) (app f x))) (app g 0))";
+    assert_eq!(contracted(source), parse(source).unwrap());
+  }
+
+  #[test]
+  fn a_call_with_the_wrong_number_of_arguments_is_kept() {
+    let source = "(fix ((f (x k) (app k x))) (app f 1))";
+    assert_eq!(contracted(source), parse(source).unwrap());
+  }
+
+  #[test]
+  fn a_call_under_a_binder_of_a_free_variable_of_the_body_is_kept() {
+    // Inlining would move the free `y` of the body under the `record` that binds `y`.
+    let source = "(fix ((f (x) (app k x y))) (record (1) y (app f y)))";
+    assert_eq!(contracted(source), parse(source).unwrap());
+  }
+}
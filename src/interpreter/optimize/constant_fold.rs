@@ -0,0 +1,167 @@
+/*!
+
+  Constant folding. A primitive operation whose arguments are all literals is evaluated at
+  compile time when it is pure and cannot raise an exception:
+
+  ```text
+  PRIMOP(+, [1, 2], [w], [e])    ⟶  e[3/w]
+  PRIMOP(<, [1, 2], [], [t, f])  ⟶  t
+  ```
+
+  Likewise a `Switch` on an integer literal is replaced by the selected arm. Operations that would
+  raise, such as `div` by zero or an overflowing `+`, are left in place to raise at run time, as
+  are operations whose result depends on the `EvalContext`: real arithmetic, which depends on the
+  `FloatMode`, and equality of anything but integers, which depends on the `EqualityMode`.
+
+*/
+
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Integer,
+  continuation_expression::ContinuationExpression,
+  optimize::map_children,
+  primitive_op::PrimitiveOp,
+  substitution::Substitution,
+  value::Value
+};
+
+/// The outcome of folding a primitive operation.
+enum Folded {
+  /// The operation produces the value, passing it to its only continuation.
+  Value(Value),
+  /// The operation is a test that takes its "true" (`0`) or "false" (`1`) continuation.
+  Branch(usize)
+}
+
+/// Folds every primitive operation on literals and every `Switch` on a literal in `expression`.
+pub fn constant_fold(expression: ContinuationExpression) -> ContinuationExpression {
+  match expression {
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, mut expressions }
+    if expressions.len() == operation.arity().1 => {
+      match fold(operation, &values) {
+
+        Some(Folded::Value(value)) if variables.len() == 1 => {
+          let mut substitution = Substitution::new();
+          substitution.insert(variables[0].clone(), value);
+          constant_fold(expressions.swap_remove(0).substitute(&substitution))
+        }
+
+        Some(Folded::Branch(arm)) => constant_fold(*expressions.swap_remove(arm)),
+
+        _ => {
+          let expression = ContinuationExpression::PrimitiveOp { operation, values, variables, expressions };
+          map_children(expression, constant_fold)
+        }
+
+      }
+    }
+
+    ContinuationExpression::Switch { value: Value::Integer(i), mut arms }
+    if usize::try_from(i).is_ok_and(|i| i < arms.len()) => {
+      constant_fold(Rc::unwrap_or_clone(arms.swap_remove(i as usize)))
+    }
+
+    expression => map_children(expression, constant_fold)
+
+  }
+}
+
+/// The result of `operation` on `values`, if they are literals on which the operation is pure and
+/// does not raise. This agrees with `PrimitiveOp::evaluate`.
+fn fold(operation: PrimitiveOp, values: &[Value]) -> Option<Folded> {
+  let branch = | test: bool | Some(Folded::Branch(if test { 0 } else { 1 }));
+  let integer = | result: Option<Integer> | result.map(|k| Folded::Value(Value::Integer(k)));
+
+  match (operation, values) {
+
+    (PrimitiveOp::Multiply, [Value::Integer(i), Value::Integer(j)]) => integer(i.checked_mul(*j)),
+    (PrimitiveOp::Add,      [Value::Integer(i), Value::Integer(j)]) => integer(i.checked_add(*j)),
+    (PrimitiveOp::Subtract, [Value::Integer(i), Value::Integer(j)]) => integer(i.checked_sub(*j)),
+    // `checked_div` is `None` for division by zero as well as for overflow.
+    (PrimitiveOp::Divide,   [Value::Integer(i), Value::Integer(j)]) => integer(i.checked_div(*j)),
+    (PrimitiveOp::Tilde,    [Value::Integer(i)])                    => integer((0 as Integer).checked_sub(*i)),
+
+    (PrimitiveOp::IEqual,       [Value::Integer(i), Value::Integer(j)]) => branch(i == j),
+    (PrimitiveOp::INEqual,      [Value::Integer(i), Value::Integer(j)]) => branch(i != j),
+    (PrimitiveOp::Less,         [Value::Integer(i), Value::Integer(j)]) => branch(i < j),
+    (PrimitiveOp::LessEqual,    [Value::Integer(i), Value::Integer(j)]) => branch(i <= j),
+    (PrimitiveOp::Greater,      [Value::Integer(i), Value::Integer(j)]) => branch(i > j),
+    (PrimitiveOp::GreaterEqual, [Value::Integer(i), Value::Integer(j)]) => branch(i >= j),
+    // An unsigned comparison. See `PrimitiveOp::RangeCheck`.
+    (PrimitiveOp::RangeCheck,   [Value::Integer(i), Value::Integer(j)]) => {
+      branch((*i as u64) < (*j as u64))
+    }
+
+    (PrimitiveOp::FEqual,        [Value::Real(a), Value::Real(b)]) => branch(a == b),
+    (PrimitiveOp::FNEqual,       [Value::Real(a), Value::Real(b)]) => branch(a != b),
+    (PrimitiveOp::FGreaterEqual, [Value::Real(a), Value::Real(b)]) => branch(a >= b),
+    (PrimitiveOp::FGreater,      [Value::Real(a), Value::Real(b)]) => branch(a > b),
+    (PrimitiveOp::FLessEqual,    [Value::Real(a), Value::Real(b)]) => branch(a <= b),
+    (PrimitiveOp::FLess,         [Value::Real(a), Value::Real(b)]) => branch(a < b),
+
+    _ => None
+
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{Variable, cps::store::Store, evaluate, parser::parse, run};
+
+  fn folded(source: &str) -> ContinuationExpression {
+    constant_fold(parse(source).unwrap())
+  }
+
+  #[test]
+  fn arithmetic_on_literals_is_folded_into_the_continuation() {
+    assert_eq!(
+      folded("(primop + (1 2) (w) ((primop * (w 4) (v) ((app k w v)))))"),
+      parse("(app k 3 12)").unwrap()
+    );
+    assert_eq!(folded("(primop ~ (5) (w) ((app k w)))"), parse("(app k -5)").unwrap());
+  }
+
+  #[test]
+  fn a_comparison_on_literals_selects_its_branch() {
+    assert_eq!(folded("(primop < (1 2) () ((app k 1) (app k 0)))"), parse("(app k 1)").unwrap());
+    assert_eq!(folded("(primop fgt (1.0 2.0) () ((app k 1) (app k 0)))"), parse("(app k 0)").unwrap());
+    assert_eq!(folded("(switch 1 (app k 0) (app k 1))"), parse("(app k 1)").unwrap());
+  }
+
+  #[test]
+  fn operations_that_raise_are_left_in_place() {
+    for source in [
+      "(primop div (1 0) (w) ((app halt w)))",
+      "(switch 2 (app halt 0) (app halt 1))",
+      "(primop fadd (1.0 2.0) (w) ((app halt w)))",
+    ].iter() {
+      assert_eq!(folded(source), parse(source).unwrap(), "{}", source);
+    }
+    let overflow = format!("(primop + ({} 1) (w) ((app halt w)))", Integer::MAX);
+    assert_eq!(folded(&overflow), parse(&overflow).unwrap());
+  }
+
+  /// The final values of `expression` rendered with `Debug`.
+  fn run_expression(expression: ContinuationExpression) -> String {
+    let answer = evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], expression).unwrap();
+    format!("{:?}", run(answer, 4))
+  }
+
+  #[test]
+  fn folding_agrees_with_evaluation() {
+    let tokens = ["ieql", "ineq", "<", "<=", ">", ">=", "rangechk"];
+    for token in tokens.iter() {
+      for i in [-2, 0, 3].iter() {
+        for j in [-1, 0, 3].iter() {
+          let source = format!("(primop {} ({} {}) () ((app halt 1) (app halt 0)))", token, i, j);
+          assert!(matches!(folded(&source), ContinuationExpression::Apply { .. }), "{}", source);
+          assert_eq!(run_expression(folded(&source)), run_expression(parse(&source).unwrap()), "{}", source);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,198 @@
+/*!
+
+  Dead-code elimination. Removes
+
+  - the functions of a `Fix` that cannot be reached from its continuation, including groups of
+    functions that only call each other, and the whole `Fix` if none are reachable;
+  - a `Record` whose variable is unused;
+  - a `Select` or `Offset` whose variable is unused, if it is known to stay within its record:
+    the record is bound by an enclosing `Record`, directly or through `Offset`s of it, so its
+    length and where it points are known. Any other `Select` or `Offset` might raise
+    `IndexOutOfBounds` or `InvalidAccess`, so it is kept;
+  - a `PrimitiveOp` with a single continuation whose result variables are unused, if the
+    operation is pure (see `PrimitiveOp::is_pure`).
+
+  Operations on the store, such as `update`, `store`, and `:=`, are never pure, so they are kept
+  even when their result is unused. Because subexpressions are simplified before the nodes
+  containing them, removing one binding can make the variables it used dead in turn.
+
+*/
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::interpreter::{
+  FieldOffset,
+  Variable,
+  continuation_expression::ContinuationExpression,
+  optimize::map_children,
+  persistent_map::PersistentMap,
+  value::Value
+};
+
+/// The records whose shape is known where they are in scope: for each variable bound to one,
+/// the number of its fields and the field it points to.
+type KnownRecords = PersistentMap<Variable, (usize, usize)>;
+
+/// Removes the dead functions and dead pure bindings of `expression`.
+pub fn eliminate_dead_code(expression: ContinuationExpression) -> ContinuationExpression {
+  eliminate(expression, &KnownRecords::new())
+}
+
+fn eliminate(expression: ContinuationExpression, known: &KnownRecords) -> ContinuationExpression {
+  // The record that the node binds, if it is known, is known in its child. Every other variable
+  // the node binds shadows any known record of the same name.
+  let bound_record = match &expression {
+    ContinuationExpression::Record { values, variable, .. } => Some((variable.clone(), (values.len(), 0))),
+    ContinuationExpression::Offset { location, value, variable, .. } => {
+      moved(known, value, *location).filter(|(len, idx)| idx <= len)
+                                    .map(|record| (variable.clone(), record))
+    }
+    _ => None
+  };
+  let mut scopes = expression.child_scopes().into_iter();
+  let expression = map_children(expression, |child| {
+    let mut inner = known.clone();
+    for variable in scopes.next().unwrap_or_default() {
+      inner.remove(&variable);
+    }
+    if let Some((variable, record)) = &bound_record {
+      inner.insert(variable.clone(), *record);
+    }
+    eliminate(child, &inner)
+  });
+
+  match expression {
+
+    ContinuationExpression::Record { variable, expression, .. }
+    if !expression.free_names().contains(&variable) => *expression,
+
+    ContinuationExpression::Select { location, value, variable, expression }
+    if !expression.free_names().contains(&variable)
+       && moved(known, &value, location).is_some_and(|(len, idx)| idx < len) => *expression,
+
+    ContinuationExpression::Offset { location, value, variable, expression }
+    if !expression.free_names().contains(&variable)
+       && moved(known, &value, location).is_some_and(|(len, idx)| idx <= len) => *expression,
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, mut expressions }
+    if operation.is_pure() && expressions.len() == 1 => {
+      let free = expressions[0].free_names();
+      if variables.iter().any(|variable| free.contains(variable)) {
+        ContinuationExpression::PrimitiveOp { operation, values, variables, expressions }
+      } else {
+        *expressions.swap_remove(0)
+      }
+    }
+
+    ContinuationExpression::Fix { function_defs, expression } => {
+      // The functions are live if the continuation refers to them, directly or through the bodies
+      // of other live functions.
+      let mut live   : HashSet<Variable> = HashSet::new();
+      let mut pending: Vec<Variable>      = expression.free_names().into_iter().collect();
+      while let Some(name) = pending.pop() {
+        if let Some(function_def) = function_defs.iter().find(|fd| *fd.header().0 == name) {
+          if live.insert(name) {
+            pending.extend(function_def.free_names());
+          }
+        }
+      }
+
+      if live.is_empty() {
+        *expression
+      } else if live.len() == function_defs.len() {
+        ContinuationExpression::Fix { function_defs, expression }
+      } else {
+        let function_defs = function_defs.iter()
+                                         .filter(|fd| live.contains(fd.header().0))
+                                         .cloned()
+                                         .collect();
+        ContinuationExpression::Fix { function_defs: Rc::new(function_defs), expression }
+      }
+    }
+
+    expression => expression
+
+  }
+}
+
+/// The number of fields of the record `value` and the field `location` positions past the one it
+/// points to, if `value` is a known record and that field is not before its start.
+fn moved(known: &KnownRecords, value: &Value, location: FieldOffset) -> Option<(usize, usize)> {
+  match value {
+    Value::Variable(variable) => {
+      let &(len, idx) = known.get(variable)?;
+      idx.checked_add_signed(location).map(|idx| (len, idx))
+    }
+    _ => None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{parser::{parse, printer::print}, tests::run_source};
+
+  fn eliminated(source: &str) -> ContinuationExpression {
+    eliminate_dead_code(parse(source).unwrap())
+  }
+
+  #[test]
+  fn an_unused_pure_record_is_removed() {
+    assert_eq!(eliminated("(record (a b) r (app k a))"), parse("(app k a)").unwrap());
+    // Removing `s` leaves `r` unused in turn.
+    assert_eq!(
+      eliminated("(record (a) r (select 0 r s (primop sconcat (\"a\" \"b\") (t) ((app k a)))))"),
+      parse("(app k a)").unwrap()
+    );
+    // An operation that might raise is kept even if its result is unused.
+    let source = "(primop + (a 1) (t) ((app k a)))";
+    assert_eq!(eliminated(source), parse(source).unwrap());
+  }
+
+  #[test]
+  fn an_unused_update_is_kept() {
+    let source = "(primop update (a 0 1) () ((app k a)))";
+    assert_eq!(eliminated(source), parse(source).unwrap());
+    let source = "(primop := (a 1) () ((app k a)))";
+    assert_eq!(eliminated(source), parse(source).unwrap());
+  }
+
+  #[test]
+  fn unreachable_functions_are_removed() {
+    assert_eq!(
+      eliminated("(fix ((f (x) (app g x)) (g (y) (app f y)) (h (z) (app k z))) (app h 1))"),
+      parse("(fix ((h (z) (app k z))) (app h 1))").unwrap()
+    );
+    assert_eq!(eliminated("(fix ((f (x) (app k x))) (app k 0))"), parse("(app k 0)").unwrap());
+  }
+
+  #[test]
+  fn a_select_or_offset_that_might_raise_is_kept() {
+    // Out of the bounds of a known record.
+    let source = "(record (1 2) r (select 5 r x (app halt 0)))";
+    assert_eq!(eliminated(source), parse(source).unwrap());
+    assert_eq!(
+      format!("{:?}", run_source(&print(&eliminated(source)))),
+      format!("{:?}", run_source(source))
+    );
+    let source = "(record (1 2) r (offset 3 r x (app halt 0)))";
+    assert_eq!(eliminated(source), parse(source).unwrap());
+    // Of a record that is not known.
+    let source = "(select 0 r x (offset 1 r y (app halt 0)))";
+    assert_eq!(eliminated(source), parse(source).unwrap());
+    // Of a known record shadowed by a formal parameter. Only the unused record goes.
+    assert_eq!(
+      eliminated("(record (1) r (fix ((f (r) (select 0 r x (app halt 0)))) (app f 0)))"),
+      parse("(fix ((f (r) (select 0 r x (app halt 0)))) (app f 0))").unwrap()
+    );
+  }
+
+  #[test]
+  fn a_select_or_offset_within_a_known_record_is_removed() {
+    assert_eq!(
+      eliminated("(record (1 2 3) r (offset 2 r p (select -1 p x (offset 1 p q (select 0 r y (app halt r))))))"),
+      parse("(record (1 2 3) r (app halt r))").unwrap()
+    );
+  }
+}
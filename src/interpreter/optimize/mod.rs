@@ -0,0 +1,75 @@
+/*!
+
+  Optimization passes over `ContinuationExpression`s, after \[Appel, Chapter 6]. Each pass is a
+  function from an expression to an equivalent expression: evaluating the result produces the
+  same answer, raising the same exceptions, as evaluating the original.
+
+*/
+
+pub mod beta_contract;
+pub mod constant_fold;
+pub mod dead_code;
+
+use crate::interpreter::{
+  continuation_expression::ContinuationExpression,
+  value::Value
+};
+
+/// Applies `pass` to each child of `expression` (see `ContinuationExpression::children`),
+/// replacing the child with the result.
+pub(crate) fn map_children(
+  mut expression: ContinuationExpression,
+  mut pass      : impl FnMut(ContinuationExpression) -> ContinuationExpression
+) -> ContinuationExpression
+{
+  for child in expression.children_mut() {
+    // The child is moved out for `pass` to consume, leaving a placeholder until it is replaced.
+    let placeholder = ContinuationExpression::Apply { function: Value::Integer(0), arguments: vec![] };
+    let taken       = std::mem::replace(child, placeholder);
+    *child = pass(taken);
+  }
+  expression
+}
+
+/// Applies `f` to every subexpression of `expression`, bottom-up: each node is rebuilt from the
+/// results for its children before `f` is applied to it, so `f` sees children it has already
+/// rewritten. The result for the whole expression is the result of `f` on the root.
+pub fn map_expr(
+  expression: ContinuationExpression,
+  f         : &mut impl FnMut(ContinuationExpression) -> ContinuationExpression
+) -> ContinuationExpression
+{
+  let expression = map_children(expression, |child| map_expr(child, f));
+  f(expression)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  #[test]
+  fn map_expr_can_replace_every_switch_with_its_first_arm() {
+    let expression = parse(
+      "(switch i
+         (record (1) r (switch r (app k 0) (app k 1)))
+         (app k 2))"
+    ).unwrap();
+    let mut first_arm = | expression: ContinuationExpression | match expression {
+      ContinuationExpression::Switch { arms, .. } => (*arms[0]).clone(),
+      expression                                  => expression
+    };
+    assert_eq!(map_expr(expression, &mut first_arm), parse("(record (1) r (app k 0))").unwrap());
+  }
+
+  #[test]
+  fn map_expr_rewrites_children_before_their_parents() {
+    let mut order = Vec::new();
+    let expression = parse("(record (1) r (select 0 r v (app k v)))").unwrap();
+    map_expr(expression, &mut | expression: ContinuationExpression | {
+      order.push(expression.to_string().split('(').next().unwrap().to_string());
+      expression
+    });
+    assert_eq!(order, vec!["APP", "SELECT", "RECORD"]);
+  }
+}
@@ -0,0 +1,252 @@
+/*!
+
+  Splits the source of a CPS program into tokens. A word, that is, a run of characters other than
+  whitespace, parentheses, `"`, and `;`, is an integer or real literal if it looks like a number,
+  a label if it starts with `@`, the token of a primitive operation such as `+` or `subscript`,
+  and otherwise an identifier. A `;` starts a comment that extends to the end of the line.
+
+  A word of digits, possibly signed, is an integer, and an error if it is out of the range of
+  `Integer`. The words `inf`, `+inf`, `-inf`, and `NaN` are reals, as `Display` prints them.
+
+*/
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use crate::interpreter::{
+  Integer,
+  Real,
+  primitive_op::PrimitiveOp
+};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TokenKind {
+  LeftParenthesis,
+  RightParenthesis,
+  Identifier(String),
+  /// A label `@f`, without the `@`.
+  Label(String),
+  Operator(PrimitiveOp),
+  Integer(Integer),
+  Real(Real),
+  /// A string literal with its escapes resolved.
+  String(String)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Token {
+  pub kind: TokenKind,
+  /// The bytes of the source the token was read from.
+  pub span: Range<usize>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LexError {
+  /// The byte offset into the source at which the error was found.
+  pub offset : usize,
+  pub message: String
+}
+
+impl Display for LexError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "at byte {}: {}", self.offset, self.message)
+  }
+}
+
+fn error(offset: usize, message: &str) -> LexError {
+  LexError { offset, message: message.to_string() }
+}
+
+/// Splits `source` into tokens, dropping whitespace and comments.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, LexError> {
+  let mut tokens = Vec::new();
+  let mut chars  = source.char_indices().peekable();
+
+  while let Some((start, c)) = chars.next() {
+    let kind = match c {
+
+      ';' => {
+        while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        continue;
+      }
+
+      c if c.is_whitespace() => continue,
+
+      '(' => TokenKind::LeftParenthesis,
+      ')' => TokenKind::RightParenthesis,
+
+      '"' => {
+        let mut text = String::new();
+        loop {
+          let (offset, c) = chars.next().ok_or_else(|| error(start, "unterminated string"))?;
+          match c {
+            '"'  => break,
+            '\\' => {
+              let (_, escaped) = chars.next().ok_or_else(|| error(start, "unterminated string"))?;
+              text.push(
+                match escaped {
+                  '"'  => '"',
+                  '\\' => '\\',
+                  'n'  => '\n',
+                  't'  => '\t',
+                  _    => return Err(error(offset, "unknown escape sequence"))
+                }
+              );
+            }
+            c    => text.push(c)
+          }
+        }
+        TokenKind::String(text)
+      }
+
+      _ => {
+        while chars.next_if(|&(_, c)| !ends_word(c)).is_some() {}
+        let end = chars.peek().map_or(source.len(), |&(offset, _)| offset);
+        word(&source[start..end], start)?
+      }
+
+    };
+    let end = chars.peek().map_or(source.len(), |&(offset, _)| offset);
+    tokens.push(Token { kind, span: start..end });
+  }
+
+  Ok(tokens)
+}
+
+fn ends_word(c: char) -> bool {
+  c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';')
+}
+
+fn word(text: &str, offset: usize) -> Result<TokenKind, LexError> {
+  if let Some(label) = text.strip_prefix('@') {
+    if label.is_empty() {
+      return Err(error(offset, "expected a label name after `@`"));
+    }
+    Ok(TokenKind::Label(label.to_string()))
+  } else if let Ok(i) = text.parse::<Integer>() {
+    Ok(TokenKind::Integer(i))
+  } else if is_integer(text) {
+    Err(error(offset, &format!("integer literal `{}` out of range", text)))
+  } else if let Some(x) = non_finite(text) {
+    Ok(TokenKind::Real(x.into()))
+  } else if is_number(text) {
+    text.parse::<f32>()
+        .map(|x| TokenKind::Real(x.into()))
+        .map_err(|_| error(offset, &format!("malformed number `{}`", text)))
+  } else if let Some(operation) = PrimitiveOp::from_token(text) {
+    Ok(TokenKind::Operator(operation))
+  } else {
+    Ok(TokenKind::Identifier(text.to_string()))
+  }
+}
+
+/// Whether `text` is an optional sign followed by digits only.
+fn is_integer(text: &str) -> bool {
+  let unsigned = text.strip_prefix(|c| c == '-' || c == '+').unwrap_or(text);
+  !unsigned.is_empty() && unsigned.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The infinite or NaN real that `text` names, if it names one.
+fn non_finite(text: &str) -> Option<f32> {
+  match text {
+    "inf" | "+inf" => Some(f32::INFINITY),
+    "-inf"         => Some(f32::NEG_INFINITY),
+    "NaN"          => Some(f32::NAN),
+    _              => None
+  }
+}
+
+/// Whether `text` is meant as a numeric literal: it starts with a digit, possibly after a sign or
+/// a decimal point. The operators `-` and `+` on their own are not.
+fn is_number(text: &str) -> bool {
+  let unsigned = text.strip_prefix(|c| c == '-' || c == '+').unwrap_or(text);
+  let digits   = unsigned.strip_prefix('.').unwrap_or(unsigned);
+  digits.starts_with(|c: char| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::cps::denotable_value::DValue;
+
+  fn kinds(source: &str) -> Vec<TokenKind> {
+    tokenize(source).unwrap().into_iter().map(|token| token.kind).collect()
+  }
+
+  #[test]
+  fn a_small_program_tokenizes_with_spans() {
+    let tokens = tokenize("(primop + (x 1.5) (w) ; sum\n ((app @k w \"a\\n\")))").unwrap();
+    let kinds: Vec<TokenKind> = tokens.iter().map(|token| token.kind.clone()).collect();
+    assert_eq!(
+      kinds,
+      vec![
+        TokenKind::LeftParenthesis,
+        TokenKind::Identifier("primop".to_string()),
+        TokenKind::Operator(PrimitiveOp::Add),
+        TokenKind::LeftParenthesis,
+        TokenKind::Identifier("x".to_string()),
+        TokenKind::Real(1.5.into()),
+        TokenKind::RightParenthesis,
+        TokenKind::LeftParenthesis,
+        TokenKind::Identifier("w".to_string()),
+        TokenKind::RightParenthesis,
+        TokenKind::LeftParenthesis,
+        TokenKind::LeftParenthesis,
+        TokenKind::Identifier("app".to_string()),
+        TokenKind::Label("k".to_string()),
+        TokenKind::Identifier("w".to_string()),
+        TokenKind::String("a\n".to_string()),
+        TokenKind::RightParenthesis,
+        TokenKind::RightParenthesis,
+        TokenKind::RightParenthesis,
+      ]
+    );
+    assert_eq!(tokens[1].span, 1..7);
+    assert_eq!(tokens[5].span, 13..16);
+    assert_eq!(tokens[15].span, 40..45);
+  }
+
+  #[test]
+  fn numbers_operators_and_identifiers() {
+    assert_eq!(
+      kinds("-5 - +3 1e3 .5 x1 -x"),
+      vec![
+        TokenKind::Integer(-5),
+        TokenKind::Operator(PrimitiveOp::Subtract),
+        TokenKind::Integer(3),
+        TokenKind::Real(1000.0.into()),
+        TokenKind::Real(0.5.into()),
+        TokenKind::Identifier("x1".to_string()),
+        TokenKind::Identifier("-x".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn an_integer_literal_out_of_range_is_an_error() {
+    let too_big = format!("{}0", Integer::MAX);
+    let error   = tokenize(&format!("(app k {})", too_big)).unwrap_err();
+    assert_eq!(error.offset, 7);
+    assert_eq!(error.message, format!("integer literal `{}` out of range", too_big));
+    assert!(tokenize(&format!("-{}", too_big)).is_err());
+    assert_eq!(kinds(&Integer::MIN.to_string()), vec![TokenKind::Integer(Integer::MIN)]);
+  }
+
+  #[test]
+  fn the_non_finite_reals_display_prints_read_back() {
+    for x in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN].iter() {
+      let text = DValue::Real((*x).into()).to_string();
+      assert_eq!(kinds(&text), vec![TokenKind::Real((*x).into())], "{}", text);
+    }
+    assert_eq!(kinds("+inf"), vec![TokenKind::Real(f32::INFINITY.into())]);
+    assert_eq!(kinds("infinity"), vec![TokenKind::Identifier("infinity".to_string())]);
+  }
+
+  #[test]
+  fn malformed_tokens_are_errors() {
+    assert_eq!(tokenize("(app k \"abc").unwrap_err(), error(7, "unterminated string"));
+    assert_eq!(tokenize("\"a\\qb\"").unwrap_err(), error(2, "unknown escape sequence"));
+    assert_eq!(tokenize("(app @ x)").unwrap_err(), error(5, "expected a label name after `@`"));
+    assert_eq!(tokenize("1.2.3").unwrap_err().message, "malformed number `1.2.3`");
+  }
+}
@@ -0,0 +1,413 @@
+/*!
+
+  A parser for an S-expression syntax of CPS programs, after the constructors of \[Appel]:
+
+  ```text
+  (record (v (v 1) (v (sel 0 1))) w e)   ; fields with access paths OFFp 1 and SELp(0, OFFp 1)
+  (select i v w e)
+  (offset i v w e)
+  (app f a0 a1 ...)
+  (fix ((f (x1 x2 ...) body) ...) e)
+  (switch v e0 e1 ...)
+  (primop op (v0 v1 ...) (w0 w1 ...) (e0 e1 ...))
+  ```
+
+  A whole program, a `Module`, is read by `parse_module` from
+  `(module ((f (x1 x2 ...) body) ...) (p1 p2 ...) main)`: the top-level functions, the parameters
+  of `main`, and `main` itself.
+
+  An operation `op` is written as its token (see `PrimitiveOp::token`), e.g. `+`, `div`, or
+  `subscript`. A value is a variable `x`, a label `@f`, an integer `42`, a real `1.5` or `inf`,
+  `-inf`, or `NaN`, or a string `"text"` with the escapes `\"`, `\\`, `\n`, and `\t`. A `;`
+  starts a comment that extends to the end of the line. The source is split into tokens by
+  `lexer::tokenize`, and errors are reported with the byte offset at which they occur.
+
+  Parentheses may nest at most `MAX_NESTING` deep. Parsing, the passes over expressions, and the
+  evaluation of nested bindings all recurse on the nesting, so deeper sources are rejected rather
+  than allowed to overflow the stack.
+
+  `printer::print` and `printer::print_module` write expressions and modules back out in this
+  syntax.
+
+*/
+
+pub mod lexer;
+pub mod printer;
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::interpreter::{
+  FieldOffset,
+  Location,
+  Variable,
+  VariableList,
+  continuation_expression::{ContinuationExpression, FunctionDefinition},
+  cps::store::AccessPath,
+  module::Module,
+  value::{Value, ValueList}
+};
+use lexer::{tokenize, LexError, Token, TokenKind};
+
+/// The deepest that parentheses may nest in a source.
+pub const MAX_NESTING: usize = 256;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+  /// The byte offset into the source at which the error was found.
+  pub offset : usize,
+  pub message: String
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "at byte {}: {}", self.offset, self.message)
+  }
+}
+
+impl From<LexError> for ParseError {
+  fn from(error: LexError) -> Self {
+    ParseError { offset: error.offset, message: error.message }
+  }
+}
+
+/// Parses `source`, which must contain exactly one expression.
+pub fn parse(source: &str) -> Result<ContinuationExpression, ParseError> {
+  let tokens   = tokenize(source)?;
+  let mut next = 0;
+  let sexp     = read(&tokens, &mut next, source.len(), 0)?;
+  if let Some(token) = tokens.get(next) {
+    return Err(error(token.span.start, "unexpected text after the expression"));
+  }
+  expression(&sexp)
+}
+
+/// Parses `source`, which must contain exactly one module.
+pub fn parse_module(source: &str) -> Result<Module, ParseError> {
+  let tokens   = tokenize(source)?;
+  let mut next = 0;
+  let sexp     = read(&tokens, &mut next, source.len(), 0)?;
+  if let Some(token) = tokens.get(next) {
+    return Err(error(token.span.start, "unexpected text after the module"));
+  }
+  match sexp.list("a module")? {
+    [keyword, functions, parameters, main] if keyword.name() == Some("module") => {
+      Ok(
+        Module::new(
+          functions.list("function definitions")?
+                   .iter()
+                   .map(function_definition)
+                   .collect::<Result<_, _>>()?,
+          variables(parameters.list("parameters")?)?,
+          expression(main)?
+        )
+      )
+    }
+    _ => Err(error(sexp.offset(), "expected a module `(module (definitions...) (parameters...) main)`"))
+  }
+}
+
+fn error(offset: usize, message: &str) -> ParseError {
+  ParseError { offset, message: message.to_string() }
+}
+
+// region S-expressions
+
+/// An S-expression together with the byte offset at which it starts.
+enum SExp {
+  Atom(TokenKind, usize),
+  List(Vec<SExp>, usize)
+}
+
+impl SExp {
+  fn offset(&self) -> usize {
+    match self {
+      | SExp::Atom(_, offset)
+      | SExp::List(_, offset) => *offset
+    }
+  }
+
+  fn list(&self, what: &str) -> Result<&[SExp], ParseError> {
+    match self {
+      SExp::List(items, _) => Ok(items),
+      _                    => Err(error(self.offset(), &format!("expected a list of {}", what)))
+    }
+  }
+
+  /// The name of an identifier. The token of an operation is a name too, as in `(fix ((tag ...`.
+  fn name(&self) -> Option<&str> {
+    match self {
+      SExp::Atom(TokenKind::Identifier(name), _)    => Some(name),
+      SExp::Atom(TokenKind::Operator(operation), _) => Some(operation.token()),
+      _                                             => None
+    }
+  }
+}
+
+/// Reads the S-expression starting at `tokens[*position]`, advancing `position` past it. `depth`
+/// is the number of lists the S-expression is nested in.
+fn read(tokens: &[Token], position: &mut usize, end: usize, depth: usize) -> Result<SExp, ParseError> {
+  let token = tokens.get(*position).ok_or_else(|| error(end, "unexpected end of input"))?;
+  *position += 1;
+  match &token.kind {
+
+    TokenKind::LeftParenthesis if depth == MAX_NESTING => {
+      Err(error(token.span.start, &format!("parentheses nested more than {} deep", MAX_NESTING)))
+    }
+
+    TokenKind::LeftParenthesis => {
+      let mut items = Vec::new();
+      loop {
+        match tokens.get(*position).map(|token| &token.kind) {
+          Some(TokenKind::RightParenthesis) => {
+            *position += 1;
+            return Ok(SExp::List(items, token.span.start));
+          }
+          None => return Err(error(token.span.start, "unclosed parenthesis")),
+          _    => items.push(read(tokens, position, end, depth + 1)?)
+        }
+      }
+    }
+
+    TokenKind::RightParenthesis => Err(error(token.span.start, "unexpected closing parenthesis")),
+
+    kind => Ok(SExp::Atom(kind.clone(), token.span.start))
+
+  }
+}
+
+// endregion
+
+// region Expressions
+
+fn expression(sexp: &SExp) -> Result<ContinuationExpression, ParseError> {
+  let items = sexp.list("an expression")?;
+  let (keyword, operands) = match items.split_first() {
+    Some((SExp::Atom(TokenKind::Identifier(keyword), _), operands)) => (keyword.as_str(), operands),
+    _ => return Err(error(sexp.offset(), "expected an expression"))
+  };
+  let arity = | n: usize | {
+    if operands.len() == n {
+      Ok(())
+    } else {
+      Err(error(sexp.offset(), &format!("`{}` takes {} operands but was given {}", keyword, n, operands.len())))
+    }
+  };
+
+  match keyword {
+
+    "record" => {
+      arity(3)?;
+      let values = operands[0].list("fields")?
+                              .iter()
+                              .map(field)
+                              .collect::<Result<_, _>>()?;
+      Ok(
+        ContinuationExpression::Record {
+          values,
+          variable  : variable(&operands[1])?,
+          expression: Box::new(expression(&operands[2])?)
+        }
+      )
+    }
+
+    "select" | "offset" => {
+      arity(4)?;
+      let location   = field_offset(&operands[0])?;
+      let value      = value(&operands[1])?;
+      let variable   = variable(&operands[2])?;
+      let expression = Box::new(expression(&operands[3])?);
+      if keyword == "select" {
+        Ok(ContinuationExpression::Select { location, value, variable, expression })
+      } else {
+        Ok(ContinuationExpression::Offset { location, value, variable, expression })
+      }
+    }
+
+    "app" => {
+      let (function, arguments) = operands.split_first()
+                                          .ok_or_else(|| error(sexp.offset(), "`app` needs a function"))?;
+      Ok(
+        ContinuationExpression::Apply {
+          function : value(function)?,
+          arguments: values(arguments)?
+        }
+      )
+    }
+
+    "fix" => {
+      arity(2)?;
+      let function_defs = operands[0].list("function definitions")?
+                                     .iter()
+                                     .map(|definition| function_definition(definition).map(Rc::new))
+                                     .collect::<Result<_, _>>()?;
+      Ok(
+        ContinuationExpression::Fix {
+          function_defs: Rc::new(function_defs),
+          expression   : Box::new(expression(&operands[1])?)
+        }
+      )
+    }
+
+    "switch" => {
+      let (selector, arms) = operands.split_first()
+                                     .ok_or_else(|| error(sexp.offset(), "`switch` needs a value"))?;
+      Ok(
+        ContinuationExpression::Switch {
+          value: value(selector)?,
+          arms : arms.iter().map(|arm| expression(arm).map(Rc::new)).collect::<Result<_, _>>()?
+        }
+      )
+    }
+
+    "primop" => {
+      arity(4)?;
+      let operation = match &operands[0] {
+        SExp::Atom(TokenKind::Operator(operation), _) => *operation,
+        other => {
+          let message = match other.name() {
+            Some(name) => format!("unknown primitive operation `{}`", name),
+            None       => "expected a primitive operation".to_string()
+          };
+          return Err(error(other.offset(), &message));
+        }
+      };
+      Ok(
+        ContinuationExpression::PrimitiveOp {
+          operation,
+          values     : values(operands[1].list("arguments")?)?,
+          variables  : variables(operands[2].list("result variables")?)?,
+          expressions: operands[3].list("continuations")?
+                                  .iter()
+                                  .map(|e| expression(e).map(Box::new))
+                                  .collect::<Result<_, _>>()?
+        }
+      )
+    }
+
+    _ => Err(error(items[0].offset(), &format!("unknown expression `{}`", keyword)))
+
+  }
+}
+
+/// `(f (x1 x2 ...) body)`
+fn function_definition(sexp: &SExp) -> Result<FunctionDefinition, ParseError> {
+  match sexp.list("a function definition")? {
+    [name, formal_parameters, body] => {
+      Ok(
+        FunctionDefinition::new(
+          variable(name)?,
+          variables(formal_parameters.list("formal parameters")?)?,
+          expression(body)?
+        )
+      )
+    }
+    _ => Err(error(sexp.offset(), "expected a function definition `(name (parameters...) body)`"))
+  }
+}
+
+/// A record field: a value, or `(v path)` with an access path.
+fn field(sexp: &SExp) -> Result<(Value, Rc<AccessPath>), ParseError> {
+  match sexp {
+    SExp::List(items, offset) => {
+      match items.as_slice() {
+        [v, path] => Ok((value(v)?, Rc::new(access_path(path)?))),
+        _         => Err(error(*offset, "expected a field `(value path)`"))
+      }
+    }
+    _ => Ok((value(sexp)?, Rc::new(AccessPath::Offset(0))))
+  }
+}
+
+/// An offset `i`, or a selection `(sel i path)`.
+fn access_path(sexp: &SExp) -> Result<AccessPath, ParseError> {
+  match sexp {
+    SExp::List(items, offset) => {
+      match items.as_slice() {
+        [sel, i, path] if sel.name() == Some("sel") => {
+          Ok(AccessPath::Select { offset: location(i)?, access_path: Rc::new(access_path(path)?) })
+        }
+        _ => Err(error(*offset, "expected an access path `(sel i path)`"))
+      }
+    }
+    _ => Ok(AccessPath::Offset(location(sexp)?))
+  }
+}
+
+fn location(sexp: &SExp) -> Result<Location, ParseError> {
+  match sexp {
+    &SExp::Atom(TokenKind::Integer(i), offset) => Location::try_from(i).map_err(|_| error(offset, "expected a nonnegative integer")),
+    _                                          => Err(error(sexp.offset(), "expected a nonnegative integer"))
+  }
+}
+
+fn field_offset(sexp: &SExp) -> Result<FieldOffset, ParseError> {
+  match sexp {
+    &SExp::Atom(TokenKind::Integer(i), offset) => FieldOffset::try_from(i).map_err(|_| error(offset, "field offset out of range")),
+    _                                          => Err(error(sexp.offset(), "expected an integer field offset"))
+  }
+}
+
+// endregion
+
+// region Values
+
+fn value(sexp: &SExp) -> Result<Value, ParseError> {
+  if let Some(name) = sexp.name() {
+    return Ok(Value::Variable(Variable::new(name)));
+  }
+  match sexp {
+    SExp::Atom(TokenKind::Label(name), _) => Ok(Value::Label(Variable::new(name))),
+    &SExp::Atom(TokenKind::Integer(i), _) => Ok(Value::Integer(i)),
+    &SExp::Atom(TokenKind::Real(r), _)    => Ok(Value::Real(r)),
+    SExp::Atom(TokenKind::String(s), _)   => Ok(Value::String(s.clone())),
+    _                                     => Err(error(sexp.offset(), "expected a value"))
+  }
+}
+
+fn values(sexps: &[SExp]) -> Result<ValueList, ParseError> {
+  sexps.iter().map(value).collect()
+}
+
+fn variable(sexp: &SExp) -> Result<Variable, ParseError> {
+  match value(sexp)? {
+    Value::Variable(v) => Ok(v),
+    _                  => Err(error(sexp.offset(), "expected a variable"))
+  }
+}
+
+fn variables(sexps: &[SExp]) -> Result<VariableList, ParseError> {
+  sexps.iter().map(variable).collect()
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::tests::run_source;
+
+  /// A chain of `n` records, each nested in the previous one, ending in `(app halt 0)`: `n + 1`
+  /// levels of parentheses.
+  fn nested_records(n: usize) -> String {
+    format!("{}(app halt 0){}", "(record (1) r ".repeat(n), ")".repeat(n))
+  }
+
+  #[test]
+  fn parentheses_nested_too_deeply_are_an_error() {
+    let source = "(".repeat(100_000);
+    assert_eq!(
+      parse(&source),
+      Err(error(MAX_NESTING, &format!("parentheses nested more than {} deep", MAX_NESTING)))
+    );
+    assert!(parse_module(&source).is_err());
+    assert!(parse(&nested_records(MAX_NESTING)).is_err());
+  }
+
+  #[test]
+  fn a_program_nested_to_the_limit_parses_and_runs() {
+    let source = nested_records(MAX_NESTING - 1);
+    assert_eq!(format!("{:?}", run_source(&source)), "[0]");
+  }
+}
@@ -0,0 +1,240 @@
+/*!
+
+  Prints a `ContinuationExpression` or a `Module` in the S-expression syntax that `parse` and
+  `parse_module` read, so that a program can be saved as text and loaded again:
+  `parse(&print(&e)) == Ok(e)`.
+
+  Each continuation expression starts on its own line, indented one level deeper than the node it
+  belongs to. A label prints as `@f`, a record field with the trivial access path `OFFp 0` as its
+  bare value, and reals with a fraction or an exponent, or as `inf`, `-inf`, or `NaN`, so that
+  they read back as reals. Names print as they are, so a program round-trips as long as each of
+  its names reads back as an identifier, which is the case for every name `parse` produces and
+  every fresh variable.
+
+*/
+
+use std::fmt::Write;
+
+use crate::interpreter::{
+  Variable,
+  continuation_expression::{ContinuationExpression, FunctionDefinition},
+  cps::store::AccessPath,
+  module::Module,
+  value::Value
+};
+
+/// The number of spaces each level of nesting is indented by.
+const INDENT: usize = 2;
+
+/// Prints `expression` so that `parse` reads it back.
+pub fn print(expression: &ContinuationExpression) -> String {
+  let mut text = String::new();
+  write_expression(&mut text, expression, 0);
+  text
+}
+
+/// Prints `module` so that `parse_module` reads it back.
+pub fn print_module(module: &Module) -> String {
+  let mut text = String::from("(module (");
+  for (i, function_def) in module.functions.iter().enumerate() {
+    if i > 0 {
+      write!(text, "\n{:width$}", "", width = 9).unwrap();
+    }
+    write_definition(&mut text, function_def, 9);
+  }
+  write!(text, ") ({})", names(&module.parameters)).unwrap();
+  write_continuation(&mut text, &module.main, INDENT);
+  text.push(')');
+  text
+}
+
+/// Writes `expression`, whose first line is already indented to `indent`.
+fn write_expression(text: &mut String, expression: &ContinuationExpression, indent: usize) {
+  let inner = indent + INDENT;
+  match expression {
+
+    ContinuationExpression::Record { values, variable, expression } => {
+      let fields: Vec<String> = values.iter()
+                                      .map(|(value, access_path)| field(value, access_path))
+                                      .collect();
+      write!(text, "(record ({}) {}", fields.join(" "), variable.name()).unwrap();
+      write_continuation(text, expression, inner);
+      text.push(')');
+    }
+
+    ContinuationExpression::Select { location, value, variable, expression } => {
+      write!(text, "(select {} {} {}", location, atom(value), variable.name()).unwrap();
+      write_continuation(text, expression, inner);
+      text.push(')');
+    }
+
+    ContinuationExpression::Offset { location, value, variable, expression } => {
+      write!(text, "(offset {} {} {}", location, atom(value), variable.name()).unwrap();
+      write_continuation(text, expression, inner);
+      text.push(')');
+    }
+
+    ContinuationExpression::Apply { function, arguments } => {
+      write!(text, "(app {}", atom(function)).unwrap();
+      for argument in arguments {
+        write!(text, " {}", atom(argument)).unwrap();
+      }
+      text.push(')');
+    }
+
+    ContinuationExpression::Fix { function_defs, expression } => {
+      text.push_str("(fix (");
+      for (i, function_def) in function_defs.iter().enumerate() {
+        if i > 0 {
+          write!(text, "\n{:width$}", "", width = indent + 6).unwrap();
+        }
+        write_definition(text, function_def, indent + 6);
+      }
+      text.push(')');
+      write_continuation(text, expression, inner);
+      text.push(')');
+    }
+
+    ContinuationExpression::Switch { value, arms } => {
+      write!(text, "(switch {}", atom(value)).unwrap();
+      for arm in arms.iter() {
+        write_continuation(text, arm, inner);
+      }
+      text.push(')');
+    }
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+      let arguments: Vec<String> = values.iter().map(atom).collect();
+      write!(text, "(primop {} ({}) ({}) (", operation, arguments.join(" "), names(variables)).unwrap();
+      for continuation in expressions {
+        write_continuation(text, continuation, inner);
+      }
+      text.push_str("))");
+    }
+
+  }
+}
+
+/// Writes `(f (x1 x2 ...) body)`, whose first line is already indented to `indent`.
+fn write_definition(text: &mut String, function_def: &FunctionDefinition, indent: usize) {
+  let (name, formal_parameters) = function_def.header();
+  write!(text, "({} ({})", name.name(), names(formal_parameters)).unwrap();
+  write_continuation(text, function_def.body(), indent + INDENT);
+  text.push(')');
+}
+
+/// Writes `expression` on a new line indented to `indent`.
+fn write_continuation(text: &mut String, expression: &ContinuationExpression, indent: usize) {
+  write!(text, "\n{:width$}", "", width = indent).unwrap();
+  write_expression(text, expression, indent);
+}
+
+/// A value as the parser reads it. Unlike `Value`'s `Display`, strings are escaped with only the
+/// escapes the lexer knows.
+fn atom(value: &Value) -> String {
+  match value {
+    Value::String(s) => string(s),
+    value            => value.to_string()
+  }
+}
+
+fn string(s: &str) -> String {
+  let mut text = String::from('"');
+  for c in s.chars() {
+    match c {
+      '"'  => text.push_str("\\\""),
+      '\\' => text.push_str("\\\\"),
+      '\n' => text.push_str("\\n"),
+      '\t' => text.push_str("\\t"),
+      c    => text.push(c)
+    }
+  }
+  text.push('"');
+  text
+}
+
+/// A record field: the bare value if its access path is `OFFp 0`, and `(v path)` otherwise.
+fn field(value: &Value, access_path: &AccessPath) -> String {
+  match access_path {
+    AccessPath::Offset(0) => atom(value),
+    access_path           => format!("({} {})", atom(value), path(access_path))
+  }
+}
+
+fn path(access_path: &AccessPath) -> String {
+  match access_path {
+    AccessPath::Offset(i)                      => i.to_string(),
+    AccessPath::Select { offset, access_path } => format!("(sel {} {})", offset, path(access_path))
+  }
+}
+
+fn names(variables: &[Variable]) -> String {
+  variables.iter().map(|v| v.name()).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::{parser::{parse, parse_module}, reference::generate};
+
+  fn round_trips(source: &str) {
+    let expression = parse(source).unwrap();
+    let printed    = print(&expression);
+    assert_eq!(parse(&printed), Ok(expression), "{}", printed);
+  }
+
+  #[test]
+  fn every_construct_round_trips() {
+    round_trips(
+      "(fix ((f (x k) (primop + (x 1) (y) ((app k y))))
+             (g () (app @f 41 halt)))
+         (record (1 (r 1) (r (sel 0 (sel 2 1))) \"a \\\"quoted\\\"\\n\\tline\" 2.5 -0.0 1e-10 @f) w
+           (select -1 w v (offset 2 w u (switch v
+             (app halt u)
+             (primop < (u 3) () ((app g) (app halt -7)))
+             (primop mkarray (2 0) (a) ((primop update (a 0 1) () ((app halt a))))))))))"
+    );
+  }
+
+  #[test]
+  fn non_finite_reals_round_trip() {
+    round_trips("(app halt inf -inf NaN)");
+  }
+
+  #[test]
+  fn nested_expressions_are_indented() {
+    let expression = parse("(primop + (x 1) (y) ((switch y (app k 0) (app k 1))))").unwrap();
+    assert_eq!(print(&expression), "(primop + (x 1) (y) (\n  (switch y\n    (app k 0)\n    (app k 1))))");
+  }
+
+  #[test]
+  fn generated_programs_round_trip() {
+    for seed in 1..=100 {
+      let expression = generate(seed, 5);
+      assert_eq!(parse(&print(&expression)), Ok(expression.clone()), "seed {}", seed);
+    }
+  }
+
+  #[test]
+  fn fresh_variables_round_trip() {
+    let x = ContinuationExpression::fresh_variable("x");
+    let expression = ContinuationExpression::Apply {
+      function : Value::Label(x.clone()),
+      arguments: vec![Value::Variable(x)]
+    };
+    assert_eq!(parse(&print(&expression)), Ok(expression));
+  }
+
+  #[test]
+  fn a_module_round_trips() {
+    let source = "(module ((even (n k) (primop ieql (n 0) () ((app k 1) (primop - (n 1) (m) ((app odd m k))))))
+                           (odd (n k) (primop ieql (n 0) () ((app k 0) (primop - (n 1) (m) ((app even m k)))))))
+                          (n)
+                    (app even n halt))";
+    let module  = parse_module(source).unwrap();
+    let printed = print_module(&module);
+    let reread  = parse_module(&printed).unwrap();
+    assert_eq!(reread.parameters, module.parameters);
+    assert_eq!(reread.into_expression(), module.into_expression(), "{}", printed);
+  }
+}
@@ -0,0 +1,271 @@
+/*!
+
+  A persistent hash map, the hash array mapped trie of Bagwell. Cloning is O(1), and the clones
+  share their structure until one of them is written. Each level of the trie is indexed by
+  `BITS` bits of a key's hash, and a branch stores only the children it has, located by a bitmap,
+  so an insertion or removal copies O(log n) small nodes rather than the whole map.
+
+  Keys whose hashes are equal in all 64 bits share a leaf, which is searched linearly.
+
+  This is what lets every `Environment` bind a variable without copying the bindings of its
+  parent.
+
+*/
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/// The number of bits of a hash consumed by each level of the trie.
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+#[derive(Clone)]
+enum Node<K, V> {
+  Branch {
+    /// Bit `i` is set if the branch has a child for the hash chunk `i`.
+    bitmap  : u32,
+    /// The children, in the order of their bits.
+    children: Vec<Rc<Node<K, V>>>
+  },
+  Leaf {
+    hash   : u64,
+    entries: Vec<(K, V)>
+  }
+}
+
+#[derive(Clone)]
+pub struct PersistentMap<K, V> {
+  root: Rc<Node<K, V>>,
+  len : usize
+}
+
+fn hash_of<K: Hash>(key: &K) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  key.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// The bit of `hash` at the level whose chunk starts at `shift`.
+fn bit(hash: u64, shift: u32) -> u32 {
+  1 << ((hash >> shift) & MASK)
+}
+
+/// The position among the children of a branch with `bitmap` of the child for `bit`.
+fn position(bitmap: u32, bit: u32) -> usize {
+  (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> PersistentMap<K, V> {
+  pub fn new() -> Self {
+    PersistentMap {
+      root: Rc::new(Node::Branch { bitmap: 0, children: Vec::new() }),
+      len : 0
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn get(&self, key: &K) -> Option<&V> {
+    let hash      = hash_of(key);
+    let mut node  = self.root.as_ref();
+    let mut shift = 0;
+    loop {
+      match node {
+        Node::Branch { bitmap, children } => {
+          let bit = bit(hash, shift);
+          if bitmap & bit == 0 {
+            return None;
+          }
+          node   = &children[position(*bitmap, bit)];
+          shift += BITS;
+        }
+        Node::Leaf { hash: leaf_hash, entries } => {
+          if *leaf_hash != hash {
+            return None;
+          }
+          return entries.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+        }
+      }
+    }
+  }
+
+  pub fn contains_key(&self, key: &K) -> bool {
+    self.get(key).is_some()
+  }
+
+  /// Binds `key` to `value`, returning the value it was bound to before, if any.
+  pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+    let hash     = hash_of(&key);
+    let previous = insert(&mut self.root, 0, hash, key, value);
+    if previous.is_none() {
+      self.len += 1;
+    }
+    previous
+  }
+
+  /// Removes the binding of `key`, returning the value it was bound to, if any. A map without
+  /// such a binding is not copied.
+  pub fn remove(&mut self, key: &K) -> Option<V> {
+    if !self.contains_key(key) {
+      return None;
+    }
+    let removed = remove(&mut self.root, 0, hash_of(key), key);
+    if removed.is_some() {
+      self.len -= 1;
+    }
+    removed
+  }
+}
+
+fn insert<K: Clone + Eq, V: Clone>(node: &mut Rc<Node<K, V>>, shift: u32, hash: u64, key: K, value: V) -> Option<V> {
+  let node = Rc::make_mut(node);
+
+  // A leaf in the way of a key with a different hash is pushed down into a new branch, which the
+  // key is then inserted into.
+  if let Node::Leaf { hash: leaf_hash, .. } = node {
+    if *leaf_hash != hash {
+      let bitmap = bit(*leaf_hash, shift);
+      let leaf   = std::mem::replace(node, Node::Branch { bitmap, children: Vec::new() });
+      if let Node::Branch { children, .. } = node {
+        children.push(Rc::new(leaf));
+      }
+    }
+  }
+
+  match node {
+    Node::Branch { bitmap, children } => {
+      let bit      = bit(hash, shift);
+      let position = position(*bitmap, bit);
+      if *bitmap & bit == 0 {
+        *bitmap |= bit;
+        children.insert(position, Rc::new(Node::Leaf { hash, entries: vec![(key, value)] }));
+        None
+      } else {
+        insert(&mut children[position], shift + BITS, hash, key, value)
+      }
+    }
+    Node::Leaf { entries, .. } => {
+      match entries.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, v)) => Some(std::mem::replace(v, value)),
+        None         => {
+          entries.push((key, value));
+          None
+        }
+      }
+    }
+  }
+}
+
+/// Removes `key` below `node`, dropping the leaves and branches left empty.
+fn remove<K: Clone + Eq, V: Clone>(node: &mut Rc<Node<K, V>>, shift: u32, hash: u64, key: &K) -> Option<V> {
+  match Rc::make_mut(node) {
+    Node::Branch { bitmap, children } => {
+      let bit = bit(hash, shift);
+      if *bitmap & bit == 0 {
+        return None;
+      }
+      let position = position(*bitmap, bit);
+      let removed  = remove(&mut children[position], shift + BITS, hash, key);
+      let is_empty = match children[position].as_ref() {
+        Node::Branch { children, .. } => children.is_empty(),
+        Node::Leaf { entries, .. }    => entries.is_empty()
+      };
+      if is_empty {
+        *bitmap &= !bit;
+        children.remove(position);
+      }
+      removed
+    }
+    Node::Leaf { entries, .. } => {
+      let index = entries.iter().position(|(k, _)| k == key)?;
+      Some(entries.swap_remove(index).1)
+    }
+  }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Default for PersistentMap<K, V> {
+  fn default() -> Self {
+    PersistentMap::new()
+  }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Extend<(K, V)> for PersistentMap<K, V> {
+  fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iterator: T) {
+    for (key, value) in iterator {
+      self.insert(key, value);
+    }
+  }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> std::iter::FromIterator<(K, V)> for PersistentMap<K, V> {
+  fn from_iter<T: IntoIterator<Item = (K, V)>>(iterator: T) -> Self {
+    let mut map = PersistentMap::new();
+    map.extend(iterator);
+    map
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A key whose hash is its value modulo 4, so keys collide in all 64 bits of their hash.
+  #[derive(Clone, PartialEq, Eq, Debug)]
+  struct Colliding(u32);
+
+  impl Hash for Colliding {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+      (self.0 % 4).hash(state);
+    }
+  }
+
+  #[test]
+  fn insert_get_and_remove_over_many_keys() {
+    let mut map: PersistentMap<u32, u32> = (0..5000).map(|i| (i, i * 2)).collect();
+    assert_eq!(map.len(), 5000);
+    assert!((0..5000).all(|i| map.get(&i) == Some(&(i * 2))));
+    assert_eq!(map.get(&5000), None);
+
+    assert_eq!(map.insert(7, 0), Some(14));
+    assert_eq!(map.len(), 5000);
+    for i in (0..5000).step_by(2) {
+      assert_eq!(map.remove(&i), Some(if i == 7 { 0 } else { i * 2 }));
+    }
+    assert_eq!(map.remove(&0), None);
+    assert_eq!(map.len(), 2500);
+    assert!((0..5000).all(|i| map.contains_key(&i) == (i % 2 == 1)));
+  }
+
+  #[test]
+  fn a_write_leaves_clones_untouched() {
+    let original: PersistentMap<u32, &str> = (0..100).map(|i| (i, "old")).collect();
+    let mut copy = original.clone();
+    copy.insert(3, "new");
+    copy.insert(100, "added");
+    copy.remove(&4);
+
+    assert_eq!((copy.get(&3), copy.get(&4), copy.get(&100)), (Some(&"new"), None, Some(&"added")));
+    assert_eq!((original.get(&3), original.get(&4), original.get(&100)), (Some(&"old"), Some(&"old"), None));
+    assert_eq!((original.len(), copy.len()), (100, 100));
+  }
+
+  #[test]
+  fn keys_with_equal_hashes_share_a_leaf() {
+    let mut map: PersistentMap<Colliding, u32> = (0..20).map(|i| (Colliding(i), i)).collect();
+    assert_eq!(map.len(), 20);
+    assert!((0..20).all(|i| map.get(&Colliding(i)) == Some(&i)));
+
+    assert_eq!(map.remove(&Colliding(4)), Some(4));
+    assert_eq!(map.remove(&Colliding(4)), None);
+    assert_eq!(map.get(&Colliding(8)), Some(&8));
+    assert_eq!(map.insert(Colliding(8), 80), Some(8));
+    assert_eq!(map.len(), 19);
+  }
+}
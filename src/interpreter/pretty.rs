@@ -0,0 +1,218 @@
+/*!
+
+  Renders a `ContinuationExpression` in the syntax \[Appel] uses for CPS terms:
+
+  ```text
+  FIX([(f, [x, k],
+      PRIMOP(+, [x, 1], [y], [
+        APP(k, [y])]))],
+    APP(@f, [41, halt]))
+  ```
+
+  Each continuation expression starts on its own line, indented one level deeper than the node it
+  belongs to. Values and primitive operations are rendered inline by their `Display`
+  implementations: `x` for a variable, `@x` for a label, literals as themselves, and operations
+  by their tokens. A record field with an access path is written `v OFFp i` or
+  `v SELp(i, p)`, as in \[Appel].
+
+*/
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::interpreter::{
+  continuation_expression::ContinuationExpression,
+  coverage::{Coverage, NodeId},
+  cps::store::AccessPath,
+  listing::operands,
+  value::Value,
+  Variable
+};
+
+/// The number of spaces each level of nesting is indented by.
+const INDENT: usize = 2;
+
+impl Display for ContinuationExpression {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write_annotated(self, None, f)
+  }
+}
+
+/// The same as `Display`, so that expressions can be inspected with `{:?}`.
+impl fmt::Debug for ContinuationExpression {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    Display::fmt(self, f)
+  }
+}
+
+/// Writes `expression`, marking the nodes that are not in `coverage` with a leading `!` if
+/// `coverage` is given. See `coverage::annotate`.
+pub(crate) fn write_annotated(
+  expression: &ContinuationExpression,
+  coverage  : Option<&Coverage>,
+  f         : &mut Formatter<'_>
+) -> fmt::Result
+{
+  write_expression(expression, 0, &mut Marks { next: 0, coverage }, f)
+}
+
+/// Numbers the nodes in the order they are written, which is the pre-order of `coverage`.
+struct Marks<'c> {
+  next    : NodeId,
+  coverage: Option<&'c Coverage>
+}
+
+impl Marks<'_> {
+  /// The mark of the next node.
+  fn next(&mut self) -> &'static str {
+    let id = self.next;
+    self.next += 1;
+    match self.coverage {
+      Some(coverage) if !coverage.contains(&id) => "!",
+      _                                         => ""
+    }
+  }
+}
+
+/// Writes `expression`, whose first line is already indented to `indent`.
+fn write_expression(
+  expression: &ContinuationExpression,
+  indent    : usize,
+  marks     : &mut Marks<'_>,
+  f         : &mut Formatter<'_>
+) -> fmt::Result
+{
+  let inner = indent + INDENT;
+  write!(f, "{}", marks.next())?;
+  match expression {
+
+    ContinuationExpression::Record { values, variable, expression } => {
+      let fields: Vec<String> = values.iter()
+                                      .map(|(value, access_path)| field(value, access_path))
+                                      .collect();
+      write!(f, "RECORD([{}], {},", fields.join(", "), variable.name())?;
+      write_continuation(expression, inner, marks, f)?;
+      write!(f, ")")
+    }
+
+    ContinuationExpression::Select { location, value, variable, expression } => {
+      write!(f, "SELECT({}, {}, {},", location, value, variable.name())?;
+      write_continuation(expression, inner, marks, f)?;
+      write!(f, ")")
+    }
+
+    ContinuationExpression::Offset { location, value, variable, expression } => {
+      write!(f, "OFFSET({}, {}, {},", location, value, variable.name())?;
+      write_continuation(expression, inner, marks, f)?;
+      write!(f, ")")
+    }
+
+    ContinuationExpression::Apply { function, arguments } => {
+      write!(f, "APP({}, [{}])", function, operands(arguments))
+    }
+
+    ContinuationExpression::Fix { function_defs, expression } => {
+      write!(f, "FIX([")?;
+      for (i, function_def) in function_defs.iter().enumerate() {
+        if i > 0 {
+          write!(f, ",\n{:width$}", "", width = inner)?;
+        }
+        let (name, formal_parameters) = function_def.header();
+        write!(f, "({}, [{}],", name.name(), names(formal_parameters))?;
+        write_continuation(function_def.body(), inner + INDENT, marks, f)?;
+        write!(f, ")")?;
+      }
+      write!(f, "],")?;
+      write_continuation(expression, inner, marks, f)?;
+      write!(f, ")")
+    }
+
+    ContinuationExpression::Switch { value, arms } => {
+      write!(f, "SWITCH({}, [", value)?;
+      write_arms(arms.iter().map(|arm| arm.as_ref()), inner, marks, f)?;
+      write!(f, "])")
+    }
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+      write!(f, "PRIMOP({}, [{}], [{}], [", operation, operands(values), names(variables))?;
+      write_arms(expressions.iter().map(|expression| expression.as_ref()), inner, marks, f)?;
+      write!(f, "])")
+    }
+
+  }
+}
+
+/// Writes `expression` on a new line indented to `indent`.
+fn write_continuation(
+  expression: &ContinuationExpression,
+  indent    : usize,
+  marks     : &mut Marks<'_>,
+  f         : &mut Formatter<'_>
+) -> fmt::Result
+{
+  write!(f, "\n{:width$}", "", width = indent)?;
+  write_expression(expression, indent, marks, f)
+}
+
+/// Writes the comma-separated `arms`, each on a new line indented to `indent`.
+fn write_arms<'e>(
+  arms  : impl Iterator<Item = &'e ContinuationExpression>,
+  indent: usize,
+  marks : &mut Marks<'_>,
+  f     : &mut Formatter<'_>
+) -> fmt::Result
+{
+  for (i, arm) in arms.enumerate() {
+    if i > 0 {
+      write!(f, ",")?;
+    }
+    write_continuation(arm, indent, marks, f)?;
+  }
+  Ok(())
+}
+
+/// A record field: the value, followed by its access path unless the path is the trivial
+/// `OFFp 0`.
+fn field(value: &Value, access_path: &AccessPath) -> String {
+  match access_path {
+    AccessPath::Offset(0) => value.to_string(),
+    access_path           => format!("{} {}", value, path(access_path))
+  }
+}
+
+fn path(access_path: &AccessPath) -> String {
+  match access_path {
+    AccessPath::Offset(i)                      => format!("OFFp {}", i),
+    AccessPath::Select { offset, access_path } => format!("SELp({}, {})", offset, path(access_path))
+  }
+}
+
+fn names(variables: &[Variable]) -> String {
+  variables.iter().map(|v| v.name()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::interpreter::parser::parse;
+
+  #[test]
+  fn nested_expressions_are_indented_one_level_deeper() {
+    let expression = parse("(fix ((f (x k) (primop + (x 1) (y) ((app k y))))) (app @f 41 halt))").unwrap();
+    assert_eq!(
+      expression.to_string(),
+      "FIX([(f, [x, k],\n    PRIMOP(+, [x, 1], [y], [\n      APP(k, [y])]))],\n  APP(@f, [41, halt]))"
+    );
+  }
+
+  #[test]
+  fn fields_with_access_paths_and_literals() {
+    let expression = parse(
+      "(record (1 (r 1) (r (sel 0 2))) w
+         (select 0 w v (offset 1 w u (switch v (app halt u) (app halt \"s\" 2.5)))))"
+    ).unwrap();
+    assert_eq!(
+      expression.to_string(),
+      "RECORD([1, r OFFp 1, r SELp(0, OFFp 2)], w,\n  SELECT(0, w, v,\n    OFFSET(1, w, u,\n      \
+       SWITCH(v, [\n        APP(halt, [u]),\n        APP(halt, [\"s\", 2.5])]))))"
+    );
+  }
+}
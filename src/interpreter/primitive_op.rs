@@ -1,30 +1,43 @@
 #![allow(dead_code)]
 
 
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
 use std::rc::Rc;
 
 use ordered_float::OrderedFloat;
+use strum::{EnumIter, IntoEnumIterator};
 
 use crate::{
   interpreter::{
     cps::{
-      denotable_value::{DValue, EMPTY, ZERO},
+      denotable_value::{DValue, DValueList, EMPTY, ZERO},
       continuation::{Answer, ContinuationList, Parameters}
     },
-    exception::{Exception},
+    exception::Exception,
     Integer,
-    Location
+    IntegerList,
+    Location,
+    Real
   }
 };
 use crate::interpreter::cps::continuation::Continuation;
+use crate::interpreter::cps::observer::notify;
+use crate::interpreter::cps::store::Store;
+use crate::interpreter::context::{EqualityMode, EvalContext, FloatMode};
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, EnumIter)]
 pub enum PrimitiveOp {
   Multiply,     // *
   Add,          // +
   Subtract,     // -
   Divide,       // div
   Tilde,        // ~
+  /// Usage: `ieql a b`
+  ///
+  /// Equality of `DValue`s, see `DValue::eq`. On two reals it agrees with `feql`: `0.0` and
+  /// `-0.0` are equal, and so are two NaNs. `ineq` is its negation.
   IEqual,       // ieql
   INEqual,      // ineq
   Less,         // <
@@ -59,7 +72,7 @@ pub enum PrimitiveOp {
   /// type : `string -> int -> int`
   ///
   /// Returns the nth byte as its ASCII code (`DValue::Integer`)
-  OrdinalOf,
+  OrdinalOf,      // ordof
 
   /// Usage: `a := i`
   ///
@@ -76,6 +89,23 @@ pub enum PrimitiveOp {
   Store,          // store
   MakeRef,        // makeref
   MakeRefUnboxed, // makerefunboxed
+
+  /// Usage: `mkarray n v`
+  ///
+  /// type : `int -> 'a -> ['a]`
+  ///
+  /// Allocates an array of `n` consecutive cells, each initially `v`. Raises `IndexOutOfBounds`
+  /// if `n` is negative and `OutOfMemory` if growing the store by `n` cells would exceed
+  /// `EvalContext::max_store_size` or `store::MAX_STORE_CELLS`, without growing it.
+  MakeArray,      // mkarray
+
+  /// Usage: `mkarrayunboxed n i`
+  ///
+  /// type : `int -> int -> [int]`
+  ///
+  /// The integer analogue of `mkarray`: the cells are in the integer mapping, so the result is an
+  /// `UnboxedArray`. Raises as `mkarray` does.
+  MakeUnboxedArray, // mkarrayunboxed
   ArrayLength,    // alength
   StringLength,   // slength
   GetHandler,     // gethdlr
@@ -85,23 +115,474 @@ pub enum PrimitiveOp {
   FSubtract,      // fsub
   FMultiply,      // fmul
   FDivide,        // fdiv
+  /// Usage: `feql x y`
+  ///
+  /// Equality of reals as `OrderedFloat`s rather than IEEE 754 equality: `0.0` and `-0.0` are
+  /// equal, but, unlike in IEEE 754, every NaN is equal to every other NaN. `ieql` on reals makes
+  /// the same comparison, and `fneq` is the negation.
   FEqual,         // feql
   FNEqual,        // fneq
   FGreaterEqual,  // fge
   FGreater,       // fgt
   FLessEqual,     // fle
   FLess,          // flt
-  // RShift,         // rshift
-  // LShift,         // lshift
-  // OrBinary,       // orb
-  // AndBinary,      // andb
-  // XOrBinary,      // xorb
-  // NotBinary,      // notb
+
+  /// Usage: `fsign x`
+  ///
+  /// type : `real -> int`
+  ///
+  /// The sign of `x` as `-1`, `0`, or `1`, suitable as the scrutinee of a three-armed `Switch`
+  /// (after adding `1`). Both `0.0` and `-0.0` have sign `0`, and so does NaN.
+  FSign,          // fsign
+
+  /// Usage: `floor x`
+  ///
+  /// type : `real -> int`
+  ///
+  /// The greatest integer not greater than `x`. Raises `Overflow` if `x` is NaN, infinite, or
+  /// outside the range of `Integer`.
+  Floor,          // floor
+
+  /// Usage: `truncate x`
+  ///
+  /// type : `real -> int`
+  ///
+  /// `x` rounded toward zero. Raises `Overflow` under the same conditions as `floor`.
+  Truncate,       // truncate
+
+  /// Usage: `float i`
+  ///
+  /// type : `int -> real`
+  ///
+  /// The real nearest to `i`. Reals are single precision, so integers of magnitude above 2^24
+  /// are rounded to the nearest representable real, ties to even; for example, `Integer::MAX`
+  /// becomes 2^63 (or 2^31 with the `int32` feature). The conversion never fails.
+  Float,          // float
+
+  /// Usage: `fsqrt x`
+  ///
+  /// type : `real -> real`
+  ///
+  /// The square root of `x`. Raises `DomainError` if `x` is negative (`-0.0` is not), rather than
+  /// producing NaN. Honors `FloatMode::Trapping` like the other real operations.
+  FSqrt,          // fsqrt
+
+  /// Usage: `exntag e`
+  ///
+  /// type : `exn -> int`
+  ///
+  /// Reads the tag of an exception in its record encoding, as received by an exception handler.
+  /// See `Exception::as_record` for the tag of each exception. Raises `TypeMismatch`, as `gettag`
+  /// does, if the record has no integer where the tag should be.
+  ExceptionTag,   // exntag
+
+  /// Usage: `raise v`
+  ///
+  /// type : `'a -> 'b`
+  ///
+  /// Raises a user exception carrying the value `v`, applying the current exception handler to
+  /// `Exception::user_record(v)`. Takes no continuation, as control passes to the handler.
+  Raise,          // raise
+
+  /// Usage: `pack a`
+  ///
+  /// type : `[int] -> uarray`
+  ///
+  /// Copies a boxed `Array` of integers into a freshly allocated `UnboxedArray`. Raises
+  /// `TypeMismatch` if any element is not an integer.
+  Pack,           // pack
+
+  /// Usage: `unpack a`
+  ///
+  /// type : `uarray -> [int]`
+  ///
+  /// Copies an `UnboxedArray` into a freshly allocated boxed `Array` of integers.
+  Unpack,         // unpack
+
+  /// Usage: `mkweak a`
+  ///
+  /// type : `['a] -> weak 'a`
+  ///
+  /// Makes a weak reference to the array `a`. The reference does not keep `a` alive across
+  /// `Store::compact`.
+  MakeWeak,       // mkweak
+
+  /// Usage: `deweak w`
+  ///
+  /// type : `weak 'a -> ['a]`
+  ///
+  /// Passes the array that `w` refers to, or the unboxed sentinel `0` if the array has been
+  /// collected. Use `boxed` to tell the two apart.
+  DerefWeak,      // deweak
+
+  /// Usage: `aeql a b`
+  ///
+  /// type : `['a] -> ['a] -> bool`
+  ///
+  /// Compares the contents of two arrays of the same kind elementwise, as opposed to `ieql`,
+  /// which compares their identity. Arrays of different lengths are unequal.
+  ArrayEqual,     // aeql
+
+  /// Usage: `beql a b`
+  ///
+  /// type : `bytearray -> bytearray -> bool`
+  ///
+  /// Compares the contents of two byte arrays. Byte arrays of different lengths are unequal
+  /// without reading the store.
+  BytesEqual,     // beql
+
+  /// Usage: `seql a b`
+  ///
+  /// type : `string -> string -> bool`
+  ///
+  /// Compares the contents of two strings, as opposed to `ieql`, which is only permitted to
+  /// compare them conservatively (see `DValue::eq`).
+  SEqual,         // seql
+
+  /// Usage: `spawn t`
+  ///
+  /// type : `(cont -> 'a) -> unit`
+  ///
+  /// Adds the function `t` as a new task to the back of the cooperative scheduler's queue and
+  /// continues with the current task. When the task is run, `t` is given a single argument, a
+  /// continuation that the task applies (to no arguments) when it is finished, which runs the next
+  /// queued task.
+  Spawn,          // spawn
+
+  /// Usage: `yield`
+  ///
+  /// type : `unit -> unit`
+  ///
+  /// Suspends the current task at the back of the scheduler's queue and runs the task at the
+  /// front. Tasks are thus scheduled round-robin. If there are no other tasks, the current task
+  /// simply continues.
+  Yield,          // yield
+
+  /// Usage: `mkcyclic n b`
+  ///
+  /// type : `int -> (['a] -> (['a] -> unit) -> unit) -> ['a]`
+  ///
+  /// Records are pure values and so cannot refer to themselves, but arrays live in the store and
+  /// can. `mkcyclic` allocates an array of `n` cells (each initially `0`) and applies the builder
+  /// `b` to the array and a continuation. The builder fills in the cells, storing the array
+  /// itself (or arrays that refer back to it) wherever the structure is cyclic, and is done when
+  /// it applies the continuation to no arguments, which passes the array on. `Store::compact`
+  /// traces cyclic arrays without looping. Raises as `mkarray` does.
+  MakeCyclic,     // mkcyclic
+
+  /// Usage: `akind a`
+  ///
+  /// type : `'a -> int`
+  ///
+  /// The representation of the array `a`: `0` for a boxed `Array`, `1` for an `UnboxedArray`,
+  /// and `2` for a `ByteArray`, so that code generic over arrays can choose the matching
+  /// read/write operations with a `Switch`. Raises `TypeMismatch` if `a` is not an array.
+  ArrayKind,      // akind
+
+  /// Usage: `itos i`
+  ///
+  /// type : `int -> string`
+  ///
+  /// The decimal representation of `i`, with a leading `-` if `i` is negative.
+  IntToString,    // itos
+
+  /// Usage: `rtos x`
+  ///
+  /// type : `real -> string`
+  ///
+  /// The shortest decimal representation of `x` that reads back as the same real, without an
+  /// exponent and without a fractional part if `x` is integral (`1.5`, `-3`, `0.001`). The
+  /// non-finite reals are `inf`, `-inf`, and `NaN`.
+  RealToString,   // rtos
+
+  /// Usage: `stoi s`
+  ///
+  /// type : `string -> (int -> 'a) -> (unit -> 'a) -> 'a`
+  ///
+  /// Parses `s` as a base-10 integer with an optional leading `+` or `-`. On success the first
+  /// continuation receives the integer. Otherwise, including when `s` has surrounding whitespace
+  /// or the integer does not fit in an `Integer`, the second continuation is taken with no
+  /// arguments.
+  StringToInt,    // stoi
+
+  /// Usage: `sconcat a b`
+  ///
+  /// type : `string -> string -> string`
+  ///
+  /// The string of the bytes of `a` followed by the bytes of `b`. Strings are immutable pure
+  /// values, so the result is a fresh string and neither argument is modified.
+  StringConcat,   // sconcat
+
+  /// Usage: `substring s start len`
+  ///
+  /// type : `string -> int -> int -> string`
+  ///
+  /// The `len` bytes of `s` beginning at byte `start`, as a fresh string. Raises
+  /// `IndexOutOfBounds` if either integer is negative, if the slice extends past the end of `s`,
+  /// or if either end of the slice falls inside a multi-byte character.
+  Substring,      // substring
+
+  /// Usage: `tag t v`
+  ///
+  /// type : `int -> 'a -> tagged`
+  ///
+  /// Tags the payload `v` with the constructor index `t`, producing the record
+  /// `Record { values: [Integer(t), v], idx: 0 }`. A `Switch` on `gettag` of the record then
+  /// dispatches on the constructor. The representation is the same as the record encoding of
+  /// exceptions (see `Exception::as_record`), whose payload is the exception's name, so
+  /// `gettag` also reads the tag of an exception record.
+  Tag,            // tag
+
+  /// Usage: `gettag r`
+  ///
+  /// type : `tagged -> int`
+  ///
+  /// The constructor index of a tagged record. Raises `TypeMismatch` if `r` is not a record
+  /// whose first field is an integer.
+  GetTag,         // gettag
+
+  /// Usage: `getpayload r`
+  ///
+  /// type : `tagged -> 'a`
+  ///
+  /// The payload of a tagged record. Raises `TypeMismatch` if `r` is not a record with a second
+  /// field.
+  GetPayload,     // getpayload
+
+  /// Usage: `rshift i j`
+  ///
+  /// type : `int -> int -> int`
+  ///
+  /// Arithmetic right shift of `i` by `j` bits, so the sign of `i` is preserved. Raises
+  /// `Overflow` if `j` is negative or not less than the bit width of `Integer`.
+  RShift,         // rshift
+
+  /// Usage: `lshift i j`
+  ///
+  /// type : `int -> int -> int`
+  ///
+  /// Left shift of `i` by `j` bits. Bits shifted out are lost without raising `Overflow`, but, as
+  /// for `rshift`, `j` must be in `0..Integer::BITS` or `Overflow` is raised.
+  LShift,         // lshift
+  OrBinary,       // orb
+  AndBinary,      // andb
+  XOrBinary,      // xorb
+  NotBinary,      // notb
+}
+
+impl Display for PrimitiveOp {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.token())
+  }
 }
 
 impl PrimitiveOp{
+  /// The operation's token in the source syntax, e.g. `+`, `div`, or `subscript`. This is the
+  /// one mapping between operations and their tokens: `Display` renders it and `from_token`
+  /// reads it back.
+  pub fn token(self) -> &'static str {
+    match self {
+      PrimitiveOp::Multiply         => "*",
+      PrimitiveOp::Add              => "+",
+      PrimitiveOp::Subtract         => "-",
+      PrimitiveOp::Divide           => "div",
+      PrimitiveOp::Tilde            => "~",
+      PrimitiveOp::IEqual           => "ieql",
+      PrimitiveOp::INEqual          => "ineq",
+      PrimitiveOp::Less             => "<",
+      PrimitiveOp::LessEqual        => "<=",
+      PrimitiveOp::Greater          => ">",
+      PrimitiveOp::GreaterEqual     => ">=",
+      PrimitiveOp::RangeCheck       => "rangechk",
+      PrimitiveOp::Bang             => "!",
+      PrimitiveOp::Subscript        => "subscript",
+      PrimitiveOp::OrdinalOf        => "ordof",
+      PrimitiveOp::ColonEqual       => ":=",
+      PrimitiveOp::UnboxedAssign    => "unboxedassign",
+      PrimitiveOp::Update           => "update",
+      PrimitiveOp::UnboxedUpdate    => "unboxedupdate",
+      PrimitiveOp::Store            => "store",
+      PrimitiveOp::MakeRef          => "makeref",
+      PrimitiveOp::MakeRefUnboxed   => "makerefunboxed",
+      PrimitiveOp::MakeArray        => "mkarray",
+      PrimitiveOp::MakeUnboxedArray => "mkarrayunboxed",
+      PrimitiveOp::ArrayLength      => "alength",
+      PrimitiveOp::StringLength     => "slength",
+      PrimitiveOp::GetHandler       => "gethdlr",
+      PrimitiveOp::SetHandler       => "sethdlr",
+      PrimitiveOp::Boxed            => "boxed",
+      PrimitiveOp::FAdd             => "fadd",
+      PrimitiveOp::FSubtract        => "fsub",
+      PrimitiveOp::FMultiply        => "fmul",
+      PrimitiveOp::FDivide          => "fdiv",
+      PrimitiveOp::FEqual           => "feql",
+      PrimitiveOp::FNEqual          => "fneq",
+      PrimitiveOp::FGreaterEqual    => "fge",
+      PrimitiveOp::FGreater         => "fgt",
+      PrimitiveOp::FLessEqual       => "fle",
+      PrimitiveOp::FLess            => "flt",
+      PrimitiveOp::FSign            => "fsign",
+      PrimitiveOp::Floor            => "floor",
+      PrimitiveOp::Truncate         => "truncate",
+      PrimitiveOp::Float            => "float",
+      PrimitiveOp::FSqrt            => "fsqrt",
+      PrimitiveOp::ExceptionTag     => "exntag",
+      PrimitiveOp::Raise            => "raise",
+      PrimitiveOp::Pack             => "pack",
+      PrimitiveOp::Unpack           => "unpack",
+      PrimitiveOp::MakeWeak         => "mkweak",
+      PrimitiveOp::DerefWeak        => "deweak",
+      PrimitiveOp::ArrayEqual       => "aeql",
+      PrimitiveOp::BytesEqual       => "beql",
+      PrimitiveOp::SEqual           => "seql",
+      PrimitiveOp::Spawn            => "spawn",
+      PrimitiveOp::Yield            => "yield",
+      PrimitiveOp::MakeCyclic       => "mkcyclic",
+      PrimitiveOp::ArrayKind        => "akind",
+      PrimitiveOp::IntToString      => "itos",
+      PrimitiveOp::RealToString     => "rtos",
+      PrimitiveOp::StringToInt      => "stoi",
+      PrimitiveOp::StringConcat     => "sconcat",
+      PrimitiveOp::Substring        => "substring",
+      PrimitiveOp::Tag              => "tag",
+      PrimitiveOp::GetTag           => "gettag",
+      PrimitiveOp::GetPayload       => "getpayload",
+      PrimitiveOp::RShift           => "rshift",
+      PrimitiveOp::LShift           => "lshift",
+      PrimitiveOp::OrBinary         => "orb",
+      PrimitiveOp::AndBinary        => "andb",
+      PrimitiveOp::XOrBinary        => "xorb",
+      PrimitiveOp::NotBinary        => "notb",
+    }
+  }
+
+  /// The operation whose token is `token`. See `token`.
+  pub fn from_token(token: &str) -> Option<PrimitiveOp> {
+    PrimitiveOp::iter().find(|operation| operation.token() == token)
+  }
+
+  /// Whether an application of the operation whose results are unused can be dropped: the
+  /// operation neither reads nor writes the store, does not allocate, and raises no exception on
+  /// arguments of the types it expects. Arithmetic that can overflow is therefore not pure.
+  pub fn is_pure(self) -> bool {
+    matches!(
+      self,
+      | PrimitiveOp::FSign
+      | PrimitiveOp::Float
+      | PrimitiveOp::ExceptionTag
+      | PrimitiveOp::ArrayLength
+      | PrimitiveOp::StringLength
+      | PrimitiveOp::ArrayKind
+      | PrimitiveOp::IntToString
+      | PrimitiveOp::RealToString
+      | PrimitiveOp::StringConcat
+      | PrimitiveOp::Tag
+      | PrimitiveOp::OrBinary
+      | PrimitiveOp::AndBinary
+      | PrimitiveOp::XOrBinary
+      | PrimitiveOp::NotBinary
+    )
+  }
+
+  /// Whether the operation is a test that selects one of two continuations, a "true" continuation
+  /// followed by a "false" continuation.
+  pub fn is_branching(self) -> bool {
+    self.arity().1 == 2
+  }
+
+  /// The number of arguments and the number of continuations the operation takes.
+  pub fn arity(self) -> (usize, usize) {
+    match self {
+      | PrimitiveOp::GetHandler
+      | PrimitiveOp::Yield => (0, 1),
+
+      | PrimitiveOp::Raise => (1, 0),
+
+      | PrimitiveOp::Tilde
+      | PrimitiveOp::Bang
+      | PrimitiveOp::MakeRef
+      | PrimitiveOp::MakeRefUnboxed
+      | PrimitiveOp::ArrayLength
+      | PrimitiveOp::StringLength
+      | PrimitiveOp::SetHandler
+      | PrimitiveOp::FSign
+      | PrimitiveOp::Floor
+      | PrimitiveOp::Truncate
+      | PrimitiveOp::Float
+      | PrimitiveOp::FSqrt
+      | PrimitiveOp::ExceptionTag
+      | PrimitiveOp::Pack
+      | PrimitiveOp::Unpack
+      | PrimitiveOp::MakeWeak
+      | PrimitiveOp::DerefWeak
+      | PrimitiveOp::Spawn
+      | PrimitiveOp::ArrayKind
+      | PrimitiveOp::IntToString
+      | PrimitiveOp::RealToString
+      | PrimitiveOp::GetTag
+      | PrimitiveOp::GetPayload
+      | PrimitiveOp::NotBinary => (1, 1),
+
+      | PrimitiveOp::Boxed
+      | PrimitiveOp::StringToInt => (1, 2),
+
+      | PrimitiveOp::Multiply
+      | PrimitiveOp::Add
+      | PrimitiveOp::Subtract
+      | PrimitiveOp::Divide
+      | PrimitiveOp::Subscript
+      | PrimitiveOp::OrdinalOf
+      | PrimitiveOp::ColonEqual
+      | PrimitiveOp::UnboxedAssign
+      | PrimitiveOp::FAdd
+      | PrimitiveOp::FSubtract
+      | PrimitiveOp::FMultiply
+      | PrimitiveOp::FDivide
+      | PrimitiveOp::MakeCyclic
+      | PrimitiveOp::MakeArray
+      | PrimitiveOp::MakeUnboxedArray
+      | PrimitiveOp::Tag
+      | PrimitiveOp::StringConcat
+      | PrimitiveOp::RShift
+      | PrimitiveOp::LShift
+      | PrimitiveOp::OrBinary
+      | PrimitiveOp::AndBinary
+      | PrimitiveOp::XOrBinary => (2, 1),
+
+      | PrimitiveOp::IEqual
+      | PrimitiveOp::INEqual
+      | PrimitiveOp::Less
+      | PrimitiveOp::LessEqual
+      | PrimitiveOp::Greater
+      | PrimitiveOp::GreaterEqual
+      | PrimitiveOp::RangeCheck
+      | PrimitiveOp::FEqual
+      | PrimitiveOp::FNEqual
+      | PrimitiveOp::FGreaterEqual
+      | PrimitiveOp::FGreater
+      | PrimitiveOp::FLessEqual
+      | PrimitiveOp::FLess
+      | PrimitiveOp::ArrayEqual
+      | PrimitiveOp::BytesEqual
+      | PrimitiveOp::SEqual => (2, 2),
+
+      | PrimitiveOp::Update
+      | PrimitiveOp::UnboxedUpdate
+      | PrimitiveOp::Store
+      | PrimitiveOp::Substring => (3, 1),
+    }
+  }
+
   pub fn evaluate(self, mut parameters: Parameters, mut continuation_list: ContinuationList) -> Answer{
     let continuation_count = continuation_list.len();
+    EvalContext::record_primitive_op(self);
+
+    // A wrong number of arguments or continuations raises `Arity` here, so that the arms below
+    // only fail to match because of the kinds of the arguments.
+    let (argument_count, expected_continuation_count) = self.arity();
+    if parameters.len() != argument_count || continuation_count != expected_continuation_count {
+      return Exception::Arity.as_answer();
+    }
 
     // Have to work around inability to destructure ContinuationList.
     match (self, parameters.as_mut_slice(), continuation_count) {
@@ -173,7 +654,7 @@ impl PrimitiveOp{
         1
       ) =>  {
               let c = continuation_list.pop().unwrap();
-              if let Some(k) = 0i64.checked_sub(*i){
+              if let Some(k) = (0 as Integer).checked_sub(*i){
                 c(vec![DValue::Integer(k)])
               } else {
                 Exception::Overflow.as_answer()
@@ -181,6 +662,39 @@ impl PrimitiveOp{
             },
 
 
+      // Reals compare as they do under `feql`, whatever the equality mode.
+      (PrimitiveOp::IEqual, [DValue::Real(a), DValue::Real(b)], 2)
+      | (PrimitiveOp::INEqual, [DValue::Real(a), DValue::Real(b)], 2) => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        if reals_equal(*a, *b) != (self == PrimitiveOp::INEqual) {
+          t(EMPTY)
+        } else {
+          f(EMPTY)
+        }
+      },
+
+      (PrimitiveOp::IEqual, [a, b], 2)
+      | (PrimitiveOp::INEqual, [a, b], 2)
+      if EvalContext::with_current(|context| context.equality_mode) == EqualityMode::StructuralDeep
+      => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        let (a, b) = (a.clone(), b.clone());
+        let negate = self == PrimitiveOp::INEqual;
+        // Array contents live in the store.
+        Answer{
+          f: Rc::new(move | _, store | {
+            if a.structurally_equal(&b, store) != negate {
+              (t.f)(&EMPTY, store)
+            } else {
+              (f.f)(&EMPTY, store)
+            }
+          }),
+          parameters: EMPTY
+        }
+      },
+
       (PrimitiveOp::IEqual, [a, b], 2) => {
         let f = continuation_list.pop().unwrap();
         let t = continuation_list.pop().unwrap();
@@ -293,8 +807,10 @@ impl PrimitiveOp{
         1
       ) => {
         let continuation: Continuation = continuation_list.pop().unwrap();
-        let range = array_range.clone();
-        let m  = *n;
+        let location = match element_location(array_range, *n) {
+          Some(location) => location,
+          None           => return Exception::IndexOutOfBounds.as_answer()
+        };
         // The `Subscript` operation requires that we fetch a value from the store. However, we
         // do not have access to a `Store` at this point. The solution is to construct a closure
         // that fetches the right value when given a store, and wrap that closure into an answer.
@@ -302,8 +818,10 @@ impl PrimitiveOp{
           // We capture the needed parameters instead of packing and unpacking the `Answer`'s
           // parameters member.
           f: Rc::new(move | _, store | {
-            let i = store.fetch(range.start + m as usize);
-            (continuation.f)(&vec![i.clone()], store)
+            match store.fetch(location) {
+              Ok(i)          => (continuation.f)(&vec![i.clone()], store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -315,8 +833,10 @@ impl PrimitiveOp{
         1
       ) => {
         let continuation: Continuation = continuation_list.pop().unwrap();
-        let range = array_range.clone();
-        let m  = *n;
+        let location = match element_location(array_range, *n) {
+          Some(location) => location,
+          None           => return Exception::IndexOutOfBounds.as_answer()
+        };
         // The `Subscript` operation requires that we fetch a value from the store. However, we
         // do not have access to a `Store` at this point. The solution is to construct a closure
         // that fetches the right value when given a store, and wrap that closure into an answer.
@@ -324,8 +844,10 @@ impl PrimitiveOp{
           // We capture the needed parameters instead of packing and unpacking the `Answer`'s
           // parameters member.
           f: Rc::new(move | _, store | {
-            let i = store.fetch_integer(range.start + m as usize);
-            (continuation.f)(&vec![i], store)
+            match store.fetch_integer(location) {
+              Ok(i)          => (continuation.f)(&vec![i], store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -333,11 +855,17 @@ impl PrimitiveOp{
 
       (
         PrimitiveOp::Subscript,
-        [DValue::Record { values, idx: i }, DValue::Integer(j)],
+        [record @ DValue::Record { .. }, DValue::Integer(j)],
         1
       ) => {
-        let c: Continuation = continuation_list.pop().unwrap();
-        c(vec![values[*i + *j as usize].clone()])
+        // A negative `j` must not wrap around to a large index.
+        match usize::try_from(*j).ok().and_then(|j| record.field(j)) {
+          Some(value) => {
+            let c: Continuation = continuation_list.pop().unwrap();
+            c(vec![value.clone()])
+          }
+          None => Exception::IndexOutOfBounds.as_answer()
+        }
       },
 
       (
@@ -345,8 +873,13 @@ impl PrimitiveOp{
         [DValue::String(a), DValue::Integer(i)],
         1
       ) => {
-        let c: Continuation = continuation_list.pop().unwrap();
-        c( vec![DValue::Integer(a.as_bytes()[*i as usize] as Integer)] )
+        match usize::try_from(*i).ok().and_then(|i| a.as_bytes().get(i)) {
+          Some(byte) => {
+            let c: Continuation = continuation_list.pop().unwrap();
+            c( vec![DValue::Integer(*byte as Integer)] )
+          }
+          None => Exception::IndexOutOfBounds.as_answer()
+        }
       },
 
       (
@@ -367,8 +900,10 @@ impl PrimitiveOp{
         1
       ) => {
         let continuation: Continuation = continuation_list.pop().unwrap();
-        let range = array_range.clone();
-        let m  = *n;
+        let location = match element_location(array_range, *n) {
+          Some(location) => location,
+          None           => return Exception::IndexOutOfBounds.as_answer()
+        };
         let v = value.clone();
         // The `Update` operation requires that we update a value in the store. However, we
         // do not have access to a `Store` at this point. The solution is to construct a closure
@@ -376,8 +911,10 @@ impl PrimitiveOp{
         Answer{
           // We capture the needed parameters instead of packing and unpacking.
           f: Rc::new(move | _, store | {
-            let new_store = store.update(range.start as Location + m as usize, v.clone());
-            (continuation.f)(&EMPTY, &new_store)
+            match store.update(location, v.clone()) {
+              Ok(new_store)  => (continuation.f)(&EMPTY, &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -390,19 +927,21 @@ impl PrimitiveOp{
         1
       ) => {
         let continuation = continuation_list.pop().unwrap();
-        let range = array_range.clone();
-        let m  = *n;
-        let v = value.clone();
+        let location = match element_location(array_range, *n) {
+          Some(location) => location,
+          None           => return Exception::IndexOutOfBounds.as_answer()
+        };
+        let v = *value;
         // The `Update` operation requires that we update a value in the store. However, we
         // do not have access to a `Store` at this point. The solution is to construct a closure
         // that updates the right value when given a store, and wrap that closure into an answer.
         Answer{
           // We capture the needed parameters instead of packing and unpacking.
           f: Rc::new(move | _, store | {
-
-            let new_store =
-                store.update_integer(range.start as Location + m as usize, v.clone());
-            (continuation.f)(&EMPTY, &new_store)
+            match store.update_integer(location, v) {
+              Ok(new_store)  => (continuation.f)(&EMPTY, &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -418,13 +957,17 @@ impl PrimitiveOp{
         1
       ) => {
         let continuation = continuation_list.pop().unwrap();
-        let range = array_range.clone();
-        let m  = *n;
+        let location = match element_location(array_range, *n) {
+          Some(location) => location,
+          None           => return Exception::IndexOutOfBounds.as_answer()
+        };
         let v = value.clone();
         Answer{
           f: Rc::new(move | _, store | {
-            let new_store = store.update(range.start + m as Location, v.clone());
-            (continuation.f)(&EMPTY, &new_store)
+            match store.update(location, v.clone()) {
+              Ok(new_store)  => (continuation.f)(&EMPTY, &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -440,14 +983,17 @@ impl PrimitiveOp{
         1
       ) => {
         let continuation = continuation_list.pop().unwrap();
-        let range = array_range.clone();
-        let m  = *n;
-        let v = value.clone();
+        let location = match element_location(array_range, *n) {
+          Some(location) => location,
+          None           => return Exception::IndexOutOfBounds.as_answer()
+        };
+        let v = *value;
         Answer{
           f: Rc::new(move | _, store | {
-            let new_store =
-                store.update_integer(range.start + m as Location, v.clone());
-            (continuation.f)(&EMPTY, &new_store)
+            match store.update_integer(location, v) {
+              Ok(new_store)  => (continuation.f)(&EMPTY, &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -463,14 +1009,17 @@ impl PrimitiveOp{
           Exception::Overflow.as_answer()
         } else {
           let continuation = continuation_list.pop().unwrap();
-          let range = array_range.clone();
-          let j = *i;
+          let location = match element_location(array_range, *i) {
+            Some(location) => location,
+            None           => return Exception::IndexOutOfBounds.as_answer()
+          };
           let u = *v;
           Answer{
             f: Rc::new(move | _, store | {
-              let new_store
-                  = store.update_integer(range.start + j as Location, u);
-              (continuation.f)(&EMPTY, &new_store)
+              match store.update_integer(location, u) {
+                Ok(new_store)  => (continuation.f)(&EMPTY, &new_store),
+                Err(exception) => store.raise_exception(exception)
+              }
             }),
             parameters: EMPTY
           }
@@ -483,12 +1032,18 @@ impl PrimitiveOp{
         Answer{
           f: Rc::new(move | _, store | {
             let last_address = store.next_unused_address;
-            let mut new_store =
-                store.update(last_address, v.clone());
-            new_store.next_unused_address = next_location(last_address);
-            // Todo: Should this be `DValue::Array(last_address..last_address+1)]` or
-            //       `DValue::Array(new_store.next_unused_address..new_store.next_unused_address+1)]`?
-            (continuation.f)(&vec![DValue::Array(last_address..last_address+1)], &new_store)
+            if let Err(exception) = Store::check_store_size(next_location(last_address)) {
+              return store.raise_exception(exception);
+            }
+            notify(|observer| observer.on_allocate(&(last_address..last_address + 1)));
+            // The cell is allocated before it is written, so that the write is within bounds.
+            let mut allocated = store.clone();
+            allocated.next_unused_address = next_location(last_address);
+            match allocated.update(last_address, v.clone()) {
+              // The new reference is the cell that was just written.
+              Ok(new_store)  => (continuation.f)(&vec![DValue::Array(last_address..last_address+1)], &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -500,12 +1055,62 @@ impl PrimitiveOp{
         Answer{
           f: Rc::new(move | _, store | {
             let last_address = store.next_unused_address;
-            let mut new_store =
-                store.update_integer(last_address, v);
-            new_store.next_unused_address = next_location(last_address);
-            // Todo: Should this be `DValue::Array(last_address..last_address+1)]` or
-            //       `DValue::Array(new_store.next_unused_address..new_store.next_unused_address+1)]`?
-            (continuation.f)(&vec![DValue::Array(last_address..last_address+1)], &new_store)
+            if let Err(exception) = Store::check_store_size(next_location(last_address)) {
+              return store.raise_exception(exception);
+            }
+            notify(|observer| observer.on_allocate(&(last_address..last_address + 1)));
+            let mut allocated = store.clone();
+            allocated.next_unused_address = next_location(last_address);
+            match allocated.update_integer(last_address, v) {
+              // The integer lives in the integer mapping, so the reference is unboxed, as in
+              // \[Appel].
+              Ok(new_store)  => (continuation.f)(&vec![DValue::UnboxedArray(last_address..last_address+1)], &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::MakeArray, [DValue::Integer(n), value], 1) => {
+        if *n < 0 {
+          return Exception::IndexOutOfBounds.as_answer();
+        }
+        let length = *n as usize;
+        let v = value.clone();
+        let continuation = continuation_list.pop().unwrap();
+        Answer{
+          f: Rc::new(move | _, store | {
+            let (new_store, range) = match store.allocate(length) {
+              Ok(allocation) => allocation,
+              Err(exception) => return store.raise_exception(exception)
+            };
+            match new_store.fill(range.clone(), &v) {
+              Ok(new_store)  => (continuation.f)(&vec![DValue::Array(range)], &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::MakeUnboxedArray, [DValue::Integer(n), DValue::Integer(value)], 1) => {
+        if *n < 0 {
+          return Exception::IndexOutOfBounds.as_answer();
+        }
+        let length = *n as usize;
+        let v = *value;
+        let continuation = continuation_list.pop().unwrap();
+        Answer{
+          f: Rc::new(move | _, store | {
+            let (new_store, range) = match store.allocate(length) {
+              Ok(allocation) => allocation,
+              Err(exception) => return store.raise_exception(exception)
+            };
+            match new_store.fill_integer(range.clone(), v) {
+              Ok(new_store)  => (continuation.f)(&vec![DValue::UnboxedArray(range)], &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -536,7 +1141,10 @@ impl PrimitiveOp{
         let continuation = continuation_list.pop().unwrap();
         Answer{
           f: Rc::new(move | _, store | {
-            (continuation.f)(&vec![store.fetch(store.exception_handler).clone()], store)
+            match store.fetch(store.exception_handler) {
+              Ok(handler)    => (continuation.f)(&vec![handler.clone()], store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -547,8 +1155,10 @@ impl PrimitiveOp{
         let handler = new_handler.clone();
         Answer{
           f: Rc::new(move | _, store | {
-            let new_store = store.update(store.exception_handler, handler.clone());
-            (continuation.f)(&EMPTY, &new_store)
+            match store.update(store.exception_handler, handler.clone()) {
+              Ok(new_store)  => (continuation.f)(&EMPTY, &new_store),
+              Err(exception) => store.raise_exception(exception)
+            }
           }),
           parameters: EMPTY
         }
@@ -589,48 +1199,46 @@ impl PrimitiveOp{
         t(EMPTY)
       },
 
+      (PrimitiveOp::Boxed, [DValue::WeakRef(_)], 2) => {
+        let t = continuation_list.remove(0);
+        t(EMPTY)
+      },
+
       (PrimitiveOp::Boxed, [DValue::Function(_)], 2) => {
         let t = continuation_list.remove(0);
         t(EMPTY)
       },
 
       (PrimitiveOp::FAdd, [DValue::Real(a), DValue::Real(b)], 1) => {
-        let c = continuation_list.pop().unwrap();
-        c(vec![DValue::Real(OrderedFloat(a.0 + b.0))])
-
-        // No overflow detection for reals.
-        // if let Some(k) = a.0.checked_add(b){
-        //   c(vec![DValue::Real(k)])
-        // } else {
-        //   Exception::Overflow.as_answer()
-        // }
+        match checked_real(&[*a, *b], a.0 + b.0) {
+          Ok(result)     => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![result])
+          }
+          Err(exception) => exception.as_answer()
+        }
       },
 
       (PrimitiveOp::FSubtract, [DValue::Real(a), DValue::Real(b)], 1) => {
-        let c = continuation_list.pop().unwrap();
-        c(vec![DValue::Real(OrderedFloat(a.0 - b.0))])
-
-        // No overflow detection for reals.
-        // if let Some(k) = a.0.checked_sub(b){
-        //   c(vec![DValue::Real(k)])
-        // } else {
-        //   Exception::Overflow.as_answer()
-        // }
+        match checked_real(&[*a, *b], a.0 - b.0) {
+          Ok(result)     => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![result])
+          }
+          Err(exception) => exception.as_answer()
+        }
       },
 
       (PrimitiveOp::FMultiply, [DValue::Real(a), DValue::Real(b)], 1) => {
-        let c = continuation_list.pop().unwrap();
-        c(vec![DValue::Real(OrderedFloat(a.0 * b.0))])
-
-        // No overflow detection for reals.
-        // if let Some(k) = a.0.checked_mul(b){
-        //   c(vec![DValue::Real(k)])
-        // } else {
-        //   Exception::Overflow.as_answer()
-        // }
+        match checked_real(&[*a, *b], a.0 * b.0) {
+          Ok(result)     => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![result])
+          }
+          Err(exception) => exception.as_answer()
+        }
       },
 
-      #[allow(illegal_floating_point_literal_pattern)]
       (
         PrimitiveOp::FDivide,
         [DValue::Real(_a), DValue::Real(OrderedFloat(0.0))],
@@ -644,22 +1252,20 @@ impl PrimitiveOp{
         [DValue::Real(a), DValue::Real(b)],
         1
       ) => {
-        let c = continuation_list.pop().unwrap();
-        c(vec![DValue::Real(OrderedFloat(a.0 / b.0))])
-
-        // No overflow detection for reals.
-        // if let Some(k) = a.0.checked_div(b){
-        //   c(vec![DValue::Real(k)])
-        // } else {
-        //   Exception::Overflow.as_answer()
-        // }
+        match checked_real(&[*a, *b], a.0 / b.0) {
+          Ok(result)     => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![result])
+          }
+          Err(exception) => exception.as_answer()
+        }
       },
 
 
       (PrimitiveOp::FEqual, [DValue::Real(a), DValue::Real(b)], 2) => {
         let f = continuation_list.pop().unwrap();
         let t = continuation_list.pop().unwrap();
-        if a == b {
+        if reals_equal(*a, *b) {
           t(EMPTY)
         } else {
           f(EMPTY)
@@ -669,7 +1275,7 @@ impl PrimitiveOp{
       (PrimitiveOp::FNEqual, [DValue::Real(a), DValue::Real(b)], 2) => {
         let f = continuation_list.pop().unwrap();
         let t = continuation_list.pop().unwrap();
-        if a != b {
+        if !reals_equal(*a, *b) {
           t(EMPTY)
         } else {
           f(EMPTY)
@@ -716,36 +1322,435 @@ impl PrimitiveOp{
         }
       },
 
-      _ => {
-        unreachable!()
-      }
+      (PrimitiveOp::FSign, [DValue::Real(a)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        let sign = if a.0 > 0.0 {
+          1
+        } else if a.0 < 0.0 {
+          -1
+        } else {
+          // Zero of either sign, or NaN.
+          0
+        };
+        c(vec![DValue::Integer(sign)])
+      },
+
+      (PrimitiveOp::Floor, [DValue::Real(x)], 1) => {
+        match real_to_integer(x.0.floor()) {
+          Some(i) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![DValue::Integer(i)])
+          }
+          None => Exception::Overflow.as_answer()
+        }
+      },
+
+      (PrimitiveOp::Truncate, [DValue::Real(x)], 1) => {
+        match real_to_integer(x.0.trunc()) {
+          Some(i) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![DValue::Integer(i)])
+          }
+          None => Exception::Overflow.as_answer()
+        }
+      },
+
+      (PrimitiveOp::Float, [DValue::Integer(i)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Real(OrderedFloat(*i as f32))])
+      },
+
+      (PrimitiveOp::FSqrt, [DValue::Real(x)], 1) => {
+        if x.0 < 0.0 {
+          return Exception::DomainError.as_answer();
+        }
+        match checked_real(&[*x], x.0.sqrt()) {
+          Ok(result)     => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![result])
+          }
+          Err(exception) => exception.as_answer()
+        }
+      },
+
+      (PrimitiveOp::ExceptionTag, [DValue::Record { values, idx }], 1) => {
+        if let Some(tag @ DValue::Integer(_)) = values.get(*idx) {
+          let c = continuation_list.pop().unwrap();
+          c(vec![tag.clone()])
+        } else {
+          Exception::TypeMismatch.as_answer()
+        }
+      },
+
+      (PrimitiveOp::ExceptionTag, [DValue::Exception(e)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(e.tag())])
+      },
+
+      (PrimitiveOp::Raise, [payload], 0) => {
+        let exception = Exception::user_record(payload.clone());
+        // The handler is read from the store when the exception is raised.
+        Answer{
+          f: Rc::new(move | _, store | store.raise(exception.clone())),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::Pack, [DValue::Array(array_range)], 1) => {
+        let continuation = continuation_list.pop().unwrap();
+        let range = array_range.clone();
+        // Both reading the source array and allocating the destination array require the store.
+        // See `Subscript`.
+        Answer{
+          f: Rc::new(move | _, store | {
+            let mut integers = IntegerList::with_capacity(range.len());
+            for location in range.clone() {
+              match store.fetch(location) {
+                Ok(DValue::Integer(i)) => integers.push(*i),
+                Ok(_)                  => return store.raise_exception(Exception::TypeMismatch),
+                Err(exception)         => return store.raise_exception(exception)
+              }
+            }
+
+            let (mut new_store, packed) = match store.allocate(integers.len()) {
+              Ok(allocation) => allocation,
+              Err(exception) => return store.raise_exception(exception)
+            };
+            for (location, i) in packed.clone().zip(integers) {
+              new_store = match new_store.update_integer(location, i) {
+                Ok(new_store)  => new_store,
+                Err(exception) => return store.raise_exception(exception)
+              };
+            }
+            (continuation.f)(&vec![DValue::UnboxedArray(packed)], &new_store)
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::Unpack, [DValue::UnboxedArray(array_range)], 1) => {
+        let continuation = continuation_list.pop().unwrap();
+        let range = array_range.clone();
+        Answer{
+          f: Rc::new(move | _, store | {
+            let mut integers = DValueList::with_capacity(range.len());
+            for location in range.clone() {
+              match store.fetch_integer(location) {
+                Ok(i)          => integers.push(i),
+                Err(exception) => return store.raise_exception(exception)
+              }
+            }
+
+            let (mut new_store, unpacked) = match store.allocate(integers.len()) {
+              Ok(allocation) => allocation,
+              Err(exception) => return store.raise_exception(exception)
+            };
+            for (location, i) in unpacked.clone().zip(integers) {
+              new_store = match new_store.update(location, i) {
+                Ok(new_store)  => new_store,
+                Err(exception) => return store.raise_exception(exception)
+              };
+            }
+            (continuation.f)(&vec![DValue::Array(unpacked)], &new_store)
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::MakeWeak, [DValue::Array(array_range)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::WeakRef(array_range.clone())])
+      },
+
+      (PrimitiveOp::DerefWeak, [DValue::WeakRef(array_range)], 1) => {
+        let continuation = continuation_list.pop().unwrap();
+        let range = array_range.clone();
+        // Whether the referent is still alive is a property of the store.
+        Answer{
+          f: Rc::new(move | _, store | {
+            if store.is_collected(&range) {
+              (continuation.f)(&vec![ZERO], store)
+            } else {
+              (continuation.f)(&vec![DValue::Array(range.clone())], store)
+            }
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::ArrayEqual, [DValue::Array(range_a), DValue::Array(range_b)], 2) => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        let (range_a, range_b) = (range_a.clone(), range_b.clone());
+        // The contents of the arrays live in the store.
+        Answer{
+          f: Rc::new(move | _, store | {
+            let mut equal = range_a.len() == range_b.len();
+            for (a, b) in range_a.clone().zip(range_b.clone()) {
+              if !equal {
+                break;
+              }
+              match (store.fetch(a), store.fetch(b)) {
+                (Ok(x), Ok(y)) => equal = x == y,
+                (Err(exception), _)
+                | (_, Err(exception)) => return store.raise_exception(exception)
+              }
+            }
+
+            if equal {
+              (t.f)(&EMPTY, store)
+            } else {
+              (f.f)(&EMPTY, store)
+            }
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (
+        PrimitiveOp::ArrayEqual,
+        [DValue::UnboxedArray(range_a), DValue::UnboxedArray(range_b)],
+        2
+      ) => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        let (range_a, range_b) = (range_a.clone(), range_b.clone());
+        Answer{
+          f: Rc::new(move | _, store | {
+            let mut equal = range_a.len() == range_b.len();
+            for (a, b) in range_a.clone().zip(range_b.clone()) {
+              if !equal {
+                break;
+              }
+              match (store.fetch_integer(a), store.fetch_integer(b)) {
+                (Ok(x), Ok(y)) => equal = x == y,
+                (Err(exception), _)
+                | (_, Err(exception)) => return store.raise_exception(exception)
+              }
+            }
+
+            if equal {
+              (t.f)(&EMPTY, store)
+            } else {
+              (f.f)(&EMPTY, store)
+            }
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::BytesEqual, [DValue::ByteArray(range_a), DValue::ByteArray(range_b)], 2) => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        if range_a.len() != range_b.len() {
+          return f(EMPTY);
+        }
+        let (range_a, range_b) = (range_a.clone(), range_b.clone());
+        // The bytes live in the store.
+        Answer{
+          f: Rc::new(move | _, store | {
+            for (a, b) in range_a.clone().zip(range_b.clone()) {
+              match (store.fetch_integer(a), store.fetch_integer(b)) {
+                (Ok(x), Ok(y)) => if x != y {
+                  return (f.f)(&EMPTY, store);
+                },
+                (Err(exception), _)
+                | (_, Err(exception)) => return store.raise_exception(exception)
+              }
+            }
+            (t.f)(&EMPTY, store)
+          }),
+          parameters: EMPTY
+        }
+      },
+
+      (PrimitiveOp::SEqual, [DValue::String(a), DValue::String(b)], 2) => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        // `str` equality already compares lengths before bytes.
+        if a.as_bytes() == b.as_bytes() {
+          t(EMPTY)
+        } else {
+          f(EMPTY)
+        }
+      },
+
+      (PrimitiveOp::Spawn, [DValue::Function(thunk)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        let thunk = thunk.clone();
+        EvalContext::enqueue_task(
+          Continuation::from_fn(move | _, store | (thunk.f)(&vec![finish_task()], store))
+        );
+        c(EMPTY)
+      },
+
+      (PrimitiveOp::Yield, [], 1) => {
+        let continuation = continuation_list.pop().unwrap();
+        match EvalContext::dequeue_task() {
+          Some(next_task) => {
+            EvalContext::enqueue_task(continuation);
+            next_task(EMPTY)
+          }
+          None => continuation(EMPTY)
+        }
+      },
 
-      // Todo: \[Appel] doesn't implement these operations?
-      /*
-      (PrimitiveOp::RShift, [DValue::Real(a), DValue::Real(b)], [c]) => {
+      (PrimitiveOp::MakeCyclic, [DValue::Integer(n), DValue::Function(builder)], 1) => {
+        if *n < 0 {
+          return Exception::IndexOutOfBounds.as_answer();
+        }
+        let continuation = continuation_list.pop().unwrap();
+        let builder = builder.clone();
+        let length = *n as usize;
+        Answer{
+          f: Rc::new(move | _, store | {
+            let (new_store, range) = match store.allocate(length) {
+              Ok(allocation) => allocation,
+              Err(exception) => return store.raise_exception(exception)
+            };
+            let array = DValue::Array(range);
+
+            let result = array.clone();
+            let continuation = continuation.clone();
+            let done = DValue::Function(
+              Continuation::from_fn(move | _, store | (continuation.f)(&vec![result.clone()], store))
+            );
+
+            (builder.f)(&vec![array, done], &new_store)
+          }),
+          parameters: EMPTY
+        }
+      },
 
+      (PrimitiveOp::ArrayKind, [DValue::Array(_)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(0)])
       },
 
-      (PrimitiveOp::LShift, [DValue::Real(a), DValue::Real(b)], [c]) => {
+      (PrimitiveOp::ArrayKind, [DValue::UnboxedArray(_)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(1)])
+      },
 
+      (PrimitiveOp::ArrayKind, [DValue::ByteArray(_)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(2)])
       },
 
-      (PrimitiveOp::OrBinary, [DValue::Real(a), DValue::Real(b)], [c]) => {
+      (PrimitiveOp::ArrayKind, [_], 1) => {
+        Exception::TypeMismatch.as_answer()
+      },
 
+      (PrimitiveOp::IntToString, [DValue::Integer(i)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::String(i.to_string())])
       },
 
-      (PrimitiveOp::AndBinary, [DValue::Real(a), DValue::Real(b)], [c]) => {
+      (PrimitiveOp::RealToString, [DValue::Real(x)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::String(x.0.to_string())])
+      },
 
+      (PrimitiveOp::Tag, [DValue::Integer(t), payload], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Record { values: vec![DValue::Integer(*t), payload.clone()], idx: 0 }])
       },
 
-      (PrimitiveOp::XOrBinary, [DValue::Real(a), DValue::Real(b)], [c]) => {
+      (PrimitiveOp::GetTag, [tagged], 1) => {
+        match tagged.field(0) {
+          Some(tag @ DValue::Integer(_)) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![tag.clone()])
+          }
+          _ => Exception::TypeMismatch.as_answer()
+        }
+      },
+
+      (PrimitiveOp::GetPayload, [tagged], 1) => {
+        match tagged.field(1) {
+          Some(payload) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![payload.clone()])
+          }
+          None => Exception::TypeMismatch.as_answer()
+        }
+      },
+
+      (PrimitiveOp::StringToInt, [DValue::String(s)], 2) => {
+        let f = continuation_list.pop().unwrap();
+        let t = continuation_list.pop().unwrap();
+        match s.parse::<Integer>() {
+          Ok(i)  => t(vec![DValue::Integer(i)]),
+          Err(_) => f(EMPTY)
+        }
+      },
+
+      (PrimitiveOp::StringConcat, [DValue::String(a), DValue::String(b)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::String(a.clone() + b)])
+      },
+
+      (
+        PrimitiveOp::Substring,
+        [DValue::String(s), DValue::Integer(start), DValue::Integer(len)],
+        1
+      ) => {
+        let slice = usize::try_from(*start).ok()
+                          .zip(usize::try_from(*len).ok())
+                          .and_then(|(start, len)| Some(start..start.checked_add(len)?))
+                          // `str::get` is `None` both out of range and off a character boundary.
+                          .and_then(|range| s.get(range));
+        match slice {
+          Some(substring) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![DValue::String(substring.to_string())])
+          }
+          None => Exception::IndexOutOfBounds.as_answer()
+        }
+      },
+
+      (PrimitiveOp::RShift, [DValue::Integer(i), DValue::Integer(j)], 1) => {
+        match u32::try_from(*j).ok().and_then(|j| i.checked_shr(j)) {
+          Some(k) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![DValue::Integer(k)])
+          }
+          None => Exception::Overflow.as_answer()
+        }
+      },
+
+      (PrimitiveOp::LShift, [DValue::Integer(i), DValue::Integer(j)], 1) => {
+        match u32::try_from(*j).ok().and_then(|j| i.checked_shl(j)) {
+          Some(k) => {
+            let c = continuation_list.pop().unwrap();
+            c(vec![DValue::Integer(k)])
+          }
+          None => Exception::Overflow.as_answer()
+        }
+      },
+
+      (PrimitiveOp::OrBinary, [DValue::Integer(i), DValue::Integer(j)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(*i | *j)])
+      },
 
+      (PrimitiveOp::AndBinary, [DValue::Integer(i), DValue::Integer(j)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(*i & *j)])
       },
 
-      (PrimitiveOp::NotBinary, [DValue::Real(a), DValue::Real(b)], [c]) => {
+      (PrimitiveOp::XOrBinary, [DValue::Integer(i), DValue::Integer(j)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(*i ^ *j)])
+      },
 
+      (PrimitiveOp::NotBinary, [DValue::Integer(i)], 1) => {
+        let c = continuation_list.pop().unwrap();
+        c(vec![DValue::Integer(!*i)])
       },
-      */
+
+      // The exception goes to the program's handler, which is the whole report.
+      _ => Exception::TypeMismatch.as_answer()
 
     }
 
@@ -755,7 +1760,709 @@ impl PrimitiveOp{
 
 
 
-fn next_location(last_address: Location) -> Location {//-> Span<'static> {
-  last_address + std::mem::size_of::<DValue>()
+/// The continuation a spawned task applies when it is finished. It runs the next queued task. A
+/// spawned task only runs while the task that yielded to it waits in the queue, so an empty queue
+/// means the scheduler has lost track of a task, which is `Undefined`.
+fn finish_task() -> DValue {
+  DValue::Function(
+    Continuation::from_fn(| _, store | {
+      match EvalContext::dequeue_task() {
+        Some(next_task) => (next_task.f)(&EMPTY, store),
+        None            => store.raise_exception(Exception::Undefined)
+      }
+    })
+  )
+}
+
+/// The location of the element `n` of the array occupying `range`, if `n` is a valid index.
+fn element_location(range: &Range<Location>, n: Integer) -> Option<Location> {
+  usize::try_from(n).ok()
+                    .filter(|n| *n < range.len())
+                    .map(|n| range.start + n)
+}
+
+/// Converts an integral real to an `Integer`, if it is in range. NaN and the infinities are not.
+fn real_to_integer(x: f32) -> Option<Integer> {
+  // `Integer::MIN` is a power of two, so it and its negation are exact as reals, and every real
+  // in between converts without overflow.
+  let bound = -(Integer::MIN as f32);
+  if x >= -bound && x < bound {
+    Some(x as Integer)
+  } else {
+    None
+  }
+}
+
+/// The equality of reals shared by `feql`, `fneq`, `ieql`, and `ineq`: the equality of
+/// `OrderedFloat`, under which `0.0` equals `-0.0` and every NaN equals every other NaN.
+fn reals_equal(a: Real, b: Real) -> bool {
+  a == b
+}
+
+/// Wraps the result of a real operation on `operands`. Under `FloatMode::Trapping`, a NaN result
+/// from non-NaN operands is `FloatInvalid`, and an infinite result from finite operands is
+/// `FloatOverflow`. Under `FloatMode::Ieee`, every result is passed on as it is.
+fn checked_real(operands: &[Real], result: f32) -> Result<DValue, Exception> {
+  if EvalContext::with_current(|context| context.float_mode) == FloatMode::Trapping {
+    if result.is_nan() && !operands.iter().any(|x| x.is_nan()) {
+      return Err(Exception::FloatInvalid);
+    }
+    if result.is_infinite() && operands.iter().all(|x| x.is_finite()) {
+      return Err(Exception::FloatOverflow);
+    }
+  }
+  Ok(DValue::Real(OrderedFloat(result)))
+}
+
+/// The location after `last_address`. Locations index cells of the store, not bytes, so the
+/// next location is the next cell. This is `nextloc` of \[Appel].
+fn next_location(last_address: Location) -> Location {
+  last_address + 1
 }
 
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+
+  use super::*;
+  use crate::interpreter::tests::run_source;
+
+  /// The final values of `source` rendered with `Debug`.
+  fn run(source: &str) -> String {
+    format!("{:?}", run_source(source))
+  }
+
+  /// The rendering of the final values of a program ended by the uncaught `exception`.
+  fn raised(exception: Exception) -> String {
+    format!("{:?}", vec![exception.as_record()])
+  }
+
+  #[test]
+  fn pack_and_unpack_round_trip() {
+    let result = run(
+      "(primop mkarray (3 7) (a) (
+         (primop update (a 1 8) () (
+           (primop pack (a) (u) (
+             (primop akind (u) (kind) (
+               (primop unpack (u) (b) (
+                 (primop subscript (b 1) (x) (
+                   (primop aeql (a b) () (
+                     (app halt kind x 1)
+                     (app halt kind x 0)))))))))))))))"
+    );
+    assert_eq!(result, "[1, 8, 1]");
+  }
+
+  #[test]
+  fn packing_a_non_integer_is_a_type_mismatch() {
+    let result = run("(primop mkarray (2 \"s\") (a) ((primop pack (a) (u) ((app halt u)))))");
+    assert_eq!(result, raised(Exception::TypeMismatch));
+  }
+
+  #[test]
+  fn a_live_weak_reference_dereferences_to_its_array() {
+    let result = run(
+      "(primop mkarray (2 5) (a) (
+         (primop mkweak (a) (w) (
+           (primop deweak (w) (b) (
+             (primop subscript (b 1) (x) ((app halt x)))))))))"
+    );
+    assert_eq!(result, "[5]");
+  }
+
+  #[test]
+  fn array_equal_compares_contents() {
+    let compare = | second: &str | run(&format!(
+      "(primop mkarray (3 4) (a) (
+         (primop mkarray (3 4) (b) (
+           {}
+           (primop aeql (a b) () ((app halt 1) (app halt 0)))))))))",
+      second
+    ));
+    assert_eq!(compare("(primop update (b 0 4) () ("), "[1]");
+    assert_eq!(compare("(primop update (b 2 5) () ("), "[0]");
+  }
+
+  #[test]
+  fn arrays_of_different_lengths_are_unequal() {
+    let result = run(
+      "(primop mkarray (3 4) (a) (
+         (primop mkarray (2 4) (b) (
+           (primop aeql (a b) () ((app halt 1) (app halt 0)))))))"
+    );
+    assert_eq!(result, "[0]");
+  }
+
+  #[test]
+  #[cfg(not(feature = "int32"))]
+  fn integers_overflow_at_64_bits_by_default() {
+    assert_eq!(run("(primop + (2147483647 1) (x) ((app halt x)))"), "[2147483648]");
+    assert_eq!(run("(primop + (9223372036854775807 1) (x) ((app halt x)))"), raised(Exception::Overflow));
+  }
+
+  #[test]
+  #[cfg(feature = "int32")]
+  fn integers_overflow_at_32_bits_under_int32() {
+    assert_eq!(run("(primop + (2147483646 1) (x) ((app halt x)))"), "[2147483647]");
+    assert_eq!(run("(primop + (2147483647 1) (x) ((app halt x)))"), raised(Exception::Overflow));
+  }
+
+  #[test]
+  fn spawned_tasks_run_round_robin() {
+    // Each task appends to `log` through the cursor `pos`, yielding between entries.
+    let result = run(
+      "(primop mkarray (5 0) (log) (
+         (primop makeref (0) (pos) (
+           (fix ((emit (v k)
+                   (primop ! (pos) (i) (
+                     (primop update (log i v) () (
+                       (primop + (i 1) (j) (
+                         (primop := (pos j) () ((app k))))))))))
+                 (a (done) (fix ((a1 () (primop yield () () ((fix ((a2 () (primop yield () () ((app done))))) (app emit 2 a2)))))) (app emit 1 a1)))
+                 (b (done) (fix ((b1 () (primop yield () () ((app emit 20 done))))) (app emit 10 b1)))
+                 (main ()
+                   (primop yield () () ((primop yield () () ((primop yield () () (
+                     (primop subscript (log 0) (x0) ((primop subscript (log 1) (x1) (
+                       (primop subscript (log 2) (x2) ((primop subscript (log 3) (x3) (
+                         (primop subscript (log 4) (x4) ((app halt x0 x1 x2 x3 x4)))))))))))))))))))
+             (primop spawn (a) () ((primop spawn (b) () ((app emit 0 main))))))))))"
+    );
+    assert_eq!(result, "[0, 1, 10, 2, 20]");
+  }
+
+  #[test]
+  fn a_real_operation_on_an_integer_raises_type_mismatch() {
+    assert_eq!(run("(primop fadd (1 2.0) (x) ((app halt x)))"), raised(Exception::TypeMismatch));
+  }
+
+  #[test]
+  fn branching_operations_check_their_continuation_count() {
+    assert_eq!(run("(primop feql (1.0 1.0) () ((app halt 1)))"), raised(Exception::Arity));
+    assert_eq!(
+      run("(primop ieql (1 1) () ((app halt 1) (app halt 0) (app halt 2)))"),
+      raised(Exception::Arity)
+    );
+  }
+
+  #[test]
+  fn a_cyclic_structure_can_be_traversed() {
+    // Two nodes `[value, next]` pointing at each other, summed along five steps.
+    let result = run(
+      "(fix ((build (a done)
+               (primop mkarray (2 2) (b) (
+                 (primop update (b 1 a) () (
+                   (primop update (a 0 1) () (
+                     (primop update (a 1 b) () ((app done))))))))))
+             (walk (node n sum)
+               (primop ieql (n 0) () (
+                 (app halt sum)
+                 (primop subscript (node 0) (v) (
+                   (primop + (sum v) (sum2) (
+                     (primop subscript (node 1) (next) (
+                       (primop - (n 1) (n2) ((app walk next n2 sum2)))))))))))))
+         (primop mkcyclic (2 build) (a) ((app walk a 5 0))))"
+    );
+    assert_eq!(result, "[7]");
+  }
+
+  #[test]
+  fn fsign_discretizes_reals() {
+    let sign = | x: &str | run(&format!("(primop fsign ({}) (s) ((app halt s)))", x));
+    assert_eq!(sign("2.5"), "[1]");
+    assert_eq!(sign("-0.5"), "[-1]");
+    assert_eq!(sign("0.0"), "[0]");
+    assert_eq!(sign("-0.0"), "[0]");
+    // NaN, made as infinity minus infinity.
+    let nan_sign = run(
+      "(primop fmul (3.0e38 10.0) (i) ((primop fsub (i i) (n) ((primop fsign (n) (s) ((app halt s)))))))"
+    );
+    assert_eq!(nan_sign, "[0]");
+  }
+
+  #[test]
+  fn a_switch_on_fsign_selects_by_sign() {
+    let classify = | x: &str | run(&format!(
+      "(primop fsign ({}) (s) ((primop + (s 1) (i) ((switch i (app halt 100) (app halt 200) (app halt 300))))))",
+      x
+    ));
+    assert_eq!(classify("-3.0"), "[100]");
+    assert_eq!(classify("0.0"), "[200]");
+    assert_eq!(classify("3.0"), "[300]");
+  }
+
+  #[test]
+  fn bytes_equal_compares_contents_and_lengths() {
+    let (store, cells) = Store::new(4).allocate(9).unwrap();
+    let start = cells.start;
+    let store = store.fill_integer(cells, 1).unwrap().update_integer(start + 8, 9).unwrap();
+    let (a, b, c, d) = (start..start + 3, start + 3..start + 6, start + 6..start + 9, start..start + 2);
+
+    let compare = | x: &Range<Location>, y: &Range<Location> | {
+      let answer = PrimitiveOp::BytesEqual.evaluate(
+        vec![DValue::ByteArray(x.clone()), DValue::ByteArray(y.clone())],
+        vec![
+          Continuation::from_fn(| _, _ | Answer::halt(vec![DValue::Integer(1)])),
+          Continuation::from_fn(| _, _ | Answer::halt(vec![DValue::Integer(0)]))
+        ]
+      );
+      format!("{:?}", crate::interpreter::run_with_fuel(answer, store.clone(), 10).unwrap().parameters())
+    };
+    assert_eq!(compare(&a, &b), "[1]");
+    assert_eq!(compare(&a, &c), "[0]");
+    assert_eq!(compare(&a, &d), "[0]");
+  }
+
+  #[test]
+  fn string_equality_compares_contents() {
+    assert_eq!(run("(primop seql (\"abc\" \"abc\") () ((app halt 1) (app halt 0)))"), "[1]");
+    assert_eq!(run("(primop seql (\"abc\" \"abd\") () ((app halt 1) (app halt 0)))"), "[0]");
+    assert_eq!(run("(primop seql (\"abc\" \"ab\") () ((app halt 1) (app halt 0)))"), "[0]");
+  }
+
+  #[test]
+  fn array_kind_tags_each_representation() {
+    assert_eq!(run("(primop mkarray (2 0) (a) ((primop akind (a) (k) ((app halt k)))))"), "[0]");
+    assert_eq!(run("(primop mkarrayunboxed (2 0) (a) ((primop akind (a) (k) ((app halt k)))))"), "[1]");
+
+    let answer = PrimitiveOp::ArrayKind.evaluate(
+      vec![DValue::ByteArray(1..3)],
+      vec![Continuation::from_fn(| parameters, _ | Answer::halt(parameters.clone()))]
+    );
+    let result = crate::interpreter::run_with_fuel(answer, Store::new(4), 10).unwrap();
+    assert_eq!(format!("{:?}", result.parameters()), "[2]");
+
+    assert_eq!(run("(primop akind (3) (k) ((app halt k)))"), raised(Exception::TypeMismatch));
+  }
+
+  #[test]
+  fn subscripting_a_record_checks_its_bounds() {
+    let subscript = | index: &str | run(&format!(
+      "(record (1 2 3) r (offset 1 r q (primop subscript (q {}) (x) ((app halt x)))))",
+      index
+    ));
+    assert_eq!(subscript("1"), "[3]");
+    assert_eq!(subscript("2"), raised(Exception::IndexOutOfBounds));
+    assert_eq!(subscript("-1"), raised(Exception::IndexOutOfBounds));
+  }
+
+  #[test]
+  fn numbers_convert_to_strings() {
+    assert_eq!(run("(primop itos (-42) (s) ((app halt s)))"), r#"["-42"]"#);
+    assert_eq!(
+      run("(primop rtos (1.5) (a) ((primop rtos (-3.0) (b) ((primop rtos (0.001) (c) ((app halt a b c)))))))"),
+      r#"["1.5", "-3", "0.001"]"#
+    );
+  }
+
+  #[test]
+  fn non_finite_reals_convert_to_strings() {
+    // 3.0e38 * 10 overflows to infinity, and infinity minus itself is NaN.
+    assert_eq!(
+      run("(primop fmul (3.0e38 10.0) (i) ((primop fsub (i i) (n) ((primop fsub (0.0 i) (m) (
+             (primop rtos (i) (a) ((primop rtos (m) (b) ((primop rtos (n) (c) ((app halt a b c)))))))))))))"),
+      r#"["inf", "-inf", "NaN"]"#
+    );
+  }
+
+  #[test]
+  fn strings_parse_as_integers_or_take_the_failure_branch() {
+    let parse = | text: &str | run(&format!("(primop stoi (\"{}\") (i) ((app halt i) (app halt \"no\")))", text));
+    assert_eq!(parse("-17"), "[-17]");
+    assert_eq!(parse("+5"), "[5]");
+    assert_eq!(parse("12a"), r#"["no"]"#);
+    assert_eq!(parse(" 3"), r#"["no"]"#);
+    assert_eq!(parse(""), r#"["no"]"#);
+    assert_eq!(parse(&format!("{}0", Integer::MAX)), r#"["no"]"#);
+    assert_eq!(parse(&Integer::MIN.to_string()), format!("[{}]", Integer::MIN));
+  }
+
+  #[test]
+  fn integer_and_real_equality_agree_on_nan_and_signed_zero() {
+    // Applies `op` to `x` and `y`, where `i` is infinity and `n` is NaN, and reports the branch.
+    let compare = | op: &str, x: &str, y: &str | run(&format!(
+      "(primop fmul (3.0e38 10.0) (i) ((primop fsub (i i) (n) ((primop {} ({} {}) () ((app halt 1) (app halt 0)))))))",
+      op, x, y
+    ));
+    for (op, equal) in [("ieql", "[1]"), ("feql", "[1]"), ("ineq", "[0]"), ("fneq", "[0]")] {
+      assert_eq!(compare(op, "n", "n"), equal, "{} on NaN", op);
+      assert_eq!(compare(op, "0.0", "-0.0"), equal, "{} on signed zeros", op);
+      assert_ne!(compare(op, "n", "i"), equal, "{} on NaN and infinity", op);
+    }
+  }
+
+  #[test]
+  fn float_overflow_traps_only_in_trapping_mode() {
+    let overflow = "(primop fmul (3.0e38 10.0) (x) ((primop rtos (x) (s) ((app halt s)))))";
+    // Zero times infinity is NaN from operands that are not NaN.
+    let invalid  = || {
+      let answer = PrimitiveOp::FMultiply.evaluate(
+        vec![DValue::Real(0.0.into()), DValue::Real(f32::INFINITY.into())],
+        vec![Continuation::from_fn(| parameters, _ | Answer::halt(parameters.clone()))]
+      );
+      format!("{:?}", crate::interpreter::run_with_fuel(answer, Store::new(4), 10).unwrap().parameters())
+    };
+    assert_eq!(run(overflow), r#"["inf"]"#);
+    assert_eq!(invalid(), "[NaN]");
+
+    let previous = EvalContext::new().with_float_mode(FloatMode::Trapping).install();
+    let trapped  = (run(overflow), invalid(), run("(primop fadd (1.5 2.0) (x) ((app halt x)))"));
+    previous.install();
+
+    assert_eq!(trapped.0, raised(Exception::FloatOverflow));
+    assert_eq!(trapped.1, raised(Exception::FloatInvalid));
+    assert_eq!(trapped.2, "[3.5]");
+  }
+
+  #[test]
+  fn a_switch_on_the_tag_dispatches_on_the_constructor() {
+    // `area` of the sum type `Square of int | Rectangle of (int, int)`.
+    let area = | construct: &str | run(&format!(
+      "(fix ((area (v k) (primop gettag (v) (t) ((primop getpayload (v) (p) (
+                 (switch t
+                   (primop * (p p) (a) ((app k a)))
+                   (select 0 p w (select 1 p h (primop * (w h) (a) ((app k a))))))))))))
+         {})",
+      construct
+    ));
+    assert_eq!(area("(primop tag (0 3) (v) ((app area v halt)))"), "[9]");
+    assert_eq!(area("(record (2 5) p (primop tag (1 p) (v) ((app area v halt))))"), "[10]");
+    assert_eq!(run("(primop tag (4 7) (v) ((select 0 v t (select 1 v p (app halt t p)))))"), "[4, 7]");
+  }
+
+  #[test]
+  fn makeref_grows_the_store_past_its_initial_size() {
+    // Twenty references need more cells than the 16 of the store `run` starts with.
+    let mut source = String::from("(primop ! (r0) (x) ((primop ! (r19) (y) ((app halt x y)))))");
+    for i in (0..20).rev() {
+      source = format!("(primop makeref ({}) (r{}) ({}))", 10 * i, i, source);
+    }
+    assert_eq!(run(&source), "[0, 190]");
+  }
+
+  #[test]
+  fn shifts_check_their_amount() {
+    let shift = | op: &str, i: Integer, j: Integer | run(&format!("(primop {} ({} {}) (k) ((app halt k)))", op, i, j));
+    assert_eq!(shift("lshift", 5, 0), "[5]");
+    assert_eq!(shift("lshift", 5, 3), "[40]");
+    assert_eq!(shift("rshift", -40, 3), "[-5]", "the right shift is arithmetic");
+    assert_eq!(shift("rshift", 40, 0), "[40]");
+    let bits = Integer::BITS as Integer;
+    assert_eq!(shift("lshift", 1, bits), raised(Exception::Overflow));
+    assert_eq!(shift("rshift", 1, bits), raised(Exception::Overflow));
+    assert_eq!(shift("lshift", 1, -1), raised(Exception::Overflow));
+  }
+
+  #[test]
+  fn bitwise_operations_on_integers() {
+    assert_eq!(
+      run("(primop orb (12 10) (a) ((primop andb (12 10) (b) ((primop xorb (12 10) (c) ((primop notb (0) (d) ((app halt a b c d)))))))))"),
+      "[14, 8, 6, -1]"
+    );
+    assert_eq!(run("(primop orb (1.0 2.0) (a) ((app halt a)))"), raised(Exception::TypeMismatch));
+  }
+
+  #[test]
+  fn ordof_checks_its_index() {
+    assert_eq!(run("(primop ordof (\"abc\" 1) (c) ((app halt c)))"), "[98]");
+    assert_eq!(run("(primop ordof (\"abc\" 5) (c) ((app halt c)))"), raised(Exception::IndexOutOfBounds));
+    assert_eq!(run("(primop ordof (\"abc\" -1) (c) ((app halt c)))"), raised(Exception::IndexOutOfBounds));
+  }
+
+  #[test]
+  fn selects_and_subscripts_past_the_end_are_out_of_bounds() {
+    assert_eq!(run("(record (1 2) r (select 2 r x (app halt x)))"), raised(Exception::IndexOutOfBounds));
+    // The second array directly follows the first, so a subscript past the end of `a` would read
+    // the first cell of `b` if it were not checked.
+    let array = | access: &str | run(&format!(
+      "(primop mkarray (2 1) (a) ((primop mkarray (2 9) (b) ({}))))",
+      access
+    ));
+    assert_eq!(array("(primop subscript (a 1) (v) ((app halt v)))"), "[1]");
+    assert_eq!(array("(primop subscript (a 2) (v) ((app halt v)))"), raised(Exception::IndexOutOfBounds));
+    assert_eq!(array("(primop update (a 2 5) () ((app halt 0)))"), raised(Exception::IndexOutOfBounds));
+    assert_eq!(
+      array("(primop update (a 1 5) () ((primop subscript (b 0) (v) ((app halt v)))))"),
+      "[9]"
+    );
+  }
+
+  #[test]
+  fn makeref_then_subscript_reads_the_new_cell() {
+    assert_eq!(run("(primop makeref (42) (r) ((primop subscript (r 0) (v) ((app halt v)))))"), "[42]");
+    assert_eq!(run("(primop makerefunboxed (7) (r) ((primop subscript (r 0) (v) ((app halt v)))))"), "[7]");
+  }
+
+  #[test]
+  fn consecutive_makerefs_take_consecutive_cells() {
+    let values = run_source(
+      "(primop makeref (1) (a) ((primop makeref (2) (b) ((primop makerefunboxed (3) (c) ((app halt a b c)))))))"
+    );
+    let starts: Vec<Location> = values.iter().map(|value| match value {
+      | DValue::Array(range)
+      | DValue::UnboxedArray(range) => {
+        assert_eq!(range.len(), 1);
+        range.start
+      }
+      other => panic!("expected a reference, got {:?}", other)
+    }).collect();
+    assert_eq!(starts, vec![starts[0], starts[0] + 1, starts[0] + 2]);
+    assert!(matches!(values[2], DValue::UnboxedArray(_)));
+  }
+
+  #[test]
+  fn adding_mismatched_kinds_raises_instead_of_aborting() {
+    assert_eq!(run("(primop + (\"a\" \"b\") (x) ((app halt x)))"), raised(Exception::TypeMismatch));
+    assert_eq!(run("(primop + (1 2.0) (x) ((app halt x)))"), raised(Exception::TypeMismatch));
+  }
+
+  #[test]
+  fn exntag_of_a_record_without_an_integer_tag_raises_type_mismatch() {
+    assert_eq!(run("(record (\"a\" 1) r (primop exntag (r) (t) ((app halt t))))"), raised(Exception::TypeMismatch));
+    assert_eq!(run("(record (1) r (offset 1 r s (primop exntag (s) (t) ((app halt t)))))"), raised(Exception::TypeMismatch));
+    assert_eq!(run("(record (7 1) r (primop exntag (r) (t) ((app halt t))))"), "[7]");
+  }
+
+  #[test]
+  fn a_wrong_argument_count_raises_arity_rather_than_type_mismatch() {
+    assert_eq!(run("(primop + (1) (x) ((app halt x)))"), raised(Exception::Arity));
+    assert_eq!(run("(primop + (1 2 3) (x) ((app halt x)))"), raised(Exception::Arity));
+    let result = run_source(
+      "(fix ((h (e) (primop exntag (e) (t) ((app halt t)))))
+         (primop sethdlr (h) () ((primop subscript (1) (x) ((app halt x))))))"
+    );
+    assert_eq!(format!("{:?}", result), "[12]");
+  }
+
+  #[test]
+  fn every_operation_checks_its_arity() {
+    // Written out again rather than read from `arity`, and exhaustive, so that a new operation
+    // must be listed here and a wrong entry in `arity` fails.
+    let expected = | operation: PrimitiveOp | match operation {
+      | PrimitiveOp::GetHandler
+      | PrimitiveOp::Yield => (0, 1),
+
+      | PrimitiveOp::Raise => (1, 0),
+
+      | PrimitiveOp::Tilde
+      | PrimitiveOp::Bang
+      | PrimitiveOp::MakeRef
+      | PrimitiveOp::MakeRefUnboxed
+      | PrimitiveOp::ArrayLength
+      | PrimitiveOp::StringLength
+      | PrimitiveOp::SetHandler
+      | PrimitiveOp::FSign
+      | PrimitiveOp::Floor
+      | PrimitiveOp::Truncate
+      | PrimitiveOp::Float
+      | PrimitiveOp::FSqrt
+      | PrimitiveOp::ExceptionTag
+      | PrimitiveOp::Pack
+      | PrimitiveOp::Unpack
+      | PrimitiveOp::MakeWeak
+      | PrimitiveOp::DerefWeak
+      | PrimitiveOp::Spawn
+      | PrimitiveOp::ArrayKind
+      | PrimitiveOp::IntToString
+      | PrimitiveOp::RealToString
+      | PrimitiveOp::GetTag
+      | PrimitiveOp::GetPayload
+      | PrimitiveOp::NotBinary => (1, 1),
+
+      | PrimitiveOp::Boxed
+      | PrimitiveOp::StringToInt => (1, 2),
+
+      | PrimitiveOp::Multiply
+      | PrimitiveOp::Add
+      | PrimitiveOp::Subtract
+      | PrimitiveOp::Divide
+      | PrimitiveOp::Subscript
+      | PrimitiveOp::OrdinalOf
+      | PrimitiveOp::ColonEqual
+      | PrimitiveOp::UnboxedAssign
+      | PrimitiveOp::FAdd
+      | PrimitiveOp::FSubtract
+      | PrimitiveOp::FMultiply
+      | PrimitiveOp::FDivide
+      | PrimitiveOp::MakeCyclic
+      | PrimitiveOp::MakeArray
+      | PrimitiveOp::MakeUnboxedArray
+      | PrimitiveOp::Tag
+      | PrimitiveOp::StringConcat
+      | PrimitiveOp::RShift
+      | PrimitiveOp::LShift
+      | PrimitiveOp::OrBinary
+      | PrimitiveOp::AndBinary
+      | PrimitiveOp::XOrBinary => (2, 1),
+
+      | PrimitiveOp::IEqual
+      | PrimitiveOp::INEqual
+      | PrimitiveOp::Less
+      | PrimitiveOp::LessEqual
+      | PrimitiveOp::Greater
+      | PrimitiveOp::GreaterEqual
+      | PrimitiveOp::RangeCheck
+      | PrimitiveOp::FEqual
+      | PrimitiveOp::FNEqual
+      | PrimitiveOp::FGreaterEqual
+      | PrimitiveOp::FGreater
+      | PrimitiveOp::FLessEqual
+      | PrimitiveOp::FLess
+      | PrimitiveOp::ArrayEqual
+      | PrimitiveOp::BytesEqual
+      | PrimitiveOp::SEqual => (2, 2),
+
+      | PrimitiveOp::Update
+      | PrimitiveOp::UnboxedUpdate
+      | PrimitiveOp::Store
+      | PrimitiveOp::Substring => (3, 1),
+    };
+    for operation in PrimitiveOp::iter() {
+      assert_eq!(operation.arity(), expected(operation), "{:?}", operation);
+    }
+
+    // One argument or one continuation too many is an `Arity` exception for every operation.
+    let halt = || Continuation::from_fn(| parameters, _ | Answer::halt(parameters.clone()));
+    for operation in PrimitiveOp::iter() {
+      let (argument_count, continuation_count) = operation.arity();
+      let wrong = [(argument_count + 1, continuation_count), (argument_count, continuation_count + 1)];
+      for (arguments, continuations) in wrong.iter() {
+        let answer = operation.evaluate(
+          vec![DValue::Integer(0); *arguments],
+          (0..*continuations).map(|_| halt()).collect()
+        );
+        assert_eq!(
+          format!("{:?}", crate::interpreter::run_with_fuel(answer, Store::new(4), 10).unwrap().parameters()),
+          raised(Exception::Arity),
+          "{:?}",
+          operation
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn floor_and_truncate_convert_reals_to_integers() {
+    let convert = | x: &str | run(&format!(
+      "(primop floor ({x}) (f) ((primop truncate ({x}) (t) ((app halt f t)))))", x = x
+    ));
+    assert_eq!(convert("3.7"), "[3, 3]");
+    assert_eq!(convert("-3.7"), "[-4, -3]");
+    // Finite, but out of the range of `Integer`.
+    assert_eq!(run("(primop floor (3.0e38) (f) ((app halt f)))"), raised(Exception::Overflow));
+    assert_eq!(
+      run("(primop fmul (3.0e38 10.0) (x) ((primop floor (x) (f) ((app halt f)))))"),
+      raised(Exception::Overflow)
+    );
+    assert_eq!(
+      run("(primop fmul (3.0e38 10.0) (x) ((primop truncate (x) (t) ((app halt t)))))"),
+      raised(Exception::Overflow)
+    );
+  }
+
+  #[test]
+  fn float_converts_integers_to_the_nearest_real() {
+    let float = | i: Integer | {
+      let answer = PrimitiveOp::Float.evaluate(
+        vec![DValue::Integer(i)],
+        vec![Continuation::from_fn(| parameters, _ | Answer::halt(parameters.clone()))]
+      );
+      format!("{:?}", crate::interpreter::run_with_fuel(answer, Store::new(4), 10).unwrap().parameters())
+    };
+    assert_eq!(float(0), "[0.0]");
+    assert_eq!(float(-12), "[-12.0]");
+    // `MAX` is not representable and rounds to the nearest real, the next power of two.
+    let power_of_two = (Integer::MAX as f64 + 1.0) as f32;
+    assert_eq!(float(Integer::MAX), format!("{:?}", vec![DValue::Real(OrderedFloat(power_of_two))]));
+    assert_eq!(float(Integer::MAX), float(Integer::MAX - 1));
+  }
+
+  #[test]
+  fn the_square_root_of_a_negative_real_is_a_domain_error() {
+    assert_eq!(run("(primop fsqrt (4.0) (r) ((app halt r)))"), "[2.0]");
+    assert_eq!(run("(primop fsqrt (2.0) (r) ((app halt r)))"), format!("[{:?}]", 2.0f32.sqrt()));
+    assert_eq!(run("(primop fsqrt (-1.0) (r) ((app halt r)))"), raised(Exception::DomainError));
+  }
+
+  #[test]
+  fn sconcat_builds_a_new_string() {
+    assert_eq!(run("(primop sconcat (\"con\" \"tinuation\") (s) ((app halt s)))"), r#"["continuation"]"#);
+    assert_eq!(run("(primop sconcat (\"abc\" \"\") (s) ((app halt s)))"), r#"["abc"]"#);
+    assert_eq!(run("(primop sconcat (\"\" \"abc\") (s) ((app halt s)))"), r#"["abc"]"#);
+  }
+
+  #[test]
+  fn substring_is_bounded_by_the_string_and_its_characters() {
+    let substring = | s: &str, start: Integer, len: Integer | run(&format!(
+      "(primop substring ({:?} {} {}) (t) ((app halt t)))", s, start, len
+    ));
+    assert_eq!(substring("continuation", 3, 5), r#"["tinua"]"#);
+    assert_eq!(substring("continuation", 12, 0), r#"[""]"#);
+    assert_eq!(substring("continuation", 10, 3), raised(Exception::IndexOutOfBounds));
+    assert_eq!(substring("continuation", -1, 2), raised(Exception::IndexOutOfBounds));
+    // `é` is two bytes, so a slice ending inside it is off a character boundary.
+    assert_eq!(substring("é!", 0, 1), raised(Exception::IndexOutOfBounds));
+    assert_eq!(substring("é!", 0, 2), r#"["é"]"#);
+  }
+
+  #[test]
+  fn makearray_initializes_every_element() {
+    for operation in ["mkarray", "mkarrayunboxed"].iter() {
+      let result = run(&format!(
+        "(primop {} (3 7) (a) (
+           (primop subscript (a 0) (x) ((primop subscript (a 1) (y) (
+             (primop subscript (a 2) (z) ((primop alength (a) (n) ((app halt x y z n)))))))))))",
+        operation
+      ));
+      assert_eq!(result, "[7, 7, 7, 3]", "{}", operation);
+    }
+  }
+
+  #[test]
+  fn every_real_operation_traps_an_infinity_from_finite_operands() {
+    let programs = [
+      "(primop fdiv (3.0e38 1.0e-10) (x) ((app halt x)))",
+      "(primop fmul (3.0e38 3.0e38) (x) ((app halt x)))",
+      "(primop fadd (3.0e38 3.0e38) (x) ((app halt x)))",
+      "(primop fsub (-3.0e38 3.0e38) (x) ((app halt x)))",
+    ];
+    // A handler sees the trap like any other exception.
+    let caught = "(fix ((h (e) (primop exntag (e) (t) ((app halt t)))))
+                    (primop sethdlr (h) () ((primop fdiv (3.0e38 1.0e-10) (x) ((app halt x))))))";
+
+    let ieee: Vec<String> = programs.iter().map(|program| run(program)).collect();
+    let previous = EvalContext::new().with_float_mode(FloatMode::Trapping).install();
+    let trapping: Vec<String> = programs.iter().map(|program| run(program)).collect();
+    let handled  = run(caught);
+    previous.install();
+
+    assert_eq!(ieee, vec!["[inf]", "[inf]", "[inf]", "[-inf]"]);
+    assert!(trapping.iter().all(|result| *result == raised(Exception::FloatOverflow)), "{:?}", trapping);
+    assert_eq!(handled, format!("[{}]", Exception::FloatOverflow.tag()));
+  }
+
+  #[test]
+  fn every_operation_displays_as_a_distinct_token_that_reads_back() {
+    let tokens: HashSet<String> = PrimitiveOp::iter().map(|operation| operation.to_string()).collect();
+    assert_eq!(tokens.len(), PrimitiveOp::iter().count());
+    for operation in PrimitiveOp::iter() {
+      assert_eq!(PrimitiveOp::from_token(&operation.to_string()), Some(operation));
+    }
+    assert_eq!(PrimitiveOp::Multiply.to_string(), "*");
+    assert_eq!(PrimitiveOp::Tilde.to_string(), "~");
+    assert_eq!(PrimitiveOp::Divide.to_string(), "div");
+    assert_eq!(PrimitiveOp::from_token("nope"), None);
+  }
+
+  #[test]
+  fn an_array_too_large_for_the_store_raises_out_of_memory_without_allocating() {
+    let n = Integer::MAX;
+    for source in [
+      format!("(primop mkarray ({} 0) (a) ((app halt a)))", n),
+      format!("(primop mkarrayunboxed ({} 0) (a) ((app halt a)))", n),
+      format!("(fix ((b (a k) (app k))) (primop mkcyclic ({} b) (a) ((app halt a))))", n),
+    ].iter() {
+      assert_eq!(run(source), raised(Exception::OutOfMemory), "{}", source);
+    }
+  }
+}
@@ -0,0 +1,113 @@
+/*!
+
+  Profiling of interpreted programs. `run_profiled` is `evaluate` followed by `run` that also
+  reports how much work the program did: how often each `PrimitiveOp` was evaluated, how many
+  continuations were applied, how many times the store was written, and how much of the store
+  those writes copied.
+
+  Only `run_profiled` collects a profile. Other evaluation pays for it with a read of a
+  thread-local flag per primitive operation, the same flag that coverage tracking already needs.
+
+*/
+
+use std::collections::HashMap;
+
+use crate::interpreter::{
+  VariableList,
+  context::EvalContext,
+  continuation_expression::ContinuationExpression,
+  cps::{
+    denotable_value::DenotableValueList,
+    store::Store
+  },
+  evaluate,
+  exception::InternalException,
+  primitive_op::PrimitiveOp
+};
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Profile {
+  /// The number of times each `PrimitiveOp` was evaluated. Operations that were never evaluated
+  /// are absent. As with `eval_coverage`, an operation defined in terms of another counts for
+  /// both.
+  pub primop_counts: HashMap<PrimitiveOp, u64>,
+  /// The number of steps of the trampoline, each of which applies one continuation.
+  pub applies      : u64,
+  /// The number of stores produced by writes to the store. See `CloneStats::clones`.
+  pub store_writes : u64,
+  /// The number of slots of the store those writes copied. See `CloneStats::cells_copied`.
+  pub cells_copied : u64,
+}
+
+/// Like `evaluate` followed by `run`, but also returns the `Profile` of the run, which starts
+/// before `evaluate` so that the operations evaluated on the way to the first application are
+/// counted too. A `run_profiled` nested in another, for example one started by a primitive
+/// operation, does not add to the profile of the outer one.
+pub fn run_profiled(
+  variables : VariableList,
+  values    : DenotableValueList,
+  expression: ContinuationExpression,
+  store_size: usize
+) -> Result<(DenotableValueList, Profile), InternalException>
+{
+  let previous = EvalContext::replace_profile(Some(HashMap::new()));
+  let store    = Store::new(store_size);
+
+  let mut applies = 0;
+  let result = evaluate(variables, values, expression).map(|answer| {
+    let mut answer = answer;
+    while !answer.is_halted() {
+      answer   = answer(&store);
+      applies += 1;
+    }
+    answer.parameters().clone()
+  });
+
+  let primop_counts = EvalContext::replace_profile(previous).unwrap_or_default();
+  let clone_stats = store.clone_stats();
+  let profile = Profile {
+    primop_counts,
+    applies,
+    store_writes: clone_stats.clones,
+    cells_copied: clone_stats.cells_copied
+  };
+  result.map(|parameters| (parameters, profile))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::Cell;
+
+  use crate::interpreter::{Variable, context::COUNTING_PRIMITIVE_OPS, parser::parse};
+
+  fn profile_of(source: &str) -> (DenotableValueList, Profile) {
+    run_profiled(vec![Variable::new("halt")], vec![Store::default_handler()], parse(source).unwrap(), 16).unwrap()
+  }
+
+  #[test]
+  fn a_loop_counts_one_add_per_iteration() {
+    let (values, profile) = profile_of(
+      "(fix ((loop (i a) (primop + (i 1) (j) ((primop update (a 0 j) () ((primop < (j 10) () ((app loop j a) (app halt j)))))))))
+         (primop mkarray (1 0) (a) ((app loop 0 a))))"
+    );
+    assert_eq!(format!("{:?}", values), "[10]");
+    assert_eq!(profile.primop_counts.get(&PrimitiveOp::Add), Some(&10));
+    assert_eq!(profile.primop_counts.get(&PrimitiveOp::Less), Some(&10));
+    assert_eq!(profile.primop_counts.get(&PrimitiveOp::Multiply), None);
+    assert!(profile.store_writes >= 10, "{:?}", profile);
+    assert!(profile.applies >= 10, "{:?}", profile);
+  }
+
+  #[test]
+  fn operations_are_only_counted_while_a_profile_is_taken() {
+    profile_of("(primop + (1 2) (x) ((app halt x)))");
+    // The profile is gone once the run is over, so evaluation afterwards counts nothing.
+    assert!(EvalContext::with_current(|context| context.profile.is_none()));
+    assert!(!COUNTING_PRIMITIVE_OPS.with(Cell::get));
+
+    // The operations before the first application count too.
+    let (_, profile) = profile_of("(primop + (1 2) (x) ((primop + (x 2) (y) ((app halt y)))))");
+    assert_eq!(profile.primop_counts.get(&PrimitiveOp::Add), Some(&2));
+  }
+}
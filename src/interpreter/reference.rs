@@ -0,0 +1,375 @@
+/*!
+
+  A reference evaluator and a differential test of the interpreter against it.
+
+  `reference_eval` is a deliberately simple big-step evaluator: it recurses on the structure of
+  the expression, binds variables in a plain `HashMap`, and represents a function as the `Fix`
+  group that defines it together with the environment of the `Fix`. It covers the integer
+  fragment of the language that `generate` produces, which is enough to exercise records,
+  offsets, closures, mutual scoping within a `Fix`, switches, branches, and the arithmetic
+  exceptions.
+
+  `generate` builds small random programs that are well typed by construction and always halt,
+  naming their variables with a `Gensym`. The differential test runs each of them through both
+  evaluators and compares the final values, or the uncaught exception, of the two.
+
+*/
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Integer,
+  Variable,
+  VariableList,
+  continuation_expression::{ContinuationExpression, FunctionDefinition},
+  cps::store::{AccessPath, Store},
+  evaluate,
+  exception::Exception,
+  gensym::Gensym,
+  primitive_op::PrimitiveOp,
+  run,
+  value::{Value, ValueList}
+};
+
+// region Reference evaluator
+
+type Group = Rc<Vec<Rc<FunctionDefinition>>>;
+type Bindings = HashMap<Variable, Reference>;
+
+#[derive(Clone)]
+enum Reference {
+  Integer(Integer),
+  /// The fields of a record and the index of the field the value points to.
+  Record(Rc<Vec<Reference>>, isize),
+  /// The `i`-th function of a `Fix` group, closed over the environment of the `Fix`.
+  Function(Group, usize, Bindings),
+  Halt
+}
+
+/// Evaluates `expression` with `halt` bound to a continuation that ends the program, returning
+/// the integers `halt` is applied to or the exception the program raises.
+pub fn reference_eval(expression: &ContinuationExpression) -> Result<Vec<Integer>, Exception> {
+  let mut bindings = Bindings::new();
+  bindings.insert(Variable::new("halt"), Reference::Halt);
+  eval(expression, bindings)
+}
+
+fn eval(expression: &ContinuationExpression, mut bindings: Bindings) -> Result<Vec<Integer>, Exception> {
+  match expression {
+
+    ContinuationExpression::Record { values, variable, expression } => {
+      let fields = values.iter()
+                         .map(|(value, path)| {
+                           assert_eq!(**path, AccessPath::Offset(0), "fields are plain values");
+                           lookup(value, &bindings)
+                         })
+                         .collect();
+      bindings.insert(variable.clone(), Reference::Record(Rc::new(fields), 0));
+      eval(expression, bindings)
+    }
+
+    ContinuationExpression::Select { location, value, variable, expression } => {
+      let (fields, index) = record(value, &bindings);
+      let field = usize::try_from(index + location).ok()
+                                                   .and_then(|i| fields.get(i))
+                                                   .ok_or(Exception::IndexOutOfBounds)?
+                                                   .clone();
+      bindings.insert(variable.clone(), field);
+      eval(expression, bindings)
+    }
+
+    ContinuationExpression::Offset { location, value, variable, expression } => {
+      let (fields, index) = record(value, &bindings);
+      bindings.insert(variable.clone(), Reference::Record(fields, index + location));
+      eval(expression, bindings)
+    }
+
+    ContinuationExpression::Apply { function, arguments } => {
+      let arguments: Vec<Reference> = arguments.iter().map(|a| lookup(a, &bindings)).collect();
+      match lookup(function, &bindings) {
+        Reference::Halt => Ok(arguments.iter().map(integer).collect()),
+        Reference::Function(group, i, mut closure) => {
+          bind_group(&group, &mut closure);
+          let (_, parameters) = group[i].header();
+          closure.extend(parameters.iter().cloned().zip(arguments));
+          eval(group[i].body(), closure)
+        }
+        _ => panic!("applied a value that is not a function")
+      }
+    }
+
+    ContinuationExpression::Fix { function_defs, expression } => {
+      bind_group(function_defs, &mut bindings);
+      eval(expression, bindings)
+    }
+
+    ContinuationExpression::Switch { value, arms } => {
+      let i = integer(&lookup(value, &bindings));
+      eval(&arms[i as usize], bindings)
+    }
+
+    ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+      let a = integer(&lookup(&values[0], &bindings));
+      let b = integer(&lookup(&values[1], &bindings));
+      let result = match operation {
+        PrimitiveOp::Add      => a.checked_add(b),
+        PrimitiveOp::Subtract => a.checked_sub(b),
+        PrimitiveOp::Multiply => a.checked_mul(b),
+        PrimitiveOp::Divide if b == 0 => return Err(Exception::DivideByZero),
+        PrimitiveOp::Divide   => a.checked_div(b),
+        PrimitiveOp::Less     => return eval(&expressions[if a < b { 0 } else { 1 }], bindings),
+        PrimitiveOp::IEqual     => return eval(&expressions[if a == b { 0 } else { 1 }], bindings),
+        _                     => panic!("{:?} is outside the reference fragment", operation)
+      };
+      bindings.insert(variables[0].clone(), Reference::Integer(result.ok_or(Exception::Overflow)?));
+      eval(&expressions[0], bindings)
+    }
+
+  }
+}
+
+/// Binds each function of `group` to its closure over `bindings`, as they are before the group
+/// is bound, so that a closure does not contain itself.
+fn bind_group(group: &Group, bindings: &mut Bindings) {
+  let closure = bindings.clone();
+  for (i, definition) in group.iter().enumerate() {
+    bindings.insert(definition.header().0.clone(), Reference::Function(group.clone(), i, closure.clone()));
+  }
+}
+
+fn lookup(value: &Value, bindings: &Bindings) -> Reference {
+  match value {
+    | Value::Variable(variable)
+    | Value::Label(variable) => bindings[variable].clone(),
+    Value::Integer(i)        => Reference::Integer(*i),
+    _                        => panic!("{:?} is outside the reference fragment", value)
+  }
+}
+
+fn integer(value: &Reference) -> Integer {
+  match value {
+    Reference::Integer(i) => *i,
+    _                     => panic!("expected an integer")
+  }
+}
+
+fn record(value: &Value, bindings: &Bindings) -> (Rc<Vec<Reference>>, isize) {
+  match lookup(value, bindings) {
+    Reference::Record(fields, index) => (fields, index),
+    _                                => panic!("expected a record")
+  }
+}
+
+// endregion
+
+// region Program generator
+
+/// A small xorshift generator, so that the programs depend only on the seed.
+struct Random(u64);
+
+impl Random {
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+
+  fn below(&mut self, n: usize) -> usize {
+    (self.next() % n as u64) as usize
+  }
+}
+
+/// The variables in scope at a point of a generated program, by type.
+#[derive(Clone, Default)]
+struct Scope {
+  integers : Vec<Variable>,
+  /// Each record with its length and the index of the field it points to.
+  records  : Vec<(Variable, usize, isize)>,
+  /// Each function with the number of parameters it takes.
+  functions: Vec<(Variable, usize)>
+}
+
+struct Generator {
+  random: Random,
+  names : Gensym
+}
+
+impl Generator {
+  /// An integer variable in scope or a literal, which is now and then large enough to overflow.
+  fn atom(&mut self, scope: &Scope) -> Value {
+    match self.random.below(10) {
+      0                                       => Value::Integer(Integer::MAX),
+      1..=3                                   => Value::Integer(self.random.below(7) as Integer - 3),
+      _ if scope.integers.is_empty()          => Value::Integer(self.random.below(7) as Integer - 3),
+      _ => Value::Variable(scope.integers[self.random.below(scope.integers.len())].clone())
+    }
+  }
+
+  fn atoms(&mut self, scope: &Scope, n: usize) -> ValueList {
+    (0..n).map(|_| self.atom(scope)).collect()
+  }
+
+  /// An expression that ends by applying `halt` or a function in `scope` after at most about
+  /// `depth` nested constructs.
+  fn expression(&mut self, scope: &Scope, depth: usize) -> ContinuationExpression {
+    if depth == 0 || self.random.below(8) == 0 {
+      return self.terminal(scope);
+    }
+    let mut scope = scope.clone();
+    match self.random.below(7) {
+
+      0 => {
+        let operation = [PrimitiveOp::Add, PrimitiveOp::Subtract, PrimitiveOp::Multiply, PrimitiveOp::Divide]
+                          [self.random.below(4)];
+        let values    = self.atoms(&scope, 2);
+        let variable  = self.names.fresh("x");
+        scope.integers.push(variable.clone());
+        ContinuationExpression::PrimitiveOp {
+          operation,
+          values,
+          variables  : vec![variable],
+          expressions: vec![Box::new(self.expression(&scope, depth - 1))]
+        }
+      }
+
+      1 => {
+        let operation = [PrimitiveOp::Less, PrimitiveOp::IEqual][self.random.below(2)];
+        ContinuationExpression::PrimitiveOp {
+          operation,
+          values     : self.atoms(&scope, 2),
+          variables  : vec![],
+          expressions: vec![
+            Box::new(self.expression(&scope, depth - 1)),
+            Box::new(self.expression(&scope, depth - 1))
+          ]
+        }
+      }
+
+      2 => {
+        let length   = 1 + self.random.below(4);
+        let values   = self.atoms(&scope, length)
+                           .into_iter()
+                           .map(|value| (value, Rc::new(AccessPath::Offset(0))))
+                           .collect();
+        let variable = self.names.fresh("r");
+        scope.records.push((variable.clone(), length, 0));
+        ContinuationExpression::Record { values, variable, expression: Box::new(self.expression(&scope, depth - 1)) }
+      }
+
+      3 if !scope.records.is_empty() => {
+        let (record, length, index) = scope.records[self.random.below(scope.records.len())].clone();
+        let location = self.random.below(length) as isize - index;
+        let variable = self.names.fresh("s");
+        scope.integers.push(variable.clone());
+        ContinuationExpression::Select {
+          location,
+          value     : Value::Variable(record),
+          variable,
+          expression: Box::new(self.expression(&scope, depth - 1))
+        }
+      }
+
+      4 if !scope.records.is_empty() => {
+        let (record, length, index) = scope.records[self.random.below(scope.records.len())].clone();
+        let location = self.random.below(length) as isize - index;
+        let variable = self.names.fresh("o");
+        scope.records.push((variable.clone(), length, index + location));
+        ContinuationExpression::Offset {
+          location,
+          value     : Value::Variable(record),
+          variable,
+          expression: Box::new(self.expression(&scope, depth - 1))
+        }
+      }
+
+      5 => {
+        let arm_count = 2 + self.random.below(2);
+        ContinuationExpression::Switch {
+          value: Value::Integer(self.random.below(arm_count) as Integer),
+          arms : (0..arm_count).map(|_| Rc::new(self.expression(&scope, depth - 1))).collect()
+        }
+      }
+
+      _ => {
+        // The functions of a group do not call each other, so every program halts.
+        let group: Vec<Rc<FunctionDefinition>> =
+          (0..1 + self.random.below(2)).map(|_| {
+            let parameters: VariableList = (0..1 + self.random.below(2)).map(|_| self.names.fresh("p")).collect();
+            let mut inner = scope.clone();
+            inner.integers.extend(parameters.iter().cloned());
+            let body = self.expression(&inner, depth - 1);
+            Rc::new(FunctionDefinition::new(self.names.fresh("f"), parameters, body))
+          }).collect();
+        scope.functions.extend(group.iter().map(|f| (f.header().0.clone(), f.header().1.len())));
+        ContinuationExpression::Fix {
+          function_defs: Rc::new(group),
+          expression   : Box::new(self.expression(&scope, depth - 1))
+        }
+      }
+
+    }
+  }
+
+  fn terminal(&mut self, scope: &Scope) -> ContinuationExpression {
+    if !scope.functions.is_empty() && self.random.below(2) == 0 {
+      let (function, arity) = scope.functions[self.random.below(scope.functions.len())].clone();
+      ContinuationExpression::Apply { function: Value::Variable(function), arguments: self.atoms(scope, arity) }
+    } else {
+      let count = 1 + self.random.below(2);
+      ContinuationExpression::Apply { function: Value::Variable(Variable::new("halt")), arguments: self.atoms(scope, count) }
+    }
+  }
+}
+
+/// A random program of the reference fragment, determined by `seed`.
+pub fn generate(seed: u64, depth: usize) -> ContinuationExpression {
+  let mut generator = Generator { random: Random(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1), names: Gensym::new() };
+  generator.expression(&Scope::default(), depth)
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  /// The final values of `expression` under the interpreter, rendered as `reference_eval`'s are.
+  fn interpret(expression: ContinuationExpression) -> String {
+    let answer = evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], expression).unwrap();
+    format!("{:?}", run(answer, 16))
+  }
+
+  fn render(outcome: Result<Vec<Integer>, Exception>) -> String {
+    match outcome {
+      Ok(values)     => format!("{:?}", values),
+      Err(exception) => format!("{:?}", vec![exception.as_record()])
+    }
+  }
+
+  #[test]
+  fn the_reference_evaluates_a_closure_over_an_offset_record() {
+    let program = parse(
+      "(record (1 2 3) r (offset 1 r q (fix ((f (a) (select 1 q y (primop + (a y) (z) ((app halt z))))))
+         (select -1 q x (app f x)))))"
+    ).unwrap();
+    assert_eq!(render(reference_eval(&program)), "[4]");
+    assert_eq!(interpret(program), "[4]");
+  }
+
+  #[test]
+  fn generated_programs_agree_with_the_reference() {
+    let mut exceptions = 0;
+    for seed in 0..200 {
+      let program  = generate(seed, 6);
+      let expected = reference_eval(&program);
+      exceptions  += expected.is_err() as usize;
+      assert_eq!(interpret(program.clone()), render(expected), "seed {}: {}", seed, program);
+    }
+    // The corpus exercises the exceptions as well as normal results.
+    assert!(exceptions > 0 && exceptions < 200, "{} of 200 programs raised", exceptions);
+  }
+}
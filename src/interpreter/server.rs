@@ -0,0 +1,344 @@
+/*!
+
+  A JSON-RPC server for remote evaluation, for editors and notebooks. It reads one request per
+  line and writes one response per line, following the shape of JSON-RPC 2.0. The server is built
+  only with the `server` feature, and `cps_compiler --server` serves stdin and stdout.
+
+  A request is an object
+
+  ```text
+  {"jsonrpc": "2.0", "id": 1, "method": "eval", "params": {"program": "(app halt 42)"}}
+  ```
+
+  where `id` is any JSON value and is echoed in the response. The `params` of every method are
+
+  - `program`, required: the program in the S-expression syntax of `parser::parse`;
+  - `bindings`, optional: an object binding further variables of the program to integers, reals,
+    or strings. The variable `halt` is always bound to a continuation that ends the program;
+  - `fuel`, optional: the maximum number of steps of the trampoline to take (see
+    `run_with_fuel`), ten million if absent.
+
+  The methods are
+
+  - `eval`: runs the program to the end. The result is `{"values": [...]}`, the final values of
+    the program. Running out of fuel is an error;
+  - `step`: runs the program for at most `fuel` steps. The result is `{"halted": true, "values":
+    [...]}` if the program ended within them and `{"halted": false}` otherwise;
+  - `trace`: like `eval`, with the steps of the evaluation logged by a `WriteTracer`. The result is
+    `{"values": [...], "trace": ["0: Apply (1 binding)", ...]}`.
+
+  Final values are encoded as JSON: integers and reals as numbers, strings as strings, records as
+  `{"record": [fields...]}` (with `"offset"` if the record is offset), arrays as `{"array": kind,
+  "start": s, "end": e}` with the range of store cells they occupy, functions as `{"function":
+  name}` (the name is `null` unless a `Fix` defined the function), and exceptions as
+  `{"exception": name}`. An uncaught exception ends a program with its record encoding.
+
+  A successful response is `{"jsonrpc": "2.0", "id": ..., "result": ...}`, and a failed one is
+  `{"jsonrpc": "2.0", "id": ..., "error": {"code": c, "message": m}}` with the codes of JSON-RPC:
+  -32700 for a line that is not JSON, -32600 for a request that is not an object with a `method`,
+  -32601 for an unknown method, and -32602 for missing or malformed `params`, including a program
+  that does not parse. A program that runs out of fuel under `eval` or `trace` is -32000.
+
+  Programs are untrusted, so each request is evaluated in a fresh `EvalContext` whose store may
+  grow to at most `MAX_STORE_SIZE` cells; an allocation past it raises `OutOfMemory` in the
+  program. A line nested more than `json::MAX_DEPTH` deep is not JSON, and a program nested more
+  than `parser::MAX_NESTING` deep does not parse, so neither can overflow the server's stack.
+
+*/
+
+use std::cell::RefCell;
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Variable,
+  context::EvalContext,
+  cps::{
+    continuation::Answer,
+    denotable_value::{DValue, DenotableValueList},
+    store::Store
+  },
+  evaluate,
+  json::{self, Json},
+  parser,
+  run_with_fuel,
+  tracer::WriteTracer
+};
+
+/// The number of trampoline steps a request may take if it does not say.
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// The number of cells of the store each program starts with.
+const STORE_SIZE: usize = 16;
+
+/// The number of cells the store of a program may grow to.
+pub const MAX_STORE_SIZE: usize = 1 << 20;
+
+const PARSE_ERROR     : i64 = -32700;
+const INVALID_REQUEST : i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS  : i64 = -32602;
+const OUT_OF_FUEL     : i64 = -32000;
+
+/// A failed request: a JSON-RPC error code and a message.
+type Failure = (i64, String);
+
+/// Answers each line of `input` with a line of `output` until `input` ends. Blank lines are
+/// skipped.
+pub fn serve<R: BufRead, W: Write>(input: R, mut output: W) -> io::Result<()> {
+  for line in input.lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    writeln!(output, "{}", handle_line(&line))?;
+    output.flush()?;
+  }
+  Ok(())
+}
+
+/// The response to the request on `line`.
+pub fn handle_line(line: &str) -> Json {
+  let request = match json::parse(line) {
+    Ok(request) => request,
+    Err(error)  => return response(Json::Null, Err((PARSE_ERROR, error.to_string())))
+  };
+  let id = request.get("id").cloned().unwrap_or(Json::Null);
+  let method = match request.get("method").and_then(Json::as_str) {
+    Some(method) => method,
+    None         => return response(id, Err((INVALID_REQUEST, "a request needs a `method`".to_string())))
+  };
+  let params = request.get("params").cloned().unwrap_or_else(|| Json::Object(vec![]));
+
+  let previous = EvalContext::new().with_max_store_size(MAX_STORE_SIZE).install();
+  let result = match method {
+    "eval"  => eval(&params),
+    "step"  => step(&params),
+    "trace" => trace(&params),
+    _       => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method)))
+  };
+  previous.install();
+  response(id, result)
+}
+
+fn response(id: Json, result: Result<Json, Failure>) -> Json {
+  let outcome = match result {
+    Ok(result)           => ("result", result),
+    Err((code, message)) => (
+      "error",
+      Json::object(vec![("code", Json::Integer(code)), ("message", Json::String(message))])
+    )
+  };
+  Json::object(vec![("jsonrpc", Json::String("2.0".to_string())), ("id", id), outcome])
+}
+
+fn eval(params: &Json) -> Result<Json, Failure> {
+  let (answer, fuel) = prepare(params)?;
+  let values = run_to_end(answer, fuel)?;
+  Ok(Json::object(vec![("values", values_to_json(&values))]))
+}
+
+fn step(params: &Json) -> Result<Json, Failure> {
+  let (answer, fuel) = prepare(params)?;
+  match run_with_fuel(answer, Store::new(STORE_SIZE), fuel) {
+    Ok(answer) => Ok(Json::object(vec![
+      ("halted", Json::Bool(true)),
+      ("values", values_to_json(answer.parameters()))
+    ])),
+    Err(_)     => Ok(Json::object(vec![("halted", Json::Bool(false))]))
+  }
+}
+
+fn trace(params: &Json) -> Result<Json, Failure> {
+  // The tracer is installed before `prepare`, which already evaluates the program up to its
+  // first application.
+  let tracer   = Rc::new(RefCell::new(WriteTracer::new(Vec::new())));
+  let context  = EvalContext::with_current(|context| context.clone()).with_tracer(tracer.clone());
+  let previous = context.install();
+  let values   = prepare(params).and_then(|(answer, fuel)| run_to_end(answer, fuel));
+  previous.install();
+
+  let log = String::from_utf8_lossy(tracer.borrow().writer()).into_owned();
+  Ok(Json::object(vec![
+    ("values", values_to_json(&values?)),
+    ("trace", Json::Array(log.lines().map(|line| Json::String(line.to_string())).collect()))
+  ]))
+}
+
+fn run_to_end(answer: Answer, fuel: u64) -> Result<DenotableValueList, Failure> {
+  match run_with_fuel(answer, Store::new(STORE_SIZE), fuel) {
+    Ok(answer) => Ok(answer.parameters().clone()),
+    Err(_)     => Err((OUT_OF_FUEL, format!("the program did not halt within {} steps", fuel)))
+  }
+}
+
+/// The first step of the program of `params`, with its bindings, and the fuel to run it with.
+fn prepare(params: &Json) -> Result<(Answer, u64), Failure> {
+  let invalid = | message: String | (INVALID_PARAMS, message);
+
+  let source = params.get("program")
+                     .and_then(Json::as_str)
+                     .ok_or_else(|| invalid("`params.program` must be a string".to_string()))?;
+  let program = parser::parse(source).map_err(|error| invalid(format!("program {}", error)))?;
+
+  let fuel = match params.get("fuel") {
+    None       => DEFAULT_FUEL,
+    Some(fuel) => fuel.as_i64()
+                      .and_then(|fuel| u64::try_from(fuel).ok())
+                      .ok_or_else(|| invalid("`params.fuel` must be a non-negative integer".to_string()))?
+  };
+
+  let mut variables = vec![Variable::new("halt")];
+  let mut values    = vec![Store::default_handler()];
+  match params.get("bindings") {
+    None                         => {}
+    Some(Json::Object(bindings)) => {
+      for (name, value) in bindings {
+        variables.push(Variable::new(name));
+        values.push(json_to_value(value).ok_or_else(
+          || invalid(format!("the binding of `{}` must be an integer, a real, or a string", name))
+        )?);
+      }
+    }
+    Some(_)                      => return Err(invalid("`params.bindings` must be an object".to_string()))
+  }
+
+  let answer = evaluate(variables, values, program).expect("as many values as variables");
+  Ok((answer, fuel))
+}
+
+#[allow(clippy::useless_conversion)] // `Integer` is `i32` under the `int32` feature.
+fn json_to_value(value: &Json) -> Option<DValue> {
+  match value {
+    Json::Integer(i) => Some(DValue::Integer((*i).try_into().ok()?)),
+    Json::Real(x)    => Some(DValue::Real((*x as f32).into())),
+    Json::String(s)  => Some(DValue::String(s.clone())),
+    _                => None
+  }
+}
+
+fn values_to_json(values: &[DValue]) -> Json {
+  Json::Array(values.iter().map(value_to_json).collect())
+}
+
+/// The encoding of a final value described in the module documentation.
+#[allow(clippy::unnecessary_cast)] // `Integer` is `i32` under the `int32` feature.
+pub fn value_to_json(value: &DValue) -> Json {
+  match value {
+
+    DValue::Integer(i) => Json::Integer(*i as i64),
+
+    // The shortest decimal form of the single precision real, rather than its exact double.
+    DValue::Real(x) => Json::Real(x.0.to_string().parse().unwrap_or(f64::NAN)),
+
+    DValue::String(s) => Json::String(s.clone()),
+
+    DValue::Record { values, idx } => {
+      let mut members = vec![("record", values_to_json(values))];
+      if *idx != 0 {
+        members.push(("offset", Json::Integer(*idx as i64)));
+      }
+      Json::object(members)
+    }
+
+    | DValue::Array(range)
+    | DValue::UnboxedArray(range)
+    | DValue::ByteArray(range)
+    | DValue::WeakRef(range) => Json::object(vec![
+      ("array", Json::String(value.kind().to_string())),
+      ("start", Json::Integer(range.start as i64)),
+      ("end", Json::Integer(range.end as i64))
+    ]),
+
+    DValue::Function(_) => {
+      let name = value.closure_info()
+                      .map_or(Json::Null, |info| Json::String(info.name.name().to_string()));
+      Json::object(vec![("function", name)])
+    }
+
+    DValue::Exception(exception) => {
+      Json::object(vec![("exception", Json::String(format!("{:?}", exception)))])
+    }
+
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(line: &str) -> String {
+    handle_line(line).to_string()
+  }
+
+  #[test]
+  fn eval_returns_the_final_values() {
+    assert_eq!(
+      request(r#"{"jsonrpc":"2.0","id":7,"method":"eval","params":{"program":"(primop + (x 2) (y) ((app halt y \"done\")))","bindings":{"x":40}}}"#),
+      r#"{"jsonrpc":"2.0","id":7,"result":{"values":[42,"done"]}}"#
+    );
+    assert_eq!(
+      request(r#"{"id":1,"method":"eval","params":{"program":"(primop div (1 0) (x) ((app halt x)))"}}"#),
+      r#"{"jsonrpc":"2.0","id":1,"result":{"values":[{"record":[1,"DivideByZero"]}]}}"#
+    );
+  }
+
+  #[test]
+  fn step_and_trace() {
+    let looping = r#""(fix ((f (x) (app f x))) (app f 0))""#;
+    assert_eq!(
+      request(&format!(r#"{{"id":2,"method":"step","params":{{"program":{},"fuel":50}}}}"#, looping)),
+      r#"{"jsonrpc":"2.0","id":2,"result":{"halted":false}}"#
+    );
+    assert_eq!(
+      request(&format!(r#"{{"id":3,"method":"eval","params":{{"program":{},"fuel":50}}}}"#, looping)),
+      r#"{"jsonrpc":"2.0","id":3,"error":{"code":-32000,"message":"the program did not halt within 50 steps"}}"#
+    );
+
+    let traced = handle_line(r#"{"id":4,"method":"trace","params":{"program":"(record (1) r (app halt r))"}}"#);
+    let result = traced.get("result").unwrap();
+    assert_eq!(result.get("values").unwrap().to_string(), r#"[{"record":[1]}]"#);
+    assert_eq!(result.get("trace").unwrap().to_string(), r#"["0: Record r (1 binding)","1: Apply (2 bindings)"]"#);
+  }
+
+  #[test]
+  fn malformed_requests_are_errors() {
+    let code = | line: &str | handle_line(line).get("error").and_then(|e| e.get("code")).and_then(Json::as_i64);
+    assert_eq!(code("not json"), Some(PARSE_ERROR));
+    assert_eq!(code(r#"{"id":1}"#), Some(INVALID_REQUEST));
+    assert_eq!(code(r#"{"id":1,"method":"compile"}"#), Some(METHOD_NOT_FOUND));
+    assert_eq!(code(r#"{"id":1,"method":"eval","params":{"program":"(app"}}"#), Some(INVALID_PARAMS));
+    assert_eq!(code(r#"{"id":1,"method":"eval","params":{"program":"(app halt)","bindings":{"x":[]}}}"#), Some(INVALID_PARAMS));
+  }
+
+  #[test]
+  fn serve_answers_each_line() {
+    let input  = "{\"id\":1,\"method\":\"eval\",\"params\":{\"program\":\"(app halt 1)\"}}\n\n{\"id\":2,\"method\":\"nope\"}\n";
+    let mut output = Vec::new();
+    serve(input.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.lines().count(), 2);
+    assert!(output.starts_with(r#"{"jsonrpc":"2.0","id":1,"result":{"values":[1]}}"#));
+  }
+
+  #[test]
+  fn a_program_cannot_grow_the_store_past_the_limit() {
+    let allocate = | n: usize | request(&format!(
+      r#"{{"id":5,"method":"eval","params":{{"program":"(primop mkarray ({} 0) (a) ((primop alength (a) (n) ((app halt n)))))"}}}}"#,
+      n
+    ));
+    assert_eq!(allocate(MAX_STORE_SIZE + 1), r#"{"jsonrpc":"2.0","id":5,"result":{"values":[{"record":[6,"OutOfMemory"]}]}}"#);
+    assert_eq!(allocate(1000), r#"{"jsonrpc":"2.0","id":5,"result":{"values":[1000]}}"#);
+    // The limit is the server's, not the caller's.
+    assert_eq!(EvalContext::with_current(|context| context.max_store_size), None);
+  }
+
+  #[test]
+  fn requests_nested_too_deeply_are_errors() {
+    let code = | line: &str | handle_line(line).get("error").and_then(|e| e.get("code")).and_then(Json::as_i64);
+    assert_eq!(code(&"[".repeat(100_000)), Some(PARSE_ERROR));
+    let program = "(".repeat(100_000);
+    assert_eq!(code(&format!(r#"{{"id":1,"method":"eval","params":{{"program":"{}"}}}}"#, program)), Some(INVALID_PARAMS));
+  }
+}
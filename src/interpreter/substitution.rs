@@ -0,0 +1,193 @@
+/*!
+
+  Capture-avoiding substitution of values for variables, the basis of renaming, inlining, and
+  constant propagation.
+
+  A substitution replaces the free occurrences of variables and labels with values, following the
+  scope rules documented on each `ContinuationExpression` variant: it stops at a binder that
+  rebinds a substituted variable. Where a binder would capture a variable occurring in a
+  substituted value, the binder is renamed to a fresh variable (see
+  `ContinuationExpression::fresh_variable`) throughout its scope first.
+
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::interpreter::{
+  Variable,
+  VariableList,
+  continuation_expression::{ContinuationExpression, FunctionDefinition},
+  value::Value
+};
+
+/// A mapping from variables to the values that replace their free occurrences.
+pub type Substitution = HashMap<Variable, Value>;
+
+impl ContinuationExpression {
+  /// The expression with each free occurrence of a variable or label in the domain of
+  /// `substitution` replaced by its value. An occurrence that is a label stays a label when it
+  /// is replaced by a variable.
+  pub fn substitute(&self, substitution: &Substitution) -> ContinuationExpression {
+    if substitution.is_empty() {
+      return self.clone();
+    }
+
+    match self {
+
+      ContinuationExpression::Record { values, variable, expression } => {
+        let values = values.iter()
+                           .map(|(value, access_path)| (substitute_value(value, substitution), access_path.clone()))
+                           .collect();
+        let (variables, inner) = enter_scope(std::slice::from_ref(variable), substitution);
+        ContinuationExpression::Record {
+          values,
+          variable  : variables.into_iter().next().unwrap(),
+          expression: Box::new(expression.substitute(&inner))
+        }
+      }
+
+      ContinuationExpression::Select { location, value, variable, expression } => {
+        let (variables, inner) = enter_scope(std::slice::from_ref(variable), substitution);
+        ContinuationExpression::Select {
+          location  : *location,
+          value     : substitute_value(value, substitution),
+          variable  : variables.into_iter().next().unwrap(),
+          expression: Box::new(expression.substitute(&inner))
+        }
+      }
+
+      ContinuationExpression::Offset { location, value, variable, expression } => {
+        let (variables, inner) = enter_scope(std::slice::from_ref(variable), substitution);
+        ContinuationExpression::Offset {
+          location  : *location,
+          value     : substitute_value(value, substitution),
+          variable  : variables.into_iter().next().unwrap(),
+          expression: Box::new(expression.substitute(&inner))
+        }
+      }
+
+      ContinuationExpression::Apply { function, arguments } => {
+        ContinuationExpression::Apply {
+          function : substitute_value(function, substitution),
+          arguments: arguments.iter().map(|value| substitute_value(value, substitution)).collect()
+        }
+      }
+
+      ContinuationExpression::Fix { function_defs, expression } => {
+        // The function names scope over every body and the continuation.
+        let names: VariableList = function_defs.iter().map(|fd| fd.header().0.clone()).collect();
+        let (names, scope) = enter_scope(&names, substitution);
+
+        let function_defs = function_defs.iter().zip(names).map(
+          | (function_def, name) | {
+            let (formal_parameters, inner) = enter_scope(function_def.header().1, &scope);
+            Rc::new(FunctionDefinition::new(name, formal_parameters, function_def.body().substitute(&inner)))
+          }
+        ).collect();
+
+        ContinuationExpression::Fix {
+          function_defs: Rc::new(function_defs),
+          expression   : Box::new(expression.substitute(&scope))
+        }
+      }
+
+      ContinuationExpression::Switch { value, arms } => {
+        ContinuationExpression::Switch {
+          value: substitute_value(value, substitution),
+          arms : arms.iter().map(|arm| Rc::new(arm.substitute(substitution))).collect()
+        }
+      }
+
+      ContinuationExpression::PrimitiveOp { operation, values, variables, expressions } => {
+        let (variables, inner) = enter_scope(variables, substitution);
+        ContinuationExpression::PrimitiveOp {
+          operation  : *operation,
+          values     : values.iter().map(|value| substitute_value(value, substitution)).collect(),
+          variables,
+          expressions: expressions.iter().map(|e| Box::new(e.substitute(&inner))).collect()
+        }
+      }
+
+    }
+  }
+
+  /// The expression with each free occurrence of `old` replaced by `new`, renaming any binder
+  /// that would capture `new`. Occurrences of `old` within the scope of a binder of `old` are
+  /// bound by it and are left alone.
+  pub fn rename(&self, old: &Variable, new: &Variable) -> ContinuationExpression {
+    let mut substitution = Substitution::new();
+    substitution.insert(old.clone(), Value::Variable(new.clone()));
+    self.substitute(&substitution)
+  }
+}
+
+fn substitute_value(value: &Value, substitution: &Substitution) -> Value {
+  match value {
+    Value::Variable(v) => substitution.get(v).cloned().unwrap_or_else(|| value.clone()),
+    Value::Label(v)    => {
+      match substitution.get(v) {
+        Some(Value::Variable(w)) => Value::Label(w.clone()),
+        Some(replacement)        => replacement.clone(),
+        None                     => value.clone()
+      }
+    }
+    literal            => literal.clone()
+  }
+}
+
+/// The substitution that applies in the scope of `binders`, together with the binders to use.
+/// Substitution stops at the binders, and a binder that occurs in one of the values still being
+/// substituted is replaced by a fresh variable so that it does not capture it.
+fn enter_scope(binders: &[Variable], substitution: &Substitution) -> (VariableList, Substitution) {
+  let mut inner = substitution.clone();
+  for binder in binders {
+    inner.remove(binder);
+  }
+
+  let captured: HashSet<Variable> = inner.values()
+                                         .filter_map(|value| match value {
+                                           Value::Variable(v) | Value::Label(v) => Some(v.clone()),
+                                           _                                    => None
+                                         })
+                                         .collect();
+  let binders = binders.iter().map(
+    | binder | {
+      if captured.contains(binder) {
+        let fresh = ContinuationExpression::fresh_variable(binder.name());
+        inner.insert(binder.clone(), Value::Variable(fresh.clone()));
+        fresh
+      } else {
+        binder.clone()
+      }
+    }
+  ).collect();
+
+  (binders, inner)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::interpreter::{Variable, continuation_expression::ContinuationExpression, parser::parse};
+
+  #[test]
+  fn rename_stops_at_a_binder_of_the_old_name() {
+    let expression = parse("(primop + (x 1) (x) ((app k x)))").unwrap();
+    let renamed = expression.rename(&Variable::new("x"), &Variable::new("y"));
+    assert_eq!(renamed, parse("(primop + (y 1) (x) ((app k x)))").unwrap());
+  }
+
+  #[test]
+  fn rename_renames_a_binder_that_would_capture_the_new_name() {
+    let expression = parse("(record (1) y (app k x y))").unwrap();
+    let renamed = expression.rename(&Variable::new("x"), &Variable::new("y"));
+    match &renamed {
+      ContinuationExpression::Record { variable, .. } => {
+        assert_ne!(variable.name(), "y");
+        let expected = format!("(record (1) {} (app k y {}))", variable.name(), variable.name());
+        assert_eq!(renamed, parse(&expected).unwrap());
+      }
+      _ => panic!("expected a record, found {}", renamed)
+    }
+  }
+}
@@ -0,0 +1,179 @@
+/*!
+
+  A `Tracer` is called on every step of `ContinuationExpression::evaluate`, that is, every time
+  an expression is about to be evaluated, with the kind of the expression, the variables it binds,
+  and the environment it is evaluated in. This makes the order in which the interpreter takes the
+  program apart visible, which is useful both for debugging and for teaching the semantics.
+
+  A tracer is installed with `EvalContext::with_tracer`. Without one, tracing costs a check of the
+  context and nothing more. `WriteTracer` writes a readable log of the steps:
+
+  ```text
+  0: Fix f (1 binding)
+  1: Apply (2 bindings)
+  2: PrimitiveOp y (3 bindings)
+  ```
+
+*/
+
+use std::io;
+
+use crate::interpreter::{
+  Variable,
+  context::EvalContext,
+  continuation_expression::ContinuationExpression,
+  environment::Environment
+};
+
+pub trait Tracer {
+  /// An expression of the variant `kind` (see `ContinuationExpression::kind`) is about to be
+  /// evaluated in `environment`. `bound` holds the variables it binds: the variable of a
+  /// `Record`, `Select`, or `Offset`, the function names of a `Fix`, and the result variables of
+  /// a `PrimitiveOp`. Environments are persistent, so cloning `environment` keeps a snapshot of it
+  /// cheaply.
+  fn on_step(&mut self, kind: &'static str, bound: &[Variable], environment: &Environment);
+}
+
+/// Calls the tracer of the current context, if there is one, on the evaluation of `expression`
+/// in `environment`. The tracer is called outside of the borrow of the context, but it must not
+/// evaluate anything itself, as it is borrowed mutably while it runs.
+pub(crate) fn trace(expression: &ContinuationExpression, environment: &Environment) {
+  if let Some(tracer) = EvalContext::with_current(|context| context.tracer.clone()) {
+    let bound = match expression {
+      | ContinuationExpression::Record { variable, .. }
+      | ContinuationExpression::Select { variable, .. }
+      | ContinuationExpression::Offset { variable, .. } => vec![variable.clone()],
+
+      ContinuationExpression::Fix { function_defs, .. } => {
+        function_defs.iter().map(|function_def| function_def.header().0.clone()).collect()
+      }
+
+      ContinuationExpression::PrimitiveOp { variables, .. } => variables.clone(),
+
+      | ContinuationExpression::Apply { .. }
+      | ContinuationExpression::Switch { .. } => vec![]
+    };
+    tracer.borrow_mut().on_step(expression.kind(), &bound, environment);
+  }
+}
+
+/// Writes one line per step to a writer: the number of the step, the kind of the expression, the
+/// variables it binds, and the number of bindings in its environment. Writing stops at the first
+/// error, which is kept in `error`.
+pub struct WriteTracer<W: io::Write> {
+  writer: W,
+  steps : usize,
+  error : Option<io::Error>
+}
+
+impl<W: io::Write> WriteTracer<W> {
+  pub fn new(writer: W) -> Self {
+    WriteTracer {
+      writer,
+      steps: 0,
+      error: None
+    }
+  }
+
+  pub fn writer(&self) -> &W {
+    &self.writer
+  }
+
+  /// The error that stopped the log, if writing has failed.
+  pub fn error(&self) -> Option<&io::Error> {
+    self.error.as_ref()
+  }
+}
+
+impl<W: io::Write> Tracer for WriteTracer<W> {
+  fn on_step(&mut self, kind: &'static str, bound: &[Variable], environment: &Environment) {
+    if self.error.is_some() {
+      return;
+    }
+
+    let mut line = format!("{}: {}", self.steps, kind);
+    for variable in bound {
+      line.push(' ');
+      line.push_str(variable.name());
+    }
+    let bindings = environment.len();
+    line.push_str(&format!(" ({} binding{})", bindings, if bindings == 1 { "" } else { "s" }));
+
+    self.steps += 1;
+    if let Err(error) = writeln!(self.writer, "{}", line) {
+      self.error = Some(error);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::RefCell, rc::Rc};
+
+  use super::*;
+  use crate::interpreter::tests::run_source;
+
+  /// Runs `source` with a `WriteTracer` installed and returns its log.
+  fn trace_of(source: &str) -> String {
+    let tracer   = Rc::new(RefCell::new(WriteTracer::new(Vec::new())));
+    let previous = EvalContext::new().with_tracer(tracer.clone()).install();
+    run_source(source);
+    previous.install();
+    let log = String::from_utf8(tracer.borrow().writer().clone()).unwrap();
+    log
+  }
+
+  /// Keeps every step with a snapshot of its environment.
+  #[derive(Default)]
+  struct Recorder {
+    steps: Vec<(&'static str, Vec<Variable>, Environment)>
+  }
+
+  impl Tracer for Recorder {
+    fn on_step(&mut self, kind: &'static str, bound: &[Variable], environment: &Environment) {
+      self.steps.push((kind, bound.to_vec(), environment.clone()));
+    }
+  }
+
+  const PROGRAM: &str = "(fix ((f (x k) (primop + (x 1) (y) ((switch 0 (app k y) (app k x)))))) (app f 41 halt))";
+
+  #[test]
+  fn the_log_lists_each_step_in_order() {
+    assert_eq!(
+      trace_of(PROGRAM),
+      "0: Fix f (1 binding)\n\
+       1: Apply (2 bindings)\n\
+       2: PrimitiveOp y (3 bindings)\n\
+       3: Switch (4 bindings)\n\
+       4: Apply (4 bindings)\n"
+    );
+  }
+
+  #[test]
+  fn each_step_sees_the_environment_it_is_evaluated_in() {
+    let recorder = Rc::new(RefCell::new(Recorder::default()));
+    let previous = EvalContext::new().with_tracer(recorder.clone()).install();
+    run_source(PROGRAM);
+    previous.install();
+
+    let steps = &recorder.borrow().steps;
+    let kinds: Vec<&str> = steps.iter().map(|(kind, _, _)| *kind).collect();
+    assert_eq!(kinds, ["Fix", "Apply", "PrimitiveOp", "Switch", "Apply"]);
+    assert_eq!(steps[0].1, vec![Variable::new("f")]);
+    assert_eq!(steps[2].1, vec![Variable::new("y")]);
+
+    let value_of = | step: usize, name: &str | steps[step].2.get(&Variable::new(name)).map(|value| format!("{:?}", value));
+    assert_eq!(value_of(2, "x"), Some("41".to_string()));
+    assert_eq!(value_of(2, "y"), None);
+    assert_eq!(value_of(4, "y"), Some("42".to_string()));
+  }
+
+  #[test]
+  fn without_a_tracer_nothing_is_traced() {
+    let recorder = Rc::new(RefCell::new(Recorder::default()));
+    let previous = EvalContext::new().with_tracer(recorder.clone()).install();
+    previous.install();
+    run_source(PROGRAM);
+    assert!(recorder.borrow().steps.is_empty());
+  }
+}
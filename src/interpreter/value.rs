@@ -6,6 +6,7 @@
 
 */
 
+use std::fmt::{self, Display, Formatter};
 use std::hash::{Hash, Hasher};
 
 use super::{
@@ -81,3 +82,31 @@ impl Hash for Value{
       }
     }
 }
+
+/// Renders a variable as its name and a label as its name prefixed with `@`. Literals render as
+/// themselves, with reals always having a fractional part or exponent and strings quoted.
+impl Display for Value {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Value::Variable(v) => write!(f, "{}", v.name()),
+      Value::Label(v)    => write!(f, "@{}", v.name()),
+      Value::Integer(i)  => write!(f, "{}", i),
+      Value::Real(r)     => write!(f, "{:?}", r.0),
+      Value::String(s)   => write!(f, "{:?}", s),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn values_render_as_in_the_source() {
+    assert_eq!(Value::Variable(Variable::new("x")).to_string(), "x");
+    assert_eq!(Value::Label(Variable::new("f")).to_string(), "@f");
+    assert_eq!(Value::Integer(-7).to_string(), "-7");
+    assert_eq!(Value::Real(2.0.into()).to_string(), "2.0");
+    assert_eq!(Value::String("a\"b".to_string()).to_string(), r#""a\"b""#);
+  }
+}
@@ -0,0 +1,118 @@
+/*!
+
+  A `Visitor` traverses a `ContinuationExpression` read-only. Each `visit_*` method is called on
+  the nodes of one variant and by default continues into the node's children, so an analysis only
+  overrides the cases it cares about. For example, counting the `Apply` nodes:
+
+  ```text
+  struct CountApplies(usize);
+
+  impl Visitor for CountApplies {
+    fn visit_apply(&mut self, _expression: &ContinuationExpression) {
+      self.0 += 1;
+    }
+  }
+
+  let mut counter = CountApplies(0);
+  walk(&mut counter, &expression);
+  ```
+
+  An overriding method that should also visit the children calls `walk_children`.
+
+*/
+
+use crate::interpreter::continuation_expression::ContinuationExpression;
+
+pub trait Visitor {
+  fn visit_record(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+
+  fn visit_select(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+
+  fn visit_offset(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+
+  fn visit_apply(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+
+  /// Visits a `Fix`. Its children are the function bodies followed by the continuation.
+  fn visit_fix(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+
+  fn visit_switch(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+
+  fn visit_primop(&mut self, expression: &ContinuationExpression) {
+    walk_children(self, expression);
+  }
+}
+
+/// Visits `expression` with the `visit_*` method of `visitor` for its variant.
+pub fn walk<V: Visitor + ?Sized>(visitor: &mut V, expression: &ContinuationExpression) {
+  match expression {
+    ContinuationExpression::Record { .. }      => visitor.visit_record(expression),
+    ContinuationExpression::Select { .. }      => visitor.visit_select(expression),
+    ContinuationExpression::Offset { .. }      => visitor.visit_offset(expression),
+    ContinuationExpression::Apply { .. }       => visitor.visit_apply(expression),
+    ContinuationExpression::Fix { .. }         => visitor.visit_fix(expression),
+    ContinuationExpression::Switch { .. }      => visitor.visit_switch(expression),
+    ContinuationExpression::PrimitiveOp { .. } => visitor.visit_primop(expression),
+  }
+}
+
+/// Walks each child of `expression` in order. See `ContinuationExpression::children`.
+pub fn walk_children<V: Visitor + ?Sized>(visitor: &mut V, expression: &ContinuationExpression) {
+  for child in expression.children() {
+    walk(visitor, child);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interpreter::parser::parse;
+
+  struct CountApplies(usize);
+
+  impl Visitor for CountApplies {
+    fn visit_apply(&mut self, _expression: &ContinuationExpression) {
+      self.0 += 1;
+    }
+  }
+
+  /// Counts the `Switch` nodes that are not inside a `Fix`, by not walking the children of one.
+  struct CountSwitchesOutsideFix(usize);
+
+  impl Visitor for CountSwitchesOutsideFix {
+    fn visit_fix(&mut self, _expression: &ContinuationExpression) {}
+
+    fn visit_switch(&mut self, expression: &ContinuationExpression) {
+      self.0 += 1;
+      walk_children(self, expression);
+    }
+  }
+
+  #[test]
+  fn a_visitor_overrides_only_the_cases_it_cares_about() {
+    let expression = parse(
+      "(switch i
+         (fix ((f (x) (switch x (app k 0) (app k 1)))) (app f 0))
+         (record (1) r (primop + (i 1) (j) ((switch j (app k r) (app k j))))))"
+    ).unwrap();
+
+    let mut applies = CountApplies(0);
+    walk(&mut applies, &expression);
+    assert_eq!(applies.0, 5);
+
+    let mut switches = CountSwitchesOutsideFix(0);
+    walk(&mut switches, &expression);
+    assert_eq!(switches.0, 2);
+  }
+}
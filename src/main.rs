@@ -1,10 +1,66 @@
 #![feature(unboxed_closures)] // To make `ContinuationExpression` a callable struct
 #![feature(fn_traits)]
-#![feature(arc_unwrap_or_clone)]
-#![feature(get_mut_unchecked)]        // To make `ContinuationExpression` a callable struct
 
 mod interpreter;
 
+use std::{env, fs, process};
+
+use interpreter::{
+    Variable,
+    bench::bench,
+    cps::store::Store,
+    evaluate,
+    parser::parse,
+    run
+};
+
+#[cfg(not(feature = "server"))]
+const USAGE: &str = "usage: cps_compiler [--bench N] <file>";
+#[cfg(feature = "server")]
+const USAGE: &str = "usage: cps_compiler [--bench N] <file> | --server";
+
+/// Runs the program in the given file with `halt` bound to a continuation that ends it, and
+/// prints its final values. With `--bench N`, runs it `N` times and also prints a line of timings
+/// (see `BenchReport`). With `--server`, if the `server` feature is enabled, answers JSON-RPC
+/// requests on stdin instead (see `interpreter::server`).
 fn main() {
-    println!("Hello, world!");
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    #[cfg(feature = "server")]
+    if arguments.len() == 1 && arguments[0] == "--server" {
+        let stdin = std::io::stdin();
+        if let Err(error) = interpreter::server::serve(stdin.lock(), std::io::stdout()) {
+            fail(&error.to_string());
+        }
+        return;
+    }
+
+    let (iterations, path) = match arguments.as_slice() {
+        [path] => (None, path),
+        [flag, n, path] if flag == "--bench" => match n.parse::<u32>() {
+            Ok(n) if n > 0 => (Some(n), path),
+            _              => fail("--bench takes a positive number of iterations"),
+        },
+        _ => fail(USAGE),
+    };
+
+    let source  = fs::read_to_string(path).unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+    let program = parse(&source).unwrap_or_else(|error| fail(&format!("{}: {}", path, error)));
+
+    match iterations {
+        Some(iterations) => {
+            let report = bench(&program, iterations);
+            println!("{:?}", report.values);
+            println!("{}", report);
+        }
+        None => {
+            let answer = evaluate(vec![Variable::new("halt")], vec![Store::default_handler()], program)
+                           .expect("one variable is bound to one value");
+            println!("{:?}", run(answer, 16));
+        }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2)
 }